@@ -60,6 +60,12 @@ pub(crate) fn build_sysroot(
     fs::create_dir_all(&host_rustlib_lib).unwrap();
     fs::create_dir_all(&target_rustlib_lib).unwrap();
 
+    // Targets shipped with cg_lua are found by rustc in `lib/rustlib/<triple>/target.json`
+    let target_spec = Path::new("target_specs").join(format!("{}.json", target_triple));
+    if target_spec.exists() {
+        try_hard_link(&target_spec, rustlib.join(target_triple).join("target.json"));
+    }
+
     if target_triple == "x86_64-pc-windows-gnu" {
         if !default_sysroot
             .join("lib")
@@ -216,6 +222,9 @@ fn build_lua_sysroot_for_triple(
         build_cmd.arg("--release");
         rustflags.push_str(" -Zmir-opt-level=3");
     }
+    if crate::config::get_bool("panic_unwind") {
+        rustflags.push_str(" -Cpanic=unwind");
+    }
     if let Some(linker) = linker {
         use std::fmt::Write;
         write!(rustflags, " -Clinker={}", linker).unwrap();
@@ -85,6 +85,7 @@ fn prepare_sysroot() {
     spawn_and_wait(git_commit_cmd);
 
     apply_patches("sysroot", &sysroot_src);
+    add_lua_platform(&sysroot_src);
 
     clone_repo(
         "build_sysroot/compiler-builtins",
@@ -94,6 +95,58 @@ fn prepare_sysroot() {
     apply_patches("compiler-builtins", Path::new("build_sysroot/compiler-builtins"));
 }
 
+/// Adds the `lua` platform of std in `sys_lua` to the sysroot source and selects it for
+/// `target_os = "lua"`.
+fn add_lua_platform(sysroot_src: &Path) {
+    eprintln!("[COPY] lua platform");
+    let std_src = sysroot_src.join("library").join("std");
+    fs::create_dir_all(std_src.join("src/sys/lua")).unwrap();
+    copy_dir_recursively(Path::new("sys_lua"), &std_src.join("src/sys/lua"));
+
+    // Don't mark std as unstable with the `restricted-std` feature
+    insert_before(
+        &std_src.join("build.rs"),
+        "        || target.contains(\"netbsd\")",
+        "        || target.contains(\"lua\")\n",
+    );
+    insert_before(
+        &std_src.join("src/sys/mod.rs"),
+        "    } else {\n        mod unsupported;",
+        concat!(
+            "    } else if #[cfg(target_os = \"lua\")] {\n",
+            "        mod lua;\n",
+            "        pub use self::lua::*;\n",
+        ),
+    );
+    // Networking is unsupported
+    insert_before(
+        &std_src.join("src/sys_common/mod.rs"),
+        "        feature = \"restricted-std\",",
+        "        target_os = \"lua\",\n",
+    );
+
+    let mut git_add_cmd = Command::new("git");
+    git_add_cmd.arg("add").arg(".").current_dir(sysroot_src);
+    spawn_and_wait(git_add_cmd);
+
+    let mut git_commit_cmd = Command::new("git");
+    git_commit_cmd
+        .arg("commit")
+        .arg("-m")
+        .arg("Add the lua platform")
+        .arg("-q")
+        .current_dir(sysroot_src);
+    spawn_and_wait(git_commit_cmd);
+}
+
+fn insert_before(file: &Path, anchor: &str, text: &str) {
+    let contents = fs::read_to_string(file).unwrap();
+    let pos = contents
+        .find(anchor)
+        .unwrap_or_else(|| panic!("{:?} not found in {}", anchor, file.display()));
+    fs::write(file, format!("{}{}{}", &contents[..pos], text, &contents[pos..])).unwrap();
+}
+
 fn clone_repo(target_dir: &str, repo: &str, rev: &str) {
     eprintln!("[CLONE] {}", repo);
     // Ignore exit code as the repo may already have been checked out
@@ -0,0 +1,237 @@
+//! The tests run by `./y.rs test`
+//!
+//! The example programs are compiled with the backend and their lua code is run by the embedded
+//! interpreter of `cglua`, through its `lua` example, so no lua installation is needed. Programs
+//! which print something are compared with the same program compiled by rustc for the host.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+
+use crate::rustc_info::get_file_name;
+use crate::utils::spawn_and_wait;
+
+struct TestCase {
+    name: &'static str,
+    func: &'static dyn Fn(&TestRunner),
+}
+
+impl TestCase {
+    const fn new(name: &'static str, func: &'static dyn Fn(&TestRunner)) -> Self {
+        Self { name, func }
+    }
+}
+
+const TESTS: &[TestCase] = &[
+    TestCase::new("aot.std_example", &|runner| {
+        runner.run_rustc(&["example/std_example.rs", "--crate-type", "bin"]);
+        let output = runner.run_lua(&runner.out_dir.join("std_example.lua"), &[]);
+        runner.check_output(&output, &runner.run_native("example/std_example.rs"));
+    }),
+    TestCase::new("jit.std_example", &|runner| {
+        let output = runner.run_jit("example/std_example.rs");
+        runner.check_output(&output, &runner.run_native("example/std_example.rs"));
+    }),
+    TestCase::new("aot.casts", &|runner| {
+        runner.run_rustc(&["example/casts.rs", "--crate-type", "bin"]);
+        let output = runner.run_lua(&runner.out_dir.join("casts.lua"), &[]);
+        runner.check_output(&output, &runner.run_native("example/casts.rs"));
+    }),
+    TestCase::new("aot.consts", &|runner| {
+        runner.run_rustc(&["example/consts.rs", "--crate-type", "bin"]);
+        let output = runner.run_lua(&runner.out_dir.join("consts.lua"), &[]);
+        runner.check_output(&output, &runner.run_native("example/consts.rs"));
+    }),
+    TestCase::new("aot.statics", &|runner| {
+        runner.run_rustc(&["example/statics.rs", "--crate-type", "bin"]);
+        let output = runner.run_lua(&runner.out_dir.join("statics.lua"), &[]);
+        runner.check_output(&output, &runner.run_native("example/statics.rs"));
+    }),
+    TestCase::new("aot.intrinsics", &|runner| {
+        runner.run_rustc(&["example/intrinsics.rs", "--crate-type", "bin"]);
+        let output = runner.run_lua(&runner.out_dir.join("intrinsics.lua"), &[]);
+        runner.check_output(&output, &runner.run_native("example/intrinsics.rs"));
+    }),
+    TestCase::new("aot.exports", &|runner| {
+        runner.run_rustc(&["example/exports.rs", "--crate-type", "cdylib"]);
+        let output = runner.run_lua(
+            Path::new("example/exports_driver.lua"),
+            &[runner.out_dir.as_os_str()],
+        );
+        runner.check_output(&output, b"ok\n");
+    }),
+    TestCase::new("aot.nvim_plugin", &|runner| {
+        runner.run_rustc(&[
+            "lua/src/lib.rs",
+            "--crate-type",
+            "rlib",
+            "--crate-name",
+            "lua",
+            "--cfg",
+            "feature=\"nvim\"",
+        ]);
+        let lua_rlib = format!("lua={}", runner.out_dir.join("liblua.rlib").display());
+        runner.run_rustc(&[
+            "example/nvim_plugin.rs",
+            "--crate-type",
+            "bin",
+            "--crate-type",
+            "cdylib",
+            "-Cllvm-args=output=neovim",
+            "--extern",
+            &lua_rlib,
+        ]);
+        for file in &["plugin/nvim_plugin.lua", "lua/nvim_plugin/init.lua"] {
+            if !runner.out_dir.join(file).is_file() {
+                eprintln!("{} wasn't written", file);
+                process::exit(1);
+            }
+        }
+        let output = runner.run_lua(
+            Path::new("example/nvim_stub.lua"),
+            &[runner.out_dir.as_os_str()],
+        );
+        runner.check_output(&output, b"ok\n");
+    }),
+    TestCase::new("aot.unwind", &|runner| {
+        // Programs can only unwind with a sysroot built with `-Cpanic=unwind`
+        if !crate::config::get_bool("panic_unwind") {
+            eprintln!("[SKIP] aot.unwind, enable panic_unwind in config.txt to run it");
+            return;
+        }
+        runner.run_rustc(&["example/unwind.rs", "--crate-type", "bin", "-Cpanic=unwind"]);
+        let output = runner.lua_output(&runner.out_dir.join("unwind.lua"), &[]);
+        let expected = runner.native_output("example/unwind.rs");
+        runner.check_output(&output.stdout, &expected.stdout);
+        runner.check_output(&output.stderr, &expected.stderr);
+    }),
+];
+
+pub(crate) fn run_tests(channel: &str, target_dir: &Path, host_triple: &str, target_triple: &str) {
+    let runner = TestRunner::new(channel, target_dir, host_triple, target_triple);
+    for test in TESTS {
+        eprintln!("[TEST] {}", test.name);
+        (test.func)(&runner);
+    }
+}
+
+struct TestRunner {
+    /// Where the tests write the compiled programs
+    out_dir: PathBuf,
+    cg_lua: PathBuf,
+    /// The `lua` example of `cglua`, running lua files with the embedded interpreter
+    lua: PathBuf,
+    target_triple: String,
+}
+
+impl TestRunner {
+    fn new(channel: &str, target_dir: &Path, host_triple: &str, target_triple: &str) -> Self {
+        let mut cmd = Command::new("cargo");
+        cmd.arg("build")
+            .arg("--manifest-path")
+            .arg("cglua/Cargo.toml")
+            .arg("--features")
+            .arg("interpreter")
+            .arg("--example")
+            .arg("lua")
+            .arg("--target")
+            .arg(host_triple);
+        if channel == "release" {
+            cmd.arg("--release");
+        }
+        eprintln!("[BUILD] lua interpreter");
+        spawn_and_wait(cmd);
+
+        let out_dir = target_dir.join("example");
+        fs::create_dir_all(&out_dir).unwrap();
+        TestRunner {
+            out_dir,
+            cg_lua: target_dir.join("bin").join(get_file_name("cg_lua", "bin")),
+            lua: Path::new("target")
+                .join(host_triple)
+                .join(channel)
+                .join("examples")
+                .join(get_file_name("lua", "bin")),
+            target_triple: target_triple.to_string(),
+        }
+    }
+
+    fn rustc_command(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(&self.cg_lua);
+        cmd.arg("--edition")
+            .arg("2018")
+            .arg("--target")
+            .arg(&self.target_triple)
+            .arg("--out-dir")
+            .arg(&self.out_dir)
+            .args(args);
+        cmd
+    }
+
+    /// Compiles with the backend
+    fn run_rustc(&self, args: &[&str]) {
+        spawn_and_wait(self.rustc_command(args));
+    }
+
+    /// Compiles and runs the program `source` in the jit mode, returning what it printed
+    fn run_jit(&self, source: &str) -> Vec<u8> {
+        let mut cmd = self.rustc_command(&[source, "--crate-type", "bin", "-Cllvm-args=mode=jit"]);
+        let stdout = checked_output(&mut cmd).stdout;
+        // The jit mode announces itself on the first line
+        let start = stdout.iter().position(|&c| c == b'\n').map_or(0, |i| i + 1);
+        stdout[start..].to_vec()
+    }
+
+    /// Runs a lua file with the interpreter, returning what it printed
+    fn run_lua(&self, script: &Path, args: &[&OsStr]) -> Vec<u8> {
+        self.lua_output(script, args).stdout
+    }
+
+    /// Runs a lua file with the interpreter, returning what it printed on stdout and stderr
+    fn lua_output(&self, script: &Path, args: &[&OsStr]) -> Output {
+        let mut cmd = Command::new(&self.lua);
+        cmd.arg(script).args(args);
+        checked_output(&mut cmd)
+    }
+
+    /// Compiles the program `source` with rustc for the host and runs it, returning what it
+    /// printed
+    fn run_native(&self, source: &str) -> Vec<u8> {
+        self.native_output(source).stdout
+    }
+
+    /// Compiles the program `source` with rustc for the host and runs it, returning what it
+    /// printed on stdout and stderr
+    fn native_output(&self, source: &str) -> Output {
+        let out_dir = self.out_dir.join("native");
+        let mut cmd = Command::new("rustc");
+        cmd.arg("--edition")
+            .arg("2018")
+            .arg(source)
+            .arg("--out-dir")
+            .arg(&out_dir);
+        spawn_and_wait(cmd);
+        let name = Path::new(source).file_stem().unwrap().to_str().unwrap();
+        checked_output(&mut Command::new(out_dir.join(get_file_name(name, "bin"))))
+    }
+
+    fn check_output(&self, output: &[u8], expected: &[u8]) {
+        if output != expected {
+            eprintln!("Output:\n{}", String::from_utf8_lossy(output));
+            eprintln!("Expected:\n{}", String::from_utf8_lossy(expected));
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs `cmd`, exiting when it fails
+fn checked_output(cmd: &mut Command) -> Output {
+    let output = cmd.output().unwrap();
+    if !output.status.success() {
+        eprintln!("{:?} failed with {}", cmd, output.status);
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        process::exit(1);
+    }
+    output
+}
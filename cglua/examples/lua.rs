@@ -0,0 +1,29 @@
+//! Runs a lua file with the embedded interpreter: `cargo run --example lua --features interpreter -- <file> [args]`
+//!
+//! Like the `lua` binary, the script name and its arguments are in the `arg` table.
+
+use cglua::interpreter::{Error, Lua, Table};
+
+pub fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = args.next().expect("usage: lua <file> [args]");
+    let source = std::fs::read(&path).unwrap();
+
+    let mut lua = Lua::new();
+    lua.inherit_stdio();
+    let arg = Table::new();
+    arg.set(0i64, path.as_str());
+    for (i, a) in args.enumerate() {
+        arg.set(i as i64 + 1, a);
+    }
+    lua.globals().set("arg", arg);
+    let res = lua.load(&path, &source).and_then(|f| lua.call(&f, vec![]));
+    match res {
+        Ok(_) => {}
+        Err(Error::Exit(code)) => std::process::exit(code),
+        Err(err) => {
+            eprintln!("lua: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
@@ -9,9 +9,8 @@ pub fn main() {
         {
             ctx.start_raw_block();
             {
-                let x = ctx.declare("x".to_string());
                 let nil = ctx.expr().nil();
-                ctx.stat().assign(x, nil);
+                ctx.stat().local(vec!["x".to_string()], vec![nil]);
             }
             ctx.finish_block().unwrap();
             ctx.start_function("rat".into(), vec!["a".into(), "b".into()]);
@@ -19,7 +18,7 @@ pub fn main() {
                 let x = ctx.declare("x".to_string());
                 let ft = ctx.expr().int(42);
                 let ft_f = ctx.expr().double(42.5);
-                ctx.stat().assign(x.clone(), ft);
+                ctx.stat().local(vec!["x".to_string()], vec![ft]);
                 ctx.stat().assign(x, ft_f);
             }
             ctx.finish_block().unwrap();
@@ -29,8 +28,7 @@ pub fn main() {
         let one = ctx.expr().int(1);
         let two = ctx.expr().int(2);
         let table = ctx.expr().table([(test, one.clone()), (one, two)]);
-        let x = ctx.declare("x".into());
-        ctx.stat().assign(x, table);
+        ctx.stat().local(vec!["x".into()], vec![table]);
     }
     ctx.finish_block().unwrap();
 
@@ -0,0 +1,535 @@
+//! A binary encoding of contexts, to store generated code without rendering it
+//!
+//! The bytes start with `MAGIC` and the version of the format, followed by the statements.
+//! Integers are LEB128 varints, except for the numbers of the code which are stored as 8 little
+//! endian bytes. Enums are a tag byte followed by the fields of the variant, lists are their length
+//! followed by their elements.
+
+use crate::{
+    BinOp, Block, Error, Expression, Function, FunctionTarget, Number, Result, Stat, UnOp, Var,
+};
+
+const MAGIC: &[u8] = b"\0cglua";
+
+/// The version of the format, bumped on each change of the encoding or of the AST
+pub const FORMAT_VERSION: u64 = 2;
+
+// In the order of their tags
+const BIN_OPS: &[BinOp] = &[
+    BinOp::Add,
+    BinOp::Sub,
+    BinOp::Mul,
+    BinOp::Div,
+    BinOp::IDiv,
+    BinOp::Mod,
+    BinOp::Pow,
+    BinOp::Concat,
+    BinOp::Eq,
+    BinOp::Ne,
+    BinOp::Lt,
+    BinOp::Le,
+    BinOp::Gt,
+    BinOp::Ge,
+    BinOp::And,
+    BinOp::Or,
+    BinOp::BAnd,
+    BinOp::BOr,
+    BinOp::BXor,
+    BinOp::Shl,
+    BinOp::Shr,
+];
+const UN_OPS: &[UnOp] = &[UnOp::Neg, UnOp::Not, UnOp::Len, UnOp::BNot];
+
+pub(crate) fn encode(code: &[Stat]) -> Vec<u8> {
+    let mut encoder = Encoder {
+        out: MAGIC.to_vec(),
+    };
+    encoder.uint(FORMAT_VERSION);
+    encoder.code(code);
+    encoder.out
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<Vec<Stat>> {
+    let bytes = bytes.strip_prefix(MAGIC).ok_or(Error::NotSerialized)?;
+    let mut decoder = Decoder { bytes };
+    let version = decoder.uint()?;
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version, FORMAT_VERSION));
+    }
+    let code = decoder.code()?;
+    if !decoder.bytes.is_empty() {
+        return Err(Error::InvalidBytes("trailing bytes"));
+    }
+    Ok(code)
+}
+
+struct Encoder {
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    fn uint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.out.push(v as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.uint(bytes.len() as u64);
+        self.out.extend_from_slice(bytes);
+    }
+
+    fn strings(&mut self, strings: &[String]) {
+        self.uint(strings.len() as u64);
+        for s in strings {
+            self.bytes(s.as_bytes());
+        }
+    }
+
+    fn code(&mut self, code: &[Stat]) {
+        self.uint(code.len() as u64);
+        for stat in code {
+            self.stat(stat);
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match stat {
+            Stat::Block(block) => {
+                self.out.push(0);
+                self.block(block);
+            }
+            Stat::Local { names, values } => {
+                self.out.push(1);
+                self.strings(names);
+                self.exprs(values);
+            }
+            Stat::Assign { places, values } => {
+                self.out.push(2);
+                self.uint(places.len() as u64);
+                for place in places {
+                    self.var(place);
+                }
+                self.exprs(values);
+            }
+            Stat::Call(call) => {
+                self.out.push(3);
+                self.expr(call);
+            }
+            Stat::Return(values) => {
+                self.out.push(4);
+                self.exprs(values);
+            }
+            Stat::Break => self.out.push(5),
+            Stat::Comment(text) => {
+                self.out.push(6);
+                self.bytes(text.as_bytes());
+            }
+            Stat::Rendered(code) => {
+                self.out.push(7);
+                self.bytes(code.as_bytes());
+            }
+            Stat::Goto(label) => {
+                self.out.push(8);
+                self.bytes(label.as_bytes());
+            }
+            Stat::Label(label) => {
+                self.out.push(9);
+                self.bytes(label.as_bytes());
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        match block {
+            Block::Function(Function {
+                target,
+                params,
+                code,
+            }) => {
+                self.out.push(0);
+                match target {
+                    FunctionTarget::Local(name) => {
+                        self.out.push(0);
+                        self.bytes(name.as_bytes());
+                    }
+                    FunctionTarget::Place(place) => {
+                        self.out.push(1);
+                        self.var(place);
+                    }
+                }
+                self.strings(params);
+                self.code(code);
+            }
+            Block::Raw { code } => {
+                self.out.push(1);
+                self.code(code);
+            }
+            Block::If {
+                branches,
+                otherwise,
+            } => {
+                self.out.push(2);
+                self.uint(branches.len() as u64);
+                for (cond, code) in branches {
+                    self.expr(cond);
+                    self.code(code);
+                }
+                match otherwise {
+                    Some(code) => {
+                        self.out.push(1);
+                        self.code(code);
+                    }
+                    None => self.out.push(0),
+                }
+            }
+            Block::While { cond, code } => {
+                self.out.push(3);
+                self.expr(cond);
+                self.code(code);
+            }
+            Block::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                code,
+            } => {
+                self.out.push(4);
+                self.bytes(var.as_bytes());
+                self.expr(start);
+                self.expr(limit);
+                match step {
+                    Some(step) => {
+                        self.out.push(1);
+                        self.expr(step);
+                    }
+                    None => self.out.push(0),
+                }
+                self.code(code);
+            }
+            Block::GenericFor {
+                names,
+                values,
+                code,
+            } => {
+                self.out.push(5);
+                self.strings(names);
+                self.exprs(values);
+                self.code(code);
+            }
+            Block::Repeat { code, cond } => {
+                self.out.push(6);
+                self.code(code);
+                self.expr(cond);
+            }
+        }
+    }
+
+    fn var(&mut self, var: &Var) {
+        match var {
+            Var::Ident(name) => {
+                self.out.push(0);
+                self.bytes(name.as_bytes());
+            }
+            Var::Expression(e) => {
+                self.out.push(1);
+                self.expr(e);
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expression]) {
+        self.uint(exprs.len() as u64);
+        for e in exprs {
+            self.expr(e);
+        }
+    }
+
+    fn expr(&mut self, e: &Expression) {
+        match e {
+            Expression::Number(Number::Int(v)) => {
+                self.out.push(0);
+                self.out.extend_from_slice(&v.to_le_bytes());
+            }
+            Expression::Number(Number::Float(v)) => {
+                self.out.push(1);
+                self.out.extend_from_slice(&v.to_bits().to_le_bytes());
+            }
+            Expression::Bool(b) => self.out.extend_from_slice(&[2, *b as u8]),
+            Expression::Ident(name) => {
+                self.out.push(3);
+                self.bytes(name.as_bytes());
+            }
+            Expression::TableAccess { table, key } => {
+                self.out.push(4);
+                self.var(table);
+                self.expr(key);
+            }
+            Expression::Nil => self.out.push(5),
+            Expression::Table(fields) => {
+                self.out.push(6);
+                self.uint(fields.len() as u64);
+                for (k, v) in fields {
+                    self.expr(k);
+                    self.expr(v);
+                }
+            }
+            Expression::List(values) => {
+                self.out.push(7);
+                self.exprs(values);
+            }
+            Expression::String(s) => {
+                self.out.push(8);
+                self.bytes(s);
+            }
+            Expression::Call {
+                function,
+                parameters,
+            } => {
+                self.out.push(9);
+                self.expr(function);
+                self.exprs(parameters);
+            }
+            Expression::BinOp { op, lhs, rhs } => {
+                let tag = BIN_OPS.iter().position(|o| o == op).unwrap();
+                self.out.extend_from_slice(&[10, tag as u8]);
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expression::UnOp { op, value } => {
+                let tag = UN_OPS.iter().position(|o| o == op).unwrap();
+                self.out.extend_from_slice(&[11, tag as u8]);
+                self.expr(value);
+            }
+            Expression::Function { params, code } => {
+                self.out.push(12);
+                self.strings(params);
+                self.code(code);
+            }
+            Expression::Vararg => self.out.push(13),
+            Expression::Method {
+                object,
+                name,
+                parameters,
+            } => {
+                self.out.push(14);
+                self.expr(object);
+                self.bytes(name.as_bytes());
+                self.exprs(parameters);
+            }
+            Expression::Paren(value) => {
+                self.out.push(15);
+                self.expr(value);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let (&b, rest) = self
+            .bytes
+            .split_first()
+            .ok_or(Error::InvalidBytes("unexpected end"))?;
+        self.bytes = rest;
+        Ok(b)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(Error::InvalidBytes("unexpected end"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn uint(&mut self) -> Result<u64> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(Error::InvalidBytes("integer overflow"))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let len = self.uint()?;
+        // Each element takes at least a byte
+        if len > self.bytes.len() as u64 {
+            return Err(Error::InvalidBytes("unexpected end"));
+        }
+        Ok(len as usize)
+    }
+
+    fn u64_le(&mut self) -> Result<u64> {
+        let mut le = [0; 8];
+        le.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(le))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::InvalidBytes("invalid utf-8"))
+    }
+
+    fn list<T>(&mut self, mut element: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let len = self.len()?;
+        let mut list = Vec::with_capacity(len);
+        for _ in 0..len {
+            list.push(element(self)?);
+        }
+        Ok(list)
+    }
+
+    fn code(&mut self) -> Result<Vec<Stat>> {
+        self.list(Self::stat)
+    }
+
+    fn stat(&mut self) -> Result<Stat> {
+        Ok(match self.byte()? {
+            0 => Stat::Block(self.block()?),
+            1 => Stat::Local {
+                names: self.list(Self::string)?,
+                values: self.list(Self::expr)?,
+            },
+            2 => Stat::Assign {
+                places: self.list(Self::var)?,
+                values: self.list(Self::expr)?,
+            },
+            3 => Stat::Call(self.expr()?),
+            4 => Stat::Return(self.list(Self::expr)?),
+            5 => Stat::Break,
+            6 => Stat::Comment(self.string()?),
+            7 => Stat::Rendered(self.string()?),
+            8 => Stat::Goto(self.string()?),
+            9 => Stat::Label(self.string()?),
+            _ => return Err(Error::InvalidBytes("invalid statement")),
+        })
+    }
+
+    fn block(&mut self) -> Result<Block> {
+        Ok(match self.byte()? {
+            0 => {
+                let target = match self.byte()? {
+                    0 => FunctionTarget::Local(self.string()?),
+                    1 => FunctionTarget::Place(self.var()?),
+                    _ => return Err(Error::InvalidBytes("invalid function target")),
+                };
+                Block::Function(Function {
+                    target,
+                    params: self.list(Self::string)?,
+                    code: self.code()?,
+                })
+            }
+            1 => Block::Raw { code: self.code()? },
+            2 => Block::If {
+                branches: self.list(|d| Ok((d.expr()?, d.code()?)))?,
+                otherwise: match self.byte()? {
+                    0 => None,
+                    1 => Some(self.code()?),
+                    _ => return Err(Error::InvalidBytes("invalid else branch")),
+                },
+            },
+            3 => Block::While {
+                cond: self.expr()?,
+                code: self.code()?,
+            },
+            4 => Block::NumericFor {
+                var: self.string()?,
+                start: self.expr()?,
+                limit: self.expr()?,
+                step: match self.byte()? {
+                    0 => None,
+                    1 => Some(self.expr()?),
+                    _ => return Err(Error::InvalidBytes("invalid for step")),
+                },
+                code: self.code()?,
+            },
+            5 => Block::GenericFor {
+                names: self.list(Self::string)?,
+                values: self.list(Self::expr)?,
+                code: self.code()?,
+            },
+            6 => Block::Repeat {
+                code: self.code()?,
+                cond: self.expr()?,
+            },
+            _ => return Err(Error::InvalidBytes("invalid block")),
+        })
+    }
+
+    fn var(&mut self) -> Result<Var> {
+        Ok(match self.byte()? {
+            0 => Var::Ident(self.string()?),
+            1 => Var::Expression(self.expr()?),
+            _ => return Err(Error::InvalidBytes("invalid place")),
+        })
+    }
+
+    fn expr(&mut self) -> Result<Expression> {
+        Ok(match self.byte()? {
+            0 => Expression::Number(Number::Int(self.u64_le()? as i64)),
+            1 => Expression::Number(Number::Float(f64::from_bits(self.u64_le()?))),
+            2 => Expression::Bool(self.byte()? != 0),
+            3 => Expression::Ident(self.string()?),
+            4 => Expression::TableAccess {
+                table: Box::new(self.var()?),
+                key: Box::new(self.expr()?),
+            },
+            5 => Expression::Nil,
+            6 => Expression::Table(self.list(|d| Ok((d.expr()?, d.expr()?)))?),
+            7 => Expression::List(self.list(Self::expr)?),
+            8 => Expression::String(self.bytes()?),
+            9 => Expression::Call {
+                function: Box::new(self.expr()?),
+                parameters: self.list(Self::expr)?,
+            },
+            10 => {
+                let op = *BIN_OPS
+                    .get(self.byte()? as usize)
+                    .ok_or(Error::InvalidBytes("invalid binary operator"))?;
+                Expression::BinOp {
+                    op,
+                    lhs: Box::new(self.expr()?),
+                    rhs: Box::new(self.expr()?),
+                }
+            }
+            11 => {
+                let op = *UN_OPS
+                    .get(self.byte()? as usize)
+                    .ok_or(Error::InvalidBytes("invalid unary operator"))?;
+                Expression::UnOp {
+                    op,
+                    value: Box::new(self.expr()?),
+                }
+            }
+            12 => Expression::Function {
+                params: self.list(Self::string)?,
+                code: self.code()?,
+            },
+            13 => Expression::Vararg,
+            14 => Expression::Method {
+                object: Box::new(self.expr()?),
+                name: self.string()?,
+                parameters: self.list(Self::expr)?,
+            },
+            15 => Expression::Paren(Box::new(self.expr()?)),
+            _ => return Err(Error::InvalidBytes("invalid expression")),
+        })
+    }
+}
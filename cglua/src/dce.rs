@@ -0,0 +1,721 @@
+//! Removal of dead stores and unused locals
+//!
+//! Stores to locals which are never read are removed, then the locals themselves once nothing
+//! assigns them and their values have no side effect. Locals read once, by the statement following
+//! them, are forwarded to their use when no other side effect can happen in between.
+//!
+//! Only calls and integer divisions by a divisor which could be zero are considered to have side
+//! effects: other operators and table accesses are assumed not to run metamethods, which the
+//! generated code doesn't rely on, and calls not to redefine functions. Names are compared without
+//! resolving shadowing, which only makes the pass keep more locals.
+
+use std::collections::HashSet;
+
+use crate::{BinOp, Block, Expression, Function, FunctionTarget, Number, Stat, Var};
+
+/// A use of a name
+enum Use<'a> {
+    Read(&'a str),
+    Write(&'a str),
+    /// Code which can use any name
+    Rendered,
+}
+
+pub(crate) struct Cleaner {
+    /// The number of statements removed
+    pub(crate) removed: usize,
+    /// The locals of the function being cleaned which no closure can change
+    locals: Vec<String>,
+    /// The names used by the closures of the function being cleaned, or `None` when it contains
+    /// rendered code
+    captured: Option<HashSet<String>>,
+}
+
+impl Cleaner {
+    pub(crate) fn new() -> Self {
+        Cleaner {
+            removed: 0,
+            locals: Vec::new(),
+            captured: Some(HashSet::new()),
+        }
+    }
+
+    /// Cleans the functions of a chunk. The locals of the chunk itself are kept, as the code of
+    /// other modules appended to it can use them.
+    pub(crate) fn chunk(&mut self, code: &mut [Stat]) {
+        for stat in code {
+            self.stat(stat);
+        }
+    }
+
+    fn function(&mut self, params: &[String], code: &mut Vec<Stat>) {
+        let mut captured = Some(HashSet::new());
+        walk_code(
+            code,
+            false,
+            &mut |name, closure| match (name, &mut captured) {
+                (Use::Rendered, captured) => *captured = None,
+                (Use::Read(name) | Use::Write(name), Some(captured)) if closure => {
+                    captured.insert(name.to_string());
+                }
+                _ => {}
+            },
+        );
+        let outer_captured = std::mem::replace(&mut self.captured, captured);
+        let outer_locals = std::mem::take(&mut self.locals);
+        self.declare(params);
+        self.code(code, None);
+        self.captured = outer_captured;
+        self.locals = outer_locals;
+    }
+
+    /// Adds locals which closures don't use to `self.locals`
+    fn declare(&mut self, names: &[String]) {
+        if let Some(captured) = &self.captured {
+            let locals = names.iter().filter(|name| !captured.contains(*name));
+            self.locals.extend(locals.cloned());
+        }
+    }
+
+    /// Cleans a block, `tail` being the condition of a `repeat` which can read its locals
+    fn code(&mut self, code: &mut Vec<Stat>, tail: Option<&Expression>) {
+        let depth = self.locals.len();
+        let mut i = 0;
+        while i < code.len() {
+            self.stat(&mut code[i]);
+            match &code[i] {
+                Stat::Local { .. } => {
+                    let mut rest = code.split_off(i + 1);
+                    let local = code.pop().unwrap();
+                    let kept = self.local(local, &mut rest, tail);
+                    let removed = kept.is_none();
+                    code.extend(kept);
+                    code.append(&mut rest);
+                    if removed {
+                        // The next statement is now at `i`
+                        continue;
+                    }
+                    if let Stat::Local { names, .. } = &code[i] {
+                        let names = names.clone();
+                        self.declare(&names);
+                    }
+                }
+                Stat::Block(Block::Function(Function {
+                    target: FunctionTarget::Local(name),
+                    ..
+                })) => {
+                    let name = name.clone();
+                    self.declare(std::slice::from_ref(&name));
+                }
+                // No declarations
+                _ => {}
+            }
+            i += 1;
+        }
+        self.locals.truncate(depth);
+    }
+
+    /// Cleans the nested code of a statement
+    fn stat(&mut self, stat: &mut Stat) {
+        match stat {
+            Stat::Block(block) => match block {
+                Block::Function(f) => {
+                    if let FunctionTarget::Place(Var::Expression(e)) = &mut f.target {
+                        self.expr(e);
+                    }
+                    self.function(&f.params, &mut f.code);
+                }
+                Block::Raw { code } => self.code(code, None),
+                Block::If {
+                    branches,
+                    otherwise,
+                } => {
+                    for (cond, code) in branches {
+                        self.expr(cond);
+                        self.code(code, None);
+                    }
+                    if let Some(code) = otherwise {
+                        self.code(code, None);
+                    }
+                }
+                Block::While { cond, code } => {
+                    self.expr(cond);
+                    self.code(code, None);
+                }
+                Block::Repeat { code, cond } => {
+                    self.expr(cond);
+                    self.code(code, Some(cond));
+                }
+                Block::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                    code,
+                } => {
+                    self.expr(start);
+                    self.expr(limit);
+                    if let Some(step) = step {
+                        self.expr(step);
+                    }
+                    self.loop_code(std::slice::from_ref(var), code);
+                }
+                Block::GenericFor {
+                    names,
+                    values,
+                    code,
+                } => {
+                    values.iter_mut().for_each(|e| self.expr(e));
+                    self.loop_code(names, code);
+                }
+            },
+            Stat::Local { values, .. } | Stat::Return(values) => {
+                values.iter_mut().for_each(|e| self.expr(e))
+            }
+            Stat::Assign { places, values } => {
+                for place in places {
+                    if let Var::Expression(e) = place {
+                        self.expr(e);
+                    }
+                }
+                values.iter_mut().for_each(|e| self.expr(e));
+            }
+            Stat::Call(call) => self.expr(call),
+            // No nested code
+            _ => {}
+        }
+    }
+
+    /// Cleans the code of a loop declaring `names`
+    fn loop_code(&mut self, names: &[String], code: &mut Vec<Stat>) {
+        let depth = self.locals.len();
+        self.declare(names);
+        self.code(code, None);
+        self.locals.truncate(depth);
+    }
+
+    /// Cleans the functions defined in an expression
+    fn expr(&mut self, e: &mut Expression) {
+        match e {
+            Expression::Function { params, code } => self.function(params, code),
+            Expression::Vararg
+            | Expression::Number(_)
+            | Expression::Bool(_)
+            | Expression::Ident(_)
+            | Expression::Nil
+            | Expression::String(_) => {}
+            Expression::TableAccess { table, key } => {
+                if let Var::Expression(e) = &mut **table {
+                    self.expr(e);
+                }
+                self.expr(key);
+            }
+            Expression::Table(fields) => {
+                for (k, v) in fields {
+                    self.expr(k);
+                    self.expr(v);
+                }
+            }
+            Expression::List(values) => values.iter_mut().for_each(|e| self.expr(e)),
+            Expression::Call {
+                function: callee,
+                parameters,
+            }
+            | Expression::Method {
+                object: callee,
+                parameters,
+                ..
+            } => {
+                self.expr(callee);
+                parameters.iter_mut().for_each(|e| self.expr(e));
+            }
+            Expression::BinOp { lhs, rhs, .. } => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expression::UnOp { value, .. } | Expression::Paren(value) => self.expr(value),
+        }
+    }
+
+    /// Removes the stores to the locals declared by `local` which are never read in `rest`, then
+    /// the declaration itself when it's unused or forwarded. Returns the statement to keep.
+    fn local(
+        &mut self,
+        local: Stat,
+        rest: &mut Vec<Stat>,
+        tail: Option<&Expression>,
+    ) -> Option<Stat> {
+        let (mut names, mut values) = match local {
+            Stat::Local { names, values } => (names, values),
+            _ => unreachable!(),
+        };
+
+        let mut reads = Vec::with_capacity(names.len());
+        for name in &names {
+            let (count, _) = uses(rest, tail, name);
+            if count == 0 {
+                self.removed += remove_stores(rest, name);
+            }
+            reads.push(count);
+        }
+        let writes: Vec<bool> = names.iter().map(|name| uses(rest, tail, name).1).collect();
+        let unused: Vec<bool> = reads
+            .iter()
+            .zip(&writes)
+            .map(|(&reads, &writes)| reads == 0 && !writes)
+            .collect();
+
+        if unused.iter().all(|&unused| unused) {
+            if !values.iter().any(has_effects) {
+                self.removed += 1;
+                return None;
+            }
+            // The call is kept for its side effects
+            if let [Expression::Call { .. } | Expression::Method { .. }] = &values[..] {
+                return values.pop().map(Stat::Call);
+            }
+        }
+        if values.is_empty() {
+            // Locals without values can be left out of their declaration
+            let mut unused = unused.iter();
+            names.retain(|_| !unused.next().unwrap());
+            return Some(Stat::Local { names, values });
+        }
+
+        if let ([name], [value], [1], [false], [next, ..]) = (
+            &names[..],
+            &values[..],
+            &reads[..],
+            &writes[..],
+            &mut rest[..],
+        ) {
+            let mut forward = Forward {
+                name,
+                value: value.clone().truncated(),
+                value_calls: has_calls(value),
+                locals: &self.locals,
+                done: false,
+                blocked: false,
+            };
+            let mut forwarded = next.clone();
+            forward.stat(&mut forwarded);
+            if forward.done {
+                *next = forwarded;
+                self.removed += 1;
+                return None;
+            }
+        }
+        Some(Stat::Local { names, values })
+    }
+}
+
+/// Replaces the use of a local in the parts of a statement evaluated before anything else runs,
+/// when the value of the local can be evaluated there instead
+struct Forward<'a> {
+    name: &'a str,
+    value: Expression,
+    /// Calls in the value can change what the statement reads before the use
+    value_calls: bool,
+    /// The locals which only the function itself can change
+    locals: &'a [String],
+    done: bool,
+    /// Evaluating the value at the use would change the result
+    blocked: bool,
+}
+
+impl Forward<'_> {
+    fn stat(&mut self, stat: &mut Stat) {
+        match stat {
+            Stat::Local { values, .. } | Stat::Return(values) => self.exprs(values),
+            Stat::Assign { places, values } => {
+                // The places are evaluated before the values and assigned after them
+                for place in places {
+                    if let Var::Expression(Expression::TableAccess { table, key }) = place {
+                        self.var(table);
+                        self.expr(key);
+                    }
+                }
+                self.exprs(values);
+            }
+            Stat::Call(call) => self.expr(call),
+            // The other conditions are only evaluated when the first one is false
+            Stat::Block(Block::If { branches, .. }) => self.expr(&mut branches[0].0),
+            Stat::Block(Block::NumericFor {
+                start, limit, step, ..
+            }) => {
+                self.expr(start);
+                self.expr(limit);
+                if let Some(step) = step {
+                    self.expr(step);
+                }
+            }
+            Stat::Block(Block::GenericFor { values, .. }) => self.exprs(values),
+            Stat::Block(Block::Function(Function {
+                target:
+                    FunctionTarget::Place(Var::Expression(Expression::TableAccess { table, key })),
+                ..
+            })) => {
+                self.var(table);
+                self.expr(key);
+            }
+            // Evaluated several times or after other code
+            _ => {}
+        }
+    }
+
+    /// Walks the function of a call. The functions called by name, like `rt.load` or `S["f"]`,
+    /// aren't redefined while the code runs, so the value can't change them.
+    fn callee(&mut self, function: &mut Expression) {
+        if self.done || self.blocked {
+            return;
+        }
+        match function {
+            Expression::Ident(name) if name != self.name => {}
+            Expression::TableAccess { table, key } if matches!(**key, Expression::String(_)) => {
+                match &mut **table {
+                    Var::Ident(name) if name != self.name => {}
+                    Var::Expression(e) => self.callee(e),
+                    table => self.var(table),
+                }
+            }
+            function => self.expr(function),
+        }
+    }
+
+    fn exprs(&mut self, exprs: &mut [Expression]) {
+        exprs.iter_mut().for_each(|e| self.expr(e));
+    }
+
+    fn var(&mut self, var: &mut Var) {
+        if self.done || self.blocked {
+            return;
+        }
+        match var {
+            Var::Ident(name) if name == self.name => {
+                *var = Var::Expression(self.value.clone());
+                self.done = true;
+            }
+            Var::Ident(name) => self.blocked = self.changed_by_value(name),
+            Var::Expression(e) => self.expr(e),
+        }
+    }
+
+    /// Can evaluating the value change the variable `name`
+    fn changed_by_value(&self, name: &str) -> bool {
+        self.value_calls && !self.locals.iter().any(|local| local == name)
+    }
+
+    fn expr(&mut self, e: &mut Expression) {
+        if self.done || self.blocked {
+            return;
+        }
+        match e {
+            Expression::Ident(name) if name == self.name => {
+                *e = self.value.clone();
+                self.done = true;
+            }
+            Expression::Ident(name) => self.blocked = self.changed_by_value(name),
+            // The code of functions doesn't run here
+            Expression::Function { .. }
+            | Expression::Vararg
+            | Expression::Number(_)
+            | Expression::Bool(_)
+            | Expression::Nil
+            | Expression::String(_) => {}
+            Expression::TableAccess { table, key } => {
+                self.var(table);
+                self.expr(key);
+                // Reading the table comes after its key
+                self.blocked |= !self.done && self.value_calls;
+            }
+            Expression::Table(fields) => {
+                for (k, v) in fields {
+                    self.expr(k);
+                    self.expr(v);
+                }
+            }
+            Expression::List(values) => self.exprs(values),
+            Expression::Call {
+                function,
+                parameters,
+            } => {
+                self.callee(function);
+                self.exprs(parameters);
+                // The call would run before the value
+                self.blocked |= !self.done;
+            }
+            Expression::Method {
+                object, parameters, ..
+            } => {
+                // The method is looked up by name, like the functions in `callee`
+                self.expr(object);
+                self.exprs(parameters);
+                self.blocked |= !self.done;
+            }
+            Expression::BinOp { op, lhs, rhs } => {
+                self.expr(lhs);
+                // The right operand of `and` and `or` isn't always evaluated
+                if let BinOp::And | BinOp::Or = op {
+                    self.blocked |= !self.done;
+                }
+                self.expr(rhs);
+            }
+            Expression::UnOp { value, .. } | Expression::Paren(value) => self.expr(value),
+        }
+    }
+}
+
+/// Counts the reads of the name `name` in `code` and `tail`, and whether it's assigned there.
+/// Rendered code counts as several reads and an assignment.
+fn uses(code: &[Stat], tail: Option<&Expression>, name: &str) -> (usize, bool) {
+    let (mut reads, mut writes) = (0, false);
+    let mut count = |u: Use, _| match u {
+        Use::Read(n) if n == name => reads += 1,
+        Use::Write(n) if n == name => writes = true,
+        Use::Rendered => {
+            reads += 2;
+            writes = true;
+        }
+        _ => {}
+    };
+    walk_code(code, false, &mut count);
+    if let Some(tail) = tail {
+        walk_expr(tail, false, &mut count);
+    }
+    (reads, writes)
+}
+
+/// Calls `f` on every use of a name in `code`, with whether it's inside a closure defined by
+/// the code
+fn walk_code(code: &[Stat], closure: bool, f: &mut dyn FnMut(Use, bool)) {
+    for stat in code {
+        match stat {
+            Stat::Block(block) => match block {
+                Block::Function(function) => {
+                    match &function.target {
+                        FunctionTarget::Place(var) => walk_place(var, closure, f),
+                        FunctionTarget::Local(_) => {}
+                    }
+                    walk_code(&function.code, true, f);
+                }
+                Block::Raw { code } => walk_code(code, closure, f),
+                Block::If {
+                    branches,
+                    otherwise,
+                } => {
+                    for (cond, code) in branches {
+                        walk_expr(cond, closure, f);
+                        walk_code(code, closure, f);
+                    }
+                    if let Some(code) = otherwise {
+                        walk_code(code, closure, f);
+                    }
+                }
+                Block::While { cond, code } | Block::Repeat { code, cond } => {
+                    walk_expr(cond, closure, f);
+                    walk_code(code, closure, f);
+                }
+                Block::NumericFor {
+                    start,
+                    limit,
+                    step,
+                    code,
+                    ..
+                } => {
+                    walk_expr(start, closure, f);
+                    walk_expr(limit, closure, f);
+                    if let Some(step) = step {
+                        walk_expr(step, closure, f);
+                    }
+                    walk_code(code, closure, f);
+                }
+                Block::GenericFor { values, code, .. } => {
+                    values.iter().for_each(|e| walk_expr(e, closure, f));
+                    walk_code(code, closure, f);
+                }
+            },
+            Stat::Local { values, .. } | Stat::Return(values) => {
+                values.iter().for_each(|e| walk_expr(e, closure, f))
+            }
+            Stat::Assign { places, values } => {
+                for place in places {
+                    walk_place(place, closure, f);
+                }
+                values.iter().for_each(|e| walk_expr(e, closure, f));
+            }
+            Stat::Call(call) => walk_expr(call, closure, f),
+            Stat::Rendered(_) => f(Use::Rendered, closure),
+            // No names
+            Stat::Break | Stat::Comment(_) | Stat::Goto(_) | Stat::Label(_) => {}
+        }
+    }
+}
+
+/// Walks a variable which is assigned
+fn walk_place(var: &Var, closure: bool, f: &mut dyn FnMut(Use, bool)) {
+    match var {
+        Var::Ident(name) => f(Use::Write(name), closure),
+        Var::Expression(e) => walk_expr(e, closure, f),
+    }
+}
+
+fn walk_expr(e: &Expression, closure: bool, f: &mut dyn FnMut(Use, bool)) {
+    match e {
+        Expression::Function { code, .. } => walk_code(code, true, f),
+        Expression::Ident(name) => f(Use::Read(name), closure),
+        Expression::Vararg
+        | Expression::Number(_)
+        | Expression::Bool(_)
+        | Expression::Nil
+        | Expression::String(_) => {}
+        Expression::TableAccess { table, key } => {
+            match &**table {
+                Var::Ident(name) => f(Use::Read(name), closure),
+                Var::Expression(e) => walk_expr(e, closure, f),
+            }
+            walk_expr(key, closure, f);
+        }
+        Expression::Table(fields) => {
+            for (k, v) in fields {
+                walk_expr(k, closure, f);
+                walk_expr(v, closure, f);
+            }
+        }
+        Expression::List(values) => values.iter().for_each(|e| walk_expr(e, closure, f)),
+        Expression::Call {
+            function: callee,
+            parameters,
+        }
+        | Expression::Method {
+            object: callee,
+            parameters,
+            ..
+        } => {
+            walk_expr(callee, closure, f);
+            parameters.iter().for_each(|e| walk_expr(e, closure, f));
+        }
+        Expression::BinOp { lhs, rhs, .. } => {
+            walk_expr(lhs, closure, f);
+            walk_expr(rhs, closure, f);
+        }
+        Expression::UnOp { value, .. } | Expression::Paren(value) => walk_expr(value, closure, f),
+    }
+}
+
+/// Does evaluating `e` call functions, not counting the functions it defines
+fn has_calls(e: &Expression) -> bool {
+    any_expr(e, &|e| {
+        matches!(e, Expression::Call { .. } | Expression::Method { .. })
+    })
+}
+
+/// Can evaluating `e` have an effect: calling a function, or raising the error of an integer
+/// division by zero, which only a non-zero literal divisor rules out
+fn has_effects(e: &Expression) -> bool {
+    any_expr(e, &|e| match e {
+        Expression::Call { .. } | Expression::Method { .. } => true,
+        Expression::BinOp {
+            op: BinOp::IDiv | BinOp::Mod,
+            rhs,
+            ..
+        } => {
+            !matches!(**rhs, Expression::Number(Number::Int(i)) if i != 0)
+                && !matches!(**rhs, Expression::Number(Number::Float(f)) if f != 0.0)
+        }
+        _ => false,
+    })
+}
+
+/// Is `f` true for `e` or one of the expressions evaluated with it, not counting the code of the
+/// functions it defines
+fn any_expr(e: &Expression, f: &dyn Fn(&Expression) -> bool) -> bool {
+    if f(e) {
+        return true;
+    }
+    match e {
+        Expression::Function { .. }
+        | Expression::Vararg
+        | Expression::Number(_)
+        | Expression::Bool(_)
+        | Expression::Ident(_)
+        | Expression::Nil
+        | Expression::String(_) => false,
+        Expression::TableAccess { table, key } => {
+            matches!(&**table, Var::Expression(e) if any_expr(e, f)) || any_expr(key, f)
+        }
+        Expression::Table(fields) => fields.iter().any(|(k, v)| any_expr(k, f) || any_expr(v, f)),
+        Expression::List(values) => values.iter().any(|e| any_expr(e, f)),
+        Expression::Call {
+            function: callee,
+            parameters,
+        }
+        | Expression::Method {
+            object: callee,
+            parameters,
+            ..
+        } => any_expr(callee, f) || parameters.iter().any(|e| any_expr(e, f)),
+        Expression::BinOp { lhs, rhs, .. } => any_expr(lhs, f) || any_expr(rhs, f),
+        Expression::UnOp { value, .. } | Expression::Paren(value) => any_expr(value, f),
+    }
+}
+
+/// Removes the assignments of the local `name`, which is never read, from `code` and its nested
+/// blocks. The stores in closures are kept. Returns the number of statements removed.
+fn remove_stores(code: &mut Vec<Stat>, name: &str) -> usize {
+    let mut removed = 0;
+    code.retain(|stat| match stat {
+        Stat::Assign { places, values } => {
+            let dead = matches!(&places[..], [Var::Ident(n)] if n == name);
+            let remove = dead && !values.iter().any(has_effects);
+            removed += remove as usize;
+            !remove
+        }
+        Stat::Block(Block::Function(Function {
+            target: FunctionTarget::Place(Var::Ident(n)),
+            ..
+        })) if n == name => {
+            removed += 1;
+            false
+        }
+        _ => true,
+    });
+    for stat in code {
+        match stat {
+            Stat::Assign { places, values } => {
+                // The call is kept for its side effects
+                if let (
+                    [Var::Ident(n)],
+                    [call @ (Expression::Call { .. } | Expression::Method { .. })],
+                ) = (&places[..], &mut values[..])
+                {
+                    if n == name {
+                        *stat = Stat::Call(std::mem::replace(call, Expression::Nil));
+                    }
+                }
+            }
+            Stat::Block(block) => match block {
+                Block::Raw { code }
+                | Block::While { code, .. }
+                | Block::Repeat { code, .. }
+                | Block::NumericFor { code, .. }
+                | Block::GenericFor { code, .. } => removed += remove_stores(code, name),
+                Block::If {
+                    branches,
+                    otherwise,
+                } => {
+                    for (_, code) in branches {
+                        removed += remove_stores(code, name);
+                    }
+                    if let Some(code) = otherwise {
+                        removed += remove_stores(code, name);
+                    }
+                }
+                // Closures
+                Block::Function(_) => {}
+            },
+            // No nested blocks
+            _ => {}
+        }
+    }
+    removed
+}
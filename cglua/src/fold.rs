@@ -0,0 +1,663 @@
+//! Constant folding and propagation
+//!
+//! Operations on literals are replaced by their result, computed as lua 5.3 does: integers wrap
+//! around and only become floats with `/` and `^` or when mixed with floats. Operations whose
+//! result could differ on the machine running the code, like `^` and the formatting of floats, and
+//! those raising errors, like integer division by zero, are left to run.
+//!
+//! Locals declared with a number, a boolean or `nil` and never assigned afterwards are replaced by
+//! their value, and the branches of `if` and `while` on constant conditions are pruned.
+
+use std::cmp::Ordering;
+
+use crate::{BinOp, Block, Expression, FunctionTarget, Number, Stat, UnOp, Var};
+
+pub(crate) struct Folder;
+
+impl Folder {
+    pub(crate) fn code(&self, code: &mut Vec<Stat>) {
+        let mut i = 0;
+        while i < code.len() {
+            let stat = std::mem::replace(&mut code[i], Stat::Break);
+            match self.stat(stat) {
+                Some(stat) => {
+                    code[i] = stat;
+                    if let Stat::Local { names, values } = &code[i] {
+                        for (name, value) in constant_locals(names, values) {
+                            if !assigns(&code[i + 1..], &name) {
+                                substitute_code(&mut code[i + 1..], &name, &value);
+                            }
+                        }
+                    }
+                    i += 1;
+                }
+                None => {
+                    code.remove(i);
+                }
+            }
+        }
+    }
+
+    /// Folds a statement, returning `None` if it's removed
+    fn stat(&self, mut stat: Stat) -> Option<Stat> {
+        match &mut stat {
+            Stat::Block(block) => return self.block(std::mem::replace(block, empty_block())),
+            Stat::Local { values, .. } | Stat::Return(values) => self.exprs(values),
+            Stat::Assign { places, values } => {
+                places.iter_mut().for_each(|place| self.var(place));
+                self.exprs(values);
+            }
+            Stat::Call(call) => self.expr(call),
+            // No expressions
+            _ => {}
+        }
+        Some(stat)
+    }
+
+    fn block(&self, mut block: Block) -> Option<Stat> {
+        match &mut block {
+            Block::Function(f) => {
+                if let FunctionTarget::Place(place) = &mut f.target {
+                    self.var(place);
+                }
+                self.code(&mut f.code);
+            }
+            Block::Raw { code } => self.code(code),
+            Block::If {
+                branches,
+                otherwise,
+            } => {
+                let mut kept = vec![];
+                for (mut cond, mut code) in branches.drain(..) {
+                    self.expr(&mut cond);
+                    match truthy(&cond) {
+                        Some(false) => {}
+                        // The following branches are never taken
+                        Some(true) => {
+                            self.code(&mut code);
+                            *otherwise = Some(code);
+                            break;
+                        }
+                        None => {
+                            self.code(&mut code);
+                            kept.push((cond, code));
+                        }
+                    }
+                }
+                if kept.is_empty() {
+                    // The code keeps its own scope
+                    return otherwise
+                        .take()
+                        .filter(|code| !code.is_empty())
+                        .map(|code| Stat::Block(Block::Raw { code }));
+                }
+                *branches = kept;
+                if let Some(code) = otherwise {
+                    self.code(code);
+                }
+            }
+            Block::While { cond, code } => {
+                self.expr(cond);
+                if truthy(cond) == Some(false) {
+                    return None;
+                }
+                self.code(code);
+            }
+            Block::NumericFor {
+                start,
+                limit,
+                step,
+                code,
+                ..
+            } => {
+                self.expr(start);
+                self.expr(limit);
+                if let Some(step) = step {
+                    self.expr(step);
+                }
+                self.code(code);
+            }
+            Block::GenericFor { values, code, .. } => {
+                self.exprs(values);
+                self.code(code);
+            }
+            Block::Repeat { code, cond } => {
+                self.code(code);
+                self.expr(cond);
+            }
+        }
+        Some(Stat::Block(block))
+    }
+
+    fn var(&self, var: &mut Var) {
+        match var {
+            Var::Ident(_) => {}
+            Var::Expression(e) => self.expr(e),
+        }
+    }
+
+    fn exprs(&self, exprs: &mut [Expression]) {
+        exprs.iter_mut().for_each(|e| self.expr(e));
+    }
+
+    fn expr(&self, e: &mut Expression) {
+        match e {
+            Expression::Function { code, .. } => self.code(code),
+            Expression::Vararg
+            | Expression::Number(_)
+            | Expression::Bool(_)
+            | Expression::Ident(_)
+            | Expression::Nil
+            | Expression::String(_) => {}
+            Expression::TableAccess { table, key } => {
+                self.var(table);
+                self.expr(key);
+            }
+            Expression::Table(fields) => {
+                for (k, v) in fields {
+                    self.expr(k);
+                    self.expr(v);
+                }
+            }
+            Expression::List(values) => self.exprs(values),
+            Expression::Call {
+                function,
+                parameters,
+            } => {
+                self.expr(function);
+                self.exprs(parameters);
+            }
+            Expression::Method {
+                object, parameters, ..
+            } => {
+                self.expr(object);
+                self.exprs(parameters);
+            }
+            Expression::BinOp { op, lhs, rhs } => {
+                self.expr(lhs);
+                self.expr(rhs);
+                let op = *op;
+                let folded = match (op, truthy(lhs)) {
+                    // `and` and `or` only evaluate their right operand when needed
+                    (BinOp::And, Some(false)) | (BinOp::Or, Some(true)) => Some(take(lhs)),
+                    (BinOp::And, Some(true)) | (BinOp::Or, Some(false)) => {
+                        Some(take(rhs).truncated())
+                    }
+                    _ => self.binop(op, lhs, rhs),
+                };
+                if let Some(folded) = folded {
+                    *e = folded;
+                }
+            }
+            Expression::UnOp { op, value } => {
+                self.expr(value);
+                if let Some(folded) = self.unop(*op, value) {
+                    *e = folded;
+                }
+            }
+            Expression::Paren(value) => {
+                self.expr(value);
+                if !value.is_multi() {
+                    *e = take(value);
+                }
+            }
+        }
+    }
+
+    fn binop(&self, op: BinOp, lhs: &Expression, rhs: &Expression) -> Option<Expression> {
+        use Expression::{Bool, Nil, Number as N, String as S};
+        let equal = match (lhs, rhs) {
+            (N(a), N(b)) => {
+                let ord = self.compare(*a, *b);
+                return Some(Bool(match op {
+                    BinOp::Eq => ord == Some(Ordering::Equal),
+                    BinOp::Ne => ord != Some(Ordering::Equal),
+                    BinOp::Lt => ord == Some(Ordering::Less),
+                    BinOp::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+                    BinOp::Gt => ord == Some(Ordering::Greater),
+                    BinOp::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                    BinOp::Concat => return self.concat(lhs, rhs),
+                    _ => return self.arith(op, *a, *b).map(N),
+                }));
+            }
+            (S(_), N(_)) | (N(_), S(_)) if op == BinOp::Concat => return self.concat(lhs, rhs),
+            // Strings are ordered by the locale
+            (S(a), S(b)) if op == BinOp::Concat || op == BinOp::Eq || op == BinOp::Ne => {
+                if op == BinOp::Concat {
+                    return self.concat(lhs, rhs);
+                }
+                a == b
+            }
+            (Bool(a), Bool(b)) => a == b,
+            (Nil, Nil) => true,
+            // Values of different types are never equal, numbers aren't converted to strings
+            (N(_) | S(_) | Bool(_) | Nil, N(_) | S(_) | Bool(_) | Nil) => false,
+            _ => return None,
+        };
+        match op {
+            BinOp::Eq => Some(Bool(equal)),
+            BinOp::Ne => Some(Bool(!equal)),
+            _ => None,
+        }
+    }
+
+    fn unop(&self, op: UnOp, value: &Expression) -> Option<Expression> {
+        Some(match (op, value) {
+            (UnOp::Not, value) => Expression::Bool(!truthy(value)?),
+            (UnOp::Neg, Expression::Number(Number::Int(i))) => {
+                Expression::Number(Number::Int(i.wrapping_neg()))
+            }
+            (UnOp::Neg, Expression::Number(Number::Float(f))) => {
+                Expression::Number(Number::Float(-f))
+            }
+            (UnOp::Len, Expression::String(s)) => Expression::Number(Number::Int(s.len() as i64)),
+            (UnOp::BNot, Expression::Number(Number::Int(i))) => Expression::Number(Number::Int(!i)),
+            _ => return None,
+        })
+    }
+
+    fn arith(&self, op: BinOp, a: Number, b: Number) -> Option<Number> {
+        if let (Number::Int(a), Number::Int(b)) = (a, b) {
+            let int = match op {
+                BinOp::Add => a.wrapping_add(b),
+                BinOp::Sub => a.wrapping_sub(b),
+                BinOp::Mul => a.wrapping_mul(b),
+                // Dividing an integer by zero is an error
+                BinOp::IDiv | BinOp::Mod if b == 0 => return None,
+                BinOp::IDiv => {
+                    let q = a.wrapping_div(b);
+                    if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                        q - 1
+                    } else {
+                        q
+                    }
+                }
+                BinOp::Mod => {
+                    let r = a.wrapping_rem(b);
+                    if r != 0 && (r ^ b) < 0 {
+                        r + b
+                    } else {
+                        r
+                    }
+                }
+                BinOp::BAnd => a & b,
+                BinOp::BOr => a | b,
+                BinOp::BXor => a ^ b,
+                BinOp::Shl => shift_left(a, b),
+                BinOp::Shr => shift_left(a, b.wrapping_neg()),
+                _ => return self.float_arith(op, a as f64, b as f64),
+            };
+            return Some(Number::Int(int));
+        }
+        self.float_arith(op, self.to_f64(a), self.to_f64(b))
+    }
+
+    fn float_arith(&self, op: BinOp, a: f64, b: f64) -> Option<Number> {
+        let v = match op {
+            BinOp::Add => a + b,
+            BinOp::Sub => a - b,
+            BinOp::Mul => a * b,
+            BinOp::Div => a / b,
+            BinOp::IDiv => (a / b).floor(),
+            BinOp::Mod => {
+                let m = a % b;
+                if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+                    m + b
+                } else {
+                    m
+                }
+            }
+            // `^` depends on the `pow` of the C library, bitwise operators need integers
+            _ => return None,
+        };
+        Some(Number::Float(v))
+    }
+
+    fn concat(&self, lhs: &Expression, rhs: &Expression) -> Option<Expression> {
+        let mut s = self.concat_operand(lhs)?;
+        s.extend(self.concat_operand(rhs)?);
+        Some(Expression::String(s))
+    }
+
+    /// Floats are formatted with the `%.14g` of the C library, so they aren't folded
+    fn concat_operand(&self, e: &Expression) -> Option<Vec<u8>> {
+        match e {
+            Expression::String(s) => Some(s.clone()),
+            Expression::Number(Number::Int(i)) => Some(i.to_string().into_bytes()),
+            _ => None,
+        }
+    }
+
+    fn compare(&self, a: Number, b: Number) -> Option<Ordering> {
+        match (a, b) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+            (Number::Int(i), Number::Float(f)) => compare_int_float(i, f),
+            (Number::Float(f), Number::Int(i)) => compare_int_float(i, f).map(Ordering::reverse),
+            (Number::Float(a), Number::Float(b)) => a.partial_cmp(&b),
+        }
+    }
+
+    fn to_f64(&self, n: Number) -> f64 {
+        match n {
+            Number::Int(i) => i as f64,
+            Number::Float(f) => f,
+        }
+    }
+}
+
+/// Compares an integer and a float exactly, as lua does
+fn compare_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    // 2^63, the first float above all integers
+    if f >= 9223372036854775808.0 {
+        return Some(Ordering::Less);
+    }
+    if f < -9223372036854775808.0 {
+        return Some(Ordering::Greater);
+    }
+    let floor = f.floor();
+    match i.cmp(&(floor as i64)) {
+        Ordering::Equal if floor != f => Some(Ordering::Less),
+        ord => Some(ord),
+    }
+}
+
+/// The logical shift of lua, shifting right for negative amounts
+fn shift_left(a: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((a as u64) << n) as i64
+    } else {
+        ((a as u64) >> -n) as i64
+    }
+}
+
+/// Whether a literal is true in conditions, `None` for other expressions
+fn truthy(e: &Expression) -> Option<bool> {
+    match e {
+        Expression::Nil => Some(false),
+        Expression::Bool(b) => Some(*b),
+        Expression::Number(_) | Expression::String(_) => Some(true),
+        _ => None,
+    }
+}
+
+fn take(e: &mut Expression) -> Expression {
+    std::mem::replace(e, Expression::Nil)
+}
+
+fn empty_block() -> Block {
+    Block::Raw { code: Vec::new() }
+}
+
+/// The locals of a declaration whose value is a literal worth copying to their uses
+fn constant_locals(names: &[String], values: &[Expression]) -> Vec<(String, Expression)> {
+    // Locals past the values are `nil`, unless the last value is a call giving them values
+    let extra = match values.last() {
+        Some(last) if last.is_multi() => None,
+        _ => Some(Expression::Nil),
+    };
+    names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| names.iter().filter(|n| n == name).count() == 1)
+        .filter_map(|(i, name)| {
+            let value = match values.get(i) {
+                Some(value) => value.clone(),
+                None => extra.clone()?,
+            };
+            match value {
+                Expression::Number(_) | Expression::Bool(_) | Expression::Nil => {
+                    Some((name.clone(), value))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Can `code` assign to a variable named `name`. Shadowing locals are not told apart, and
+/// pre-rendered code could assign to anything.
+fn assigns(code: &[Stat], name: &str) -> bool {
+    code.iter().any(|stat| match stat {
+        Stat::Block(block) => match block {
+            Block::Function(f) => {
+                matches!(&f.target, FunctionTarget::Place(Var::Ident(n)) if n == name)
+                    || assigns(&f.code, name)
+                    || expr_assigns_in_var(&f.target, name)
+            }
+            Block::Raw { code } => assigns(code, name),
+            Block::If {
+                branches,
+                otherwise,
+            } => {
+                branches
+                    .iter()
+                    .any(|(cond, code)| expr_assigns(cond, name) || assigns(code, name))
+                    || matches!(otherwise, Some(code) if assigns(code, name))
+            }
+            Block::While { cond, code } | Block::Repeat { code, cond } => {
+                expr_assigns(cond, name) || assigns(code, name)
+            }
+            Block::NumericFor {
+                start,
+                limit,
+                step,
+                code,
+                ..
+            } => {
+                expr_assigns(start, name)
+                    || expr_assigns(limit, name)
+                    || matches!(step, Some(step) if expr_assigns(step, name))
+                    || assigns(code, name)
+            }
+            Block::GenericFor { values, code, .. } => {
+                values.iter().any(|e| expr_assigns(e, name)) || assigns(code, name)
+            }
+        },
+        Stat::Local { values, .. } | Stat::Return(values) => {
+            values.iter().any(|e| expr_assigns(e, name))
+        }
+        Stat::Assign { places, values } => {
+            places.iter().any(|place| match place {
+                Var::Ident(n) => n == name,
+                Var::Expression(e) => expr_assigns(e, name),
+            }) || values.iter().any(|e| expr_assigns(e, name))
+        }
+        Stat::Call(call) => expr_assigns(call, name),
+        Stat::Rendered(_) => true,
+        Stat::Break | Stat::Comment(_) | Stat::Goto(_) | Stat::Label(_) => false,
+    })
+}
+
+fn expr_assigns_in_var(target: &FunctionTarget, name: &str) -> bool {
+    match target {
+        FunctionTarget::Place(Var::Expression(e)) => expr_assigns(e, name),
+        _ => false,
+    }
+}
+
+/// Can the functions defined in `e` assign to a variable named `name`
+fn expr_assigns(e: &Expression, name: &str) -> bool {
+    match e {
+        Expression::Function { code, .. } => assigns(code, name),
+        Expression::Vararg
+        | Expression::Number(_)
+        | Expression::Bool(_)
+        | Expression::Ident(_)
+        | Expression::Nil
+        | Expression::String(_) => false,
+        Expression::TableAccess { table, key } => {
+            matches!(&**table, Var::Expression(e) if expr_assigns(e, name))
+                || expr_assigns(key, name)
+        }
+        Expression::Table(fields) => fields
+            .iter()
+            .any(|(k, v)| expr_assigns(k, name) || expr_assigns(v, name)),
+        Expression::List(values) => values.iter().any(|e| expr_assigns(e, name)),
+        Expression::Call {
+            function: callee,
+            parameters,
+        }
+        | Expression::Method {
+            object: callee,
+            parameters,
+            ..
+        } => expr_assigns(callee, name) || parameters.iter().any(|e| expr_assigns(e, name)),
+        Expression::BinOp { lhs, rhs, .. } => expr_assigns(lhs, name) || expr_assigns(rhs, name),
+        Expression::UnOp { value, .. } | Expression::Paren(value) => expr_assigns(value, name),
+    }
+}
+
+/// Replaces the uses of the local `name` by `value` in `code`, until a local of the same name
+/// shadows it. Returns whether it was shadowed.
+fn substitute_code(code: &mut [Stat], name: &str, value: &Expression) -> bool {
+    for stat in code {
+        match stat {
+            Stat::Block(block) => match block {
+                Block::Function(f) => {
+                    if let FunctionTarget::Place(place) = &mut f.target {
+                        substitute_var(place, name, value);
+                    }
+                    let shadowed = matches!(&f.target, FunctionTarget::Local(n) if n == name);
+                    if !shadowed && !f.params.iter().any(|p| p == name) {
+                        substitute_code(&mut f.code, name, value);
+                    }
+                    if shadowed {
+                        return true;
+                    }
+                }
+                Block::Raw { code } => {
+                    substitute_code(code, name, value);
+                }
+                Block::If {
+                    branches,
+                    otherwise,
+                } => {
+                    for (cond, code) in branches {
+                        substitute(cond, name, value);
+                        substitute_code(code, name, value);
+                    }
+                    if let Some(code) = otherwise {
+                        substitute_code(code, name, value);
+                    }
+                }
+                Block::While { cond, code } => {
+                    substitute(cond, name, value);
+                    substitute_code(code, name, value);
+                }
+                Block::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                    code,
+                } => {
+                    substitute(start, name, value);
+                    substitute(limit, name, value);
+                    if let Some(step) = step {
+                        substitute(step, name, value);
+                    }
+                    if var != name {
+                        substitute_code(code, name, value);
+                    }
+                }
+                Block::GenericFor {
+                    names,
+                    values,
+                    code,
+                } => {
+                    values.iter_mut().for_each(|e| substitute(e, name, value));
+                    if !names.iter().any(|n| n == name) {
+                        substitute_code(code, name, value);
+                    }
+                }
+                Block::Repeat { code, cond } => {
+                    // The condition is in the scope of the code
+                    if !substitute_code(code, name, value) {
+                        substitute(cond, name, value);
+                    }
+                }
+            },
+            Stat::Local { names, values } => {
+                values.iter_mut().for_each(|e| substitute(e, name, value));
+                if names.iter().any(|n| n == name) {
+                    return true;
+                }
+            }
+            Stat::Assign { places, values } => {
+                places
+                    .iter_mut()
+                    .for_each(|place| substitute_var(place, name, value));
+                values.iter_mut().for_each(|e| substitute(e, name, value));
+            }
+            Stat::Call(call) => substitute(call, name, value),
+            Stat::Return(values) => values.iter_mut().for_each(|e| substitute(e, name, value)),
+            // No expressions
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Replaces the uses of `name` inside a place, which can't be `name` itself
+fn substitute_var(var: &mut Var, name: &str, value: &Expression) {
+    if let Var::Expression(e) = var {
+        substitute(e, name, value);
+    }
+}
+
+fn substitute(e: &mut Expression, name: &str, value: &Expression) {
+    match e {
+        Expression::Ident(n) if n == name => *e = value.clone(),
+        Expression::Function { params, code } => {
+            if !params.iter().any(|p| p == name) {
+                substitute_code(code, name, value);
+            }
+        }
+        Expression::Vararg
+        | Expression::Number(_)
+        | Expression::Bool(_)
+        | Expression::Ident(_)
+        | Expression::Nil
+        | Expression::String(_) => {}
+        Expression::TableAccess { table, key } => {
+            match &mut **table {
+                Var::Ident(n) if n == name => **table = Var::Expression(value.clone()),
+                table => substitute_var(table, name, value),
+            }
+            substitute(key, name, value);
+        }
+        Expression::Table(fields) => {
+            for (k, v) in fields {
+                substitute(k, name, value);
+                substitute(v, name, value);
+            }
+        }
+        Expression::List(values) => values.iter_mut().for_each(|e| substitute(e, name, value)),
+        Expression::Call {
+            function: callee,
+            parameters,
+        }
+        | Expression::Method {
+            object: callee,
+            parameters,
+            ..
+        } => {
+            substitute(callee, name, value);
+            parameters
+                .iter_mut()
+                .for_each(|e| substitute(e, name, value));
+        }
+        Expression::BinOp { lhs, rhs, .. } => {
+            substitute(lhs, name, value);
+            substitute(rhs, name, value);
+        }
+        Expression::UnOp { value: operand, .. } | Expression::Paren(operand) => {
+            substitute(operand, name, value)
+        }
+    }
+}
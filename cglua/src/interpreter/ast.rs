@@ -0,0 +1,100 @@
+//! The syntax tree run by the interpreter, with names resolved to local slots, upvalues and globals
+
+use std::rc::Rc;
+
+use super::Value;
+use crate::{BinOp, UnOp};
+
+pub(crate) struct Proto {
+    /// The name of the chunk the function was defined in, for error messages
+    pub(crate) chunk: Rc<str>,
+    pub(crate) line: u32,
+    /// The parameters are the first locals
+    pub(crate) params: usize,
+    pub(crate) vararg: bool,
+    pub(crate) body: Block,
+    /// The name of each local slot and whether a closure captures it
+    pub(crate) locals: Vec<(Rc<str>, bool)>,
+    pub(crate) upvalues: Vec<(Rc<str>, Upvalue)>,
+}
+
+/// Where a closure finds an upvalue when it is created
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Upvalue {
+    /// A local of the enclosing function
+    Local(usize),
+    /// An upvalue of the enclosing function
+    Upvalue(usize),
+}
+
+#[derive(Default)]
+pub(crate) struct Block {
+    pub(crate) stats: Vec<Stat>,
+    /// The labels defined in the block and the index of the statement following them
+    pub(crate) labels: Vec<(usize, usize)>,
+}
+
+pub(crate) struct Stat {
+    pub(crate) line: u32,
+    pub(crate) kind: StatKind,
+}
+
+pub(crate) enum StatKind {
+    Call(Expr),
+    Local(Vec<usize>, Vec<Expr>),
+    /// The targets are `Local`, `Upvalue`, `Global` or `Index` expressions
+    Assign(Vec<Expr>, Vec<Expr>),
+    LocalFunction(usize, Rc<Proto>),
+    Do(Block),
+    While(Expr, Block),
+    /// The condition is on the line of `until`
+    Repeat(Block, u32, Expr),
+    /// Each condition is on the line of its `if` or `elseif`
+    If(Vec<(u32, Expr, Block)>, Option<Block>),
+    NumericFor {
+        var: usize,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Block,
+    },
+    GenericFor {
+        vars: Vec<usize>,
+        values: Vec<Expr>,
+        body: Block,
+    },
+    Return(Vec<Expr>),
+    Break,
+    Goto(usize),
+}
+
+pub(crate) enum Expr {
+    Const(Value),
+    Vararg,
+    Local(usize),
+    Upvalue(usize),
+    Global(Value),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    /// `object:method(args)`
+    Method(Box<Expr>, Value, Vec<Expr>),
+    Function(Rc<Proto>),
+    /// `and` and `or` are binary operators too
+    BinOp(BinOp, Box<Expr>, Box<Expr>),
+    UnOp(UnOp, Box<Expr>),
+    Table(Vec<Field>),
+    /// Parentheses truncate multiple values to one
+    Paren(Box<Expr>),
+}
+
+pub(crate) enum Field {
+    Positional(Expr),
+    Keyed(Expr, Expr),
+}
+
+impl Expr {
+    /// Whether the expression can evaluate to several values
+    pub(crate) fn is_multi(&self) -> bool {
+        matches!(self, Expr::Vararg | Expr::Call(..) | Expr::Method(..))
+    }
+}
@@ -0,0 +1,414 @@
+//! The base library, and the helpers used by the library functions to check their arguments
+
+use std::rc::Rc;
+
+use super::value::str_to_number;
+use super::{Function, LResult, Lua, Table, Throw, Value};
+
+pub(crate) type Builtin = fn(&mut Lua, Vec<Value>) -> LResult<Vec<Value>>;
+
+pub(crate) fn register(table: &Table, functions: &[(&str, Builtin)]) {
+    for &(name, f) in functions {
+        table.set(name, Function::native(f));
+    }
+}
+
+pub(crate) fn arg(args: &[Value], i: usize) -> Value {
+    args.get(i).cloned().unwrap_or_default()
+}
+
+pub(crate) fn bad_arg(lua: &Lua, i: usize, function: &str, msg: impl std::fmt::Display) -> Throw {
+    lua.error(format!(
+        "bad argument #{} to '{}' ({})",
+        i + 1,
+        function,
+        msg
+    ))
+}
+
+fn type_error(lua: &Lua, args: &[Value], i: usize, function: &str, expected: &str) -> Throw {
+    let got = match args.get(i) {
+        Some(v) => v.type_name(),
+        None => "no value",
+    };
+    bad_arg(
+        lua,
+        i,
+        function,
+        format!("{} expected, got {}", expected, got),
+    )
+}
+
+pub(crate) fn check_any(lua: &Lua, args: &[Value], i: usize, function: &str) -> LResult<Value> {
+    match args.get(i) {
+        Some(v) => Ok(v.clone()),
+        None => Err(bad_arg(lua, i, function, "value expected")),
+    }
+}
+
+pub(crate) fn check_int(lua: &Lua, args: &[Value], i: usize, function: &str) -> LResult<i64> {
+    match args.get(i).and_then(Value::to_number) {
+        Some(Value::Int(v)) => Ok(v),
+        Some(v) => v
+            .as_int()
+            .ok_or_else(|| bad_arg(lua, i, function, "number has no integer representation")),
+        None => Err(type_error(lua, args, i, function, "number")),
+    }
+}
+
+pub(crate) fn opt_int(
+    lua: &Lua,
+    args: &[Value],
+    i: usize,
+    function: &str,
+    default: i64,
+) -> LResult<i64> {
+    match args.get(i) {
+        None | Some(Value::Nil) => Ok(default),
+        Some(_) => check_int(lua, args, i, function),
+    }
+}
+
+/// A number argument, as an integer or a float
+pub(crate) fn check_number(lua: &Lua, args: &[Value], i: usize, function: &str) -> LResult<Value> {
+    args.get(i)
+        .and_then(Value::to_number)
+        .ok_or_else(|| type_error(lua, args, i, function, "number"))
+}
+
+pub(crate) fn check_float(lua: &Lua, args: &[Value], i: usize, function: &str) -> LResult<f64> {
+    Ok(check_number(lua, args, i, function)?.as_float().unwrap())
+}
+
+/// A string argument, numbers being converted to strings
+pub(crate) fn check_bytes(
+    lua: &Lua,
+    args: &[Value],
+    i: usize,
+    function: &str,
+) -> LResult<Rc<[u8]>> {
+    args.get(i)
+        .and_then(Value::coerce_to_bytes)
+        .ok_or_else(|| type_error(lua, args, i, function, "string"))
+}
+
+pub(crate) fn check_table(lua: &Lua, args: &[Value], i: usize, function: &str) -> LResult<Table> {
+    match args.get(i) {
+        Some(Value::Table(t)) => Ok(t.clone()),
+        _ => Err(type_error(lua, args, i, function, "table")),
+    }
+}
+
+pub(crate) fn open(lua: &mut Lua) {
+    let globals = lua.globals.clone();
+    register(
+        &globals,
+        &[
+            ("assert", assert),
+            ("error", error),
+            ("getmetatable", getmetatable),
+            ("ipairs", ipairs),
+            ("load", load),
+            ("next", next),
+            ("pairs", pairs),
+            ("pcall", pcall),
+            ("print", print),
+            ("rawequal", rawequal),
+            ("rawget", rawget),
+            ("rawlen", rawlen),
+            ("rawset", rawset),
+            ("select", select),
+            ("setmetatable", setmetatable),
+            ("tonumber", tonumber),
+            ("tostring", tostring),
+            ("type", type_),
+            ("xpcall", xpcall),
+        ],
+    );
+    globals.set("_G", globals.clone());
+    globals.set("_VERSION", "Lua 5.3");
+}
+
+fn assert(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let v = check_any(lua, &args, 0, "assert")?;
+    if v.truthy() {
+        return Ok(args);
+    }
+    match args.get(1) {
+        Some(msg) => Err(Throw::Error(msg.clone())),
+        None => Err(lua.error("assertion failed!")),
+    }
+}
+
+fn error(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let value = arg(&args, 0);
+    let level = opt_int(lua, &args, 1, "error", 1)?;
+    // The level 1 is the caller of `error`
+    let value = match (&value, lua.position(level.max(0) as usize + 1)) {
+        (Value::Str(s), Some(position)) => {
+            let mut msg = position.into_bytes();
+            msg.push(b' ');
+            msg.extend_from_slice(s);
+            Value::from(msg)
+        }
+        _ => value,
+    };
+    Err(Throw::Error(value))
+}
+
+fn getmetatable(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let metatable = match check_any(lua, &args, 0, "getmetatable")? {
+        Value::Table(t) => t.metatable(),
+        Value::Str(_) => {
+            let mt = Table::new();
+            mt.set("__index", lua.string.clone());
+            Some(mt)
+        }
+        _ => None,
+    };
+    Ok(vec![match metatable {
+        Some(mt) => match mt.get("__metatable") {
+            Value::Nil => Value::Table(mt),
+            protected => protected,
+        },
+        None => Value::Nil,
+    }])
+}
+
+fn ipairs_next(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let i = check_int(lua, &args, 1, "ipairs")?.wrapping_add(1);
+    let v = lua.index(arg(&args, 0), Value::Int(i))?;
+    Ok(if v.is_nil() {
+        vec![Value::Nil]
+    } else {
+        vec![Value::Int(i), v]
+    })
+}
+
+fn ipairs(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = check_any(lua, &args, 0, "ipairs")?;
+    Ok(vec![
+        Value::Function(Function::native(ipairs_next)),
+        t,
+        Value::Int(0),
+    ])
+}
+
+fn load(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let chunk = match arg(&args, 0) {
+        Value::Str(s) => s,
+        _ => return Err(bad_arg(lua, 0, "load", "only string chunks are supported")),
+    };
+    let name = match arg(&args, 1) {
+        Value::Str(name) => String::from_utf8_lossy(&name).into_owned(),
+        _ => format!(
+            "[string \"{}\"]",
+            String::from_utf8_lossy(&chunk).lines().next().unwrap_or("")
+        ),
+    };
+    if args.len() > 3 {
+        return Err(bad_arg(
+            lua,
+            3,
+            "load",
+            "custom environments are not supported",
+        ));
+    }
+    Ok(match lua.load(&name, &chunk) {
+        Ok(f) => vec![Value::Function(f)],
+        Err(err) => vec![Value::Nil, Value::from(err.to_string())],
+    })
+}
+
+fn next(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = check_table(lua, &args, 0, "next")?;
+    let key = arg(&args, 1).into_key();
+    let entry = t.0.borrow().next(&key);
+    match entry {
+        Ok(Some((k, v))) => Ok(vec![k, v]),
+        Ok(None) => Ok(vec![Value::Nil]),
+        Err(()) => Err(lua.error("invalid key to 'next'")),
+    }
+}
+
+fn pairs(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = check_any(lua, &args, 0, "pairs")?;
+    if let Value::Table(table) = &t {
+        let handler = table.metamethod("__pairs");
+        if !handler.is_nil() {
+            let mut results = lua.call_value(&handler, vec![t])?;
+            results.resize(3, Value::Nil);
+            return Ok(results);
+        }
+    } else {
+        return Err(bad_arg(
+            lua,
+            0,
+            "pairs",
+            format!("table expected, got {}", t.type_name()),
+        ));
+    }
+    Ok(vec![Value::Function(Function::native(next)), t, Value::Nil])
+}
+
+fn pcall(lua: &mut Lua, mut args: Vec<Value>) -> LResult<Vec<Value>> {
+    let f = check_any(lua, &args, 0, "pcall")?;
+    args.remove(0);
+    let depth = lua.stack.len();
+    match lua.call_value(&f, args) {
+        Ok(mut results) => {
+            results.insert(0, Value::Bool(true));
+            Ok(results)
+        }
+        Err(Throw::Error(e)) => {
+            lua.stack.truncate(depth);
+            Ok(vec![Value::Bool(false), e])
+        }
+        Err(exit) => Err(exit),
+    }
+}
+
+fn xpcall(lua: &mut Lua, mut args: Vec<Value>) -> LResult<Vec<Value>> {
+    let handler = check_any(lua, &args, 1, "xpcall")?;
+    let f = args.remove(0);
+    args.remove(0);
+    let depth = lua.stack.len();
+    match lua.call_value(&f, args) {
+        Ok(mut results) => {
+            results.insert(0, Value::Bool(true));
+            Ok(results)
+        }
+        Err(Throw::Error(e)) => {
+            let mut results = lua.call_value(&handler, vec![e])?;
+            lua.stack.truncate(depth);
+            results.insert(0, Value::Bool(false));
+            Ok(results)
+        }
+        Err(exit) => Err(exit),
+    }
+}
+
+fn print(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let mut line = vec![];
+    for (i, v) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(&lua.tostring(v)?);
+    }
+    line.push(b'\n');
+    lua.write_stdout(&line);
+    Ok(vec![])
+}
+
+fn rawequal(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let a = check_any(lua, &args, 0, "rawequal")?;
+    let b = check_any(lua, &args, 1, "rawequal")?;
+    Ok(vec![Value::Bool(a == b)])
+}
+
+fn rawget(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = check_table(lua, &args, 0, "rawget")?;
+    Ok(vec![t.get(arg(&args, 1))])
+}
+
+fn rawlen(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    match arg(&args, 0) {
+        Value::Table(t) => Ok(vec![Value::Int(t.len())]),
+        Value::Str(s) => Ok(vec![Value::Int(s.len() as i64)]),
+        _ => Err(bad_arg(lua, 0, "rawlen", "table or string expected")),
+    }
+}
+
+fn rawset(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = check_table(lua, &args, 0, "rawset")?;
+    lua.raw_set(&t, arg(&args, 1), arg(&args, 2))?;
+    Ok(vec![Value::Table(t)])
+}
+
+fn select(lua: &mut Lua, mut args: Vec<Value>) -> LResult<Vec<Value>> {
+    let n = args.len() as i64 - 1;
+    if let Value::Str(s) = arg(&args, 0) {
+        if &*s == b"#" {
+            return Ok(vec![Value::Int(n)]);
+        }
+    }
+    let i = check_int(lua, &args, 0, "select")?;
+    let start = if i < 0 {
+        if -i > n {
+            return Err(bad_arg(lua, 0, "select", "index out of range"));
+        }
+        n + i + 1
+    } else if i == 0 {
+        return Err(bad_arg(lua, 0, "select", "index out of range"));
+    } else {
+        i.min(n + 1)
+    };
+    Ok(args.split_off(start as usize))
+}
+
+fn setmetatable(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = check_table(lua, &args, 0, "setmetatable")?;
+    let metatable = match arg(&args, 1) {
+        Value::Nil => None,
+        Value::Table(mt) => Some(mt),
+        _ => return Err(bad_arg(lua, 1, "setmetatable", "nil or table expected")),
+    };
+    if !t.metamethod("__metatable").is_nil() {
+        return Err(lua.error("cannot change a protected metatable"));
+    }
+    t.set_metatable(metatable);
+    Ok(vec![Value::Table(t)])
+}
+
+fn tonumber(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let base = match arg(&args, 1) {
+        Value::Nil => {
+            let v = check_any(lua, &args, 0, "tonumber")?;
+            return Ok(vec![match &v {
+                Value::Int(_) | Value::Float(_) => v,
+                Value::Str(s) => str_to_number(s).unwrap_or_default(),
+                _ => Value::Nil,
+            }]);
+        }
+        _ => check_int(lua, &args, 1, "tonumber")?,
+    };
+    if !(2..=36).contains(&base) {
+        return Err(bad_arg(lua, 1, "tonumber", "base out of range"));
+    }
+    let s = match arg(&args, 0) {
+        Value::Str(s) => s,
+        _ => return Err(type_error(lua, &args, 0, "tonumber", "string")),
+    };
+    let s = String::from_utf8_lossy(&s);
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        match c.to_digit(36) {
+            Some(d) if (d as i64) < base => n = n.wrapping_mul(base).wrapping_add(d as i64),
+            _ => return Ok(vec![Value::Nil]),
+        }
+    }
+    if digits.is_empty() {
+        return Ok(vec![Value::Nil]);
+    }
+    Ok(vec![Value::Int(if negative {
+        n.wrapping_neg()
+    } else {
+        n
+    })])
+}
+
+fn tostring(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let v = check_any(lua, &args, 0, "tostring")?;
+    Ok(vec![Value::Str(lua.tostring(&v)?)])
+}
+
+fn type_(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let v = check_any(lua, &args, 0, "type")?;
+    Ok(vec![Value::from(v.type_name())])
+}
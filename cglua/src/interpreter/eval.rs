@@ -0,0 +1,1082 @@
+//! Execution of the syntax tree
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::ast::{Block, Expr, Field, Proto, Stat, StatKind, Upvalue};
+use super::value::{float_to_int, Closure, FunctionKind};
+use super::{Function, LResult, Lua, Table, Throw, Value, MAX_CALL_DEPTH};
+use crate::{BinOp, UnOp};
+
+/// A local variable, in a cell shared with closures when one captures it
+enum Slot {
+    Value(Value),
+    Cell(Rc<RefCell<Value>>),
+}
+
+struct Frame<'a> {
+    closure: &'a Closure,
+    slots: Vec<Slot>,
+    varargs: Vec<Value>,
+}
+
+impl Frame<'_> {
+    fn get(&self, slot: usize) -> Value {
+        match &self.slots[slot] {
+            Slot::Value(v) => v.clone(),
+            Slot::Cell(cell) => cell.borrow().clone(),
+        }
+    }
+
+    fn set(&mut self, slot: usize, value: Value) {
+        match &mut self.slots[slot] {
+            Slot::Value(v) => *v = value,
+            Slot::Cell(cell) => *cell.borrow_mut() = value,
+        }
+    }
+
+    /// Starts the scope of a local, which gets a new cell if it is captured
+    fn declare(&mut self, slot: usize, value: Value) {
+        self.slots[slot] = if self.closure.proto.locals[slot].1 {
+            Slot::Cell(Rc::new(RefCell::new(value)))
+        } else {
+            Slot::Value(value)
+        };
+    }
+
+    fn cell(&mut self, slot: usize) -> Rc<RefCell<Value>> {
+        if let Slot::Value(v) = &mut self.slots[slot] {
+            let value = std::mem::take(v);
+            self.slots[slot] = Slot::Cell(Rc::new(RefCell::new(value)));
+        }
+        match &self.slots[slot] {
+            Slot::Cell(cell) => cell.clone(),
+            Slot::Value(_) => unreachable!(),
+        }
+    }
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+    Goto(usize),
+}
+
+pub(crate) fn main_closure(proto: Rc<Proto>) -> Function {
+    Function(Rc::new(FunctionKind::Lua(Closure {
+        proto,
+        upvalues: vec![],
+    })))
+}
+
+fn metamethod_event(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "__add",
+        BinOp::Sub => "__sub",
+        BinOp::Mul => "__mul",
+        BinOp::Div => "__div",
+        BinOp::IDiv => "__idiv",
+        BinOp::Mod => "__mod",
+        BinOp::Pow => "__pow",
+        BinOp::Concat => "__concat",
+        BinOp::BAnd => "__band",
+        BinOp::BOr => "__bor",
+        BinOp::BXor => "__bxor",
+        BinOp::Shl => "__shl",
+        BinOp::Shr => "__shr",
+        _ => unreachable!(),
+    }
+}
+
+fn shift_left(a: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((a as u64) << n) as i64
+    } else {
+        ((a as u64) >> -n) as i64
+    }
+}
+
+pub(crate) fn int_floor_div(a: i64, b: i64) -> i64 {
+    let q = a.wrapping_div(b);
+    if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+        q - 1
+    } else {
+        q
+    }
+}
+
+pub(crate) fn int_mod(a: i64, b: i64) -> i64 {
+    let r = a.wrapping_rem(b);
+    if r != 0 && (r ^ b) < 0 {
+        r + b
+    } else {
+        r
+    }
+}
+
+pub(crate) fn float_mod(a: f64, b: f64) -> f64 {
+    let r = a % b;
+    if r != 0.0 && (r < 0.0) != (b < 0.0) {
+        r + b
+    } else {
+        r
+    }
+}
+
+const TWO_POW_63: f64 = 9_223_372_036_854_775_808.0;
+
+fn int_lt_float(i: i64, f: f64) -> bool {
+    if f.is_nan() {
+        false
+    } else if f >= TWO_POW_63 {
+        true
+    } else if f > -TWO_POW_63 {
+        i < f.ceil() as i64
+    } else {
+        false
+    }
+}
+
+fn int_le_float(i: i64, f: f64) -> bool {
+    if f.is_nan() {
+        false
+    } else if f >= TWO_POW_63 {
+        true
+    } else if f >= -TWO_POW_63 {
+        i <= f.floor() as i64
+    } else {
+        false
+    }
+}
+
+/// The name of the variable or field `expr` for error messages
+fn describe(frame: &Frame<'_>, expr: &Expr) -> String {
+    let proto = &frame.closure.proto;
+    match expr {
+        Expr::Local(slot) => format!(" (local '{}')", proto.locals[*slot].0),
+        Expr::Upvalue(i) => format!(" (upvalue '{}')", proto.upvalues[*i].0),
+        Expr::Global(Value::Str(name)) => {
+            format!(" (global '{}')", String::from_utf8_lossy(name))
+        }
+        Expr::Index(_, key) => match &**key {
+            Expr::Const(Value::Str(name)) => {
+                format!(" (field '{}')", String::from_utf8_lossy(name))
+            }
+            _ => String::new(),
+        },
+        Expr::Method(_, Value::Str(name), _) => {
+            format!(" (method '{}')", String::from_utf8_lossy(name))
+        }
+        _ => String::new(),
+    }
+}
+
+impl Lua {
+    pub(crate) fn call_function(&mut self, f: &Function, args: Vec<Value>) -> LResult<Vec<Value>> {
+        if self.stack.len() >= MAX_CALL_DEPTH {
+            return Err(self.error("stack overflow"));
+        }
+        let closure = match &*f.0 {
+            FunctionKind::Native(native) => {
+                self.stack.push(None);
+                let res = native(self, args);
+                self.stack.pop();
+                return res;
+            }
+            FunctionKind::Lua(closure) => closure,
+        };
+        let proto = &closure.proto;
+        let mut frame = Frame {
+            closure,
+            slots: Vec::with_capacity(proto.locals.len()),
+            varargs: vec![],
+        };
+        frame
+            .slots
+            .resize_with(proto.locals.len(), || Slot::Value(Value::Nil));
+        let mut args = args.into_iter();
+        for slot in 0..proto.params {
+            frame.declare(slot, args.next().unwrap_or_default());
+        }
+        if proto.vararg {
+            frame.varargs = args.collect();
+        }
+
+        self.stack.push(Some((proto.chunk.clone(), proto.line)));
+        let res = self.exec_block(&mut frame, &proto.body);
+        self.stack.pop();
+        match res? {
+            Flow::Return(values) => Ok(values),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Calls `f`, or its `__call` metamethod
+    pub(crate) fn call_value(&mut self, f: &Value, mut args: Vec<Value>) -> LResult<Vec<Value>> {
+        match f {
+            Value::Function(f) => self.call_function(f, args),
+            Value::Table(t) => match t.metamethod("__call") {
+                Value::Function(call) => {
+                    args.insert(0, f.clone());
+                    self.call_function(&call, args)
+                }
+                _ => Err(self.error("attempt to call a table value")),
+            },
+            v => Err(self.error(format!("attempt to call a {} value", v.type_name()))),
+        }
+    }
+
+    /// The first result of calling `f`
+    pub(crate) fn call_value1(&mut self, f: &Value, args: Vec<Value>) -> LResult<Value> {
+        Ok(self
+            .call_value(f, args)?
+            .into_iter()
+            .next()
+            .unwrap_or_default())
+    }
+
+    fn exec_block(&mut self, frame: &mut Frame<'_>, block: &Block) -> LResult<Flow> {
+        let mut i = 0;
+        while let Some(stat) = block.stats.get(i) {
+            match self.exec_stat(frame, stat)? {
+                Flow::Normal => i += 1,
+                Flow::Goto(id) => match block.labels.iter().find(|(goto, _)| *goto == id) {
+                    Some(&(_, index)) => i = index,
+                    None => return Ok(Flow::Goto(id)),
+                },
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    /// Runs the body of a loop, returning the flow leaving the loop if it does
+    fn exec_loop_body(&mut self, frame: &mut Frame<'_>, body: &Block) -> LResult<Option<Flow>> {
+        match self.exec_block(frame, body)? {
+            Flow::Normal => Ok(None),
+            Flow::Break => Ok(Some(Flow::Normal)),
+            flow => Ok(Some(flow)),
+        }
+    }
+
+    /// Sets the current line of the running lua function
+    fn set_line(&mut self, line: u32) {
+        if let Some(Some((_, current))) = self.stack.last_mut() {
+            *current = line;
+        }
+    }
+
+    fn exec_stat(&mut self, frame: &mut Frame<'_>, stat: &Stat) -> LResult<Flow> {
+        self.set_line(stat.line);
+        match &stat.kind {
+            StatKind::Call(call) => {
+                self.eval_multi(frame, call)?;
+            }
+            StatKind::Local(slots, values) => {
+                if let ([slot], [value]) = (&slots[..], &values[..]) {
+                    let value = self.eval(frame, value)?;
+                    frame.declare(*slot, value);
+                } else {
+                    let values = self.eval_list_n(frame, values, slots.len())?;
+                    for (slot, value) in slots.iter().zip(values) {
+                        frame.declare(*slot, value);
+                    }
+                }
+            }
+            StatKind::Assign(targets, values) => self.exec_assign(frame, targets, values)?,
+            StatKind::LocalFunction(slot, proto) => {
+                frame.declare(*slot, Value::Nil);
+                let f = self.closure(frame, proto);
+                frame.set(*slot, f);
+            }
+            StatKind::Do(body) => return self.exec_block(frame, body),
+            StatKind::While(cond, body) => loop {
+                self.set_line(stat.line);
+                if !self.eval(frame, cond)?.truthy() {
+                    break;
+                }
+                if let Some(flow) = self.exec_loop_body(frame, body)? {
+                    return Ok(flow);
+                }
+            },
+            StatKind::Repeat(body, line, cond) => loop {
+                if let Some(flow) = self.exec_loop_body(frame, body)? {
+                    return Ok(flow);
+                }
+                self.set_line(*line);
+                if self.eval(frame, cond)?.truthy() {
+                    break;
+                }
+            },
+            StatKind::If(branches, otherwise) => {
+                for (line, cond, body) in branches {
+                    self.set_line(*line);
+                    if self.eval(frame, cond)?.truthy() {
+                        return self.exec_block(frame, body);
+                    }
+                }
+                if let Some(body) = otherwise {
+                    return self.exec_block(frame, body);
+                }
+            }
+            StatKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => return self.exec_numeric_for(frame, *var, start, limit, step.as_ref(), body),
+            StatKind::GenericFor { vars, values, body } => {
+                let mut values = self.eval_list_n(frame, values, 3)?.into_iter();
+                let (f, state, mut control) = (
+                    values.next().unwrap(),
+                    values.next().unwrap(),
+                    values.next().unwrap(),
+                );
+                loop {
+                    self.set_line(stat.line);
+                    let mut results = self.call_value(&f, vec![state.clone(), control])?;
+                    control = results.first().cloned().unwrap_or_default();
+                    if control.is_nil() {
+                        break;
+                    }
+                    results.resize(vars.len(), Value::Nil);
+                    for (var, value) in vars.iter().zip(results) {
+                        frame.declare(*var, value);
+                    }
+                    if let Some(flow) = self.exec_loop_body(frame, body)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            StatKind::Return(values) => {
+                let values = match &values[..] {
+                    [value] if !value.is_multi() => vec![self.eval(frame, value)?],
+                    values => self.eval_list(frame, values)?,
+                };
+                return Ok(Flow::Return(values));
+            }
+            StatKind::Break => return Ok(Flow::Break),
+            StatKind::Goto(id) => return Ok(Flow::Goto(*id)),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_numeric_for(
+        &mut self,
+        frame: &mut Frame<'_>,
+        var: usize,
+        start: &Expr,
+        limit: &Expr,
+        step: Option<&Expr>,
+        body: &Block,
+    ) -> LResult<Flow> {
+        let start = self.eval(frame, start)?;
+        let limit = self.eval(frame, limit)?;
+        let step = match step {
+            Some(step) => self.eval(frame, step)?,
+            None => Value::Int(1),
+        };
+        let number = |lua: &Lua, v: Value, what: &str| {
+            v.to_number()
+                .ok_or_else(|| lua.error(format!("'for' {} must be a number", what)))
+        };
+        let start = number(self, start, "initial value")?;
+        let limit = number(self, limit, "limit")?;
+        let step = number(self, step, "step")?;
+
+        if let (Value::Int(start), Value::Int(step)) = (&start, &step) {
+            let (start, step) = (*start, *step);
+            if step == 0 {
+                return Err(self.error("'for' step is zero"));
+            }
+            let limit = match limit {
+                Value::Int(limit) => limit,
+                Value::Float(f) if f.is_nan() => return Ok(Flow::Normal),
+                Value::Float(f) if step > 0 => {
+                    let f = f.floor();
+                    if f >= TWO_POW_63 {
+                        i64::MAX
+                    } else if f < -TWO_POW_63 {
+                        return Ok(Flow::Normal);
+                    } else {
+                        f as i64
+                    }
+                }
+                Value::Float(f) => {
+                    let f = f.ceil();
+                    if f < -TWO_POW_63 {
+                        i64::MIN
+                    } else if f >= TWO_POW_63 {
+                        return Ok(Flow::Normal);
+                    } else {
+                        f as i64
+                    }
+                }
+                _ => unreachable!(),
+            };
+            if (step > 0 && start > limit) || (step < 0 && start < limit) {
+                return Ok(Flow::Normal);
+            }
+            let mut i = start;
+            loop {
+                frame.declare(var, Value::Int(i));
+                if let Some(flow) = self.exec_loop_body(frame, body)? {
+                    return Ok(flow);
+                }
+                match i.checked_add(step) {
+                    Some(next) if (step > 0 && next <= limit) || (step < 0 && next >= limit) => {
+                        i = next
+                    }
+                    _ => return Ok(Flow::Normal),
+                }
+            }
+        }
+
+        let (start, limit, step) = (
+            start.as_float().unwrap(),
+            limit.as_float().unwrap(),
+            step.as_float().unwrap(),
+        );
+        if step == 0.0 {
+            return Err(self.error("'for' step is zero"));
+        }
+        let mut i = start;
+        while (step > 0.0 && i <= limit) || (step < 0.0 && i >= limit) {
+            frame.declare(var, Value::Float(i));
+            if let Some(flow) = self.exec_loop_body(frame, body)? {
+                return Ok(flow);
+            }
+            i += step;
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_assign(
+        &mut self,
+        frame: &mut Frame<'_>,
+        targets: &[Expr],
+        values: &[Expr],
+    ) -> LResult<()> {
+        if let ([target], [value]) = (targets, values) {
+            return match target {
+                Expr::Index(table, key) => {
+                    let table_value = self.eval(frame, table)?;
+                    let key = self.eval(frame, key)?;
+                    let value = self.eval(frame, value)?;
+                    self.set_index_described(frame, table, table_value, key, value)
+                }
+                target => {
+                    let value = self.eval(frame, value)?;
+                    self.assign_variable(frame, target, value)
+                }
+            };
+        }
+
+        // The tables and keys of the targets are evaluated before the values
+        let mut places = Vec::with_capacity(targets.len());
+        for target in targets {
+            places.push(match target {
+                Expr::Index(table, key) => Some((self.eval(frame, table)?, self.eval(frame, key)?)),
+                _ => None,
+            });
+        }
+        let values = self.eval_list_n(frame, values, targets.len())?;
+        for ((target, place), value) in targets.iter().zip(places).zip(values) {
+            match (target, place) {
+                (Expr::Index(table, _), Some((table_value, key))) => {
+                    self.set_index_described(frame, table, table_value, key, value)?
+                }
+                (target, _) => self.assign_variable(frame, target, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn assign_variable(
+        &mut self,
+        frame: &mut Frame<'_>,
+        target: &Expr,
+        value: Value,
+    ) -> LResult<()> {
+        match target {
+            Expr::Local(slot) => frame.set(*slot, value),
+            Expr::Upvalue(i) => *frame.closure.upvalues[*i].borrow_mut() = value,
+            Expr::Global(name) => {
+                let globals = Value::Table(self.globals.clone());
+                self.set_index(globals, name.clone(), value)?;
+            }
+            _ => unreachable!("invalid assignment target"),
+        }
+        Ok(())
+    }
+
+    fn closure(&mut self, frame: &mut Frame<'_>, proto: &Rc<Proto>) -> Value {
+        let upvalues = proto
+            .upvalues
+            .iter()
+            .map(|(_, upvalue)| match *upvalue {
+                Upvalue::Local(slot) => frame.cell(slot),
+                Upvalue::Upvalue(i) => frame.closure.upvalues[i].clone(),
+            })
+            .collect();
+        Value::Function(Function(Rc::new(FunctionKind::Lua(Closure {
+            proto: proto.clone(),
+            upvalues,
+        }))))
+    }
+
+    /// Evaluates `exprs`, expanding the values of the last one
+    fn eval_list(&mut self, frame: &mut Frame<'_>, exprs: &[Expr]) -> LResult<Vec<Value>> {
+        let mut values = Vec::with_capacity(exprs.len());
+        if let Some((last, exprs)) = exprs.split_last() {
+            for expr in exprs {
+                values.push(self.eval(frame, expr)?);
+            }
+            if last.is_multi() {
+                values.extend(self.eval_multi(frame, last)?);
+            } else {
+                values.push(self.eval(frame, last)?);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Evaluates `exprs`, adjusting the values to `n`
+    fn eval_list_n(
+        &mut self,
+        frame: &mut Frame<'_>,
+        exprs: &[Expr],
+        n: usize,
+    ) -> LResult<Vec<Value>> {
+        let mut values = self.eval_list(frame, exprs)?;
+        values.resize(n, Value::Nil);
+        Ok(values)
+    }
+
+    /// Evaluates an expression to all its values
+    fn eval_multi(&mut self, frame: &mut Frame<'_>, expr: &Expr) -> LResult<Vec<Value>> {
+        match expr {
+            Expr::Call(function, args) => {
+                let f = self.eval(frame, function)?;
+                let args = self.eval_list(frame, args)?;
+                if let Value::Function(f) = &f {
+                    return self.call_function(f, args);
+                }
+                if !matches!(&f, Value::Table(t) if !t.metamethod("__call").is_nil()) {
+                    return Err(self.error(format!(
+                        "attempt to call a {} value{}",
+                        f.type_name(),
+                        describe(frame, function)
+                    )));
+                }
+                self.call_value(&f, args)
+            }
+            Expr::Method(object, name, args) => {
+                let object = self.eval(frame, object)?;
+                let f = self.index(object.clone(), name.clone())?;
+                let mut values = Vec::with_capacity(args.len() + 1);
+                values.push(object);
+                values.extend(self.eval_list(frame, args)?);
+                if f.is_nil() {
+                    return Err(self.error(format!(
+                        "attempt to call a nil value{}",
+                        describe(frame, expr)
+                    )));
+                }
+                self.call_value(&f, values)
+            }
+            Expr::Vararg => Ok(frame.varargs.clone()),
+            expr => Ok(vec![self.eval(frame, expr)?]),
+        }
+    }
+
+    fn eval(&mut self, frame: &mut Frame<'_>, expr: &Expr) -> LResult<Value> {
+        Ok(match expr {
+            Expr::Const(v) => v.clone(),
+            Expr::Local(slot) => frame.get(*slot),
+            Expr::Upvalue(i) => frame.closure.upvalues[*i].borrow().clone(),
+            Expr::Global(name) => {
+                let value = self.globals.0.borrow().get(name);
+                if value.is_nil() && self.globals.metatable().is_some() {
+                    self.index(Value::Table(self.globals.clone()), name.clone())?
+                } else {
+                    value
+                }
+            }
+            Expr::Index(table, key) => {
+                let table_value = self.eval(frame, table)?;
+                let key = self.eval(frame, key)?;
+                if let Value::Table(t) = &table_value {
+                    let value = t.0.borrow().get(&key.clone().into_key());
+                    if !value.is_nil() || t.0.borrow().metatable.is_none() {
+                        return Ok(value);
+                    }
+                }
+                if !matches!(table_value, Value::Table(_) | Value::Str(_)) {
+                    return Err(self.error(format!(
+                        "attempt to index a {} value{}",
+                        table_value.type_name(),
+                        describe(frame, table)
+                    )));
+                }
+                self.index(table_value, key)?
+            }
+            Expr::Call(..) | Expr::Method(..) => self
+                .eval_multi(frame, expr)?
+                .into_iter()
+                .next()
+                .unwrap_or_default(),
+            Expr::Vararg => frame.varargs.first().cloned().unwrap_or_default(),
+            Expr::Function(proto) => self.closure(frame, proto),
+            Expr::BinOp(BinOp::And, lhs, rhs) => {
+                let lhs = self.eval(frame, lhs)?;
+                if lhs.truthy() {
+                    self.eval(frame, rhs)?
+                } else {
+                    lhs
+                }
+            }
+            Expr::BinOp(BinOp::Or, lhs, rhs) => {
+                let lhs = self.eval(frame, lhs)?;
+                if lhs.truthy() {
+                    lhs
+                } else {
+                    self.eval(frame, rhs)?
+                }
+            }
+            Expr::BinOp(op, lhs_expr, rhs_expr) => {
+                let lhs = self.eval(frame, lhs_expr)?;
+                let rhs = self.eval(frame, rhs_expr)?;
+                match self.binop(*op, lhs, rhs) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        return Err(self.operator_error(err, |rhs| {
+                            describe(frame, if rhs { rhs_expr } else { lhs_expr })
+                        }))
+                    }
+                }
+            }
+            Expr::UnOp(op, value_expr) => {
+                let value = self.eval(frame, value_expr)?;
+                match self.unop(*op, value) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        return Err(self.operator_error(err, |_| describe(frame, value_expr)))
+                    }
+                }
+            }
+            Expr::Table(fields) => self.eval_table(frame, fields)?,
+            Expr::Paren(expr) => self.eval(frame, expr)?,
+        })
+    }
+
+    fn eval_table(&mut self, frame: &mut Frame<'_>, fields: &[Field]) -> LResult<Value> {
+        let table = Table::new();
+        let mut array = vec![];
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                Field::Positional(value) if i == fields.len() - 1 && value.is_multi() => {
+                    array.extend(self.eval_multi(frame, value)?);
+                }
+                Field::Positional(value) => array.push(self.eval(frame, value)?),
+                Field::Keyed(key, value) => {
+                    let key = self.eval(frame, key)?;
+                    let value = self.eval(frame, value)?;
+                    self.raw_set(&table, key, value)?;
+                }
+            }
+        }
+        if !array.is_empty() {
+            table.0.borrow_mut().set_array(array);
+        }
+        Ok(Value::Table(table))
+    }
+
+    /// Sets `key` in `table` without metamethods, raising an error for invalid keys
+    pub(crate) fn raw_set(&self, table: &Table, key: Value, value: Value) -> LResult<()> {
+        let key = key.into_key();
+        match key {
+            Value::Nil => Err(self.error("table index is nil")),
+            Value::Float(f) if f.is_nan() => Err(self.error("table index is NaN")),
+            key => {
+                table.0.borrow_mut().set(key, value);
+                Ok(())
+            }
+        }
+    }
+
+    /// `table[key]`, with the `__index` metamethods
+    pub(crate) fn index(&mut self, mut table: Value, key: Value) -> LResult<Value> {
+        let key = key.into_key();
+        for _ in 0..100 {
+            let handler = match &table {
+                Value::Table(t) => {
+                    let value = t.0.borrow().get(&key);
+                    if !value.is_nil() {
+                        return Ok(value);
+                    }
+                    match t.metamethod("__index") {
+                        Value::Nil => return Ok(Value::Nil),
+                        handler => handler,
+                    }
+                }
+                Value::Str(_) => return Ok(self.string.0.borrow().get(&key)),
+                v => return Err(self.error(format!("attempt to index a {} value", v.type_name()))),
+            };
+            if let Value::Function(_) = handler {
+                return self.call_value1(&handler, vec![table, key]);
+            }
+            table = handler;
+        }
+        Err(self.error("'__index' chain too long; possible loop"))
+    }
+
+    fn set_index_described(
+        &mut self,
+        frame: &Frame<'_>,
+        table_expr: &Expr,
+        table: Value,
+        key: Value,
+        value: Value,
+    ) -> LResult<()> {
+        if let Value::Table(t) = &table {
+            if t.0.borrow().metatable.is_none() {
+                return self.raw_set(t, key, value);
+            }
+        } else {
+            return Err(self.error(format!(
+                "attempt to index a {} value{}",
+                table.type_name(),
+                describe(frame, table_expr)
+            )));
+        }
+        self.set_index(table, key, value)
+    }
+
+    /// `table[key] = value`, with the `__newindex` metamethods
+    pub(crate) fn set_index(&mut self, mut table: Value, key: Value, value: Value) -> LResult<()> {
+        let key = key.into_key();
+        for _ in 0..100 {
+            let handler = match &table {
+                Value::Table(t) => {
+                    let existing = t.0.borrow().get(&key);
+                    let handler = if existing.is_nil() {
+                        t.metamethod("__newindex")
+                    } else {
+                        Value::Nil
+                    };
+                    if handler.is_nil() {
+                        return self.raw_set(t, key, value);
+                    }
+                    handler
+                }
+                v => return Err(self.error(format!("attempt to index a {} value", v.type_name()))),
+            };
+            if let Value::Function(_) = handler {
+                self.call_value(&handler, vec![table, key, value])?;
+                return Ok(());
+            }
+            table = handler;
+        }
+        Err(self.error("'__newindex' chain too long; possible loop"))
+    }
+
+    /// The result of `tostring(value)`
+    pub(crate) fn tostring(&mut self, value: &Value) -> LResult<Rc<[u8]>> {
+        match value {
+            Value::Str(s) => Ok(s.clone()),
+            Value::Table(t) => match t.metamethod("__tostring") {
+                Value::Nil => Ok(value.to_bytes().into()),
+                handler => match self.call_value1(&handler, vec![value.clone()])? {
+                    Value::Str(s) => Ok(s),
+                    _ => Err(self.error("'__tostring' must return a string")),
+                },
+            },
+            value => Ok(value.to_bytes().into()),
+        }
+    }
+
+    /// The metamethod `event` of `a` or `b`
+    fn binary_metamethod(&self, a: &Value, b: &Value, event: &str) -> Value {
+        for v in [a, b] {
+            if let Value::Table(t) = v {
+                let handler = t.metamethod(event);
+                if !handler.is_nil() {
+                    return handler;
+                }
+            }
+        }
+        Value::Nil
+    }
+
+    pub(crate) fn equals(&mut self, a: &Value, b: &Value) -> LResult<bool> {
+        if a == b {
+            return Ok(true);
+        }
+        match (a, b) {
+            (Value::Table(_), Value::Table(_)) => {
+                let handler = self.binary_metamethod(a, b, "__eq");
+                if handler.is_nil() {
+                    return Ok(false);
+                }
+                Ok(self
+                    .call_value1(&handler, vec![a.clone(), b.clone()])?
+                    .truthy())
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// `a < b`, or `a <= b` with `or_equal`
+    pub(crate) fn less_than(&mut self, a: &Value, b: &Value, or_equal: bool) -> LResult<bool> {
+        Ok(match (a, b) {
+            (Value::Int(a), Value::Int(b)) => {
+                if or_equal {
+                    a <= b
+                } else {
+                    a < b
+                }
+            }
+            (Value::Float(a), Value::Float(b)) => {
+                if or_equal {
+                    a <= b
+                } else {
+                    a < b
+                }
+            }
+            (&Value::Int(i), &Value::Float(f)) => {
+                if or_equal {
+                    int_le_float(i, f)
+                } else {
+                    int_lt_float(i, f)
+                }
+            }
+            (&Value::Float(f), &Value::Int(i)) => {
+                !f.is_nan()
+                    && if or_equal {
+                        !int_lt_float(i, f)
+                    } else {
+                        !int_le_float(i, f)
+                    }
+            }
+            (Value::Str(a), Value::Str(b)) => {
+                if or_equal {
+                    a <= b
+                } else {
+                    a < b
+                }
+            }
+            _ => {
+                let event = if or_equal { "__le" } else { "__lt" };
+                let handler = self.binary_metamethod(a, b, event);
+                if handler.is_nil() {
+                    let (ta, tb) = (a.type_name(), b.type_name());
+                    return Err(if ta == tb {
+                        self.error(format!("attempt to compare two {} values", ta))
+                    } else {
+                        self.error(format!("attempt to compare {} with {}", ta, tb))
+                    });
+                }
+                self.call_value1(&handler, vec![a.clone(), b.clone()])?
+                    .truthy()
+            }
+        })
+    }
+
+    /// The integer value of an operand of a bitwise operator
+    fn bitwise_operand(&self, v: &Value, rhs: bool) -> Result<i64, BinOpError> {
+        match v.to_number() {
+            Some(Value::Int(i)) => Ok(i),
+            Some(Value::Float(f)) => float_to_int(f).ok_or(BinOpError::NoInteger { rhs }),
+            _ => Err(BinOpError::Operand {
+                msg: format!(
+                    "attempt to perform bitwise operation on a {} value",
+                    v.type_name()
+                ),
+                rhs,
+            }),
+        }
+    }
+
+    fn binop(&mut self, op: BinOp, a: Value, b: Value) -> Result<Value, BinOpError> {
+        match op {
+            BinOp::Eq => return Ok(Value::Bool(self.equals(&a, &b)?)),
+            BinOp::Ne => return Ok(Value::Bool(!self.equals(&a, &b)?)),
+            BinOp::Lt => return Ok(Value::Bool(self.less_than(&a, &b, false)?)),
+            BinOp::Le => return Ok(Value::Bool(self.less_than(&a, &b, true)?)),
+            BinOp::Gt => return Ok(Value::Bool(self.less_than(&b, &a, false)?)),
+            BinOp::Ge => return Ok(Value::Bool(self.less_than(&b, &a, true)?)),
+            BinOp::And | BinOp::Or => unreachable!("short-circuiting operators"),
+            _ => {}
+        }
+
+        if let (Value::Int(x), Value::Int(y)) = (&a, &b) {
+            let (x, y) = (*x, *y);
+            match op {
+                BinOp::Add => return Ok(Value::Int(x.wrapping_add(y))),
+                BinOp::Sub => return Ok(Value::Int(x.wrapping_sub(y))),
+                BinOp::Mul => return Ok(Value::Int(x.wrapping_mul(y))),
+                BinOp::IDiv if y == 0 => {
+                    return Err(BinOpError::Throw(self.error("attempt to divide by zero")))
+                }
+                BinOp::IDiv => return Ok(Value::Int(int_floor_div(x, y))),
+                BinOp::Mod if y == 0 => {
+                    return Err(BinOpError::Throw(self.error("attempt to perform 'n%0'")))
+                }
+                BinOp::Mod => return Ok(Value::Int(int_mod(x, y))),
+                _ => {}
+            }
+        }
+
+        let result = match op {
+            BinOp::Concat => match (a.coerce_to_bytes(), b.coerce_to_bytes()) {
+                (Some(x), Some(y)) => {
+                    let mut s = Vec::with_capacity(x.len() + y.len());
+                    s.extend_from_slice(&x);
+                    s.extend_from_slice(&y);
+                    Some(Value::from(s))
+                }
+                _ => None,
+            },
+            BinOp::BAnd | BinOp::BOr | BinOp::BXor | BinOp::Shl | BinOp::Shr
+                if a.to_number().is_some() && b.to_number().is_some() =>
+            {
+                let x = self.bitwise_operand(&a, false)?;
+                let y = self.bitwise_operand(&b, true)?;
+                Some(Value::Int(match op {
+                    BinOp::BAnd => x & y,
+                    BinOp::BOr => x | y,
+                    BinOp::BXor => x ^ y,
+                    BinOp::Shl => shift_left(x, y),
+                    _ => shift_left(x, y.wrapping_neg()),
+                }))
+            }
+            BinOp::BAnd | BinOp::BOr | BinOp::BXor | BinOp::Shl | BinOp::Shr => None,
+            _ => match (a.to_number(), b.to_number()) {
+                (Some(x), Some(y)) => {
+                    let (x, y) = (x.as_float().unwrap(), y.as_float().unwrap());
+                    Some(Value::Float(match op {
+                        BinOp::Add => x + y,
+                        BinOp::Sub => x - y,
+                        BinOp::Mul => x * y,
+                        BinOp::Div => x / y,
+                        BinOp::IDiv => (x / y).floor(),
+                        BinOp::Mod => {
+                            if y.is_infinite() && x.is_finite() {
+                                if (x >= 0.0) == (y > 0.0) {
+                                    x
+                                } else {
+                                    y
+                                }
+                            } else {
+                                float_mod(x, y)
+                            }
+                        }
+                        BinOp::Pow => x.powf(y),
+                        _ => unreachable!(),
+                    }))
+                }
+                _ => None,
+            },
+        };
+        if let Some(result) = result {
+            return Ok(result);
+        }
+
+        let handler = self.binary_metamethod(&a, &b, metamethod_event(op));
+        if !handler.is_nil() {
+            return Ok(self.call_value1(&handler, vec![a, b])?);
+        }
+        let (what, rhs) = match op {
+            BinOp::Concat => ("concatenate", a.coerce_to_bytes().is_some()),
+            BinOp::BAnd | BinOp::BOr | BinOp::BXor | BinOp::Shl | BinOp::Shr => {
+                ("perform bitwise operation on", a.to_number().is_some())
+            }
+            _ => ("perform arithmetic on", a.to_number().is_some()),
+        };
+        let operand = if rhs { &b } else { &a };
+        Err(BinOpError::Operand {
+            msg: format!("attempt to {} a {} value", what, operand.type_name()),
+            rhs,
+        })
+    }
+
+    fn unop(&mut self, op: UnOp, v: Value) -> Result<Value, BinOpError> {
+        let event = match op {
+            UnOp::Not => return Ok(Value::Bool(!v.truthy())),
+            UnOp::Neg => match v.to_number() {
+                Some(Value::Int(i)) => return Ok(Value::Int(i.wrapping_neg())),
+                Some(Value::Float(f)) => return Ok(Value::Float(-f)),
+                _ => "__unm",
+            },
+            UnOp::BNot => match v.to_number() {
+                Some(_) => return Ok(Value::Int(!self.bitwise_operand(&v, false)?)),
+                None => "__bnot",
+            },
+            UnOp::Len => match &v {
+                Value::Str(s) => return Ok(Value::Int(s.len() as i64)),
+                Value::Table(t) if t.metamethod("__len").is_nil() => {
+                    return Ok(Value::Int(t.len()))
+                }
+                _ => "__len",
+            },
+        };
+        let handler = match &v {
+            Value::Table(t) => t.metamethod(event),
+            _ => Value::Nil,
+        };
+        if !handler.is_nil() {
+            return Ok(self.call_value1(&handler, vec![v.clone(), v])?);
+        }
+        let what = match op {
+            UnOp::Neg => "perform arithmetic on",
+            UnOp::BNot => "perform bitwise operation on",
+            _ => "get length of",
+        };
+        Err(BinOpError::Operand {
+            msg: format!("attempt to {} a {} value", what, v.type_name()),
+            rhs: false,
+        })
+    }
+
+    /// The length of `v`, with the `__len` metamethod
+    pub(crate) fn len(&mut self, v: Value) -> LResult<Value> {
+        self.unop(UnOp::Len, v)
+            .map_err(|err| self.operator_error(err, |_| String::new()))
+    }
+
+    /// The error raised for `err`, `describe` giving the note naming the left or right operand
+    fn operator_error(&self, err: BinOpError, describe: impl FnOnce(bool) -> String) -> Throw {
+        match err {
+            BinOpError::Throw(throw) => throw,
+            BinOpError::Operand { msg, rhs } => self.error(format!("{}{}", msg, describe(rhs))),
+            BinOpError::NoInteger { rhs } => self.error(format!(
+                "number{} has no integer representation",
+                describe(rhs)
+            )),
+        }
+    }
+}
+
+/// An error of an operator, which names the faulty operand when it is a variable
+enum BinOpError {
+    Throw(Throw),
+    Operand {
+        msg: String,
+        rhs: bool,
+    },
+    /// A float operand of a bitwise operator isn't integral
+    NoInteger {
+        rhs: bool,
+    },
+}
+
+impl From<Throw> for BinOpError {
+    fn from(throw: Throw) -> Self {
+        BinOpError::Throw(throw)
+    }
+}
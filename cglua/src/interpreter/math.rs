@@ -0,0 +1,227 @@
+//! The `math` library
+
+use super::base::{arg, bad_arg, check_any, check_float, check_int, check_number, register};
+use super::value::float_to_int;
+use super::{LResult, Lua, Table, Value};
+
+pub(crate) fn open(lua: &mut Lua) {
+    let math = Table::new();
+    register(
+        &math,
+        &[
+            ("abs", abs),
+            ("acos", acos),
+            ("asin", asin),
+            ("atan", atan),
+            ("ceil", ceil),
+            ("cos", cos),
+            ("exp", exp),
+            ("floor", floor),
+            ("fmod", fmod),
+            ("log", log),
+            ("max", max),
+            ("min", min),
+            ("modf", modf),
+            ("random", random),
+            ("randomseed", randomseed),
+            ("sin", sin),
+            ("sqrt", sqrt),
+            ("tan", tan),
+            ("tointeger", tointeger),
+            ("type", type_),
+            ("ult", ult),
+        ],
+    );
+    math.set("huge", f64::INFINITY);
+    math.set("pi", std::f64::consts::PI);
+    math.set("maxinteger", i64::MAX);
+    math.set("mininteger", i64::MIN);
+    lua.globals.set("math", math);
+}
+
+/// Defines functions applying a float function to their first argument
+macro_rules! float_functions {
+    ($($name:ident),*) => {
+        $(
+            fn $name(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+                let x = check_float(lua, &args, 0, stringify!($name))?;
+                Ok(vec![Value::Float(x.$name())])
+            }
+        )*
+    };
+}
+
+float_functions!(acos, asin, cos, exp, sin, sqrt, tan);
+
+fn abs(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    Ok(vec![match check_number(lua, &args, 0, "abs")? {
+        Value::Int(i) => Value::Int(i.wrapping_abs()),
+        v => Value::Float(v.as_float().unwrap().abs()),
+    }])
+}
+
+fn atan(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let y = check_float(lua, &args, 0, "atan")?;
+    let x = match arg(&args, 1) {
+        Value::Nil => 1.0,
+        _ => check_float(lua, &args, 1, "atan")?,
+    };
+    Ok(vec![Value::Float(y.atan2(x))])
+}
+
+/// An integral float as an integer, kept as a float when it doesn't fit
+fn float_to_value(f: f64) -> Value {
+    match float_to_int(f) {
+        Some(i) => Value::Int(i),
+        None => Value::Float(f),
+    }
+}
+
+fn ceil(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    Ok(vec![match check_number(lua, &args, 0, "ceil")? {
+        v @ Value::Int(_) => v,
+        v => float_to_value(v.as_float().unwrap().ceil()),
+    }])
+}
+
+fn floor(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    Ok(vec![match check_number(lua, &args, 0, "floor")? {
+        v @ Value::Int(_) => v,
+        v => float_to_value(v.as_float().unwrap().floor()),
+    }])
+}
+
+fn fmod(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let a = check_number(lua, &args, 0, "fmod")?;
+    let b = check_number(lua, &args, 1, "fmod")?;
+    Ok(vec![match (a, b) {
+        (Value::Int(a), Value::Int(b)) => {
+            if b == 0 {
+                return Err(bad_arg(lua, 1, "fmod", "zero"));
+            }
+            // C semantics: the result has the sign of the dividend
+            Value::Int(a.wrapping_rem(b))
+        }
+        (a, b) => {
+            let (a, b) = (a.as_float().unwrap(), b.as_float().unwrap());
+            Value::Float(a % b)
+        }
+    }])
+}
+
+fn log(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let x = check_float(lua, &args, 0, "log")?;
+    let res = match arg(&args, 1) {
+        Value::Nil => x.ln(),
+        _ => match check_float(lua, &args, 1, "log")? {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            b => x.ln() / b.ln(),
+        },
+    };
+    Ok(vec![Value::Float(res)])
+}
+
+/// `math.max` and `math.min`
+fn extremum(lua: &mut Lua, args: Vec<Value>, name: &str, max: bool) -> LResult<Vec<Value>> {
+    let mut best = check_number(lua, &args, 0, name)?;
+    for i in 1..args.len() {
+        let v = check_number(lua, &args, i, name)?;
+        let better = if max {
+            lua.less_than(&best, &v, false)?
+        } else {
+            lua.less_than(&v, &best, false)?
+        };
+        if better {
+            best = v;
+        }
+    }
+    Ok(vec![best])
+}
+
+fn max(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    extremum(lua, args, "max", true)
+}
+
+fn min(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    extremum(lua, args, "min", false)
+}
+
+fn modf(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let x = match check_number(lua, &args, 0, "modf")? {
+        Value::Int(i) => return Ok(vec![Value::Int(i), Value::Float(0.0)]),
+        v => v.as_float().unwrap(),
+    };
+    let int = x.trunc();
+    let frac = if x.is_infinite() { 0.0 } else { x - int };
+    Ok(vec![float_to_value(int), Value::Float(frac)])
+}
+
+fn tointeger(_: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    Ok(vec![match arg(&args, 0).to_number() {
+        Some(v) => v.as_int().map_or(Value::Nil, Value::Int),
+        None => Value::Nil,
+    }])
+}
+
+fn type_(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    Ok(vec![match check_any(lua, &args, 0, "type")? {
+        Value::Int(_) => Value::from("integer"),
+        Value::Float(_) => Value::from("float"),
+        _ => Value::Nil,
+    }])
+}
+
+fn ult(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let a = check_int(lua, &args, 0, "ult")?;
+    let b = check_int(lua, &args, 1, "ult")?;
+    Ok(vec![Value::Bool((a as u64) < (b as u64))])
+}
+
+impl Lua {
+    /// The next output of a xorshift generator, good enough for tests and deterministic by default
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+}
+
+fn random(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let r = lua.next_random();
+    let (low, high) = match args.len() {
+        0 => return Ok(vec![Value::Float((r >> 11) as f64 / (1u64 << 53) as f64)]),
+        1 => (1, check_int(lua, &args, 0, "random")?),
+        2 => (
+            check_int(lua, &args, 0, "random")?,
+            check_int(lua, &args, 1, "random")?,
+        ),
+        _ => return Err(lua.error("wrong number of arguments")),
+    };
+    if low > high {
+        return Err(bad_arg(lua, args.len() - 1, "random", "interval is empty"));
+    }
+    let range = (high as u64).wrapping_sub(low as u64);
+    let offset = if range == u64::MAX {
+        r
+    } else {
+        r % (range + 1)
+    };
+    Ok(vec![Value::Int((low as u64).wrapping_add(offset) as i64)])
+}
+
+fn randomseed(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let seed = match check_number(lua, &args, 0, "randomseed")? {
+        Value::Int(i) => i as u64,
+        v => v.as_float().unwrap().to_bits(),
+    };
+    // Xorshift generators must not have a zero state
+    lua.rng = seed ^ 0x2545_f491_4f6c_dd1d;
+    if lua.rng == 0 {
+        lua.rng = 1;
+    }
+    Ok(vec![])
+}
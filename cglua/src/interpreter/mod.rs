@@ -0,0 +1,323 @@
+//! An interpreter for the lua code produced by cglua, enabled by the `interpreter` feature
+//!
+//! It implements the lua 5.3 language and the parts of its standard library needed to run the
+//! generated programs and check what they print: the base functions, `string`, `utf8`, `table`,
+//! `math`, `io` and `os` restricted to the standard streams, the clock and `os.exit`, and `require`,
+//! which loads modules from the files of `package.path`. The standard streams are captured unless
+//! `Lua::inherit_stdio` is called.
+//!
+//! ```
+//! let mut lua = cglua::interpreter::Lua::new();
+//! lua.exec("print(string.format('%d-%s', 6 * 7, 'x'))").unwrap();
+//! assert_eq!(lua.stdout(), b"42-x\n");
+//! ```
+
+mod ast;
+mod base;
+mod eval;
+mod math;
+mod os;
+mod package;
+mod parser;
+mod pattern;
+mod string;
+mod table;
+mod value;
+
+use std::rc::Rc;
+
+pub use value::{Function, Table, Value};
+
+use crate::Context;
+
+/// The maximum depth of nested calls, each one taking a few kilobytes of the native stack since
+/// the interpreter walks the syntax tree recursively
+const MAX_CALL_DEPTH: usize = 200;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Syntax(String),
+    /// An error raised and not caught by the code, converted to a string
+    #[error("{0}")]
+    Runtime(String),
+    /// The code called `os.exit`
+    #[error("exited with code {0}")]
+    Exit(i32),
+}
+
+/// The ways the execution of lua code is interrupted
+pub(crate) enum Throw {
+    /// An error, which `pcall` can catch
+    Error(Value),
+    Exit(i32),
+}
+
+type LResult<T> = Result<T, Throw>;
+
+enum Output {
+    Captured(Vec<u8>),
+    Inherited,
+}
+
+impl Output {
+    fn write(&mut self, data: &[u8], inherited: &mut dyn std::io::Write) {
+        match self {
+            Output::Captured(buf) => buf.extend_from_slice(data),
+            Output::Inherited => {
+                // Like `print` in the reference implementation, write errors are ignored
+                let _ = inherited.write_all(data);
+            }
+        }
+    }
+
+    fn captured(&self) -> &[u8] {
+        match self {
+            Output::Captured(buf) => buf,
+            Output::Inherited => &[],
+        }
+    }
+}
+
+/// The standard input of the code
+enum Input {
+    Buffer(Vec<u8>, usize),
+    Inherited,
+}
+
+impl Input {
+    /// Reads up to `n` bytes, `None` meaning the end of the input
+    fn read(&mut self, n: usize) -> Option<Vec<u8>> {
+        use std::io::Read;
+        let data = match self {
+            Input::Buffer(buf, pos) => {
+                let end = buf.len().min(*pos + n);
+                let data = buf[*pos..end].to_vec();
+                *pos = end;
+                data
+            }
+            Input::Inherited => {
+                let mut data = vec![];
+                let _ = std::io::stdin().take(n as u64).read_to_end(&mut data);
+                data
+            }
+        };
+        if data.is_empty() && n > 0 {
+            None
+        } else {
+            Some(data)
+        }
+    }
+
+    /// Reads a line including its newline, `None` meaning the end of the input
+    fn read_line(&mut self) -> Option<Vec<u8>> {
+        use std::io::BufRead;
+        let line = match self {
+            Input::Buffer(buf, pos) => {
+                let end = match buf[*pos..].iter().position(|&c| c == b'\n') {
+                    Some(i) => *pos + i + 1,
+                    None => buf.len(),
+                };
+                let line = buf[*pos..end].to_vec();
+                *pos = end;
+                line
+            }
+            Input::Inherited => {
+                let mut line = vec![];
+                let _ = std::io::stdin().lock().read_until(b'\n', &mut line);
+                line
+            }
+        };
+        if line.is_empty() {
+            None
+        } else {
+            Some(line)
+        }
+    }
+
+    fn read_all(&mut self) -> Vec<u8> {
+        use std::io::Read;
+        match self {
+            Input::Buffer(buf, pos) => {
+                let data = buf[*pos..].to_vec();
+                *pos = buf.len();
+                data
+            }
+            Input::Inherited => {
+                let mut data = vec![];
+                let _ = std::io::stdin().read_to_end(&mut data);
+                data
+            }
+        }
+    }
+}
+
+/// A lua state with the standard library loaded
+pub struct Lua {
+    globals: Table,
+    /// The `string` library, which is the `__index` of strings
+    string: Table,
+    /// The chunk name and current line of each running lua function, innermost last, `None` for
+    /// native functions
+    stack: Vec<Option<(Rc<str>, u32)>>,
+    stdin: Input,
+    stdout: Output,
+    stderr: Output,
+    rng: u64,
+    start: std::time::Instant,
+}
+
+impl Default for Lua {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lua {
+    pub fn new() -> Self {
+        let globals = Table::new();
+        let mut lua = Lua {
+            string: Table::new(),
+            globals,
+            stack: vec![],
+            stdin: Input::Buffer(vec![], 0),
+            stdout: Output::Captured(vec![]),
+            stderr: Output::Captured(vec![]),
+            rng: 0x2545_f491_4f6c_dd1d,
+            start: std::time::Instant::now(),
+        };
+        base::open(&mut lua);
+        string::open(&mut lua);
+        table::open(&mut lua);
+        math::open(&mut lua);
+        os::open(&mut lua);
+        package::open(&mut lua);
+        lua
+    }
+
+    /// Uses the standard streams of the process instead of capturing the output of the code and
+    /// giving it an empty input
+    pub fn inherit_stdio(&mut self) {
+        self.stdin = Input::Inherited;
+        self.stdout = Output::Inherited;
+        self.stderr = Output::Inherited;
+    }
+
+    /// Sets what the code reads from its standard input
+    pub fn set_stdin(&mut self, data: impl Into<Vec<u8>>) {
+        self.stdin = Input::Buffer(data.into(), 0);
+    }
+
+    /// What the code wrote to the standard output while it was captured
+    pub fn stdout(&self) -> &[u8] {
+        self.stdout.captured()
+    }
+
+    /// What the code wrote to the standard error while it was captured
+    pub fn stderr(&self) -> &[u8] {
+        self.stderr.captured()
+    }
+
+    pub(crate) fn write_stdout(&mut self, data: &[u8]) {
+        self.stdout.write(data, &mut std::io::stdout());
+    }
+
+    pub(crate) fn write_stderr(&mut self, data: &[u8]) {
+        self.stderr.write(data, &mut std::io::stderr());
+    }
+
+    pub(crate) fn flush(&mut self) {
+        use std::io::Write;
+        if let Output::Inherited = self.stdout {
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    pub fn globals(&self) -> &Table {
+        &self.globals
+    }
+
+    /// Sets the global `name` to a function implemented in rust
+    pub fn set_function(
+        &mut self,
+        name: &str,
+        f: impl Fn(&mut Lua, Vec<Value>) -> Result<Vec<Value>, Error> + 'static,
+    ) {
+        let f = Function::native(move |lua, args| {
+            f(lua, args).map_err(|err| match err {
+                Error::Exit(code) => Throw::Exit(code),
+                err => Throw::Error(Value::from(err.to_string())),
+            })
+        });
+        self.globals.set(name, f);
+    }
+
+    /// Compiles `source` into a function, using `name` as the chunk name in error messages
+    pub fn load(&mut self, name: &str, source: &[u8]) -> Result<Function, Error> {
+        let proto = parser::parse_chunk(name, source).map_err(Error::Syntax)?;
+        Ok(eval::main_closure(proto))
+    }
+
+    /// Runs the chunk `source`, returning the values it returns
+    pub fn exec(&mut self, source: &str) -> Result<Vec<Value>, Error> {
+        let f = self.load("chunk", source.as_bytes())?;
+        self.call(&f, vec![])
+    }
+
+    /// Renders `ctx` and runs the resulting chunk
+    pub fn run(&mut self, ctx: &Context) -> Result<Vec<Value>, Error> {
+        let mut source = String::new();
+        ctx.render(&mut source)
+            .expect("rendering to a string can't fail");
+        self.exec(&source)
+    }
+
+    pub fn call(&mut self, f: &Function, args: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let depth = self.stack.len();
+        let res = self.call_function(f, args);
+        self.stack.truncate(depth);
+        res.map_err(|throw| match throw {
+            Throw::Exit(code) => Error::Exit(code),
+            Throw::Error(value) => Error::Runtime(self.error_message(&value)),
+        })
+    }
+
+    /// The message of an uncaught error
+    fn error_message(&mut self, value: &Value) -> String {
+        match value {
+            Value::Str(_) | Value::Int(_) | Value::Float(_) => {
+                String::from_utf8_lossy(&value.to_bytes()).into_owned()
+            }
+            Value::Table(t) if !t.metamethod("__tostring").is_nil() => match self.tostring(value) {
+                Ok(s) => String::from_utf8_lossy(&s).into_owned(),
+                Err(_) => "(error object is not a string)".to_string(),
+            },
+            v => format!("(error object is a {} value)", v.type_name()),
+        }
+    }
+
+    /// The position of the function at `level` in the stack, 1 being the innermost one
+    pub(crate) fn position(&self, level: usize) -> Option<String> {
+        let (chunk, line) = self
+            .stack
+            .iter()
+            .rev()
+            .nth(level.checked_sub(1)?)?
+            .as_ref()?;
+        Some(format!("{}:{}:", chunk, line))
+    }
+
+    /// An error with the message `msg`, prefixed with the current position, which is the one of
+    /// the caller for errors raised by native functions
+    pub(crate) fn error(&self, msg: impl std::fmt::Display) -> Throw {
+        let level = match self.stack.last() {
+            Some(None) => 2,
+            _ => 1,
+        };
+        let msg = match self.position(level) {
+            Some(position) => format!("{} {}", position, msg),
+            None => msg.to_string(),
+        };
+        Throw::Error(Value::from(msg))
+    }
+}
@@ -0,0 +1,187 @@
+//! The `io` and `os` libraries, restricted to what doesn't touch the file system
+
+use super::base::{arg, bad_arg, check_bytes, opt_int, register};
+use super::value::fmt_g;
+use super::{Function, LResult, Lua, Table, Throw, Value};
+
+pub(crate) fn open(lua: &mut Lua) {
+    let io = Table::new();
+    let stdin = file(Stream::Stdin);
+    let stdout = file(Stream::Stdout);
+    io.set("stdin", stdin.clone());
+    io.set("stdout", stdout.clone());
+    io.set("stderr", file(Stream::Stderr));
+    io.set(
+        "read",
+        Function::native(move |lua, mut args| {
+            args.insert(0, Value::Table(stdin.clone()));
+            read(lua, Stream::Stdin, args)
+        }),
+    );
+    io.set(
+        "write",
+        Function::native(move |lua, mut args| {
+            args.insert(0, Value::Table(stdout.clone()));
+            write(lua, Stream::Stdout, args)
+        }),
+    );
+    register(&io, &[("open", io_open)]);
+    lua.globals.set("io", io);
+
+    let os = Table::new();
+    register(
+        &os,
+        &[
+            ("clock", clock),
+            ("exit", exit),
+            ("getenv", getenv),
+            ("time", time),
+        ],
+    );
+    lua.globals.set("os", os);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+/// The result of a failed io operation, with the message and code of `errno`
+fn io_error(msg: &str, code: i64) -> Vec<Value> {
+    vec![Value::Nil, Value::from(msg), Value::Int(code)]
+}
+
+/// A file object for a standard stream, with its methods as fields
+fn file(stream: Stream) -> Table {
+    let file = Table::new();
+    file.set(
+        "read",
+        Function::native(move |lua, args| read(lua, stream, args)),
+    );
+    file.set(
+        "write",
+        Function::native(move |lua, args| write(lua, stream, args)),
+    );
+    file.set(
+        "flush",
+        Function::native(move |lua, args| {
+            if stream == Stream::Stdout {
+                lua.flush();
+            }
+            Ok(vec![arg(&args, 0)])
+        }),
+    );
+    file.set(
+        "seek",
+        Function::native(|_, _| Ok(io_error("Illegal seek", 29))),
+    );
+    file.set(
+        "close",
+        Function::native(|_, _| Ok(vec![Value::Nil, Value::from("cannot close standard file")])),
+    );
+    file
+}
+
+fn write(lua: &mut Lua, stream: Stream, args: Vec<Value>) -> LResult<Vec<Value>> {
+    if stream == Stream::Stdin {
+        return Ok(io_error("Bad file descriptor", 9));
+    }
+    for i in 1..args.len() {
+        // Unlike `tostring`, integral floats are written without a `.0` suffix
+        let data = match &args[i] {
+            &Value::Float(f) => fmt_g(f, 14, false).into_bytes().into(),
+            _ => check_bytes(lua, &args, i, "write")?,
+        };
+        match stream {
+            Stream::Stdout => lua.write_stdout(&data),
+            _ => lua.write_stderr(&data),
+        }
+    }
+    Ok(vec![arg(&args, 0)])
+}
+
+fn read(lua: &mut Lua, stream: Stream, args: Vec<Value>) -> LResult<Vec<Value>> {
+    if stream != Stream::Stdin {
+        return Ok(io_error("Bad file descriptor", 9));
+    }
+    let formats = if args.len() > 1 {
+        args[1..].to_vec()
+    } else {
+        vec![Value::from("l")]
+    };
+    let mut results = vec![];
+    for (i, format) in formats.iter().enumerate() {
+        let value = match format {
+            Value::Int(_) | Value::Float(_) => {
+                let n = opt_int(lua, &args, i + 1, "read", 0)?;
+                lua.stdin.read(n.max(0) as usize).map(Value::from)
+            }
+            Value::Str(s) => match s.strip_prefix(b"*").unwrap_or(s).first() {
+                Some(b'l') => lua.stdin.read_line().map(|mut line| {
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                    }
+                    Value::from(line)
+                }),
+                Some(b'L') => lua.stdin.read_line().map(Value::from),
+                Some(b'a') => Some(Value::from(lua.stdin.read_all())),
+                _ => return Err(bad_arg(lua, i + 1, "read", "invalid format")),
+            },
+            _ => return Err(bad_arg(lua, i + 1, "read", "invalid format")),
+        };
+        match value {
+            Some(value) => results.push(value),
+            None => {
+                results.push(Value::Nil);
+                break;
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn io_open(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let path = check_bytes(lua, &args, 0, "open")?;
+    let msg = format!(
+        "{}: Operation not permitted",
+        String::from_utf8_lossy(&path)
+    );
+    Ok(io_error(&msg, 1))
+}
+
+fn clock(lua: &mut Lua, _: Vec<Value>) -> LResult<Vec<Value>> {
+    Ok(vec![Value::Float(lua.start.elapsed().as_secs_f64())])
+}
+
+fn exit(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let code = match arg(&args, 0) {
+        Value::Nil | Value::Bool(true) => 0,
+        Value::Bool(false) => 1,
+        _ => opt_int(lua, &args, 0, "exit", 0)? as i32,
+    };
+    lua.flush();
+    Err(Throw::Exit(code))
+}
+
+fn getenv(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let name = check_bytes(lua, &args, 0, "getenv")?;
+    let value = std::str::from_utf8(&name)
+        .ok()
+        .and_then(std::env::var_os)
+        .map_or(Value::Nil, |v| {
+            Value::from(v.to_string_lossy().into_owned())
+        });
+    Ok(vec![value])
+}
+
+fn time(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    if !arg(&args, 0).is_nil() {
+        return Err(bad_arg(lua, 0, "time", "dates are not supported"));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Ok(vec![Value::Int(now as i64)])
+}
@@ -0,0 +1,91 @@
+//! The `package` library, with `require` loading lua modules from the files of `package.path`
+
+use super::base::check_bytes;
+use super::{Function, LResult, Lua, Table, Value};
+
+/// The default of `package.path`, the directories of the reference implementation left out
+const DEFAULT_PATH: &str = "./?.lua;./?/init.lua";
+
+pub(crate) fn open(lua: &mut Lua) {
+    let package = Table::new();
+    let loaded = Table::new();
+    for name in &["_G", "io", "math", "os", "string", "table", "utf8"] {
+        loaded.set(*name, lua.globals.get(*name));
+    }
+    loaded.set("package", package.clone());
+    package.set("loaded", loaded);
+    package.set("preload", Table::new());
+    package.set("path", DEFAULT_PATH);
+    package.set("config", "/\n;\n?\n!\n-\n");
+    lua.globals.set("package", package.clone());
+    lua.globals.set(
+        "require",
+        Function::native(move |lua, args| require(lua, &package, args)),
+    );
+}
+
+/// A field of `package` which must be a table
+fn package_table(lua: &Lua, package: &Table, field: &str) -> LResult<Table> {
+    match package.get(field) {
+        Value::Table(t) => Ok(t),
+        _ => Err(lua.error(format!("'package.{}' must be a table", field))),
+    }
+}
+
+fn require(lua: &mut Lua, package: &Table, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let name = check_bytes(lua, &args, 0, "require")?;
+    let name = String::from_utf8_lossy(&name).into_owned();
+    let loaded = package_table(lua, package, "loaded")?;
+    let module = loaded.get(name.as_str());
+    if module.truthy() {
+        return Ok(vec![module]);
+    }
+
+    let (loader, data) = find_loader(lua, package, &name)?;
+    let module = lua.call_value1(&loader, vec![Value::from(name.as_str()), data])?;
+    if !module.is_nil() {
+        loaded.set(name.as_str(), module);
+    }
+    if loaded.get(name.as_str()).is_nil() {
+        loaded.set(name.as_str(), true);
+    }
+    Ok(vec![loaded.get(name.as_str())])
+}
+
+/// The loader of the module `name` and the extra value passed to it, looking in `package.preload`
+/// then in the files of `package.path`
+fn find_loader(lua: &mut Lua, package: &Table, name: &str) -> LResult<(Value, Value)> {
+    let preload = package_table(lua, package, "preload")?;
+    let loader = preload.get(name);
+    if !loader.is_nil() {
+        return Ok((loader, Value::from(":preload:")));
+    }
+
+    let path = match package.get("path").as_bytes() {
+        Some(path) => String::from_utf8_lossy(path).into_owned(),
+        None => return Err(lua.error("'package.path' must be a string")),
+    };
+    let mut msg = format!(
+        "module '{}' not found:\n\tno field package.preload['{}']",
+        name, name
+    );
+    let file_name = name.replace('.', "/");
+    for template in path.split(';').filter(|template| !template.is_empty()) {
+        let path = template.replace('?', &file_name);
+        let source = match std::fs::read(&path) {
+            Ok(source) => source,
+            Err(_) => {
+                msg.push_str(&format!("\n\tno file '{}'", path));
+                continue;
+            }
+        };
+        return match lua.load(&path, &source) {
+            Ok(f) => Ok((Value::Function(f), Value::from(path))),
+            Err(err) => Err(lua.error(format!(
+                "error loading module '{}' from file '{}':\n\t{}",
+                name, path, err
+            ))),
+        };
+    }
+    Err(lua.error(msg))
+}
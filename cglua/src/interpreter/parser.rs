@@ -0,0 +1,743 @@
+//! Parsing of lua source into the syntax tree of the interpreter
+
+use std::rc::Rc;
+
+use super::ast::{Block, Expr, Field, Proto, Stat, StatKind, Upvalue};
+use super::Value;
+use crate::lexer::{binary_op, Lexer, Token, UNARY_PRIORITY};
+use crate::UnOp;
+
+struct Scope {
+    active: usize,
+    /// The labels defined in the block with the index of the statement following them
+    labels: Vec<(String, usize)>,
+    /// The gotos of the block and of its nested blocks that aren't resolved yet, with the id of
+    /// the goto and its line
+    gotos: Vec<(String, usize, u32)>,
+    loop_scope: bool,
+}
+
+#[derive(Default)]
+struct FuncState {
+    locals: Vec<(Rc<str>, bool)>,
+    /// The locals in scope, innermost last
+    active: Vec<(Rc<str>, usize)>,
+    upvalues: Vec<(Rc<str>, Upvalue)>,
+    vararg: bool,
+    scopes: Vec<Scope>,
+    next_goto: usize,
+}
+
+enum Resolved {
+    Local(usize),
+    Upvalue(usize),
+    Global,
+}
+
+pub(crate) struct Parser<'a> {
+    lexer: Lexer<'a>,
+    token: Token,
+    line: u32,
+    lookahead: Option<(Token, u32)>,
+    chunk: Rc<str>,
+    funcs: Vec<FuncState>,
+}
+
+type PResult<T> = Result<T, String>;
+
+/// Parses the chunk `source`, whose name is used in error messages
+pub(crate) fn parse_chunk(chunk: &str, source: &[u8]) -> PResult<Rc<Proto>> {
+    let chunk: Rc<str> = chunk.into();
+    let mut parser = Parser {
+        lexer: Lexer::new(source),
+        token: Token::Eof,
+        line: 1,
+        lookahead: None,
+        chunk: chunk.clone(),
+        funcs: vec![],
+    };
+    let res = parser.next().and_then(|()| parser.main());
+    res.map_err(|msg| format!("{}:{}: {}", chunk, parser.line, msg))
+}
+
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> PResult<()> {
+        let (token, line) = match self.lookahead.take() {
+            Some(next) => next,
+            None => self.lexer.next_token()?,
+        };
+        self.token = token;
+        self.line = line;
+        Ok(())
+    }
+
+    fn peek(&mut self) -> PResult<&Token> {
+        if self.lookahead.is_none() {
+            self.lookahead = Some(self.lexer.next_token()?);
+        }
+        Ok(&self.lookahead.as_ref().unwrap().0)
+    }
+
+    fn error<T>(&self, msg: &str) -> PResult<T> {
+        Err(format!("{} near {}", msg, self.token))
+    }
+
+    fn check(&self, token: Token) -> PResult<()> {
+        if self.token == token {
+            Ok(())
+        } else {
+            self.error(&format!("{} expected", token))
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> PResult<()> {
+        self.check(token)?;
+        self.next()
+    }
+
+    fn test_next(&mut self, token: Token) -> PResult<bool> {
+        if self.token == token {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Expects the token closing `opening`, which started on line `line`
+    fn expect_match(&mut self, token: Token, opening: Token, line: u32) -> PResult<()> {
+        if self.token == token {
+            return self.next();
+        }
+        if line == self.line {
+            self.check(token)
+        } else {
+            self.error(&format!(
+                "{} expected (to close {} at line {})",
+                token, opening, line
+            ))
+        }
+    }
+
+    fn name(&mut self) -> PResult<String> {
+        match std::mem::replace(&mut self.token, Token::Eof) {
+            Token::Name(name) => {
+                self.next()?;
+                Ok(name)
+            }
+            token => {
+                self.token = token;
+                self.error("<name> expected")
+            }
+        }
+    }
+
+    fn func(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn main(&mut self) -> PResult<Rc<Proto>> {
+        self.funcs.push(FuncState {
+            vararg: true,
+            ..FuncState::default()
+        });
+        let body = self.block()?;
+        self.check(Token::Eof)?;
+        self.finish_function(0, 0, body)
+    }
+
+    fn finish_function(&mut self, line: u32, params: usize, body: Block) -> PResult<Rc<Proto>> {
+        let func = self.funcs.pop().unwrap();
+        Ok(Rc::new(Proto {
+            chunk: self.chunk.clone(),
+            line,
+            params,
+            vararg: func.vararg,
+            body,
+            locals: func.locals,
+            upvalues: func.upvalues,
+        }))
+    }
+
+    fn declare_local(&mut self, name: &str) -> usize {
+        let func = self.func();
+        let slot = func.locals.len();
+        let name: Rc<str> = name.into();
+        func.locals.push((name.clone(), false));
+        func.active.push((name, slot));
+        slot
+    }
+
+    fn resolve(&mut self, level: usize, name: &str) -> Resolved {
+        let func = &self.funcs[level];
+        if let Some(&(_, slot)) = func.active.iter().rev().find(|(n, _)| &**n == name) {
+            return Resolved::Local(slot);
+        }
+        if let Some(i) = func.upvalues.iter().position(|(n, _)| &**n == name) {
+            return Resolved::Upvalue(i);
+        }
+        if level == 0 {
+            return Resolved::Global;
+        }
+        let upvalue = match self.resolve(level - 1, name) {
+            Resolved::Local(slot) => {
+                self.funcs[level - 1].locals[slot].1 = true;
+                Upvalue::Local(slot)
+            }
+            Resolved::Upvalue(i) => Upvalue::Upvalue(i),
+            Resolved::Global => return Resolved::Global,
+        };
+        let upvalues = &mut self.funcs[level].upvalues;
+        upvalues.push((name.into(), upvalue));
+        Resolved::Upvalue(upvalues.len() - 1)
+    }
+
+    fn variable(&mut self, name: &str) -> Expr {
+        match self.resolve(self.funcs.len() - 1, name) {
+            Resolved::Local(slot) => Expr::Local(slot),
+            Resolved::Upvalue(i) => Expr::Upvalue(i),
+            Resolved::Global => Expr::Global(Value::from(name)),
+        }
+    }
+
+    fn enter_scope(&mut self, loop_scope: bool) {
+        let func = self.func();
+        let active = func.active.len();
+        func.scopes.push(Scope {
+            active,
+            labels: vec![],
+            gotos: vec![],
+            loop_scope,
+        });
+    }
+
+    /// Closes the innermost scope, resolving the gotos to its labels in `block`
+    fn leave_scope(&mut self, block: &mut Block) -> PResult<()> {
+        let func = self.func();
+        let scope = func.scopes.pop().unwrap();
+        func.active.truncate(scope.active);
+        for (name, id, line) in scope.gotos {
+            match scope.labels.iter().find(|(label, _)| *label == name) {
+                Some(&(_, index)) => block.labels.push((id, index)),
+                None => match func.scopes.last_mut() {
+                    Some(parent) => parent.gotos.push((name, id, line)),
+                    None => {
+                        return Err(format!(
+                            "no visible label '{}' for goto at line {}",
+                            name, line
+                        ))
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.token {
+            Token::Else | Token::Elseif | Token::End | Token::Eof => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    fn block(&mut self) -> PResult<Block> {
+        self.scoped_block(false)
+    }
+
+    fn scoped_block(&mut self, loop_scope: bool) -> PResult<Block> {
+        self.enter_scope(loop_scope);
+        let mut block = self.statements()?;
+        self.leave_scope(&mut block)?;
+        Ok(block)
+    }
+
+    /// The statements of a block, in the current scope
+    fn statements(&mut self) -> PResult<Block> {
+        let mut block = Block::default();
+        while !self.block_follow(true) {
+            if self.token == Token::Return {
+                block.stats.push(self.return_stat()?);
+                break;
+            }
+            if self.test_next(Token::DoubleColon)? {
+                self.label(block.stats.len())?;
+            } else if let Some(stat) = self.statement()? {
+                block.stats.push(stat);
+            }
+        }
+        Ok(block)
+    }
+
+    /// Defines a label before the statement `index` of the current block
+    fn label(&mut self, index: usize) -> PResult<()> {
+        let name = self.name()?;
+        self.expect(Token::DoubleColon)?;
+        let labels = &mut self.func().scopes.last_mut().unwrap().labels;
+        if labels.iter().any(|(label, _)| *label == name) {
+            return Err(format!("label '{}' already defined", name));
+        }
+        labels.push((name, index));
+        Ok(())
+    }
+
+    fn return_stat(&mut self) -> PResult<Stat> {
+        let line = self.line;
+        self.next()?;
+        let values = if self.block_follow(true) || self.token == Token::Semi {
+            vec![]
+        } else {
+            self.expr_list()?
+        };
+        self.test_next(Token::Semi)?;
+        if !self.block_follow(true) {
+            return self.error("<eof> expected");
+        }
+        Ok(Stat {
+            line,
+            kind: StatKind::Return(values),
+        })
+    }
+
+    fn statement(&mut self) -> PResult<Option<Stat>> {
+        let line = self.line;
+        let kind = match self.token {
+            Token::Semi => {
+                self.next()?;
+                return Ok(None);
+            }
+            Token::If => self.if_stat(line)?,
+            Token::While => {
+                self.next()?;
+                let cond = self.expr()?;
+                self.expect(Token::Do)?;
+                let body = self.scoped_block(true)?;
+                self.expect_match(Token::End, Token::While, line)?;
+                StatKind::While(cond, body)
+            }
+            Token::Do => {
+                self.next()?;
+                let body = self.block()?;
+                self.expect_match(Token::End, Token::Do, line)?;
+                StatKind::Do(body)
+            }
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => {
+                self.next()?;
+                self.enter_scope(true);
+                let mut body = self.statements()?;
+                let until_line = self.line;
+                self.expect_match(Token::Until, Token::Repeat, line)?;
+                // The condition can refer to the locals of the body
+                let cond = self.expr()?;
+                self.leave_scope(&mut body)?;
+                StatKind::Repeat(body, until_line, cond)
+            }
+            Token::Function => self.function_stat(line)?,
+            Token::Local => {
+                self.next()?;
+                if self.test_next(Token::Function)? {
+                    let name = self.name()?;
+                    let slot = self.declare_local(&name);
+                    let proto = self.function_body(false, line)?;
+                    StatKind::LocalFunction(slot, proto)
+                } else {
+                    self.local_stat()?
+                }
+            }
+            Token::Break => {
+                self.next()?;
+                if !self.func().scopes.iter().any(|scope| scope.loop_scope) {
+                    return Err("break outside a loop".to_string());
+                }
+                StatKind::Break
+            }
+            Token::Goto => {
+                self.next()?;
+                let name = self.name()?;
+                let func = self.func();
+                let id = func.next_goto;
+                func.next_goto += 1;
+                func.scopes.last_mut().unwrap().gotos.push((name, id, line));
+                StatKind::Goto(id)
+            }
+            _ => self.expr_stat()?,
+        };
+        Ok(Some(Stat { line, kind }))
+    }
+
+    fn if_stat(&mut self, line: u32) -> PResult<StatKind> {
+        let mut branches = vec![];
+        let mut otherwise = None;
+        loop {
+            // `if` or `elseif`
+            let cond_line = self.line;
+            self.next()?;
+            let cond = self.expr()?;
+            self.expect(Token::Then)?;
+            branches.push((cond_line, cond, self.block()?));
+            match self.token {
+                Token::Elseif => {}
+                Token::Else => {
+                    self.next()?;
+                    otherwise = Some(self.block()?);
+                    self.expect_match(Token::End, Token::If, line)?;
+                    break;
+                }
+                _ => {
+                    self.expect_match(Token::End, Token::If, line)?;
+                    break;
+                }
+            }
+        }
+        Ok(StatKind::If(branches, otherwise))
+    }
+
+    fn for_stat(&mut self, line: u32) -> PResult<StatKind> {
+        self.next()?;
+        let first = self.name()?;
+        let kind = match self.token {
+            Token::Assign => {
+                self.next()?;
+                let start = self.expr()?;
+                self.expect(Token::Comma)?;
+                let limit = self.expr()?;
+                let step = if self.test_next(Token::Comma)? {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                self.expect(Token::Do)?;
+                self.enter_scope(true);
+                let var = self.declare_local(&first);
+                let mut body = self.block()?;
+                self.leave_scope(&mut body)?;
+                StatKind::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                    body,
+                }
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.test_next(Token::Comma)? {
+                    names.push(self.name()?);
+                }
+                self.expect(Token::In)?;
+                let values = self.expr_list()?;
+                self.expect(Token::Do)?;
+                self.enter_scope(true);
+                let vars = names.iter().map(|name| self.declare_local(name)).collect();
+                let mut body = self.block()?;
+                self.leave_scope(&mut body)?;
+                StatKind::GenericFor { vars, values, body }
+            }
+            _ => return self.error("'=' or 'in' expected"),
+        };
+        self.expect_match(Token::End, Token::For, line)?;
+        Ok(kind)
+    }
+
+    fn function_stat(&mut self, line: u32) -> PResult<StatKind> {
+        self.next()?;
+        let name = self.name()?;
+        let mut target = self.variable(&name);
+        let mut method = false;
+        loop {
+            match self.token {
+                Token::Dot => {
+                    self.next()?;
+                    let key = self.name()?;
+                    target = Expr::Index(Box::new(target), Box::new(Expr::Const(key.into())));
+                }
+                Token::Colon => {
+                    self.next()?;
+                    let key = self.name()?;
+                    target = Expr::Index(Box::new(target), Box::new(Expr::Const(key.into())));
+                    method = true;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        let proto = self.function_body(method, line)?;
+        Ok(StatKind::Assign(vec![target], vec![Expr::Function(proto)]))
+    }
+
+    fn local_stat(&mut self) -> PResult<StatKind> {
+        let mut names = vec![];
+        loop {
+            names.push(self.name()?);
+            if self.test_next(Token::Lt)? {
+                match self.name()?.as_str() {
+                    "const" => {}
+                    "close" => return Err("to-be-closed variables are not supported".to_string()),
+                    attrib => return Err(format!("unknown attribute '{}'", attrib)),
+                }
+                self.expect(Token::Gt)?;
+            }
+            if !self.test_next(Token::Comma)? {
+                break;
+            }
+        }
+        let values = if self.test_next(Token::Assign)? {
+            self.expr_list()?
+        } else {
+            vec![]
+        };
+        // The new locals are only in scope after their declaration
+        let slots = names.iter().map(|name| self.declare_local(name)).collect();
+        Ok(StatKind::Local(slots, values))
+    }
+
+    fn expr_stat(&mut self) -> PResult<StatKind> {
+        let first = self.suffixed_expr()?;
+        if self.token == Token::Assign || self.token == Token::Comma {
+            let mut targets = vec![first];
+            while self.test_next(Token::Comma)? {
+                targets.push(self.suffixed_expr()?);
+            }
+            for target in &targets {
+                if !matches!(
+                    target,
+                    Expr::Local(_) | Expr::Upvalue(_) | Expr::Global(_) | Expr::Index(..)
+                ) {
+                    return self.error("syntax error");
+                }
+            }
+            self.expect(Token::Assign)?;
+            let values = self.expr_list()?;
+            Ok(StatKind::Assign(targets, values))
+        } else if matches!(first, Expr::Call(..) | Expr::Method(..)) {
+            Ok(StatKind::Call(first))
+        } else {
+            self.error("syntax error")
+        }
+    }
+
+    /// Parses the parameters and body of a function, after its name
+    fn function_body(&mut self, method: bool, line: u32) -> PResult<Rc<Proto>> {
+        self.funcs.push(FuncState::default());
+        self.enter_scope(false);
+        if method {
+            self.declare_local("self");
+        }
+        self.expect(Token::LParen)?;
+        if self.token != Token::RParen {
+            loop {
+                match self.token {
+                    Token::Dots => {
+                        self.next()?;
+                        self.func().vararg = true;
+                        break;
+                    }
+                    _ => {
+                        let name = self.name()?;
+                        self.declare_local(&name);
+                    }
+                }
+                if !self.test_next(Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        let params = self.func().locals.len();
+        self.expect(Token::RParen)?;
+        let mut body = self.statements()?;
+        self.leave_scope(&mut body)?;
+        self.expect_match(Token::End, Token::Function, line)?;
+        self.finish_function(line, params, body)
+    }
+
+    fn expr_list(&mut self) -> PResult<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(Token::Comma)? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> PResult<Expr> {
+        self.sub_expr(0)
+    }
+
+    fn sub_expr(&mut self, limit: u8) -> PResult<Expr> {
+        let unary = match self.token {
+            Token::Not => Some(UnOp::Not),
+            Token::Sub => Some(UnOp::Neg),
+            Token::Tilde => Some(UnOp::BNot),
+            Token::Len => Some(UnOp::Len),
+            _ => None,
+        };
+        let mut lhs = match unary {
+            Some(op) => {
+                self.next()?;
+                let value = self.sub_expr(UNARY_PRIORITY)?;
+                match (op, value) {
+                    // Negative literals, so that `-9223372036854775808` is an integer
+                    (UnOp::Neg, Expr::Const(Value::Int(i))) => {
+                        Expr::Const(Value::Int(i.wrapping_neg()))
+                    }
+                    (UnOp::Neg, Expr::Const(Value::Float(f))) => Expr::Const(Value::Float(-f)),
+                    (op, value) => Expr::UnOp(op, Box::new(value)),
+                }
+            }
+            None => self.simple_expr()?,
+        };
+        while let Some((op, left, right)) = binary_op(&self.token) {
+            if left <= limit {
+                break;
+            }
+            self.next()?;
+            let rhs = self.sub_expr(right)?;
+            lhs = Expr::BinOp(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn simple_expr(&mut self) -> PResult<Expr> {
+        let value = match &self.token {
+            Token::Int(i) => Value::Int(*i),
+            Token::Float(f) => Value::Float(*f),
+            Token::Str(s) => Value::from(&s[..]),
+            Token::Nil => Value::Nil,
+            Token::True => Value::Bool(true),
+            Token::False => Value::Bool(false),
+            Token::Dots => {
+                if !self.func().vararg {
+                    return self.error("cannot use '...' outside a vararg function");
+                }
+                self.next()?;
+                return Ok(Expr::Vararg);
+            }
+            Token::LBrace => return self.table(),
+            Token::Function => {
+                let line = self.line;
+                self.next()?;
+                return Ok(Expr::Function(self.function_body(false, line)?));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.next()?;
+        Ok(Expr::Const(value))
+    }
+
+    fn primary_expr(&mut self) -> PResult<Expr> {
+        match self.token {
+            Token::Name(_) => {
+                let name = self.name()?;
+                Ok(self.variable(&name))
+            }
+            Token::LParen => {
+                let line = self.line;
+                self.next()?;
+                let expr = self.expr()?;
+                self.expect_match(Token::RParen, Token::LParen, line)?;
+                Ok(match expr {
+                    expr if expr.is_multi() => Expr::Paren(Box::new(expr)),
+                    // Keeps `(a) = 1` and `(a).b = 1` apart
+                    expr @ (Expr::Local(_)
+                    | Expr::Upvalue(_)
+                    | Expr::Global(_)
+                    | Expr::Index(..)) => Expr::Paren(Box::new(expr)),
+                    expr => expr,
+                })
+            }
+            _ => self.error("unexpected symbol"),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> PResult<Expr> {
+        let mut expr = self.primary_expr()?;
+        loop {
+            match self.token {
+                Token::Dot => {
+                    self.next()?;
+                    let key = self.name()?;
+                    expr = Expr::Index(Box::new(unparen(expr)), Box::new(Expr::Const(key.into())));
+                }
+                Token::LBracket => {
+                    self.next()?;
+                    let key = self.expr()?;
+                    self.expect(Token::RBracket)?;
+                    expr = Expr::Index(Box::new(unparen(expr)), Box::new(key));
+                }
+                Token::Colon => {
+                    self.next()?;
+                    let name = self.name()?;
+                    let args = self.call_args()?;
+                    expr = Expr::Method(Box::new(unparen(expr)), name.into(), args);
+                }
+                Token::LParen | Token::Str(_) | Token::LBrace => {
+                    let args = self.call_args()?;
+                    expr = Expr::Call(Box::new(unparen(expr)), args);
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn call_args(&mut self) -> PResult<Vec<Expr>> {
+        match &self.token {
+            Token::Str(s) => {
+                let arg = Expr::Const(Value::from(&s[..]));
+                self.next()?;
+                Ok(vec![arg])
+            }
+            Token::LBrace => Ok(vec![self.table()?]),
+            Token::LParen => {
+                let line = self.line;
+                self.next()?;
+                let args = if self.token == Token::RParen {
+                    vec![]
+                } else {
+                    self.expr_list()?
+                };
+                self.expect_match(Token::RParen, Token::LParen, line)?;
+                Ok(args)
+            }
+            _ => self.error("function arguments expected"),
+        }
+    }
+
+    fn table(&mut self) -> PResult<Expr> {
+        let line = self.line;
+        self.expect(Token::LBrace)?;
+        let mut fields = vec![];
+        while self.token != Token::RBrace {
+            let keyed_by_name =
+                matches!(self.token, Token::Name(_)) && *self.peek()? == Token::Assign;
+            let field = match self.token {
+                Token::Name(_) if keyed_by_name => {
+                    let key = self.name()?;
+                    self.next()?;
+                    Field::Keyed(Expr::Const(key.into()), self.expr()?)
+                }
+                Token::LBracket => {
+                    self.next()?;
+                    let key = self.expr()?;
+                    self.expect(Token::RBracket)?;
+                    self.expect(Token::Assign)?;
+                    Field::Keyed(key, self.expr()?)
+                }
+                _ => Field::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !self.test_next(Token::Comma)? && !self.test_next(Token::Semi)? {
+                break;
+            }
+        }
+        self.expect_match(Token::RBrace, Token::LBrace, line)?;
+        Ok(Expr::Table(fields))
+    }
+}
+
+/// Parentheses around a prefix expression only matter for assignments and multiple values
+fn unparen(expr: Expr) -> Expr {
+    match expr {
+        Expr::Paren(inner) if !inner.is_multi() => *inner,
+        expr => expr,
+    }
+}
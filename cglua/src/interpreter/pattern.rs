@@ -0,0 +1,348 @@
+//! Lua patterns, a port of the matcher of the reference implementation
+
+use super::Value;
+
+const MAX_CAPTURES: usize = 32;
+const MAX_MATCH_DEPTH: usize = 200;
+const ESC: u8 = b'%';
+/// The characters making a pattern more than a plain string
+pub(crate) const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+#[derive(Clone, Copy)]
+enum CaptureLen {
+    Len(usize),
+    Position,
+    Unfinished,
+}
+
+pub(crate) struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    captures: [(usize, CaptureLen); MAX_CAPTURES],
+    depth: usize,
+}
+
+type MResult<T> = Result<T, String>;
+
+fn match_class(c: u8, class: u8) -> bool {
+    let res = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => matches!(c, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
+
+impl<'a> MatchState<'a> {
+    pub(crate) fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        MatchState {
+            src,
+            pat,
+            level: 0,
+            captures: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+            depth: MAX_MATCH_DEPTH,
+        }
+    }
+
+    pub(crate) fn src(&self) -> &'a [u8] {
+        self.src
+    }
+
+    /// Matches the pattern starting at `p` against the subject starting at `s`, returning the end
+    /// of the match
+    pub(crate) fn do_match(&mut self, s: usize, p: usize) -> MResult<Option<usize>> {
+        self.level = 0;
+        self.depth = MAX_MATCH_DEPTH;
+        self.match_(s, p)
+    }
+
+    fn class_end(&self, mut p: usize) -> MResult<usize> {
+        let c = self.pat[p];
+        p += 1;
+        if c == ESC {
+            if p >= self.pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // The first character is part of the set even if it is `]`
+            loop {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let c = self.pat[p];
+                p += 1;
+                if c == ESC && p < self.pat.len() {
+                    p += 1;
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    /// Whether `c` is in the set `[...]` from `p` to the closing bracket at `end`
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut sig = true;
+        if self.pat[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+        p += 1;
+        while p < end {
+            if self.pat[p] == ESC {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return sig;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < end {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let c = match self.src.get(s) {
+            Some(&c) => c,
+            None => return false,
+        };
+        match self.pat[p] {
+            b'.' => true,
+            ESC => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn match_(&mut self, s: usize, p: usize) -> MResult<Option<usize>> {
+        if self.depth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.depth -= 1;
+        let res = self.match_inner(s, p);
+        self.depth += 1;
+        res
+    }
+
+    fn match_inner(&mut self, mut s: usize, mut p: usize) -> MResult<Option<usize>> {
+        loop {
+            if p == self.pat.len() {
+                return Ok(Some(s));
+            }
+            match self.pat[p] {
+                b'(' => {
+                    return if self.pat.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CaptureLen::Position)
+                    } else {
+                        self.start_capture(s, p + 1, CaptureLen::Unfinished)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok(if s == self.src.len() { Some(s) } else { None });
+                }
+                ESC if self.pat.get(p + 1) == Some(&b'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+                ESC if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                ESC if matches!(self.pat.get(p + 1), Some(b'0'..=b'9')) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {}
+            }
+
+            let ep = self.class_end(p)?;
+            let next = self.pat.get(ep).copied();
+            if !self.single_match(s, p, ep) {
+                if matches!(next, Some(b'*' | b'?' | b'-')) {
+                    p = ep + 1;
+                    continue;
+                }
+                return Ok(None);
+            }
+            match next {
+                Some(b'?') => {
+                    if let Some(end) = self.match_(s + 1, ep + 1)? {
+                        return Ok(Some(end));
+                    }
+                    p = ep + 1;
+                }
+                Some(b'+') => return self.max_expand(s + 1, p, ep),
+                Some(b'*') => return self.max_expand(s, p, ep),
+                Some(b'-') => return self.min_expand(s, p, ep),
+                _ => {
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> MResult<Option<usize>> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        loop {
+            if let Some(end) = self.match_(s + i, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> MResult<Option<usize>> {
+        loop {
+            if let Some(end) = self.match_(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if self.single_match(s, p, ep) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: CaptureLen) -> MResult<Option<usize>> {
+        if self.level >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+        let res = self.match_(s, p)?;
+        if res.is_none() {
+            self.level -= 1;
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> MResult<Option<usize>> {
+        let l = (0..self.level)
+            .rev()
+            .find(|&l| matches!(self.captures[l].1, CaptureLen::Unfinished))
+            .ok_or_else(|| "invalid pattern capture".to_string())?;
+        self.captures[l].1 = CaptureLen::Len(s - self.captures[l].0);
+        let res = self.match_(s, p)?;
+        if res.is_none() {
+            self.captures[l].1 = CaptureLen::Unfinished;
+        }
+        Ok(res)
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> MResult<Option<usize>> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for i in s + 1..self.src.len() {
+            if self.src[i] == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if self.src[i] == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, l: u8) -> MResult<Option<usize>> {
+        let l = (l as usize).wrapping_sub(b'1' as usize);
+        let (start, len) = match self.captures.get(l) {
+            Some(&(start, CaptureLen::Len(len))) if l < self.level => (start, len),
+            _ => return Err(format!("invalid capture index %{}", l.wrapping_add(1))),
+        };
+        if self.src.len() - s >= len && self.src[start..start + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The capture `i` of a match from `s` to `e`, the whole match when the pattern has none
+    pub(crate) fn capture(&self, i: usize, s: usize, e: usize) -> MResult<Value> {
+        if i >= self.level {
+            if i == 0 {
+                return Ok(Value::from(&self.src[s..e]));
+            }
+            return Err(format!("invalid capture index %{}", i + 1));
+        }
+        match self.captures[i] {
+            (start, CaptureLen::Len(len)) => Ok(Value::from(&self.src[start..start + len])),
+            (start, CaptureLen::Position) => Ok(Value::Int(start as i64 + 1)),
+            (_, CaptureLen::Unfinished) => Err("unfinished capture".to_string()),
+        }
+    }
+
+    /// All the captures of a match from `s` to `e`, or the whole match when there are none
+    pub(crate) fn captures(&self, s: usize, e: usize, whole_if_none: bool) -> MResult<Vec<Value>> {
+        let n = if self.level == 0 && whole_if_none {
+            1
+        } else {
+            self.level
+        };
+        (0..n).map(|i| self.capture(i, s, e)).collect()
+    }
+}
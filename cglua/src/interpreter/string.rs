@@ -0,0 +1,1151 @@
+//! The `string` and `utf8` libraries
+
+use std::convert::TryInto;
+use std::rc::Rc;
+
+use super::base::{arg, bad_arg, check_bytes, check_float, check_int, opt_int, register};
+use super::pattern::{MatchState, SPECIALS};
+use super::value::{float_to_int, fmt_g};
+use super::{Function, LResult, Lua, Table, Value};
+use crate::lexer::encode_utf8;
+
+/// Strings longer than this can't be created by `string.rep`
+const MAX_STRING_SIZE: usize = 1 << 31;
+
+pub(crate) fn open(lua: &mut Lua) {
+    let string = lua.string.clone();
+    register(
+        &string,
+        &[
+            ("byte", byte),
+            ("char", char),
+            ("find", find),
+            ("format", format),
+            ("gmatch", gmatch),
+            ("gsub", gsub),
+            ("len", len),
+            ("lower", lower),
+            ("match", match_),
+            ("pack", pack),
+            ("packsize", packsize),
+            ("rep", rep),
+            ("reverse", reverse),
+            ("sub", sub),
+            ("unpack", unpack),
+            ("upper", upper),
+        ],
+    );
+    lua.globals.set("string", string);
+
+    let utf8 = Table::new();
+    register(
+        &utf8,
+        &[
+            ("char", utf8_char),
+            ("codepoint", utf8_codepoint),
+            ("codes", utf8_codes),
+            ("len", utf8_len),
+        ],
+    );
+    utf8.set("charpattern", &b"[\x00-\x7F\xC2-\xF4][\x80-\xBF]*"[..]);
+    lua.globals.set("utf8", utf8);
+}
+
+/// Converts a relative string position, negative positions counting from the end
+fn relative_position(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+/// The byte range `i..=j` of a string of length `len`, with relative positions
+fn byte_range(i: i64, j: i64, len: usize) -> std::ops::Range<usize> {
+    let start = relative_position(i, len).max(1);
+    let end = relative_position(j, len).min(len as i64);
+    if start > end {
+        0..0
+    } else {
+        start as usize - 1..end as usize
+    }
+}
+
+fn byte(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "byte")?;
+    let i = opt_int(lua, &args, 1, "byte", 1)?;
+    let j = opt_int(lua, &args, 2, "byte", i)?;
+    Ok(s[byte_range(i, j, s.len())]
+        .iter()
+        .map(|&b| Value::Int(b as i64))
+        .collect())
+}
+
+fn char(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let mut s = Vec::with_capacity(args.len());
+    for i in 0..args.len() {
+        let c = check_int(lua, &args, i, "char")?;
+        if !(0..=255).contains(&c) {
+            return Err(bad_arg(lua, i, "char", "value out of range"));
+        }
+        s.push(c as u8);
+    }
+    Ok(vec![Value::from(s)])
+}
+
+fn len(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "len")?;
+    Ok(vec![Value::Int(s.len() as i64)])
+}
+
+fn lower(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "lower")?;
+    Ok(vec![Value::from(s.to_ascii_lowercase())])
+}
+
+fn upper(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "upper")?;
+    Ok(vec![Value::from(s.to_ascii_uppercase())])
+}
+
+fn rep(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "rep")?;
+    let n = check_int(lua, &args, 1, "rep")?;
+    let sep = match arg(&args, 2) {
+        Value::Nil => Rc::from(&b""[..]),
+        _ => check_bytes(lua, &args, 2, "rep")?,
+    };
+    if n <= 0 {
+        return Ok(vec![Value::from("")]);
+    }
+    let total = (s.len() + sep.len()) as u128 * n as u128;
+    if total >= MAX_STRING_SIZE as u128 {
+        return Err(lua.error("resulting string too large"));
+    }
+    let mut out = Vec::with_capacity(total as usize);
+    for i in 0..n {
+        if i > 0 {
+            out.extend_from_slice(&sep);
+        }
+        out.extend_from_slice(&s);
+    }
+    Ok(vec![Value::from(out)])
+}
+
+fn reverse(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "reverse")?;
+    Ok(vec![Value::from(
+        s.iter().rev().copied().collect::<Vec<_>>(),
+    )])
+}
+
+fn sub(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "sub")?;
+    let i = opt_int(lua, &args, 1, "sub", 1)?;
+    let j = opt_int(lua, &args, 2, "sub", -1)?;
+    Ok(vec![Value::from(&s[byte_range(i, j, s.len())])])
+}
+
+/// `string.find` and `string.match`
+fn find_aux(lua: &mut Lua, args: Vec<Value>, find: bool) -> LResult<Vec<Value>> {
+    let name = if find { "find" } else { "match" };
+    let s = check_bytes(lua, &args, 0, name)?;
+    let pat = check_bytes(lua, &args, 1, name)?;
+    let init = relative_position(opt_int(lua, &args, 2, name, 1)?, s.len()).max(1) as usize - 1;
+    if init > s.len() {
+        return Ok(vec![Value::Nil]);
+    }
+    let plain = arg(&args, 3).truthy() || !pat.iter().any(|c| SPECIALS.contains(c));
+    if find && plain {
+        let pos = if pat.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(pat.len()).position(|w| *w == *pat)
+        };
+        return Ok(match pos {
+            Some(pos) => vec![
+                Value::Int((init + pos) as i64 + 1),
+                Value::Int((init + pos + pat.len()) as i64),
+            ],
+            None => vec![Value::Nil],
+        });
+    }
+
+    let (anchor, p) = match pat.first() {
+        Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut ms = MatchState::new(&s, &pat);
+    let mut start = init;
+    loop {
+        if let Some(end) = ms.do_match(start, p).map_err(|e| lua.error(e))? {
+            let captures = ms.captures(start, end, !find).map_err(|e| lua.error(e))?;
+            return Ok(if find {
+                let mut results = vec![Value::Int(start as i64 + 1), Value::Int(end as i64)];
+                results.extend(captures);
+                results
+            } else {
+                captures
+            });
+        }
+        start += 1;
+        if anchor || start > s.len() {
+            return Ok(vec![Value::Nil]);
+        }
+    }
+}
+
+fn find(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    find_aux(lua, args, true)
+}
+
+fn match_(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    find_aux(lua, args, false)
+}
+
+fn gmatch(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "gmatch")?;
+    let pat = check_bytes(lua, &args, 1, "gmatch")?;
+    let position = std::cell::Cell::new(0);
+    let last_match = std::cell::Cell::new(None);
+    let iter = Function::native(move |lua, _| {
+        let mut ms = MatchState::new(&s, &pat);
+        let mut start = position.get();
+        while start <= s.len() {
+            match ms.do_match(start, 0).map_err(|e| lua.error(e))? {
+                // Empty matches right after the previous match are skipped
+                Some(end) if Some(end) != last_match.get() => {
+                    position.set(end);
+                    last_match.set(Some(end));
+                    return ms.captures(start, end, true).map_err(|e| lua.error(e));
+                }
+                _ => start += 1,
+            }
+        }
+        position.set(start);
+        Ok(vec![Value::Nil])
+    });
+    Ok(vec![Value::Function(iter)])
+}
+
+fn gsub(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "gsub")?;
+    let pat = check_bytes(lua, &args, 1, "gsub")?;
+    let repl = arg(&args, 2);
+    if !matches!(
+        repl,
+        Value::Int(_) | Value::Float(_) | Value::Str(_) | Value::Table(_) | Value::Function(_)
+    ) {
+        return Err(bad_arg(
+            lua,
+            2,
+            "gsub",
+            format!("string/function/table expected, got {}", repl.type_name()),
+        ));
+    }
+    let max = opt_int(lua, &args, 3, "gsub", s.len() as i64 + 1)?;
+
+    let (anchor, p) = match pat.first() {
+        Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut ms = MatchState::new(&s, &pat);
+    let mut out = vec![];
+    let mut start = 0;
+    let mut last_match = None;
+    let mut n = 0;
+    while n < max {
+        match ms.do_match(start, p).map_err(|e| lua.error(e))? {
+            Some(end) if Some(end) != last_match => {
+                n += 1;
+                add_replacement(lua, &ms, &repl, start, end, &mut out)?;
+                start = end;
+                last_match = Some(end);
+            }
+            _ if start < s.len() => {
+                out.push(s[start]);
+                start += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&s[start..]);
+    Ok(vec![Value::from(out), Value::Int(n)])
+}
+
+/// Appends the replacement of the match from `start` to `end` by `repl` to `out`, for `gsub`
+fn add_replacement(
+    lua: &mut Lua,
+    ms: &MatchState,
+    repl: &Value,
+    start: usize,
+    end: usize,
+    out: &mut Vec<u8>,
+) -> LResult<()> {
+    let whole = &ms.src()[start..end];
+    let value = match repl {
+        Value::Table(_) => {
+            let first = ms.capture(0, start, end).map_err(|e| lua.error(e))?;
+            lua.index(repl.clone(), first)?
+        }
+        Value::Function(_) => {
+            let captures = ms.captures(start, end, true).map_err(|e| lua.error(e))?;
+            lua.call_value1(repl, captures)?
+        }
+        repl => {
+            let repl = repl.coerce_to_bytes().unwrap();
+            let mut i = 0;
+            while i < repl.len() {
+                let c = repl[i];
+                i += 1;
+                if c != b'%' {
+                    out.push(c);
+                    continue;
+                }
+                match repl.get(i) {
+                    Some(b'%') => out.push(b'%'),
+                    Some(b'0') => out.extend_from_slice(whole),
+                    Some(&d) if d.is_ascii_digit() => {
+                        let capture = ms
+                            .capture((d - b'1') as usize, start, end)
+                            .map_err(|e| lua.error(e))?;
+                        out.extend(capture.to_bytes());
+                    }
+                    _ => return Err(lua.error("invalid use of '%' in replacement string")),
+                }
+                i += 1;
+            }
+            return Ok(());
+        }
+    };
+    match value {
+        Value::Nil | Value::Bool(false) => out.extend_from_slice(whole),
+        v => match v.coerce_to_bytes() {
+            Some(s) => out.extend_from_slice(&s),
+            None => {
+                return Err(lua.error(format!("invalid replacement value (a {})", v.type_name())))
+            }
+        },
+    }
+    Ok(())
+}
+
+/// A parsed `%` conversion specification of `string.format`
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Pads `body`, which starts with `prefix_len` bytes of sign and base prefix
+    fn pad(&self, body: String, prefix_len: usize, zero_pad: bool) -> String {
+        if body.len() >= self.width {
+            return body;
+        }
+        let fill = self.width - body.len();
+        if self.left {
+            body + &" ".repeat(fill)
+        } else if self.zero && zero_pad {
+            format!(
+                "{}{}{}",
+                &body[..prefix_len],
+                "0".repeat(fill),
+                &body[prefix_len..]
+            )
+        } else {
+            " ".repeat(fill) + &body
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn int(&self, v: i64, conversion: u8) -> String {
+        let (negative, digits) = match conversion {
+            b'd' | b'i' => (v < 0, v.unsigned_abs().to_string()),
+            b'o' => (false, format!("{:o}", v)),
+            b'x' => (false, format!("{:x}", v)),
+            _ => (false, format!("{:X}", v)),
+        };
+        let digits = match self.precision {
+            Some(0) if v == 0 => String::new(),
+            Some(p) if digits.len() < p => "0".repeat(p - digits.len()) + &digits,
+            _ => digits,
+        };
+        let prefix = match conversion {
+            b'x' if self.alternate && v != 0 => "0x",
+            b'X' if self.alternate && v != 0 => "0X",
+            b'o' if self.alternate && !digits.starts_with('0') => "0",
+            _ => "",
+        };
+        let sign = if matches!(conversion, b'd' | b'i') {
+            self.sign(negative)
+        } else {
+            ""
+        };
+        let head = sign.len() + prefix.len();
+        self.pad(
+            format!("{}{}{}", sign, prefix, digits),
+            head,
+            self.precision.is_none(),
+        )
+    }
+
+    fn float(&self, v: f64, conversion: u8) -> String {
+        let sign = self.sign(v.is_sign_negative() && !v.is_nan());
+        let abs = v.abs();
+        let body = if !v.is_finite() {
+            if v.is_nan() { "nan" } else { "inf" }.to_string()
+        } else {
+            match conversion.to_ascii_lowercase() {
+                b'f' => format!("{:.*}", self.precision.unwrap_or(6), abs),
+                b'e' => fmt_e(abs, self.precision.unwrap_or(6)),
+                b'a' => fmt_hex_float(abs),
+                _ => fmt_g(abs, self.precision.unwrap_or(6), self.alternate),
+            }
+        };
+        let body = if conversion.is_ascii_uppercase() {
+            body.to_ascii_uppercase()
+        } else {
+            body
+        };
+        self.pad(format!("{}{}", sign, body), sign.len(), v.is_finite())
+    }
+}
+
+/// Formats a non-negative float like `printf("%.<precision>e")`
+fn fmt_e(v: f64, precision: usize) -> String {
+    let s = format!("{:.*e}", precision, v);
+    let (mantissa, exp) = s.split_at(s.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    format!(
+        "{}e{}{:02}",
+        mantissa,
+        if exp < 0 { '-' } else { '+' },
+        exp.abs()
+    )
+}
+
+/// Formats a non-negative finite float like `printf("%a")`
+fn fmt_hex_float(v: f64) -> String {
+    if v == 0.0 {
+        return "0x0p+0".to_string();
+    }
+    let bits = v.to_bits();
+    let biased_exp = ((bits >> 52) & 0x7ff) as i64;
+    let fraction = bits & ((1 << 52) - 1);
+    let (lead, exp) = if biased_exp == 0 {
+        (0, -1022)
+    } else {
+        (1, biased_exp - 1023)
+    };
+    let digits = format!("{:013x}", fraction);
+    let digits = digits.trim_end_matches('0');
+    let point = if digits.is_empty() { "" } else { "." };
+    format!(
+        "0x{}{}{}p{}{}",
+        lead,
+        point,
+        digits,
+        if exp < 0 { '-' } else { '+' },
+        exp.abs()
+    )
+}
+
+/// Quotes a string so that lua reads it back, for `%q`
+fn quote(s: &[u8], out: &mut Vec<u8>) {
+    out.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        match c {
+            b'"' | b'\\' | b'\n' => {
+                out.push(b'\\');
+                out.push(c);
+            }
+            b'\r' => out.extend_from_slice(b"\\r"),
+            0 => {
+                if matches!(s.get(i + 1), Some(b'0'..=b'9')) {
+                    out.extend_from_slice(b"\\000");
+                } else {
+                    out.extend_from_slice(b"\\0");
+                }
+            }
+            c if c.is_ascii_control() => {
+                if matches!(s.get(i + 1), Some(b'0'..=b'9')) {
+                    out.extend_from_slice(format!("\\{:03}", c).as_bytes());
+                } else {
+                    out.extend_from_slice(format!("\\{}", c).as_bytes());
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push(b'"');
+}
+
+fn format(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let fmt = check_bytes(lua, &args, 0, "format")?;
+    let mut out = vec![];
+    let mut arg_index = 0;
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+
+        let mut spec = Spec::default();
+        let spec_start = i;
+        while let Some(&flag) = fmt.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        let mut digits = 0;
+        while let Some(d) = fmt.get(i).filter(|d| d.is_ascii_digit()) {
+            spec.width = spec.width * 10 + (d - b'0') as usize;
+            digits += 1;
+            i += 1;
+        }
+        if fmt.get(i) == Some(&b'.') {
+            i += 1;
+            let mut precision = 0;
+            while let Some(d) = fmt.get(i).filter(|d| d.is_ascii_digit()) {
+                precision = precision * 10 + (d - b'0') as usize;
+                digits += 1;
+                i += 1;
+            }
+            spec.precision = Some(precision);
+        }
+        if i - spec_start > 5 || digits > 4 {
+            return Err(lua.error("invalid format (repeated flags)"));
+        }
+        let conversion = match fmt.get(i) {
+            Some(&c) => c,
+            None => {
+                return Err(lua.error("invalid conversion '%' to 'format'"));
+            }
+        };
+        i += 1;
+
+        arg_index += 1;
+        if arg_index >= args.len() && conversion != b'%' {
+            return Err(bad_arg(lua, arg_index, "format", "no value"));
+        }
+        let formatted = match conversion {
+            b'c' => {
+                let c = check_int(lua, &args, arg_index, "format")?;
+                let mut s = vec![c as u8];
+                if spec.width > 1 {
+                    let pad = vec![b' '; spec.width - 1];
+                    if spec.left {
+                        s.extend(pad);
+                    } else {
+                        s = [pad, s].concat();
+                    }
+                }
+                out.extend(s);
+                continue;
+            }
+            b'd' | b'i' | b'o' | b'x' | b'X' => {
+                let v = check_int(lua, &args, arg_index, "format")?;
+                spec.int(v, conversion)
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let v = check_float(lua, &args, arg_index, "format")?;
+                spec.float(v, conversion)
+            }
+            b'q' => {
+                match &args[arg_index] {
+                    Value::Str(s) => quote(s, &mut out),
+                    Value::Int(i64::MIN) => out.extend_from_slice(b"0x8000000000000000"),
+                    Value::Int(v) => out.extend_from_slice(v.to_string().as_bytes()),
+                    Value::Float(f) if f.is_nan() => out.extend_from_slice(b"(0/0)"),
+                    Value::Float(f) if f.is_infinite() => out.extend_from_slice(if *f > 0.0 {
+                        &b"1e9999"[..]
+                    } else {
+                        &b"-1e9999"[..]
+                    }),
+                    Value::Float(f) if float_to_int(*f).is_some() => {
+                        out.extend_from_slice(format!("{}e0", f).as_bytes())
+                    }
+                    Value::Float(f) => out.extend_from_slice(
+                        format!(
+                            "{}{}",
+                            if *f < 0.0 { "-" } else { "" },
+                            fmt_hex_float(f.abs())
+                        )
+                        .as_bytes(),
+                    ),
+                    v @ (Value::Nil | Value::Bool(_)) => out.extend(v.to_bytes()),
+                    _ => {
+                        return Err(bad_arg(
+                            lua,
+                            arg_index,
+                            "format",
+                            "value has no literal form",
+                        ))
+                    }
+                }
+                continue;
+            }
+            b's' => {
+                let s = lua.tostring(&args[arg_index])?;
+                let s = match spec.precision {
+                    Some(p) if p < s.len() => &s[..p],
+                    _ => &s[..],
+                };
+                let pad = vec![b' '; spec.width.saturating_sub(s.len())];
+                if spec.left {
+                    out.extend_from_slice(s);
+                    out.extend(pad);
+                } else {
+                    out.extend(pad);
+                    out.extend_from_slice(s);
+                }
+                continue;
+            }
+            c => {
+                return Err(lua.error(format!(
+                    "invalid conversion '%{}{}' to 'format'",
+                    String::from_utf8_lossy(&fmt[spec_start..i - 1]),
+                    c as char
+                )))
+            }
+        };
+        out.extend_from_slice(formatted.as_bytes());
+    }
+    Ok(vec![Value::from(out)])
+}
+
+/// The state of the parsing of a `string.pack` format
+struct PackFormat<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    little: bool,
+    max_align: usize,
+}
+
+enum PackOption {
+    Int {
+        size: usize,
+        signed: bool,
+    },
+    Float,
+    Double,
+    /// A string preceded by its length, as an unsigned integer of the given size
+    LenString(usize),
+    /// A string of a fixed size
+    Fixed(usize),
+    Zstr,
+    Padding,
+    /// Aligns to the alignment of the given option
+    Align,
+    Nop,
+}
+
+impl<'a> PackFormat<'a> {
+    fn new(fmt: &'a [u8]) -> Self {
+        PackFormat {
+            fmt,
+            pos: 0,
+            little: cfg!(target_endian = "little"),
+            max_align: 1,
+        }
+    }
+
+    fn number(&mut self, default: usize) -> usize {
+        let start = self.pos;
+        let mut n = 0usize;
+        while let Some(d) = self.fmt.get(self.pos).filter(|d| d.is_ascii_digit()) {
+            n = n.saturating_mul(10).saturating_add((d - b'0') as usize);
+            self.pos += 1;
+        }
+        if self.pos == start {
+            default
+        } else {
+            n
+        }
+    }
+
+    fn int_size(&mut self, default: usize) -> Result<usize, String> {
+        let size = self.number(default);
+        if !(1..=16).contains(&size) {
+            return Err(format!("integral size ({}) out of limits [1,16]", size));
+        }
+        Ok(size)
+    }
+
+    /// The next option with its size and alignment, `None` at the end of the format
+    fn next(&mut self) -> Result<Option<(PackOption, usize, usize)>, String> {
+        let c = match self.fmt.get(self.pos) {
+            Some(&c) => c,
+            None => return Ok(None),
+        };
+        self.pos += 1;
+        let (option, size) = match c {
+            b'b' => (
+                PackOption::Int {
+                    size: 1,
+                    signed: true,
+                },
+                1,
+            ),
+            b'B' => (
+                PackOption::Int {
+                    size: 1,
+                    signed: false,
+                },
+                1,
+            ),
+            b'h' => (
+                PackOption::Int {
+                    size: 2,
+                    signed: true,
+                },
+                2,
+            ),
+            b'H' => (
+                PackOption::Int {
+                    size: 2,
+                    signed: false,
+                },
+                2,
+            ),
+            b'i' | b'I' => {
+                let size = self.int_size(4)?;
+                (
+                    PackOption::Int {
+                        size,
+                        signed: c == b'i',
+                    },
+                    size,
+                )
+            }
+            b'l' | b'j' => (
+                PackOption::Int {
+                    size: 8,
+                    signed: true,
+                },
+                8,
+            ),
+            b'L' | b'J' | b'T' => (
+                PackOption::Int {
+                    size: 8,
+                    signed: false,
+                },
+                8,
+            ),
+            b'f' => (PackOption::Float, 4),
+            b'd' | b'n' => (PackOption::Double, 8),
+            b's' => {
+                let size = self.int_size(8)?;
+                (PackOption::LenString(size), size)
+            }
+            b'c' => {
+                let start = self.pos;
+                let size = self.number(0);
+                if self.pos == start {
+                    return Err("missing size for format option 'c'".to_string());
+                }
+                (PackOption::Fixed(size), size)
+            }
+            b'z' => (PackOption::Zstr, 0),
+            b'x' => (PackOption::Padding, 1),
+            b'X' => {
+                let (_, size, _) = self
+                    .next()?
+                    .filter(|(option, size, _)| {
+                        !matches!(option, PackOption::Fixed(_) | PackOption::Zstr) && *size > 0
+                    })
+                    .ok_or_else(|| "invalid next option for option 'X'".to_string())?;
+                return Ok(Some((PackOption::Align, 0, size.min(self.max_align))));
+            }
+            b' ' => (PackOption::Nop, 0),
+            b'<' => {
+                self.little = true;
+                (PackOption::Nop, 0)
+            }
+            b'>' => {
+                self.little = false;
+                (PackOption::Nop, 0)
+            }
+            b'=' => {
+                self.little = cfg!(target_endian = "little");
+                (PackOption::Nop, 0)
+            }
+            b'!' => {
+                self.max_align = self.int_size(8)?;
+                (PackOption::Nop, 0)
+            }
+            c => return Err(format!("invalid format option '{}'", c as char)),
+        };
+        let align = match option {
+            PackOption::Int { .. } | PackOption::Float | PackOption::Double => {
+                size.min(self.max_align)
+            }
+            PackOption::LenString(_) => size.min(self.max_align),
+            _ => 1,
+        };
+        Ok(Some((option, size, align)))
+    }
+
+    /// The padding needed before an option with alignment `align` at `offset`
+    fn padding(&self, offset: usize, align: usize) -> Result<usize, String> {
+        if align <= 1 {
+            return Ok(0);
+        }
+        if !align.is_power_of_two() {
+            return Err("format asks for alignment not power of 2".to_string());
+        }
+        Ok((align - offset % align) % align)
+    }
+
+    fn write_int(&self, out: &mut Vec<u8>, v: i64, size: usize) {
+        let mut bytes = (v as i128).to_le_bytes()[..size.min(16)].to_vec();
+        if size > 8 && v < 0 {
+            for b in &mut bytes[8..] {
+                *b = 0xff;
+            }
+        }
+        if !self.little {
+            bytes.reverse();
+        }
+        out.extend(bytes);
+    }
+
+    fn read_int(&self, data: &[u8], signed: bool) -> Result<i64, String> {
+        let mut bytes = data.to_vec();
+        if !self.little {
+            bytes.reverse();
+        }
+        let size = bytes.len();
+        let mut v: u64 = 0;
+        for (i, &b) in bytes.iter().enumerate().take(8) {
+            v |= (b as u64) << (8 * i);
+        }
+        if size < 8 {
+            if signed && bytes[size - 1] & 0x80 != 0 {
+                v |= u64::MAX << (8 * size);
+            }
+        } else if size > 8 {
+            let ext = if signed && (v as i64) < 0 { 0xff } else { 0 };
+            if bytes[8..].iter().any(|&b| b != ext) {
+                return Err(format!(
+                    "{}-byte integer does not fit into Lua Integer",
+                    size
+                ));
+            }
+        }
+        Ok(v as i64)
+    }
+}
+
+fn pack(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let fmt = check_bytes(lua, &args, 0, "pack")?;
+    let mut format = PackFormat::new(&fmt);
+    let mut out = vec![];
+    let mut arg_index = 0;
+    while let Some((option, size, align)) = format.next().map_err(|e| lua.error(e))? {
+        let padding = format.padding(out.len(), align).map_err(|e| lua.error(e))?;
+        out.resize(out.len() + padding, 0);
+        match option {
+            PackOption::Int { size, signed } => {
+                arg_index += 1;
+                let v = check_int(lua, &args, arg_index, "pack")?;
+                if size < 8 {
+                    let bits = 8 * size as u32;
+                    let fits = if signed {
+                        let limit = 1i64 << (bits - 1);
+                        -limit <= v && v < limit
+                    } else {
+                        (v as u64) < 1u64 << bits
+                    };
+                    if !fits {
+                        return Err(bad_arg(lua, arg_index, "pack", "integer overflow"));
+                    }
+                }
+                format.write_int(&mut out, v, size);
+            }
+            PackOption::Float | PackOption::Double => {
+                arg_index += 1;
+                let v = check_float(lua, &args, arg_index, "pack")?;
+                let mut bytes = if size == 4 {
+                    (v as f32).to_le_bytes().to_vec()
+                } else {
+                    v.to_le_bytes().to_vec()
+                };
+                if !format.little {
+                    bytes.reverse();
+                }
+                out.extend(bytes);
+            }
+            PackOption::LenString(len_size) => {
+                arg_index += 1;
+                let s = check_bytes(lua, &args, arg_index, "pack")?;
+                if len_size < 8 && (s.len() as u64) >= 1u64 << (8 * len_size) {
+                    return Err(bad_arg(
+                        lua,
+                        arg_index,
+                        "pack",
+                        "string length does not fit in given size",
+                    ));
+                }
+                format.write_int(&mut out, s.len() as i64, len_size);
+                out.extend_from_slice(&s);
+            }
+            PackOption::Fixed(size) => {
+                arg_index += 1;
+                let s = check_bytes(lua, &args, arg_index, "pack")?;
+                if s.len() > size {
+                    return Err(bad_arg(
+                        lua,
+                        arg_index,
+                        "pack",
+                        "string longer than given size",
+                    ));
+                }
+                out.extend_from_slice(&s);
+                out.resize(out.len() + size - s.len(), 0);
+            }
+            PackOption::Zstr => {
+                arg_index += 1;
+                let s = check_bytes(lua, &args, arg_index, "pack")?;
+                if s.contains(&0) {
+                    return Err(bad_arg(lua, arg_index, "pack", "string contains zeros"));
+                }
+                out.extend_from_slice(&s);
+                out.push(0);
+            }
+            PackOption::Padding => out.push(0),
+            PackOption::Align | PackOption::Nop => {}
+        }
+    }
+    Ok(vec![Value::from(out)])
+}
+
+fn packsize(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let fmt = check_bytes(lua, &args, 0, "packsize")?;
+    let mut format = PackFormat::new(&fmt);
+    let mut total = 0;
+    while let Some((option, size, align)) = format.next().map_err(|e| lua.error(e))? {
+        if matches!(option, PackOption::LenString(_) | PackOption::Zstr) {
+            return Err(bad_arg(lua, 0, "packsize", "variable-length format"));
+        }
+        total += format.padding(total, align).map_err(|e| lua.error(e))?;
+        if !matches!(option, PackOption::Align | PackOption::Nop) {
+            total += size;
+        }
+    }
+    Ok(vec![Value::Int(total as i64)])
+}
+
+fn unpack(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let fmt = check_bytes(lua, &args, 0, "unpack")?;
+    let data = check_bytes(lua, &args, 1, "unpack")?;
+    let init = relative_position(opt_int(lua, &args, 2, "unpack", 1)?, data.len());
+    if init < 1 || init as usize > data.len() + 1 {
+        return Err(bad_arg(lua, 2, "unpack", "initial position out of string"));
+    }
+    let mut pos = init as usize - 1;
+    let mut format = PackFormat::new(&fmt);
+    let mut results = vec![];
+    let too_short = |lua: &Lua| bad_arg(lua, 1, "unpack", "data string too short");
+    while let Some((option, size, align)) = format.next().map_err(|e| lua.error(e))? {
+        pos += format.padding(pos, align).map_err(|e| lua.error(e))?;
+        match option {
+            PackOption::Int { size, signed } => {
+                let bytes = data.get(pos..pos + size).ok_or_else(|| too_short(lua))?;
+                let v = format.read_int(bytes, signed).map_err(|e| lua.error(e))?;
+                results.push(Value::Int(v));
+                pos += size;
+            }
+            PackOption::Float | PackOption::Double => {
+                let mut bytes = data
+                    .get(pos..pos + size)
+                    .ok_or_else(|| too_short(lua))?
+                    .to_vec();
+                if !format.little {
+                    bytes.reverse();
+                }
+                let v = if size == 4 {
+                    f32::from_le_bytes(bytes[..].try_into().unwrap()) as f64
+                } else {
+                    f64::from_le_bytes(bytes[..].try_into().unwrap())
+                };
+                results.push(Value::Float(v));
+                pos += size;
+            }
+            PackOption::LenString(len_size) => {
+                let bytes = data
+                    .get(pos..pos + len_size)
+                    .ok_or_else(|| too_short(lua))?;
+                let len = format.read_int(bytes, false).map_err(|e| lua.error(e))? as usize;
+                pos += len_size;
+                let s = data.get(pos..pos + len).ok_or_else(|| too_short(lua))?;
+                results.push(Value::from(s));
+                pos += len;
+            }
+            PackOption::Fixed(size) => {
+                let s = data.get(pos..pos + size).ok_or_else(|| too_short(lua))?;
+                results.push(Value::from(s));
+                pos += size;
+            }
+            PackOption::Zstr => {
+                let len = data[pos..]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| bad_arg(lua, 1, "unpack", "unfinished string for format 'z'"))?;
+                results.push(Value::from(&data[pos..pos + len]));
+                pos += len + 1;
+            }
+            PackOption::Padding => {
+                data.get(pos..pos + 1).ok_or_else(|| too_short(lua))?;
+                pos += 1;
+            }
+            PackOption::Align | PackOption::Nop => {}
+        }
+    }
+    results.push(Value::Int(pos as i64 + 1));
+    Ok(results)
+}
+
+/// Decodes the UTF-8 sequence at the start of `s`, with its length
+fn decode_utf8(s: &[u8]) -> Option<(u32, usize)> {
+    let first = *s.first()?;
+    let (len, init) = match first {
+        0x00..=0x7f => return Some((first as u32, 1)),
+        0xc0..=0xdf => (2, (first & 0x1f) as u32),
+        0xe0..=0xef => (3, (first & 0x0f) as u32),
+        0xf0..=0xf7 => (4, (first & 0x07) as u32),
+        0xf8..=0xfb => (5, (first & 0x03) as u32),
+        0xfc..=0xfd => (6, (first & 0x01) as u32),
+        _ => return None,
+    };
+    let mut c = init;
+    for i in 1..len {
+        let b = *s.get(i)?;
+        if b & 0xc0 != 0x80 {
+            return None;
+        }
+        c = (c << 6) | (b & 0x3f) as u32;
+    }
+    // Overlong encodings are invalid
+    let min = [0, 0, 0x80, 0x800, 0x10000, 0x20_0000, 0x400_0000][len];
+    if c < min {
+        return None;
+    }
+    Some((c, len))
+}
+
+fn utf8_char(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let mut s = vec![];
+    for i in 0..args.len() {
+        let c = check_int(lua, &args, i, "char")?;
+        if !(0..=0x7fff_ffff).contains(&c) {
+            return Err(bad_arg(lua, i, "char", "value out of range"));
+        }
+        encode_utf8(c as u32, &mut s);
+    }
+    Ok(vec![Value::from(s)])
+}
+
+fn utf8_codepoint(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "codepoint")?;
+    let i = relative_position(opt_int(lua, &args, 1, "codepoint", 1)?, s.len());
+    let j = relative_position(opt_int(lua, &args, 2, "codepoint", i)?, s.len());
+    if i < 1 {
+        return Err(bad_arg(lua, 1, "codepoint", "out of range"));
+    }
+    if j > s.len() as i64 {
+        return Err(bad_arg(lua, 2, "codepoint", "out of range"));
+    }
+    let mut results = vec![];
+    let mut pos = i as usize - 1;
+    while (pos as i64) < j {
+        let (c, len) = decode_utf8(&s[pos..]).ok_or_else(|| lua.error("invalid UTF-8 code"))?;
+        results.push(Value::Int(c as i64));
+        pos += len;
+    }
+    Ok(results)
+}
+
+fn utf8_codes(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    check_bytes(lua, &args, 0, "codes")?;
+    let iter = Function::native(|lua, args| {
+        let s = check_bytes(lua, &args, 0, "for iterator")?;
+        // The 0-based position of the previous character, then of the next one
+        let previous = check_int(lua, &args, 1, "for iterator")? - 1;
+        let mut pos = previous.max(0) as usize;
+        if previous >= 0 && pos < s.len() {
+            pos += 1;
+            while pos < s.len() && s[pos] & 0xc0 == 0x80 {
+                pos += 1;
+            }
+        }
+        if pos >= s.len() {
+            return Ok(vec![Value::Nil]);
+        }
+        match decode_utf8(&s[pos..]) {
+            Some((c, len)) if !matches!(s.get(pos + len), Some(b) if b & 0xc0 == 0x80) => {
+                Ok(vec![Value::Int(pos as i64 + 1), Value::Int(c as i64)])
+            }
+            _ => Err(lua.error("invalid UTF-8 code")),
+        }
+    });
+    Ok(vec![Value::Function(iter), arg(&args, 0), Value::Int(0)])
+}
+
+fn utf8_len(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let s = check_bytes(lua, &args, 0, "len")?;
+    let i = relative_position(opt_int(lua, &args, 1, "len", 1)?, s.len());
+    let j = relative_position(opt_int(lua, &args, 2, "len", -1)?, s.len());
+    if i < 1 || i as usize > s.len() + 1 {
+        return Err(bad_arg(lua, 1, "len", "initial position out of string"));
+    }
+    if j > s.len() as i64 {
+        return Err(bad_arg(lua, 2, "len", "final position out of string"));
+    }
+    let mut pos = i as usize - 1;
+    let mut n = 0;
+    while (pos as i64) < j {
+        match decode_utf8(&s[pos..]) {
+            Some((_, len)) => pos += len,
+            None => return Ok(vec![Value::Nil, Value::Int(pos as i64 + 1)]),
+        }
+        n += 1;
+    }
+    Ok(vec![Value::Int(n)])
+}
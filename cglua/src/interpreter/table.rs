@@ -0,0 +1,214 @@
+//! The `table` library
+
+use super::base::{arg, bad_arg, check_int, check_table, opt_int, register};
+use super::{LResult, Lua, Table, Value};
+
+pub(crate) fn open(lua: &mut Lua) {
+    let table = Table::new();
+    register(
+        &table,
+        &[
+            ("concat", concat),
+            ("insert", insert),
+            ("pack", pack),
+            ("remove", remove),
+            ("sort", sort),
+            ("unpack", unpack),
+        ],
+    );
+    lua.globals.set("table", table);
+}
+
+/// The length of the table argument, with `__len`
+fn table_len(lua: &mut Lua, table: &Table) -> LResult<i64> {
+    match lua.len(Value::Table(table.clone()))? {
+        Value::Int(n) => Ok(n),
+        v => v
+            .as_int()
+            .ok_or_else(|| lua.error("object length is not an integer")),
+    }
+}
+
+fn get(lua: &mut Lua, table: &Table, i: i64) -> LResult<Value> {
+    lua.index(Value::Table(table.clone()), Value::Int(i))
+}
+
+fn set(lua: &mut Lua, table: &Table, i: i64, value: Value) -> LResult<()> {
+    lua.set_index(Value::Table(table.clone()), Value::Int(i), value)
+}
+
+fn concat(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let table = check_table(lua, &args, 0, "concat")?;
+    let sep = match arg(&args, 1) {
+        Value::Nil => None,
+        v => Some(
+            v.coerce_to_bytes()
+                .ok_or_else(|| bad_arg(lua, 1, "concat", "string expected"))?,
+        ),
+    };
+    let i = opt_int(lua, &args, 2, "concat", 1)?;
+    let j = match arg(&args, 3) {
+        Value::Nil => table_len(lua, &table)?,
+        _ => check_int(lua, &args, 3, "concat")?,
+    };
+    let mut out = vec![];
+    let mut k = i;
+    while k <= j {
+        let v = get(lua, &table, k)?;
+        match v.coerce_to_bytes() {
+            Some(s) => out.extend_from_slice(&s),
+            None => {
+                return Err(lua.error(format!(
+                    "invalid value (at index {}) in table for 'concat'",
+                    k
+                )))
+            }
+        }
+        if k < j {
+            if let Some(sep) = &sep {
+                out.extend_from_slice(sep);
+            }
+        }
+        // Stops before overflowing when `j` is the maximum integer
+        k = match k.checked_add(1) {
+            Some(k) => k,
+            None => break,
+        };
+    }
+    Ok(vec![Value::from(out)])
+}
+
+fn insert(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let table = check_table(lua, &args, 0, "insert")?;
+    let end = table_len(lua, &table)? + 1;
+    match args.len() {
+        2 => set(lua, &table, end, args[1].clone())?,
+        3 => {
+            let pos = check_int(lua, &args, 1, "insert")?;
+            if (pos as u64).wrapping_sub(1) >= end as u64 {
+                return Err(bad_arg(lua, 1, "insert", "position out of bounds"));
+            }
+            for i in (pos + 1..=end).rev() {
+                let v = get(lua, &table, i - 1)?;
+                set(lua, &table, i, v)?;
+            }
+            set(lua, &table, pos, args[2].clone())?;
+        }
+        _ => return Err(lua.error("wrong number of arguments to 'insert'")),
+    }
+    Ok(vec![])
+}
+
+fn remove(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let table = check_table(lua, &args, 0, "remove")?;
+    let size = table_len(lua, &table)?;
+    let pos = opt_int(lua, &args, 1, "remove", size)?;
+    if args.len() > 1 && size + 1 != pos && (pos as u64).wrapping_sub(1) > size as u64 {
+        return Err(bad_arg(lua, 1, "remove", "position out of bounds"));
+    }
+    let removed = get(lua, &table, pos)?;
+    let mut i = pos;
+    while i < size {
+        let v = get(lua, &table, i + 1)?;
+        set(lua, &table, i, v)?;
+        i += 1;
+    }
+    if i <= size || args.len() > 1 {
+        set(lua, &table, i, Value::Nil)?;
+    }
+    Ok(vec![removed])
+}
+
+fn pack(_: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let table = Table::new();
+    let n = args.len() as i64;
+    table.0.borrow_mut().set_array(args);
+    table.set("n", n);
+    Ok(vec![Value::Table(table)])
+}
+
+fn unpack(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let t = arg(&args, 0);
+    let i = opt_int(lua, &args, 1, "unpack", 1)?;
+    let j = match arg(&args, 2) {
+        Value::Nil => match lua.len(t.clone())? {
+            Value::Int(n) => n,
+            v => v
+                .as_int()
+                .ok_or_else(|| lua.error("object length is not an integer"))?,
+        },
+        _ => check_int(lua, &args, 2, "unpack")?,
+    };
+    if i > j {
+        return Ok(vec![]);
+    }
+    let n = (j as i128 - i as i128 + 1) as u128;
+    if n >= 1 << 24 {
+        return Err(lua.error("too many results to unpack"));
+    }
+    let mut results = Vec::with_capacity(n as usize);
+    for k in i..=j {
+        results.push(lua.index(t.clone(), Value::Int(k))?);
+    }
+    Ok(results)
+}
+
+fn sort(lua: &mut Lua, args: Vec<Value>) -> LResult<Vec<Value>> {
+    let table = check_table(lua, &args, 0, "sort")?;
+    let comparator = match arg(&args, 1) {
+        Value::Nil => None,
+        f @ Value::Function(_) => Some(f),
+        v => {
+            return Err(bad_arg(
+                lua,
+                1,
+                "sort",
+                format!("function expected, got {}", v.type_name()),
+            ))
+        }
+    };
+    let n = table_len(lua, &table)?;
+    if n >= i32::MAX as i64 {
+        return Err(bad_arg(lua, 0, "sort", "array too big"));
+    }
+    let mut values = Vec::with_capacity(n.max(0) as usize);
+    for i in 1..=n {
+        values.push(get(lua, &table, i)?);
+    }
+    let mut less = |lua: &mut Lua, a: &Value, b: &Value| match &comparator {
+        Some(f) => Ok(lua.call_value1(f, vec![a.clone(), b.clone()])?.truthy()),
+        None => lua.less_than(a, b, false),
+    };
+    let sorted = merge_sort(lua, values, &mut less)?;
+    for (i, v) in sorted.into_iter().enumerate() {
+        set(lua, &table, i as i64 + 1, v)?;
+    }
+    Ok(vec![])
+}
+
+/// A stable sort with a comparison which can raise errors
+fn merge_sort(
+    lua: &mut Lua,
+    mut values: Vec<Value>,
+    less: &mut impl FnMut(&mut Lua, &Value, &Value) -> LResult<bool>,
+) -> LResult<Vec<Value>> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let left = merge_sort(lua, values, less)?;
+    let right = merge_sort(lua, right, less)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        if less(lua, r, l)? {
+            merged.push(right.next().unwrap());
+        } else {
+            merged.push(left.next().unwrap());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
@@ -0,0 +1,596 @@
+//! Values manipulated by the interpreter
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::rc::Rc;
+
+use super::ast::Proto;
+use super::{Lua, Throw};
+use crate::lexer::{parse_decimal, parse_hex};
+use crate::Number;
+
+/// A lua value
+#[derive(Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    /// Lua strings are byte strings
+    Str(Rc<[u8]>),
+    Table(Table),
+    Function(Function),
+}
+
+// Deriving it needs `#[default]` on the variant, which the toolchain of the backend doesn't have
+#[allow(clippy::derivable_impls)]
+impl Default for Value {
+    fn default() -> Self {
+        Value::Nil
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            v => f.write_str(&String::from_utf8_lossy(&v.to_bytes())),
+        }
+    }
+}
+
+/// Raw equality, without `__eq` metamethods
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (&Value::Int(i), &Value::Float(f)) | (&Value::Float(f), &Value::Int(i)) => {
+                float_to_int(f) == Some(i)
+            }
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(&a.0, &b.0),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(&a.0, &b.0),
+            _ => false,
+        }
+    }
+}
+
+/// Table keys are normalized by `Value::into_key`, so floats with an integer value never need to
+/// hash like the equal integer.
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Nil => state.write_u8(0),
+            Value::Bool(b) => state.write_u8(*b as u8 + 1),
+            Value::Int(i) => state.write_i64(*i),
+            Value::Float(f) => state.write_u64(f.to_bits()),
+            Value::Str(s) => state.write(s),
+            Value::Table(t) => state.write_usize(Rc::as_ptr(&t.0) as *const u8 as usize),
+            Value::Function(f) => state.write_usize(Rc::as_ptr(&f.0) as *const u8 as usize),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.as_bytes().into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s.into_bytes().into())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(s: &[u8]) -> Self {
+        Value::Str(s.into())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(s: Vec<u8>) -> Self {
+        Value::Str(s.into())
+    }
+}
+
+impl From<Table> for Value {
+    fn from(t: Table) -> Self {
+        Value::Table(t)
+    }
+}
+
+impl From<Function> for Value {
+    fn from(f: Function) -> Self {
+        Value::Function(f)
+    }
+}
+
+impl Value {
+    /// The name of the type of the value, as returned by `type`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Int(_) | Value::Float(_) => "number",
+            Value::Str(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    /// Whether the value is neither `nil` nor `false`
+    pub fn truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// The integer value of a number or of a string convertible to an integer
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            Value::Float(f) => float_to_int(*f),
+            Value::Str(s) => str_to_number(s)?.as_int(),
+            _ => None,
+        }
+    }
+
+    /// The float value of a number or of a string convertible to a number
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            Value::Str(s) => str_to_number(s)?.as_float(),
+            _ => None,
+        }
+    }
+
+    /// The value converted to a number, following the coercions of arithmetic operators
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Int(_) | Value::Float(_) => Some(self.clone()),
+            Value::Str(s) => str_to_number(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match self {
+            Value::Table(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// The string of a string or a number, following the coercions of the concatenation operator
+    pub(crate) fn coerce_to_bytes(&self) -> Option<Rc<[u8]>> {
+        match self {
+            Value::Str(s) => Some(s.clone()),
+            Value::Int(_) | Value::Float(_) => Some(self.to_bytes().into()),
+            _ => None,
+        }
+    }
+
+    /// The representation of the value used by `tostring`, ignoring `__tostring`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Value::Nil => b"nil".to_vec(),
+            Value::Bool(b) => b.to_string().into_bytes(),
+            Value::Int(i) => i.to_string().into_bytes(),
+            Value::Float(f) => fmt_float(*f).into_bytes(),
+            Value::Str(s) => s.to_vec(),
+            Value::Table(t) => format!("table: {:p}", Rc::as_ptr(&t.0)).into_bytes(),
+            Value::Function(f) => match &*f.0 {
+                FunctionKind::Native(_) => {
+                    format!("builtin: {:p}", Rc::as_ptr(&f.0) as *const u8).into_bytes()
+                }
+                FunctionKind::Lua(_) => {
+                    format!("function: {:p}", Rc::as_ptr(&f.0) as *const u8).into_bytes()
+                }
+            },
+        }
+    }
+
+    /// The value used to index tables: floats with an integer value are converted to integers
+    pub(crate) fn into_key(self) -> Value {
+        match self {
+            Value::Float(f) => float_to_int(f).map_or(self, Value::Int),
+            v => v,
+        }
+    }
+}
+
+/// The integer equal to `f`, if there is one
+pub(crate) fn float_to_int(f: f64) -> Option<i64> {
+    // -2^63 is exactly representable, 2^63 is the first float above `i64::MAX`
+    if f.floor() == f && (-9_223_372_036_854_775_808.0..9_223_372_036_854_775_808.0).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Formats a float like lua does, with `%.14g` and a `.0` suffix for integral values
+pub(crate) fn fmt_float(f: f64) -> String {
+    let s = fmt_g(f, 14, false);
+    if s.bytes().all(|b| b.is_ascii_digit() || b == b'-') {
+        s + ".0"
+    } else {
+        s
+    }
+}
+
+/// Formats `f` like `printf("%.<precision>g")`, keeping trailing zeros with `alternate`
+pub(crate) fn fmt_g(f: f64, precision: usize, alternate: bool) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let precision = precision.max(1);
+    let exp_form = format!("{:.*e}", precision - 1, f);
+    let (mantissa, exp) = exp_form.split_at(exp_form.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    let strip = |s: String| {
+        if !alternate && s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s
+        }
+    };
+    if exp < -4 || exp >= precision as i32 {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", strip(mantissa.to_string()), sign, exp.abs())
+    } else {
+        strip(format!("{:.*}", (precision as i32 - 1 - exp) as usize, f))
+    }
+}
+
+/// Parses a lua numeral, surrounded by optional whitespace
+pub(crate) fn str_to_number(s: &[u8]) -> Option<Value> {
+    let s = std::str::from_utf8(s)
+        .ok()?
+        .trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.as_bytes().first()? {
+        b'-' => (true, &s[1..]),
+        b'+' => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        parse_hex(hex)?
+    } else {
+        parse_decimal(digits)?
+    };
+    Some(match (negative, value) {
+        (true, Number::Int(i)) => Value::Int(i.wrapping_neg()),
+        (true, Number::Float(f)) => Value::Float(-f),
+        (false, Number::Int(i)) => Value::Int(i),
+        (false, Number::Float(f)) => Value::Float(f),
+    })
+}
+
+/// A fast hasher for table keys, the one used by rustc
+#[derive(Default)]
+pub(crate) struct FxHasher(u64);
+
+impl FxHasher {
+    fn add(&mut self, word: u64) {
+        self.0 = (self.0.rotate_left(5) ^ word).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        for &b in chunks.remainder() {
+            self.add(b as u64);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.add(i as u64);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A lua table
+#[derive(Clone, Default)]
+pub struct Table(pub(crate) Rc<RefCell<TableData>>);
+
+/// The content of a table: the values of the keys `1..=array.len()` and the other entries in
+/// insertion order, so that `next` can resume from any key.
+#[derive(Default)]
+pub(crate) struct TableData {
+    array: Vec<Value>,
+    /// Removed entries keep their slot with a `nil` value until the next compaction, which only
+    /// happens when inserting keys, as lua forbids during a traversal
+    entries: Vec<(Value, Value)>,
+    index: HashMap<Value, usize, BuildHasherDefault<FxHasher>>,
+    removed: usize,
+    pub(crate) metatable: Option<Table>,
+}
+
+impl TableData {
+    pub(crate) fn get_int(&self, i: i64) -> Value {
+        if i >= 1 && (i as u64) <= self.array.len() as u64 {
+            self.array[i as usize - 1].clone()
+        } else {
+            self.get_entry(&Value::Int(i))
+        }
+    }
+
+    fn get_entry(&self, key: &Value) -> Value {
+        match self.index.get(key) {
+            Some(&i) => self.entries[i].1.clone(),
+            None => Value::Nil,
+        }
+    }
+
+    /// The value of a normalized key
+    pub(crate) fn get(&self, key: &Value) -> Value {
+        match key {
+            Value::Int(i) => self.get_int(*i),
+            Value::Nil => Value::Nil,
+            key => self.get_entry(key),
+        }
+    }
+
+    pub(crate) fn get_str(&self, key: &str) -> Value {
+        self.get_entry(&Value::from(key))
+    }
+
+    /// Sets the value of a normalized key, which must not be `nil` or NaN
+    pub(crate) fn set(&mut self, key: Value, value: Value) {
+        if let Value::Int(i) = key {
+            let len = self.array.len() as u64;
+            if i >= 1 && (i as u64) <= len + 1 {
+                let i = i as usize;
+                if i as u64 <= len {
+                    self.array[i - 1] = value;
+                    if i as u64 == len {
+                        while let Some(Value::Nil) = self.array.last() {
+                            self.array.pop();
+                        }
+                    }
+                } else if !value.is_nil() {
+                    self.array.push(value);
+                    self.migrate();
+                }
+                return;
+            }
+        }
+        match self.index.get(&key) {
+            Some(&i) => {
+                let slot = &mut self.entries[i].1;
+                match (slot.is_nil(), value.is_nil()) {
+                    (false, true) => self.removed += 1,
+                    (true, false) => self.removed -= 1,
+                    _ => {}
+                }
+                *slot = value;
+            }
+            None if value.is_nil() => {}
+            None => {
+                if self.removed > 8 && self.removed * 2 > self.entries.len() {
+                    self.compact();
+                }
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    /// Sets the keys `1..=values.len()`, `nil` values leaving holes in the sequence
+    pub(crate) fn set_array(&mut self, values: Vec<Value>) {
+        if self.array.is_empty() && self.index.is_empty() {
+            self.array = values;
+            while let Some(Value::Nil) = self.array.last() {
+                self.array.pop();
+            }
+        } else {
+            for (i, value) in values.into_iter().enumerate() {
+                self.set(Value::Int(i as i64 + 1), value);
+            }
+        }
+    }
+
+    /// Moves the entries following the array part to it
+    fn migrate(&mut self) {
+        if self.index.is_empty() {
+            return;
+        }
+        loop {
+            let key = Value::Int(self.array.len() as i64 + 1);
+            let i = match self.index.remove(&key) {
+                Some(i) => i,
+                None => return,
+            };
+            // The entry stays as a removed one until the next compaction
+            let value = std::mem::take(&mut self.entries[i].1);
+            if value.is_nil() {
+                return;
+            }
+            self.removed += 1;
+            self.array.push(value);
+        }
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, v)| !v.is_nil());
+        self.index.clear();
+        for (i, (k, _)) in self.entries.iter().enumerate() {
+            self.index.insert(k.clone(), i);
+        }
+        self.removed = 0;
+    }
+
+    /// A border of the table, as returned by the `#` operator
+    pub(crate) fn border(&self) -> i64 {
+        let mut n = self.array.len() as i64;
+        if n > 0 {
+            return n;
+        }
+        while !self.get_entry(&Value::Int(n + 1)).is_nil() {
+            n += 1;
+        }
+        n
+    }
+
+    /// The entry following `key` in the traversal order, `Err` if `key` isn't in the table
+    pub(crate) fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, ()> {
+        let mut array_start = 0;
+        let mut entries_start = 0;
+        match key {
+            Value::Nil => {}
+            &Value::Int(i) if i >= 1 && (i as u64) <= self.array.len() as u64 => {
+                array_start = i as usize;
+            }
+            // A key of the array part whose removal shrank it, the hash part comes next
+            &Value::Int(i) if i >= 1 && !self.index.contains_key(key) => {
+                array_start = self.array.len();
+            }
+            key => {
+                array_start = self.array.len();
+                entries_start = *self.index.get(key).ok_or(())? + 1;
+            }
+        }
+        for (i, v) in self.array.iter().enumerate().skip(array_start) {
+            if !v.is_nil() {
+                return Ok(Some((Value::Int(i as i64 + 1), v.clone())));
+            }
+        }
+        Ok(self.entries[entries_start..]
+            .iter()
+            .find(|(_, v)| !v.is_nil())
+            .cloned())
+    }
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of `key` without invoking metamethods
+    pub fn get(&self, key: impl Into<Value>) -> Value {
+        self.0.borrow().get(&key.into().into_key())
+    }
+
+    /// Sets `key` to `value` without invoking metamethods, panicking on `nil` and NaN keys
+    pub fn set(&self, key: impl Into<Value>, value: impl Into<Value>) {
+        let key = key.into().into_key();
+        assert!(
+            !matches!(key, Value::Nil) && !matches!(key, Value::Float(f) if f.is_nan()),
+            "invalid table key {:?}",
+            key
+        );
+        self.0.borrow_mut().set(key, value.into())
+    }
+
+    /// The length of the table, ignoring `__len`
+    pub fn len(&self) -> i64 {
+        self.0.borrow().border()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().next(&Value::Nil) == Ok(None)
+    }
+
+    pub fn metatable(&self) -> Option<Table> {
+        self.0.borrow().metatable.clone()
+    }
+
+    pub fn set_metatable(&self, metatable: Option<Table>) {
+        self.0.borrow_mut().metatable = metatable;
+    }
+
+    /// The field `event` of the metatable
+    pub(crate) fn metamethod(&self, event: &str) -> Value {
+        match &self.0.borrow().metatable {
+            Some(mt) => mt.0.borrow().get_str(event),
+            None => Value::Nil,
+        }
+    }
+}
+
+/// A native function callable from lua
+pub(crate) type NativeFn = dyn Fn(&mut Lua, Vec<Value>) -> Result<Vec<Value>, Throw>;
+
+pub(crate) enum FunctionKind {
+    Lua(Closure),
+    Native(Box<NativeFn>),
+}
+
+pub(crate) struct Closure {
+    pub(crate) proto: Rc<Proto>,
+    pub(crate) upvalues: Vec<Rc<RefCell<Value>>>,
+}
+
+/// A lua function or a native function
+#[derive(Clone)]
+pub struct Function(pub(crate) Rc<FunctionKind>);
+
+impl Function {
+    pub(crate) fn native(
+        f: impl Fn(&mut Lua, Vec<Value>) -> Result<Vec<Value>, Throw> + 'static,
+    ) -> Self {
+        Function(Rc::new(FunctionKind::Native(Box::new(f))))
+    }
+}
@@ -0,0 +1,593 @@
+//! Tokenization of lua source, shared by the parser and the interpreter
+
+use crate::{BinOp, Number};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    Name(String),
+    Str(Vec<u8>),
+    Int(i64),
+    Float(f64),
+
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+    Len,
+    BAnd,
+    Tilde,
+    BOr,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Le,
+    Ge,
+    Lt,
+    Gt,
+    Assign,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+    DoubleColon,
+    Semi,
+    Colon,
+    Comma,
+    Dot,
+    Concat,
+    Dots,
+    Eof,
+}
+
+fn keyword(name: &str) -> Option<Token> {
+    Some(match name {
+        "and" => Token::And,
+        "break" => Token::Break,
+        "do" => Token::Do,
+        "else" => Token::Else,
+        "elseif" => Token::Elseif,
+        "end" => Token::End,
+        "false" => Token::False,
+        "for" => Token::For,
+        "function" => Token::Function,
+        "goto" => Token::Goto,
+        "if" => Token::If,
+        "in" => Token::In,
+        "local" => Token::Local,
+        "nil" => Token::Nil,
+        "not" => Token::Not,
+        "or" => Token::Or,
+        "repeat" => Token::Repeat,
+        "return" => Token::Return,
+        "then" => Token::Then,
+        "true" => Token::True,
+        "until" => Token::Until,
+        "while" => Token::While,
+        _ => return None,
+    })
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Token::Name(name) => return write!(f, "'{}'", name),
+            Token::Str(s) => return write!(f, "'{}'", String::from_utf8_lossy(s)),
+            Token::Int(i) => return write!(f, "'{}'", i),
+            Token::Float(v) => return write!(f, "'{}'", v),
+            Token::Eof => return write!(f, "<eof>"),
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Add => "+",
+            Token::Sub => "-",
+            Token::Mul => "*",
+            Token::Div => "/",
+            Token::IDiv => "//",
+            Token::Mod => "%",
+            Token::Pow => "^",
+            Token::Len => "#",
+            Token::BAnd => "&",
+            Token::Tilde => "~",
+            Token::BOr => "|",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Le => "<=",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::DoubleColon => "::",
+            Token::Semi => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+        };
+        write!(f, "'{}'", s)
+    }
+}
+
+/// The priority of unary operators, binding tighter than all binary operators but `^`
+pub(crate) const UNARY_PRIORITY: u8 = 12;
+
+/// The left and right priorities of a binary operator, as in the reference implementation
+pub(crate) fn binary_op(token: &Token) -> Option<(BinOp, u8, u8)> {
+    Some(match token {
+        Token::Or => (BinOp::Or, 1, 1),
+        Token::And => (BinOp::And, 2, 2),
+        Token::Lt => (BinOp::Lt, 3, 3),
+        Token::Gt => (BinOp::Gt, 3, 3),
+        Token::Le => (BinOp::Le, 3, 3),
+        Token::Ge => (BinOp::Ge, 3, 3),
+        Token::Ne => (BinOp::Ne, 3, 3),
+        Token::Eq => (BinOp::Eq, 3, 3),
+        Token::BOr => (BinOp::BOr, 4, 4),
+        Token::Tilde => (BinOp::BXor, 5, 5),
+        Token::BAnd => (BinOp::BAnd, 6, 6),
+        Token::Shl => (BinOp::Shl, 7, 7),
+        Token::Shr => (BinOp::Shr, 7, 7),
+        Token::Concat => (BinOp::Concat, 9, 8),
+        Token::Add => (BinOp::Add, 10, 10),
+        Token::Sub => (BinOp::Sub, 10, 10),
+        Token::Mul => (BinOp::Mul, 11, 11),
+        Token::Div => (BinOp::Div, 11, 11),
+        Token::IDiv => (BinOp::IDiv, 11, 11),
+        Token::Mod => (BinOp::Mod, 11, 11),
+        Token::Pow => (BinOp::Pow, 14, 13),
+        _ => return None,
+    })
+}
+
+pub(crate) struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    pub(crate) line: u32,
+}
+
+impl<'a> Lexer<'a> {
+    pub(crate) fn new(src: &'a [u8]) -> Self {
+        let mut lexer = Lexer {
+            src,
+            pos: 0,
+            line: 1,
+        };
+        // A first line starting with `#` is skipped, for unix scripts
+        if src.starts_with(b"#") {
+            while !matches!(lexer.peek(), None | Some(b'\n')) {
+                lexer.pos += 1;
+            }
+        }
+        lexer
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.src.get(self.pos + offset).copied()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The next token and the line it starts on
+    pub(crate) fn next_token(&mut self) -> Result<(Token, u32), String> {
+        self.skip_whitespace()?;
+        let line = self.line;
+        let c = match self.bump() {
+            Some(c) => c,
+            None => return Ok((Token::Eof, line)),
+        };
+        let token = match c {
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                let start = self.pos - 1;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_') {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                keyword(name).unwrap_or_else(|| Token::Name(name.to_string()))
+            }
+            b'0'..=b'9' => self.number(self.pos - 1)?,
+            b'.' if matches!(self.peek(), Some(b'0'..=b'9')) => self.number(self.pos - 1)?,
+            b'"' | b'\'' => Token::Str(self.string(c)?),
+            b'[' if matches!(self.peek(), Some(b'[' | b'=')) => {
+                let start = self.pos;
+                match self.long_bracket()? {
+                    Some(s) => Token::Str(s),
+                    None => {
+                        self.pos = start;
+                        Token::LBracket
+                    }
+                }
+            }
+            b'+' => Token::Add,
+            b'-' => Token::Sub,
+            b'*' => Token::Mul,
+            b'/' if self.eat(b'/') => Token::IDiv,
+            b'/' => Token::Div,
+            b'%' => Token::Mod,
+            b'^' => Token::Pow,
+            b'#' => Token::Len,
+            b'&' => Token::BAnd,
+            b'~' if self.eat(b'=') => Token::Ne,
+            b'~' => Token::Tilde,
+            b'|' => Token::BOr,
+            b'<' if self.eat(b'<') => Token::Shl,
+            b'<' if self.eat(b'=') => Token::Le,
+            b'<' => Token::Lt,
+            b'>' if self.eat(b'>') => Token::Shr,
+            b'>' if self.eat(b'=') => Token::Ge,
+            b'>' => Token::Gt,
+            b'=' if self.eat(b'=') => Token::Eq,
+            b'=' => Token::Assign,
+            b'(' => Token::LParen,
+            b')' => Token::RParen,
+            b'{' => Token::LBrace,
+            b'}' => Token::RBrace,
+            b'[' => Token::LBracket,
+            b']' => Token::RBracket,
+            b':' if self.eat(b':') => Token::DoubleColon,
+            b':' => Token::Colon,
+            b';' => Token::Semi,
+            b',' => Token::Comma,
+            b'.' if self.eat(b'.') => {
+                if self.eat(b'.') {
+                    Token::Dots
+                } else {
+                    Token::Concat
+                }
+            }
+            b'.' => Token::Dot,
+            c => return Err(format!("unexpected symbol near '{}'", c as char)),
+        };
+        Ok((token, line))
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), String> {
+        loop {
+            match self.peek() {
+                Some(b' ' | b'\t' | b'\r' | b'\n' | 0x0b | 0x0c) => {
+                    self.bump();
+                }
+                Some(b'-') if self.peek_at(1) == Some(b'-') => {
+                    self.pos += 2;
+                    if self.peek() == Some(b'[') {
+                        let start = self.pos;
+                        self.pos += 1;
+                        if matches!(self.peek(), Some(b'[' | b'='))
+                            && self.long_bracket()?.is_some()
+                        {
+                            continue;
+                        }
+                        self.pos = start;
+                    }
+                    while !matches!(self.peek(), None | Some(b'\n')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Reads a long string after its first `[`, `None` if this is not an opening long bracket
+    fn long_bracket(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let mut level = 0;
+        while self.eat(b'=') {
+            level += 1;
+        }
+        if !self.eat(b'[') {
+            return Ok(None);
+        }
+        // A newline right after the opening bracket is skipped
+        if self.eat(b'\r') {
+            self.eat(b'\n');
+        } else if self.eat(b'\n') {
+            self.eat(b'\r');
+        }
+        let mut s = vec![];
+        loop {
+            match self.bump() {
+                None => return Err("unfinished long string".to_string()),
+                Some(b']') => {
+                    let mut n = 0;
+                    while self.peek_at(n) == Some(b'=') {
+                        n += 1;
+                    }
+                    if n == level && self.peek_at(n) == Some(b']') {
+                        self.pos += n + 1;
+                        return Ok(Some(s));
+                    }
+                    s.push(b']');
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self, start: usize) -> Result<Token, String> {
+        let is_hex = self.src[start] == b'0' && matches!(self.peek(), Some(b'x' | b'X'));
+        if is_hex {
+            self.pos += 1;
+        }
+        let (exp_lower, exp_upper) = if is_hex { (b'p', b'P') } else { (b'e', b'E') };
+        loop {
+            match self.peek() {
+                Some(c) if c == exp_lower || c == exp_upper => {
+                    self.pos += 1;
+                    if matches!(self.peek(), Some(b'+' | b'-')) {
+                        self.pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_alphanumeric() || c == b'.' => self.pos += 1,
+                _ => break,
+            }
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        let value = if is_hex {
+            parse_hex(&text[2..])
+        } else {
+            parse_decimal(text)
+        };
+        match value {
+            Some(Number::Int(i)) => Ok(Token::Int(i)),
+            Some(Number::Float(f)) => Ok(Token::Float(f)),
+            None => Err(format!("malformed number near '{}'", text)),
+        }
+    }
+
+    fn string(&mut self, quote: u8) -> Result<Vec<u8>, String> {
+        let mut s = vec![];
+        loop {
+            let c = match self.bump() {
+                None | Some(b'\n') => return Err("unfinished string".to_string()),
+                Some(c) => c,
+            };
+            if c == quote {
+                return Ok(s);
+            }
+            if c != b'\\' {
+                s.push(c);
+                continue;
+            }
+            let escape = self.bump().ok_or_else(|| "unfinished string".to_string())?;
+            match escape {
+                b'n' => s.push(b'\n'),
+                b't' => s.push(b'\t'),
+                b'r' => s.push(b'\r'),
+                b'a' => s.push(0x07),
+                b'b' => s.push(0x08),
+                b'f' => s.push(0x0c),
+                b'v' => s.push(0x0b),
+                b'\\' | b'"' | b'\'' | b'\n' => s.push(escape),
+                b'\r' => {
+                    self.eat(b'\n');
+                    s.push(b'\n');
+                }
+                b'x' => {
+                    let mut v = 0;
+                    for _ in 0..2 {
+                        let d = self
+                            .bump()
+                            .and_then(|c| (c as char).to_digit(16))
+                            .ok_or_else(|| "hexadecimal digit expected".to_string())?;
+                        v = v * 16 + d;
+                    }
+                    s.push(v as u8);
+                }
+                b'z' => {
+                    while matches!(self.peek(), Some(c) if c.is_ascii_whitespace()) {
+                        self.bump();
+                    }
+                }
+                b'0'..=b'9' => {
+                    let mut v = (escape - b'0') as u32;
+                    for _ in 0..2 {
+                        match self.peek() {
+                            Some(c) if c.is_ascii_digit() => {
+                                self.pos += 1;
+                                v = v * 10 + (c - b'0') as u32;
+                            }
+                            _ => break,
+                        }
+                    }
+                    if v > 255 {
+                        return Err("decimal escape too large".to_string());
+                    }
+                    s.push(v as u8);
+                }
+                b'u' => {
+                    if !self.eat(b'{') {
+                        return Err("missing '{' in \\u{xxxx}".to_string());
+                    }
+                    let mut v: u32 = 0;
+                    while let Some(d) = self.peek().and_then(|c| (c as char).to_digit(16)) {
+                        self.pos += 1;
+                        v = v
+                            .checked_mul(16)
+                            .and_then(|v| v.checked_add(d))
+                            .filter(|&v| v < 0x8000_0000)
+                            .ok_or_else(|| "UTF-8 value too large".to_string())?;
+                    }
+                    if !self.eat(b'}') {
+                        return Err("missing '}' in \\u{xxxx}".to_string());
+                    }
+                    encode_utf8(v, &mut s);
+                }
+                _ => return Err("invalid escape sequence".to_string()),
+            }
+        }
+    }
+}
+
+/// Appends the (extended, up to 31 bits) UTF-8 encoding of `c` to `out`
+pub(crate) fn encode_utf8(c: u32, out: &mut Vec<u8>) {
+    if c < 0x80 {
+        out.push(c as u8);
+        return;
+    }
+    let mut bytes = vec![];
+    let mut c = c;
+    // The number of bits that fit in the first byte with a sequence of `bytes.len() + 1` bytes
+    let mut first_bits = 0x3f;
+    while c > first_bits {
+        bytes.push(0x80 | (c & 0x3f) as u8);
+        c >>= 6;
+        first_bits >>= 1;
+    }
+    let prefix = !((first_bits << 1) | 1) as u8;
+    out.push(prefix | c as u8);
+    out.extend(bytes.iter().rev());
+}
+
+fn digit_value(b: u8) -> Option<u32> {
+    (b as char).to_digit(36)
+}
+
+/// Parses an unsigned decimal numeral, integers too large for an `i64` being read as floats
+pub(crate) fn parse_decimal(s: &str) -> Option<Number> {
+    if s.is_empty()
+        || !s
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-' || b == b'+')
+    {
+        return None;
+    }
+    if s.bytes().all(|b| b.is_ascii_digit()) {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Number::Int(i));
+        }
+    }
+    // Rust accepts `inf` and `nan` but lua doesn't
+    if !(s.as_bytes()[0].is_ascii_digit() || s.as_bytes()[0] == b'.' && s.len() > 1) {
+        return None;
+    }
+    s.parse::<f64>().ok().map(Number::Float)
+}
+
+/// Parses an unsigned hexadecimal numeral without its `0x` prefix. Integers wrap around, as in
+/// lua, and floats can have a fractional part and a binary exponent.
+pub(crate) fn parse_hex(s: &str) -> Option<Number> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    let mut mantissa = 0u64;
+    let mut float = 0.0f64;
+    let mut exp = 0i64;
+    let mut any_digit = false;
+    let mut is_float = false;
+    while i < bytes.len() {
+        match (bytes[i], digit_value(bytes[i])) {
+            (b'.', _) if !is_float => is_float = true,
+            (_, Some(d)) if d < 16 => {
+                any_digit = true;
+                mantissa = mantissa.wrapping_mul(16).wrapping_add(d as u64);
+                float = float * 16.0 + d as f64;
+                if is_float {
+                    exp -= 4;
+                }
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+    if !any_digit {
+        return None;
+    }
+    if i < bytes.len() && (bytes[i] == b'p' || bytes[i] == b'P') {
+        is_float = true;
+        let rest = &s[i + 1..];
+        let (sign, rest) = match rest.as_bytes().first()? {
+            b'-' => (-1, &rest[1..]),
+            b'+' => (1, &rest[1..]),
+            _ => (1, rest),
+        };
+        if rest.is_empty() || !rest.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        exp += sign * rest.parse::<i64>().unwrap_or(i64::MAX / 2);
+        i = s.len();
+    }
+    if i != bytes.len() {
+        return None;
+    }
+    if is_float {
+        Some(Number::Float(
+            float * 2f64.powi(exp.clamp(-2000, 2000) as i32),
+        ))
+    } else {
+        Some(Number::Int(mantissa as i64))
+    }
+}
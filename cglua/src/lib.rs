@@ -1,7 +1,28 @@
+mod bytes;
+mod dce;
+mod fold;
+#[cfg(feature = "interpreter")]
+pub mod interpreter;
+mod lexer;
+mod parser;
+
+pub use bytes::FORMAT_VERSION;
+pub use parser::Syntax;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Not currently generating a block")]
     NoCurrentBlock,
+    #[error("Not currently generating an if block")]
+    NotInIf,
+    #[error("Not a serialized context")]
+    NotSerialized,
+    #[error("Context serialized with version {0} of the format, expected version {1}")]
+    UnsupportedVersion(u64, u64),
+    #[error("Invalid serialized context: {0}")]
+    InvalidBytes(&'static str),
+    #[error("Invalid lua source, line {line}: {message}")]
+    Parse { line: u32, message: String },
 }
 
 pub struct Context {
@@ -14,35 +35,98 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 #[derive(Clone, Debug)]
 enum Block {
     Function(Function),
-    Raw { code: Vec<Stat> },
+    Raw {
+        code: Vec<Stat>,
+    },
+    If {
+        branches: Vec<(Expression, Vec<Stat>)>,
+        otherwise: Option<Vec<Stat>>,
+    },
+    While {
+        cond: Expression,
+        code: Vec<Stat>,
+    },
+    NumericFor {
+        var: String,
+        start: Expression,
+        limit: Expression,
+        step: Option<Expression>,
+        code: Vec<Stat>,
+    },
+    GenericFor {
+        names: Vec<String>,
+        values: Vec<Expression>,
+        code: Vec<Stat>,
+    },
+    /// The condition comes after the code, whose locals it can use
+    Repeat {
+        code: Vec<Stat>,
+        cond: Expression,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum FunctionTarget {
+    Local(String),
+    Place(Var),
 }
 
 #[derive(Clone, Debug)]
 struct Function {
-    name: Option<String>,
+    target: FunctionTarget,
+    /// The last parameter is `...` for vararg functions
     params: Vec<String>,
     code: Vec<Stat>,
 }
 
+fn render_code<W: std::fmt::Write>(w: &mut W, code: &[Stat], ident: Ident) -> std::fmt::Result {
+    for child in code {
+        child.render(w, ident)?;
+    }
+    Ok(())
+}
+
+fn render_list<W: std::fmt::Write>(
+    w: &mut W,
+    exprs: &[Expression],
+    ident: Ident,
+) -> std::fmt::Result {
+    if let Some(e) = exprs.first() {
+        e.render(w, ident)?;
+    }
+    if exprs.len() > 1 {
+        for e in &exprs[1..] {
+            write!(w, ",")?;
+            e.render(w, ident)?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the parameters and the code of a function, up to its `end`
+fn render_function_body<W: std::fmt::Write>(
+    w: &mut W,
+    params: &[String],
+    code: &[Stat],
+    ident: Ident,
+) -> std::fmt::Result {
+    writeln!(w, "{})", params.join(","))?;
+    render_code(w, code, ident.incr())?;
+    write!(w, "{}end", ident)
+}
+
 impl Function {
     fn render<W: std::fmt::Write>(&self, w: &mut W, ident: Ident) -> std::fmt::Result {
-        write!(w, "function {}(", self.name.as_deref().unwrap_or(""))?;
-        if let Some(p) = self.params.get(0) {
-            write!(w, "{}", p)?;
-        }
-        if self.params.len() > 1 {
-            for param in &self.params[1..] {
-                write!(w, ",{}", param)?;
+        match &self.target {
+            FunctionTarget::Local(name) => write!(w, "{}local function {}(", ident, name)?,
+            FunctionTarget::Place(place) => {
+                write!(w, "{}", ident)?;
+                place.render(w, ident)?;
+                write!(w, " = function(")?;
             }
         }
-        writeln!(w, ")")?;
-
-        let child_ident = ident.incr();
-        for child in &self.code {
-            child.render(w, child_ident)?;
-        }
-
-        writeln!(w, "{}end", ident)
+        render_function_body(w, &self.params, &self.code, ident)?;
+        writeln!(w)
     }
 }
 
@@ -51,35 +135,196 @@ impl Block {
         match self {
             Block::Function(Function { code, .. }) => code.push(node),
             Block::Raw { code } => code.push(node),
+            Block::If {
+                otherwise: Some(code),
+                ..
+            } => code.push(node),
+            Block::If { branches, .. } => branches
+                .last_mut()
+                .expect("if block without a branch")
+                .1
+                .push(node),
+            Block::While { code, .. }
+            | Block::NumericFor { code, .. }
+            | Block::GenericFor { code, .. }
+            | Block::Repeat { code, .. } => code.push(node),
         }
     }
 
     fn render<W: std::fmt::Write>(&self, w: &mut W, ident: Ident) -> std::fmt::Result {
         match self {
-            Block::Function(f) => {
-                write!(w, "{}local ", ident)?;
-                f.render(w, ident)
-            }
+            Block::Function(f) => f.render(w, ident),
             Block::Raw { code } => {
                 writeln!(w, "{}do", ident)?;
-                let child_ident = ident.incr();
-                for child in code {
-                    child.render(w, child_ident)?;
+                render_code(w, code, ident.incr())?;
+                writeln!(w, "{}end", ident)
+            }
+            Block::If {
+                branches,
+                otherwise,
+            } => {
+                for (i, (cond, code)) in branches.iter().enumerate() {
+                    if i == 0 {
+                        write!(w, "{}if ", ident)?;
+                    } else {
+                        write!(w, "{}elseif ", ident)?;
+                    }
+                    cond.render(w, ident)?;
+                    writeln!(w, " then")?;
+                    render_code(w, code, ident.incr())?;
+                }
+                if let Some(code) = otherwise {
+                    writeln!(w, "{}else", ident)?;
+                    render_code(w, code, ident.incr())?;
+                }
+                writeln!(w, "{}end", ident)
+            }
+            Block::While { cond, code } => {
+                write!(w, "{}while ", ident)?;
+                cond.render(w, ident)?;
+                writeln!(w, " do")?;
+                render_code(w, code, ident.incr())?;
+                writeln!(w, "{}end", ident)
+            }
+            Block::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                code,
+            } => {
+                write!(w, "{}for {} = ", ident, var)?;
+                start.render(w, ident)?;
+                write!(w, ",")?;
+                limit.render(w, ident)?;
+                if let Some(step) = step {
+                    write!(w, ",")?;
+                    step.render(w, ident)?;
                 }
+                writeln!(w, " do")?;
+                render_code(w, code, ident.incr())?;
+                writeln!(w, "{}end", ident)
+            }
+            Block::GenericFor {
+                names,
+                values,
+                code,
+            } => {
+                write!(w, "{}for {} in ", ident, names.join(","))?;
+                render_list(w, values, ident)?;
+                writeln!(w, " do")?;
+                render_code(w, code, ident.incr())?;
                 writeln!(w, "{}end", ident)
             }
+            Block::Repeat { code, cond } => {
+                writeln!(w, "{}repeat", ident)?;
+                render_code(w, code, ident.incr())?;
+                write!(w, "{}until ", ident)?;
+                cond.render(w, ident)?;
+                writeln!(w)
+            }
         }
     }
 }
 
-// In lua all numbers are f64, but we carry ints in order to do
-// constant propagation at a latter date
+// Lua 5.3 has an integer subtype of numbers, the constants of which are folded by
+// `Context::fold_constants`
 #[derive(Clone, Debug, Copy)]
 pub enum Number {
     Int(i64),
     Float(f64),
 }
 
+impl Number {
+    fn render<W: std::fmt::Write>(&self, w: &mut W) -> std::fmt::Result {
+        match *self {
+            // The literal `9223372036854775808` does not fit in an integer and would be read as a
+            // float before being negated.
+            Number::Int(i64::MIN) => write!(w, "(-9223372036854775807 - 1)"),
+            Number::Int(v) => write!(w, "{}", v),
+            Number::Float(v) if v.is_nan() => write!(w, "(0/0)"),
+            Number::Float(v) if v == f64::INFINITY => write!(w, "math.huge"),
+            Number::Float(v) if v == f64::NEG_INFINITY => write!(w, "(-math.huge)"),
+            // Debug formatting always keeps a `.` or an exponent, so the literal stays a float
+            Number::Float(v) => write!(w, "{:?}", v),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    /// Float division (`/`)
+    Div,
+    /// Floor division (`//`)
+    IDiv,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+}
+
+impl BinOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::IDiv => "//",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Concat => "..",
+            BinOp::Eq => "==",
+            BinOp::Ne => "~=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::BAnd => "&",
+            BinOp::BOr => "|",
+            BinOp::BXor => "~",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+    BNot,
+}
+
+impl UnOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+            UnOp::Not => "not ",
+            UnOp::Len => "#",
+            UnOp::BNot => "~",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum Var {
     Ident(String),
@@ -93,6 +338,8 @@ impl Var {
     fn render<W: std::fmt::Write>(&self, w: &mut W, ident: Ident) -> std::fmt::Result {
         match self {
             Var::Ident(i) => write!(w, "{}", i),
+            // A parenthesized table access is not a valid assignment target
+            Var::Expression(e @ Expression::TableAccess { .. }) => e.render(w, ident),
             Var::Expression(e) => {
                 write!(w, "(")?;
                 e.render(w, ident)?;
@@ -102,15 +349,16 @@ impl Var {
     }
 }
 
-pub struct Call {
-    function: Var,
-    parameters: Vec<Expression>,
-}
-
 #[derive(Clone, Debug)]
 enum Expression {
-    //Function(Function),
+    /// An anonymous function, the last parameter is `...` for vararg functions
+    Function {
+        params: Vec<String>,
+        code: Vec<Stat>,
+    },
+    Vararg,
     Number(Number),
+    Bool(bool),
     Ident(String),
     TableAccess {
         table: Box<Var>,
@@ -118,26 +366,98 @@ enum Expression {
     },
     Nil,
     Table(Vec<(Expression, Expression)>),
-    String(String),
+    List(Vec<Expression>),
+    String(Vec<u8>),
+    Call {
+        function: Box<Expression>,
+        parameters: Vec<Expression>,
+    },
+    BinOp {
+        op: BinOp,
+        lhs: Box<Expression>,
+        rhs: Box<Expression>,
+    },
+    UnOp {
+        op: UnOp,
+        value: Box<Expression>,
+    },
+    /// `object:name(parameters)`
+    Method {
+        object: Box<Expression>,
+        name: String,
+        parameters: Vec<Expression>,
+    },
+    /// Parentheses truncating the values of a call or of `...` to one
+    Paren(Box<Expression>),
 }
 
 #[derive(Clone, Debug)]
 pub struct Value(Expression);
 
+fn render_string<W: std::fmt::Write>(w: &mut W, value: &[u8]) -> std::fmt::Result {
+    write!(w, "\"")?;
+    for &b in value {
+        match b {
+            b'"' => write!(w, "\\\"")?,
+            b'\\' => write!(w, "\\\\")?,
+            b' '..=b'~' => write!(w, "{}", b as char)?,
+            // Always use three digits so that a following digit is not read as part of the escape
+            _ => write!(w, "\\{:03}", b)?,
+        }
+    }
+    write!(w, "\"")
+}
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Can `s` be used as a Lua identifier
+fn is_name(s: &[u8]) -> bool {
+    match s.first() {
+        Some(c) if c.is_ascii_alphabetic() || *c == b'_' => (),
+        _ => return false,
+    }
+    s.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        && !KEYWORDS.iter().any(|k| k.as_bytes() == s)
+}
+
+fn as_str(s: &[u8]) -> &str {
+    std::str::from_utf8(s).expect("names are ascii")
+}
+
 impl Expression {
     fn render<W: std::fmt::Write>(&self, w: &mut W, ident: Ident) -> std::fmt::Result {
         match self {
-            //Expression::Function(f) => f.render(w, ident),
-            Expression::Number(Number::Float(v)) => write!(w, "{}", v),
-            Expression::Number(Number::Int(v)) => write!(w, "{}", v),
+            Expression::Function { params, code } => {
+                write!(w, "function(")?;
+                render_function_body(w, params, code, ident)
+            }
+            Expression::Vararg => write!(w, "..."),
+            Expression::Number(n) => n.render(w),
+            Expression::Bool(b) => write!(w, "{}", b),
             Expression::Ident(name) => write!(w, "{}", name),
             Expression::Nil => write!(w, "nil"),
             Expression::TableAccess { table, key } => {
-                write!(w, "(")?;
-                table.render(w, ident)?;
-                write!(w, ")[")?;
-                key.render(w, ident)?;
-                write!(w, "]")
+                match &**table {
+                    Var::Ident(_) | Var::Expression(Expression::TableAccess { .. }) => {
+                        table.render(w, ident)?
+                    }
+                    _ => {
+                        write!(w, "(")?;
+                        table.render(w, ident)?;
+                        write!(w, ")")?;
+                    }
+                }
+                match &**key {
+                    Expression::String(k) if is_name(k) => write!(w, ".{}", as_str(k)),
+                    _ => {
+                        write!(w, "[")?;
+                        key.render(w, ident)?;
+                        write!(w, "]")
+                    }
+                }
             }
             Expression::Table(fields) => {
                 write!(w, "{{")?;
@@ -159,12 +479,91 @@ impl Expression {
                 }
                 write!(w, "}}")
             }
-            Expression::String(v) => write!(w, "\"{}\"", v),
+            Expression::List(values) => {
+                write!(w, "{{")?;
+                render_list(w, values, ident)?;
+                write!(w, "}}")
+            }
+            Expression::String(v) => render_string(w, v),
+            Expression::Call {
+                function,
+                parameters,
+            } => {
+                match &**function {
+                    Expression::Ident(_) | Expression::TableAccess { .. } => {
+                        function.render(w, ident)?
+                    }
+                    _ => {
+                        write!(w, "(")?;
+                        function.render(w, ident)?;
+                        write!(w, ")")?;
+                    }
+                }
+                write!(w, "(")?;
+                render_list(w, parameters, ident)?;
+                write!(w, ")")
+            }
+            Expression::BinOp { op, lhs, rhs } => {
+                write!(w, "(")?;
+                lhs.render(w, ident)?;
+                write!(w, ") {} (", op.as_str())?;
+                rhs.render(w, ident)?;
+                write!(w, ")")
+            }
+            Expression::UnOp { op, value } => {
+                write!(w, "{}(", op.as_str())?;
+                value.render(w, ident)?;
+                write!(w, ")")
+            }
+            Expression::Method {
+                object,
+                name,
+                parameters,
+            } => {
+                match &**object {
+                    Expression::Ident(_) | Expression::TableAccess { .. } => {
+                        object.render(w, ident)?
+                    }
+                    _ => {
+                        write!(w, "(")?;
+                        object.render(w, ident)?;
+                        write!(w, ")")?;
+                    }
+                }
+                write!(w, ":{}(", name)?;
+                render_list(w, parameters, ident)?;
+                write!(w, ")")
+            }
+            Expression::Paren(value) => {
+                write!(w, "(")?;
+                value.render(w, ident)?;
+                write!(w, ")")
+            }
         }
     }
 
     fn to_place(self) -> Place {
-        Place(Var::Expression(self))
+        match self {
+            Expression::Ident(name) => Place(Var::Ident(name)),
+            e => Place(Var::Expression(e)),
+        }
+    }
+
+    /// Can the expression have several values
+    fn is_multi(&self) -> bool {
+        matches!(
+            self,
+            Expression::Call { .. } | Expression::Method { .. } | Expression::Vararg
+        )
+    }
+
+    /// The expression giving only the first value of this one
+    fn truncated(self) -> Expression {
+        if self.is_multi() {
+            Expression::Paren(Box::new(self))
+        } else {
+            self
+        }
     }
 }
 
@@ -173,12 +572,47 @@ impl Value {
     pub fn to_place(self) -> Place {
         self.0.to_place()
     }
+
+    /// Returns the integer this value is a literal of, if any
+    pub fn as_int(&self) -> Option<i64> {
+        match self.0 {
+            Expression::Number(Number::Int(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Is this value free of side effects and cheap enough to be evaluated several times
+    pub fn is_trivial(&self) -> bool {
+        matches!(
+            self.0,
+            Expression::Number(_)
+                | Expression::Bool(_)
+                | Expression::Ident(_)
+                | Expression::Nil
+                | Expression::String(_)
+        )
+    }
 }
 
 #[derive(Clone, Debug)]
 enum Stat {
     Block(Block),
-    Assign { place: Var, value: Expression },
+    Local {
+        names: Vec<String>,
+        values: Vec<Expression>,
+    },
+    Assign {
+        places: Vec<Var>,
+        values: Vec<Expression>,
+    },
+    Call(Expression),
+    Return(Vec<Expression>),
+    Break,
+    Comment(String),
+    /// Code which was rendered before, for example by an earlier compilation
+    Rendered(String),
+    Goto(String),
+    Label(String),
 }
 
 #[derive(Clone, Copy)]
@@ -200,13 +634,50 @@ impl Stat {
     fn render<W: std::fmt::Write>(&self, w: &mut W, ident: Ident) -> std::fmt::Result {
         match self {
             Stat::Block(b) => b.render(w, ident),
-            Stat::Assign { place, value } => {
-                write!(w, "{}local ", ident)?;
-                place.render(w, ident)?;
+            Stat::Local { names, values } => {
+                write!(w, "{}local {}", ident, names.join(","))?;
+                if !values.is_empty() {
+                    write!(w, " = ")?;
+                    render_list(w, values, ident)?;
+                }
+                writeln!(w, ";")
+            }
+            Stat::Assign { places, values } => {
+                write!(w, "{}", ident)?;
+                if let Some(p) = places.first() {
+                    p.render(w, ident)?;
+                }
+                if places.len() > 1 {
+                    for place in &places[1..] {
+                        write!(w, ",")?;
+                        place.render(w, ident)?;
+                    }
+                }
                 write!(w, " = ")?;
-                value.render(w, ident)?;
+                render_list(w, values, ident)?;
+                writeln!(w, ";")
+            }
+            Stat::Call(call) => {
+                write!(w, "{}", ident)?;
+                call.render(w, ident)?;
                 writeln!(w, ";")
             }
+            Stat::Return(values) => {
+                write!(w, "{}return", ident)?;
+                if !values.is_empty() {
+                    write!(w, " ")?;
+                    render_list(w, values, ident)?;
+                }
+                writeln!(w, ";")
+            }
+            Stat::Break => writeln!(w, "{}break;", ident),
+            Stat::Comment(text) => {
+                // A line break would end the comment
+                writeln!(w, "{}-- {}", ident, text.replace(&['\n', '\r'][..], " "))
+            }
+            Stat::Rendered(code) => w.write_str(code),
+            Stat::Goto(label) => writeln!(w, "{}goto {}", ident, label),
+            Stat::Label(label) => writeln!(w, "{}::{}::", ident, label),
         }
     }
 }
@@ -218,6 +689,10 @@ impl ExprBuilder {
         Value(Expression::Nil)
     }
 
+    pub fn bool(self, value: bool) -> Value {
+        Value(Expression::Bool(value))
+    }
+
     pub fn int(self, value: i64) -> Value {
         Value(Expression::Number(Number::Int(value)))
     }
@@ -226,6 +701,10 @@ impl ExprBuilder {
         Value(Expression::Number(Number::Float(value)))
     }
 
+    pub fn ident(self, name: String) -> Value {
+        Value(Expression::Ident(name))
+    }
+
     pub fn get_place(self, place: Place) -> Value {
         match place.0 {
             Var::Ident(x) => Value(Expression::Ident(x)),
@@ -246,9 +725,41 @@ impl ExprBuilder {
         ))
     }
 
+    /// A table constructor with only positional fields: `{a, b, c}`
+    pub fn list<I: IntoIterator<Item = Value>>(self, values: I) -> Value {
+        Value(Expression::List(values.into_iter().map(|v| v.0).collect()))
+    }
+
     pub fn string(self, value: String) -> Value {
+        Value(Expression::String(value.into_bytes()))
+    }
+
+    /// A string literal containing arbitrary bytes
+    pub fn bytes(self, value: Vec<u8>) -> Value {
         Value(Expression::String(value))
     }
+
+    pub fn call<I: IntoIterator<Item = Value>>(self, function: Value, parameters: I) -> Value {
+        Value(Expression::Call {
+            function: Box::new(function.0),
+            parameters: parameters.into_iter().map(|v| v.0).collect(),
+        })
+    }
+
+    pub fn binop(self, op: BinOp, lhs: Value, rhs: Value) -> Value {
+        Value(Expression::BinOp {
+            op,
+            lhs: Box::new(lhs.0),
+            rhs: Box::new(rhs.0),
+        })
+    }
+
+    pub fn unop(self, op: UnOp, value: Value) -> Value {
+        Value(Expression::UnOp {
+            op,
+            value: Box::new(value.0),
+        })
+    }
 }
 
 pub struct StatBuilder<'ctx> {
@@ -257,12 +768,56 @@ pub struct StatBuilder<'ctx> {
 
 impl<'ctx> StatBuilder<'ctx> {
     pub fn assign(self, place: Place, value: Value) {
+        self.assign_multi(vec![place], vec![value])
+    }
+
+    pub fn assign_multi(self, places: Vec<Place>, values: Vec<Value>) {
         let stat = Stat::Assign {
-            place: place.0,
-            value: value.0,
+            places: places.into_iter().map(|p| p.0).collect(),
+            values: values.into_iter().map(|v| v.0).collect(),
+        };
+        self.ctx.add_stat(stat)
+    }
+
+    /// Declares local variables, optionally initializing them
+    pub fn local(self, names: Vec<String>, values: Vec<Value>) {
+        let stat = Stat::Local {
+            names,
+            values: values.into_iter().map(|v| v.0).collect(),
         };
         self.ctx.add_stat(stat)
     }
+
+    /// Calls a function, discarding its results
+    pub fn call<I: IntoIterator<Item = Value>>(self, function: Value, parameters: I) {
+        let call = ExprBuilder.call(function, parameters);
+        self.ctx.add_stat(Stat::Call(call.0))
+    }
+
+    pub fn ret(self, values: Vec<Value>) {
+        let stat = Stat::Return(values.into_iter().map(|v| v.0).collect());
+        self.ctx.add_stat(stat)
+    }
+
+    pub fn brk(self) {
+        self.ctx.add_stat(Stat::Break)
+    }
+
+    /// Adds a single line comment
+    pub fn comment(self, text: String) {
+        self.ctx.add_stat(Stat::Comment(text))
+    }
+
+    /// Adds lua code which was already rendered, it is written as is
+    pub fn rendered(self, code: String) {
+        self.ctx.add_stat(Stat::Rendered(code))
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Context {
@@ -282,7 +837,7 @@ impl Context {
 
     pub fn start_function(&mut self, name: String, params: Vec<String>) -> Place {
         self.current_blocks.push(Block::Function(Function {
-            name: Some(name.clone()),
+            target: FunctionTarget::Local(name.clone()),
             params,
             code: Vec::new(),
         }));
@@ -290,10 +845,58 @@ impl Context {
         Place(Var::Ident(name))
     }
 
+    /// Starts a function whose value is assigned to `place`, for example a table field
+    pub fn start_function_at(&mut self, place: Place, params: Vec<String>) {
+        self.current_blocks.push(Block::Function(Function {
+            target: FunctionTarget::Place(place.0),
+            params,
+            code: Vec::new(),
+        }));
+    }
+
     pub fn start_raw_block(&mut self) {
         self.current_blocks.push(Block::Raw { code: Vec::new() });
     }
 
+    pub fn start_if(&mut self, cond: Value) {
+        self.current_blocks.push(Block::If {
+            branches: vec![(cond.0, Vec::new())],
+            otherwise: None,
+        });
+    }
+
+    /// Starts an `elseif` branch of the if block currently being generated
+    pub fn start_else_if(&mut self, cond: Value) -> Result<()> {
+        match self.current_blocks.last_mut() {
+            Some(Block::If {
+                branches,
+                otherwise: None,
+            }) => {
+                branches.push((cond.0, Vec::new()));
+                Ok(())
+            }
+            _ => Err(Error::NotInIf),
+        }
+    }
+
+    /// Starts the `else` branch of the if block currently being generated
+    pub fn start_else(&mut self) -> Result<()> {
+        match self.current_blocks.last_mut() {
+            Some(Block::If { otherwise, .. }) if otherwise.is_none() => {
+                *otherwise = Some(Vec::new());
+                Ok(())
+            }
+            _ => Err(Error::NotInIf),
+        }
+    }
+
+    pub fn start_while(&mut self, cond: Value) {
+        self.current_blocks.push(Block::While {
+            cond: cond.0,
+            code: Vec::new(),
+        });
+    }
+
     pub fn finish_block(&mut self) -> Result<()> {
         let finished_block = match self.current_blocks.pop() {
             Some(b) => b,
@@ -316,6 +919,61 @@ impl Context {
         Place(Var::Ident(name))
     }
 
+    /// Appends all the statements of `other` at the current position
+    pub fn append(&mut self, other: Context) {
+        for stat in other.chunk {
+            self.add_stat(stat);
+        }
+    }
+
+    /// Serializes the statements of the context, without the blocks still being generated
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bytes::encode(&self.chunk)
+    }
+
+    /// Loads the statements serialized by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Context {
+            chunk: bytes::decode(bytes)?,
+            current_blocks: Vec::new(),
+        })
+    }
+
+    /// Folds the operations on literals, propagates the locals with a constant value which are
+    /// never assigned and prunes the branches on constant conditions, with the number semantics of
+    /// lua 5.3 and 5.4.
+    pub fn fold_constants(&mut self) {
+        fold::Folder.code(&mut self.chunk);
+    }
+
+    /// Removes the stores to locals which are never read and the unused locals whose values have
+    /// no side effect, and forwards the locals read once by the next statement to their use.
+    /// Table accesses and the operators other than integer divisions are assumed to have no side
+    /// effects, which holds for the generated code. The locals of the chunk itself are kept.
+    /// Returns the number of statements removed.
+    pub fn remove_dead_code(&mut self) -> usize {
+        let mut removed = 0;
+        loop {
+            let mut cleaner = dce::Cleaner::new();
+            cleaner.chunk(&mut self.chunk);
+            if cleaner.removed == 0 {
+                return removed;
+            }
+            removed += cleaner.removed;
+        }
+    }
+
+    /// Loads the statements of a lua chunk. Rendering a parsed context gives code doing the same,
+    /// without its comments.
+    pub fn parse(source: &[u8], syntax: Syntax) -> Result<Self> {
+        let chunk = parser::parse(source, syntax)
+            .map_err(|(line, message)| Error::Parse { line, message })?;
+        Ok(Context {
+            chunk,
+            current_blocks: Vec::new(),
+        })
+    }
+
     pub fn render<W: std::fmt::Write>(&self, w: &mut W) -> std::fmt::Result {
         for block in &self.chunk {
             block.render(w, Ident(0))?;
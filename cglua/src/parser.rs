@@ -0,0 +1,726 @@
+//! Parsing of lua source into the statements of a `Context`
+//!
+//! The syntax tree only keeps what the renderer needs: comments are dropped, and parentheses are
+//! only kept around calls and `...`, where they truncate the values to one. Literals which the
+//! renderer writes as expressions, like `(-9223372036854775807 - 1)`, `(0/0)` and `(-math.huge)`,
+//! are read back as literals, so that rendering a parsed context gives back the same code.
+
+use crate::lexer::{binary_op, Lexer, Token, UNARY_PRIORITY};
+use crate::{BinOp, Block, Expression, Function, FunctionTarget, Number, Stat, UnOp, Var};
+
+/// The syntax accepted by the parser, which depends on the dialect
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Syntax {
+    /// `goto` and labels, from lua 5.2. Without it, `goto` is a name.
+    pub goto: bool,
+    /// The bitwise operators and `//`, from lua 5.3
+    pub bitops: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax {
+            goto: true,
+            bitops: true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FuncState {
+    vararg: bool,
+    /// The number of loops around the statement being parsed
+    loops: usize,
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    syntax: Syntax,
+    token: Token,
+    line: u32,
+    lookahead: Option<(Token, u32)>,
+    funcs: Vec<FuncState>,
+}
+
+type PResult<T> = Result<T, String>;
+
+/// Parses the chunk `source`, returning the line of the error if it isn't valid
+pub(crate) fn parse(source: &[u8], syntax: Syntax) -> Result<Vec<Stat>, (u32, String)> {
+    let mut parser = Parser {
+        lexer: Lexer::new(source),
+        syntax,
+        token: Token::Eof,
+        line: 1,
+        lookahead: None,
+        funcs: vec![FuncState {
+            vararg: true,
+            loops: 0,
+        }],
+    };
+    let res = parser.next().and_then(|()| {
+        let code = parser.block()?;
+        parser.check(Token::Eof)?;
+        Ok(code)
+    });
+    res.map_err(|msg| (parser.line, msg))
+}
+
+impl<'a> Parser<'a> {
+    fn lex(&mut self) -> PResult<(Token, u32)> {
+        match self.lexer.next_token()? {
+            (Token::Goto, line) if !self.syntax.goto => Ok((Token::Name("goto".into()), line)),
+            next => Ok(next),
+        }
+    }
+
+    fn next(&mut self) -> PResult<()> {
+        let (token, line) = match self.lookahead.take() {
+            Some(next) => next,
+            None => self.lex()?,
+        };
+        self.token = token;
+        self.line = line;
+        Ok(())
+    }
+
+    fn peek(&mut self) -> PResult<&Token> {
+        if self.lookahead.is_none() {
+            self.lookahead = Some(self.lex()?);
+        }
+        Ok(&self.lookahead.as_ref().unwrap().0)
+    }
+
+    fn error<T>(&self, msg: &str) -> PResult<T> {
+        Err(format!("{} near {}", msg, self.token))
+    }
+
+    fn check(&self, token: Token) -> PResult<()> {
+        if self.token == token {
+            Ok(())
+        } else {
+            self.error(&format!("{} expected", token))
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> PResult<()> {
+        self.check(token)?;
+        self.next()
+    }
+
+    fn test_next(&mut self, token: Token) -> PResult<bool> {
+        if self.token == token {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Expects the token closing `opening`, which started on line `line`
+    fn expect_match(&mut self, token: Token, opening: Token, line: u32) -> PResult<()> {
+        if self.token == token {
+            return self.next();
+        }
+        if line == self.line {
+            self.check(token)
+        } else {
+            self.error(&format!(
+                "{} expected (to close {} at line {})",
+                token, opening, line
+            ))
+        }
+    }
+
+    fn name(&mut self) -> PResult<String> {
+        match std::mem::replace(&mut self.token, Token::Eof) {
+            Token::Name(name) => {
+                self.next()?;
+                Ok(name)
+            }
+            token => {
+                self.token = token;
+                self.error("<name> expected")
+            }
+        }
+    }
+
+    fn func(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.token {
+            Token::Else | Token::Elseif | Token::End | Token::Eof => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    fn block(&mut self) -> PResult<Vec<Stat>> {
+        let mut code = vec![];
+        while !self.block_follow(true) {
+            if self.token == Token::Return {
+                code.push(self.return_stat()?);
+                break;
+            }
+            if let Some(stat) = self.statement()? {
+                code.push(stat);
+            }
+        }
+        Ok(code)
+    }
+
+    /// The code of a loop
+    fn loop_block(&mut self) -> PResult<Vec<Stat>> {
+        self.func().loops += 1;
+        let code = self.block();
+        self.func().loops -= 1;
+        code
+    }
+
+    fn return_stat(&mut self) -> PResult<Stat> {
+        self.next()?;
+        let values = if self.block_follow(true) || self.token == Token::Semi {
+            vec![]
+        } else {
+            self.expr_list()?
+        };
+        self.test_next(Token::Semi)?;
+        if !self.block_follow(true) {
+            return self.error("<eof> expected");
+        }
+        Ok(Stat::Return(values))
+    }
+
+    fn statement(&mut self) -> PResult<Option<Stat>> {
+        let line = self.line;
+        let stat = match self.token {
+            Token::Semi => {
+                self.next()?;
+                return Ok(None);
+            }
+            Token::If => Stat::Block(self.if_stat(line)?),
+            Token::While => {
+                self.next()?;
+                let cond = self.single_expr()?;
+                self.expect(Token::Do)?;
+                let code = self.loop_block()?;
+                self.expect_match(Token::End, Token::While, line)?;
+                Stat::Block(Block::While { cond, code })
+            }
+            Token::Do => {
+                self.next()?;
+                let code = self.block()?;
+                self.expect_match(Token::End, Token::Do, line)?;
+                Stat::Block(Block::Raw { code })
+            }
+            Token::For => Stat::Block(self.for_stat(line)?),
+            Token::Repeat => {
+                self.next()?;
+                let code = self.loop_block()?;
+                self.expect_match(Token::Until, Token::Repeat, line)?;
+                let cond = self.single_expr()?;
+                Stat::Block(Block::Repeat { code, cond })
+            }
+            Token::Function => Stat::Block(self.function_stat(line)?),
+            Token::Local => {
+                self.next()?;
+                if self.test_next(Token::Function)? {
+                    let name = self.name()?;
+                    let (params, code) = self.function_body(false, line)?;
+                    Stat::Block(Block::Function(Function {
+                        target: FunctionTarget::Local(name),
+                        params,
+                        code,
+                    }))
+                } else {
+                    self.local_stat()?
+                }
+            }
+            Token::DoubleColon if self.syntax.goto => {
+                self.next()?;
+                let name = self.name()?;
+                self.expect(Token::DoubleColon)?;
+                Stat::Label(name)
+            }
+            Token::Break => {
+                self.next()?;
+                if self.func().loops == 0 {
+                    return Err("break outside a loop".to_string());
+                }
+                Stat::Break
+            }
+            Token::Goto => {
+                self.next()?;
+                Stat::Goto(self.name()?)
+            }
+            _ => self.expr_stat()?,
+        };
+        Ok(Some(stat))
+    }
+
+    fn if_stat(&mut self, line: u32) -> PResult<Block> {
+        let mut branches = vec![];
+        let mut otherwise = None;
+        loop {
+            // `if` or `elseif`
+            self.next()?;
+            let cond = self.single_expr()?;
+            self.expect(Token::Then)?;
+            branches.push((cond, self.block()?));
+            match self.token {
+                Token::Elseif => {}
+                Token::Else => {
+                    self.next()?;
+                    otherwise = Some(self.block()?);
+                    self.expect_match(Token::End, Token::If, line)?;
+                    break;
+                }
+                _ => {
+                    self.expect_match(Token::End, Token::If, line)?;
+                    break;
+                }
+            }
+        }
+        Ok(Block::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    fn for_stat(&mut self, line: u32) -> PResult<Block> {
+        self.next()?;
+        let first = self.name()?;
+        let block = match self.token {
+            Token::Assign => {
+                self.next()?;
+                let start = self.single_expr()?;
+                self.expect(Token::Comma)?;
+                let limit = self.single_expr()?;
+                let step = if self.test_next(Token::Comma)? {
+                    Some(self.single_expr()?)
+                } else {
+                    None
+                };
+                self.expect(Token::Do)?;
+                Block::NumericFor {
+                    var: first,
+                    start,
+                    limit,
+                    step,
+                    code: self.loop_block()?,
+                }
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.test_next(Token::Comma)? {
+                    names.push(self.name()?);
+                }
+                self.expect(Token::In)?;
+                let values = self.expr_list()?;
+                self.expect(Token::Do)?;
+                Block::GenericFor {
+                    names,
+                    values,
+                    code: self.loop_block()?,
+                }
+            }
+            _ => return self.error("'=' or 'in' expected"),
+        };
+        self.expect_match(Token::End, Token::For, line)?;
+        Ok(block)
+    }
+
+    fn function_stat(&mut self, line: u32) -> PResult<Block> {
+        self.next()?;
+        let mut target = Var::Ident(self.name()?);
+        let mut method = false;
+        // A method name ends the name of the function
+        while !method && matches!(self.token, Token::Dot | Token::Colon) {
+            method = self.token == Token::Colon;
+            self.next()?;
+            let key = Expression::String(self.name()?.into_bytes());
+            target = Var::Expression(Expression::TableAccess {
+                table: Box::new(target),
+                key: Box::new(key),
+            });
+        }
+        let (params, code) = self.function_body(method, line)?;
+        Ok(Block::Function(Function {
+            target: FunctionTarget::Place(target),
+            params,
+            code,
+        }))
+    }
+
+    fn local_stat(&mut self) -> PResult<Stat> {
+        let mut names = vec![];
+        loop {
+            names.push(self.name()?);
+            if self.token == Token::Lt {
+                return self.error("attributes are not supported");
+            }
+            if !self.test_next(Token::Comma)? {
+                break;
+            }
+        }
+        let values = if self.test_next(Token::Assign)? {
+            self.expr_list()?
+        } else {
+            vec![]
+        };
+        Ok(Stat::Local { names, values })
+    }
+
+    fn expr_stat(&mut self) -> PResult<Stat> {
+        let first = self.suffixed_expr()?;
+        if self.token == Token::Assign || self.token == Token::Comma {
+            let mut places = vec![];
+            let mut target = first;
+            loop {
+                places.push(match target {
+                    Expression::Ident(name) => Var::Ident(name),
+                    access @ Expression::TableAccess { .. } => Var::Expression(access),
+                    _ => return self.error("syntax error"),
+                });
+                if !self.test_next(Token::Comma)? {
+                    break;
+                }
+                target = self.suffixed_expr()?;
+            }
+            self.expect(Token::Assign)?;
+            let mut values = self.expr_list()?;
+            // The form of functions defined at a place
+            if let ([_], [Expression::Function { .. }]) = (&places[..], &values[..]) {
+                if let (Some(place), Some(Expression::Function { params, code })) =
+                    (places.pop(), values.pop())
+                {
+                    return Ok(Stat::Block(Block::Function(Function {
+                        target: FunctionTarget::Place(place),
+                        params,
+                        code,
+                    })));
+                }
+            }
+            Ok(Stat::Assign { places, values })
+        } else if matches!(first, Expression::Call { .. } | Expression::Method { .. }) {
+            Ok(Stat::Call(first))
+        } else {
+            self.error("syntax error")
+        }
+    }
+
+    /// Parses the parameters and the code of a function, after its name
+    fn function_body(&mut self, method: bool, line: u32) -> PResult<(Vec<String>, Vec<Stat>)> {
+        let mut params = vec![];
+        if method {
+            params.push("self".to_string());
+        }
+        let mut vararg = false;
+        self.expect(Token::LParen)?;
+        if self.token != Token::RParen {
+            loop {
+                if self.test_next(Token::Dots)? {
+                    params.push("...".to_string());
+                    vararg = true;
+                    break;
+                }
+                params.push(self.name()?);
+                if !self.test_next(Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RParen)?;
+        self.funcs.push(FuncState { vararg, loops: 0 });
+        let code = self.block();
+        self.funcs.pop();
+        let code = code?;
+        self.expect_match(Token::End, Token::Function, line)?;
+        Ok((params, code))
+    }
+
+    fn expr_list(&mut self) -> PResult<Vec<Expression>> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(Token::Comma)? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    fn expr(&mut self) -> PResult<Expression> {
+        self.sub_expr(0)
+    }
+
+    /// An expression whose first value only is used
+    fn single_expr(&mut self) -> PResult<Expression> {
+        Ok(single(self.expr()?))
+    }
+
+    fn sub_expr(&mut self, limit: u8) -> PResult<Expression> {
+        let unary = match self.token {
+            Token::Not => Some(UnOp::Not),
+            Token::Sub => Some(UnOp::Neg),
+            Token::Tilde if self.syntax.bitops => Some(UnOp::BNot),
+            Token::Len => Some(UnOp::Len),
+            _ => None,
+        };
+        let mut lhs = match unary {
+            Some(op) => {
+                self.next()?;
+                let value = single(self.sub_expr(UNARY_PRIORITY)?);
+                match (op, value) {
+                    // Negative literals, as the renderer writes them
+                    (UnOp::Neg, Expression::Number(Number::Int(i))) => {
+                        Expression::Number(Number::Int(i.wrapping_neg()))
+                    }
+                    (UnOp::Neg, Expression::Number(Number::Float(f))) => {
+                        Expression::Number(Number::Float(-f))
+                    }
+                    (UnOp::Neg, value) if is_math_huge(&value) => {
+                        Expression::Number(Number::Float(f64::NEG_INFINITY))
+                    }
+                    (op, value) => Expression::UnOp {
+                        op,
+                        value: Box::new(value),
+                    },
+                }
+            }
+            None => self.simple_expr()?,
+        };
+        while let Some((op, left, right)) = binary_op(&self.token) {
+            if left <= limit {
+                break;
+            }
+            let bitwise = matches!(
+                op,
+                BinOp::BAnd | BinOp::BOr | BinOp::BXor | BinOp::Shl | BinOp::Shr | BinOp::IDiv
+            );
+            if bitwise && !self.syntax.bitops {
+                return self.error("unexpected symbol");
+            }
+            self.next()?;
+            let rhs = single(self.sub_expr(right)?);
+            lhs = binop(op, single(lhs), rhs);
+        }
+        Ok(lhs)
+    }
+
+    fn simple_expr(&mut self) -> PResult<Expression> {
+        let e = match &self.token {
+            Token::Int(i) => Expression::Number(Number::Int(*i)),
+            Token::Float(f) => Expression::Number(Number::Float(*f)),
+            Token::Str(s) => Expression::String(s.clone()),
+            Token::Nil => Expression::Nil,
+            Token::True => Expression::Bool(true),
+            Token::False => Expression::Bool(false),
+            Token::Dots => {
+                if !self.func().vararg {
+                    return self.error("cannot use '...' outside a vararg function");
+                }
+                Expression::Vararg
+            }
+            Token::LBrace => return self.table(),
+            Token::Function => {
+                let line = self.line;
+                self.next()?;
+                let (params, code) = self.function_body(false, line)?;
+                return Ok(Expression::Function { params, code });
+            }
+            _ => {
+                // Parentheses around a name or a field only matter for assignments
+                return Ok(match self.suffixed_expr()? {
+                    Expression::Paren(e) if !e.is_multi() => *e,
+                    e => e,
+                });
+            }
+        };
+        self.next()?;
+        Ok(e)
+    }
+
+    fn primary_expr(&mut self) -> PResult<Expression> {
+        match self.token {
+            Token::Name(_) => Ok(Expression::Ident(self.name()?)),
+            Token::LParen => {
+                let line = self.line;
+                self.next()?;
+                let e = self.expr()?;
+                self.expect_match(Token::RParen, Token::LParen, line)?;
+                Ok(match e {
+                    // Keeps `(a) = 1` and `(a).b = 1` apart
+                    e @ (Expression::Ident(_) | Expression::TableAccess { .. }) => {
+                        Expression::Paren(Box::new(e))
+                    }
+                    e if e.is_multi() => Expression::Paren(Box::new(e)),
+                    e => e,
+                })
+            }
+            _ => self.error("unexpected symbol"),
+        }
+    }
+
+    fn suffixed_expr(&mut self) -> PResult<Expression> {
+        let mut e = self.primary_expr()?;
+        loop {
+            match self.token {
+                Token::Dot => {
+                    self.next()?;
+                    let key = Expression::String(self.name()?.into_bytes());
+                    e = index(single(e), key);
+                }
+                Token::LBracket => {
+                    self.next()?;
+                    let key = self.single_expr()?;
+                    self.expect(Token::RBracket)?;
+                    e = index(single(e), key);
+                }
+                Token::Colon => {
+                    self.next()?;
+                    let name = self.name()?;
+                    let parameters = self.call_args()?;
+                    e = Expression::Method {
+                        object: Box::new(single(e)),
+                        name,
+                        parameters,
+                    };
+                }
+                Token::LParen | Token::Str(_) | Token::LBrace => {
+                    let parameters = self.call_args()?;
+                    e = Expression::Call {
+                        function: Box::new(single(e)),
+                        parameters,
+                    };
+                }
+                _ => return Ok(e),
+            }
+        }
+    }
+
+    fn call_args(&mut self) -> PResult<Vec<Expression>> {
+        match &self.token {
+            Token::Str(s) => {
+                let arg = Expression::String(s.clone());
+                self.next()?;
+                Ok(vec![arg])
+            }
+            Token::LBrace => Ok(vec![self.table()?]),
+            Token::LParen => {
+                let line = self.line;
+                self.next()?;
+                let args = if self.token == Token::RParen {
+                    vec![]
+                } else {
+                    self.expr_list()?
+                };
+                self.expect_match(Token::RParen, Token::LParen, line)?;
+                Ok(args)
+            }
+            _ => self.error("function arguments expected"),
+        }
+    }
+
+    /// A table constructor is a list when all of its fields are positional and a table of keyed
+    /// fields otherwise, positional fields getting their index as key.
+    fn table(&mut self) -> PResult<Expression> {
+        let line = self.line;
+        self.expect(Token::LBrace)?;
+        let mut fields = vec![];
+        while self.token != Token::RBrace {
+            let keyed_by_name =
+                matches!(self.token, Token::Name(_)) && *self.peek()? == Token::Assign;
+            let field = match self.token {
+                Token::Name(_) if keyed_by_name => {
+                    let key = Expression::String(self.name()?.into_bytes());
+                    self.next()?;
+                    (Some(key), self.single_expr()?)
+                }
+                Token::LBracket => {
+                    self.next()?;
+                    let key = self.single_expr()?;
+                    self.expect(Token::RBracket)?;
+                    self.expect(Token::Assign)?;
+                    (Some(key), self.single_expr()?)
+                }
+                _ => (None, self.expr()?),
+            };
+            fields.push(field);
+            if !self.test_next(Token::Comma)? && !self.test_next(Token::Semi)? {
+                break;
+            }
+        }
+        self.expect_match(Token::RBrace, Token::LBrace, line)?;
+
+        if fields.iter().all(|(key, _)| key.is_none()) {
+            let last = fields.len().saturating_sub(1);
+            let values = fields.into_iter().enumerate().map(|(i, (_, value))| {
+                // Only the last field gets all the values of a call
+                if i == last {
+                    value
+                } else {
+                    single(value)
+                }
+            });
+            return Ok(Expression::List(values.collect()));
+        }
+        if let Some((None, value)) = fields.last() {
+            if value.is_multi() {
+                return Err(
+                    "a table with keys can't end with a field with multiple values".to_string(),
+                );
+            }
+        }
+        let mut position = 0;
+        let fields = fields.into_iter().map(|(key, value)| {
+            let key = key.unwrap_or_else(|| {
+                position += 1;
+                Expression::Number(Number::Int(position))
+            });
+            (key, single(value))
+        });
+        Ok(Expression::Table(fields.collect()))
+    }
+}
+
+/// Removes the parentheses of an expression whose first value only is used
+fn single(e: Expression) -> Expression {
+    match e {
+        Expression::Paren(e) => *e,
+        e => e,
+    }
+}
+
+fn index(table: Expression, key: Expression) -> Expression {
+    Expression::TableAccess {
+        table: Box::new(table.to_place().0),
+        key: Box::new(key),
+    }
+}
+
+/// `math.huge`, which the renderer writes for infinite literals. Only its negation is read back
+/// as a literal, the access itself renders the same and can be assigned.
+fn is_math_huge(e: &Expression) -> bool {
+    match e {
+        Expression::TableAccess { table, key } => {
+            matches!(&**table, Var::Ident(name) if name == "math")
+                && matches!(&**key, Expression::String(key) if key == b"huge")
+        }
+        _ => false,
+    }
+}
+
+/// Reads back the literals which the renderer writes as operations
+fn binop(op: BinOp, lhs: Expression, rhs: Expression) -> Expression {
+    use Expression::Number as N;
+    match (op, &lhs, &rhs) {
+        (BinOp::Sub, N(Number::Int(i)), N(Number::Int(1))) if *i == i64::MIN + 1 => {
+            N(Number::Int(i64::MIN))
+        }
+        (BinOp::Div, N(Number::Int(0)), N(Number::Int(0))) => N(Number::Float(f64::NAN)),
+        _ => Expression::BinOp {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+    }
+}
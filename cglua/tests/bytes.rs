@@ -0,0 +1,172 @@
+//! The binary encoding of contexts, `Context::to_bytes` and `Context::from_bytes`
+
+use cglua::{BinOp, Context, Error, ExprBuilder, Syntax, UnOp, FORMAT_VERSION};
+
+fn render(ctx: &Context) -> String {
+    let mut code = String::new();
+    ctx.render(&mut code).unwrap();
+    code
+}
+
+/// A context using every kind of statement and expression of the builder
+fn sample() -> Context {
+    let mut ctx = Context::new();
+    ctx.stat().comment("generated".to_string());
+    let f = ctx.start_function("f".to_string(), vec!["a".to_string(), "...".to_string()]);
+    ctx.stat().local(
+        vec!["t".to_string(), "s".to_string()],
+        vec![
+            ExprBuilder.table([
+                (
+                    ExprBuilder.string("k".to_string()),
+                    ExprBuilder.double(-0.5),
+                ),
+                (ExprBuilder.int(i64::MIN), ExprBuilder.bool(true)),
+            ]),
+            ExprBuilder.bytes(b"\0\xff\n\"".to_vec()),
+        ],
+    );
+    ctx.start_if(ExprBuilder.binop(
+        BinOp::And,
+        ExprBuilder.ident("a".to_string()),
+        ExprBuilder.unop(UnOp::Not, ExprBuilder.nil()),
+    ));
+    ctx.stat().assign_multi(
+        vec![
+            ExprBuilder.ident("a".to_string()).to_place(),
+            ExprBuilder
+                .table_access(
+                    ExprBuilder.ident("t".to_string()).to_place(),
+                    ExprBuilder.int(1),
+                )
+                .to_place(),
+        ],
+        vec![
+            ExprBuilder.binop(
+                BinOp::Shl,
+                ExprBuilder.ident("a".to_string()),
+                ExprBuilder.int(3),
+            ),
+            ExprBuilder.list([
+                ExprBuilder.double(f64::INFINITY),
+                ExprBuilder.double(1e-300),
+            ]),
+        ],
+    );
+    ctx.start_else_if(ExprBuilder.bool(false)).unwrap();
+    ctx.stat().rendered("goto done\n".to_string());
+    ctx.start_else().unwrap();
+    ctx.start_while(ExprBuilder.bool(true));
+    ctx.stat().brk();
+    ctx.finish_block().unwrap();
+    ctx.finish_block().unwrap();
+    ctx.start_raw_block();
+    ctx.stat().call(
+        ExprBuilder.ident("print".to_string()),
+        [ExprBuilder.unop(UnOp::Len, ExprBuilder.ident("s".to_string()))],
+    );
+    ctx.finish_block().unwrap();
+    ctx.stat().ret(vec![ExprBuilder.ident("a".to_string())]);
+    ctx.finish_block().unwrap();
+    ctx.stat()
+        .call(ExprBuilder.get_place(f), [ExprBuilder.int(1)]);
+    ctx
+}
+
+#[test]
+fn round_trip() {
+    let ctx = sample();
+    let bytes = ctx.to_bytes();
+    let decoded = Context::from_bytes(&bytes).unwrap();
+    assert_eq!(render(&decoded), render(&ctx));
+    assert_eq!(decoded.to_bytes(), bytes);
+}
+
+#[test]
+fn round_trip_parsed() {
+    let source = br##"
+local a, b = 1, 2.5
+for i = 1, 10, 2 do a = a + i end
+for k, v in pairs({x = 1, [2] = "y"}) do print(k, v) end
+repeat local x = a // 2 until x > 3 or a ~= b
+local function g(...) return select("#", ...), ... end
+t = {g(1, 2)}
+t.x:method("s", ~a, -b, a >> 1 | 2 & 3 ~ 4)
+::top::
+goto top
+"##;
+    let ctx = Context::parse(source, Syntax::default()).unwrap();
+    let decoded = Context::from_bytes(&ctx.to_bytes()).unwrap();
+    assert_eq!(render(&decoded), render(&ctx));
+}
+
+#[test]
+fn append_loaded() {
+    // Modules loaded for LTO are put together with `append`
+    let ctx = sample();
+    let mut merged = Context::from_bytes(&ctx.to_bytes()).unwrap();
+    merged.append(Context::from_bytes(&ctx.to_bytes()).unwrap());
+    assert_eq!(render(&merged), render(&ctx).repeat(2));
+}
+
+#[test]
+fn wrong_version() {
+    let mut bytes = sample().to_bytes();
+    let version = b"\0cglua".len();
+    assert_eq!(u64::from(bytes[version]), FORMAT_VERSION);
+    bytes[version] += 1;
+    match Context::from_bytes(&bytes) {
+        Err(Error::UnsupportedVersion(found, expected)) => {
+            assert_eq!(found, FORMAT_VERSION + 1);
+            assert_eq!(expected, FORMAT_VERSION);
+        }
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("loaded a context of another version"),
+    }
+}
+
+#[test]
+fn not_serialized() {
+    assert!(matches!(
+        Context::from_bytes(b"local a = 1"),
+        Err(Error::NotSerialized)
+    ));
+    assert!(matches!(
+        Context::from_bytes(b""),
+        Err(Error::NotSerialized)
+    ));
+}
+
+#[test]
+fn truncated() {
+    let bytes = sample().to_bytes();
+    let header = b"\0cglua".len() + 1;
+    for len in header..bytes.len() {
+        match Context::from_bytes(&bytes[..len]) {
+            Err(Error::InvalidBytes(_)) => {}
+            Err(err) => panic!("unexpected error at {}: {}", len, err),
+            Ok(_) => panic!("loaded the first {} of {} bytes", len, bytes.len()),
+        }
+    }
+}
+
+#[test]
+fn garbage() {
+    let header = sample().to_bytes()[..b"\0cglua".len() + 1].to_vec();
+    for garbage in [&[0xff; 12][..], &[1, 0xee, 7], &[0x80; 11]] {
+        let mut bytes = header.clone();
+        bytes.extend_from_slice(garbage);
+        assert!(
+            matches!(Context::from_bytes(&bytes), Err(Error::InvalidBytes(_))),
+            "loaded {:?}",
+            garbage
+        );
+    }
+
+    let mut trailing = sample().to_bytes();
+    trailing.push(0);
+    assert!(matches!(
+        Context::from_bytes(&trailing),
+        Err(Error::InvalidBytes("trailing bytes"))
+    ));
+}
@@ -0,0 +1,170 @@
+//! Dead code removal with `Context::remove_dead_code`
+//!
+//! The pass leaves the locals of the chunk alone, so the code is put in a function whose
+//! parameters `p` and `q` are locals no closure changes.
+
+use cglua::{Context, ExprBuilder, Syntax};
+
+fn parse(body: &str) -> Context {
+    let source = format!("local function test(p, q)\n{}\nend", body);
+    Context::parse(source.as_bytes(), Syntax::default()).unwrap()
+}
+
+fn render(ctx: &Context) -> String {
+    let mut code = String::new();
+    ctx.render(&mut code).unwrap();
+    code
+}
+
+/// Removes the dead code of `body`, returning it and the number of statements removed. The code
+/// is parsed again, which drops the parentheses truncating calls where one value is used anyway.
+fn clean(body: &str) -> (String, usize) {
+    let mut ctx = parse(body);
+    let removed = ctx.remove_dead_code();
+    let code = render(&ctx);
+    let ctx = Context::parse(code.as_bytes(), Syntax::default()).unwrap();
+    (render(&ctx), removed)
+}
+
+/// Checks that cleaning `body` gives `expected` and removes `removed` statements
+fn assert_clean(body: &str, expected: &str, removed: usize) {
+    let (code, count) = clean(body);
+    assert_eq!(code, render(&parse(expected)), "cleaning\n{}", body);
+    assert_eq!(count, removed, "cleaning\n{}", body);
+}
+
+/// Checks that the pass leaves `body` as it is
+fn assert_kept(body: &str) {
+    assert_clean(body, body, 0);
+}
+
+#[test]
+fn unused_locals_and_dead_stores() {
+    assert_clean("local a = p + 1 return q", "return q", 1);
+    assert_clean("local a = p a = q + 1 a = 2 return q", "return q", 3);
+    assert_clean(
+        "local a, b = p, q return b",
+        "local a, b = p, q return b",
+        0,
+    );
+    assert_clean(
+        "local a if p then a = 1 else a = 2 end return q",
+        "if p then else end return q",
+        3,
+    );
+    // The chunk keeps its locals, other modules can use them
+    let mut ctx = Context::parse(b"local a = 1", Syntax::default()).unwrap();
+    assert_eq!(ctx.remove_dead_code(), 0);
+}
+
+#[test]
+fn kept_calls() {
+    // The call of an unused local runs for its side effects
+    assert_clean("local a = f() return q", "f() return q", 0);
+    assert_clean("local a = p a = f() return q", "f() return q", 1);
+    assert_clean("local a = p:m() return q", "p:m() return q", 0);
+    assert_kept("local a, b = f(), g() return q");
+    assert_kept("local a = f() + 1 return q");
+    assert_kept("local a = p a = f() + 1 return q");
+}
+
+#[test]
+fn division_by_zero() {
+    // Integer `//` and `%` by zero raise an error
+    assert_kept("local a = p // q return q");
+    assert_kept("local a = p % (q + 1) return q");
+    assert_kept("local a = p a = p % 0 return q");
+    assert_clean(
+        "local a = p // 2 local b = p % -3.5 return q",
+        "return q",
+        2,
+    );
+    assert_clean("local a = p a = p // 2 return q", "return q", 2);
+    // `/` gives a float, never an error
+    assert_clean("local a = p / q return q", "return q", 1);
+}
+
+#[test]
+fn calls_before_reads_of_globals_and_tables() {
+    // Forwarded past local reads, truncated to the one value the local had
+    assert_clean("local a = f() return g(p, a)", "return g(p, (f()))", 1);
+    assert_clean(
+        "local a = p + 1 return x + t.y + a",
+        "return x + t.y + (p + 1)",
+        1,
+    );
+    // The call could change the global or the table read before the use
+    assert_kept("local a = f() return x + a");
+    assert_kept("local a = f() return p.y + a");
+    // A call in the statement would run before the value
+    assert_kept("local a = p + 1 return g() + a");
+}
+
+#[test]
+fn table_keys_before_table_reads() {
+    // The key is evaluated before the table is read
+    assert_clean("local a = f() return p[a]", "return p[(f())]", 1);
+    assert_clean("local a = f() p[a] = q", "p[(f())] = q", 1);
+    // The read of `p[q]` happens before the key `a`
+    assert_kept("local a = f() return p[q][a]");
+    assert_clean("local a = p + 1 return p[q][a]", "return p[q][p + 1]", 1);
+}
+
+#[test]
+fn and_or() {
+    assert_clean("local a = p + 1 return a and q", "return (p + 1) and q", 1);
+    // The right operand isn't always evaluated
+    assert_kept("local a = p + 1 return q and a");
+    assert_kept("local a = p + 1 return q or a");
+}
+
+#[test]
+fn multiple_assignment() {
+    // The values are evaluated before the assignments
+    assert_clean(
+        "local a = f() p, q = a, 1 return p",
+        "p, q = (f()), 1 return p",
+        1,
+    );
+    assert_clean(
+        "local a = p + 1 t[q], p = a, 2 return p",
+        "t[q], p = p + 1, 2 return p",
+        1,
+    );
+    // Only single stores are removed, the local stays declared for the others
+    assert_kept("local a = 1 a, p = 2, 3 return p");
+    assert_kept("local a, b = f() return b");
+}
+
+#[test]
+fn repeat_tail() {
+    // The condition of `repeat` reads the locals of its body
+    assert_kept("repeat local a = p + 1 until a > 3");
+    assert_kept("repeat local a = p a = q until a");
+    assert_clean(
+        "repeat local a = p + 1 local b = q until b",
+        "repeat local b = q until b",
+        1,
+    );
+}
+
+#[test]
+fn captured_locals() {
+    // Stores to locals read by closures are kept
+    assert_kept("local a = 1 local function h() return a end a = 2 return h()");
+    // The call can change a local captured by a closure, not the others
+    assert_kept("local x = p local function h() x = 2 end local a = h() return x + a");
+    assert_clean(
+        "local x = p local a = h() return x + a",
+        "return p + (h())",
+        2,
+    );
+    // Rendered code can use any local
+    let mut ctx = Context::new();
+    ctx.start_function("test".to_string(), vec![]);
+    ctx.stat()
+        .local(vec!["a".to_string()], vec![ExprBuilder.int(1)]);
+    ctx.stat().rendered("print(a)\n".to_string());
+    ctx.finish_block().unwrap();
+    assert_eq!(ctx.remove_dead_code(), 0);
+}
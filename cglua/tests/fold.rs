@@ -0,0 +1,189 @@
+//! Constant folding and propagation with `Context::fold_constants`
+
+use cglua::{Context, Syntax};
+
+/// Parses `source`, folds it and renders the result
+fn fold(source: &str) -> String {
+    let mut ctx = Context::parse(source.as_bytes(), Syntax::default()).unwrap();
+    ctx.fold_constants();
+    let mut code = String::new();
+    ctx.render(&mut code).unwrap();
+    code
+}
+
+#[test]
+fn wrapping() {
+    assert_eq!(
+        fold("return math.maxinteger, 9223372036854775807 + 1"),
+        "return math.maxinteger,(-9223372036854775807 - 1);\n"
+    );
+    assert_eq!(
+        fold("return -(-9223372036854775807 - 1), 4611686018427387904 * 4"),
+        "return (-9223372036854775807 - 1),0;\n"
+    );
+    assert_eq!(
+        fold("return 1 << 63, 1 << 64, -1 >> 60, ~0"),
+        "return (-9223372036854775807 - 1),0,15,-1;\n"
+    );
+    // Integers only become floats with `/` or when mixed with floats
+    assert_eq!(
+        fold("return 7 / 2, 6 / 2, 1 + 0.5"),
+        "return 3.5,3.0,1.5;\n"
+    );
+}
+
+#[test]
+fn floor_division_and_modulo_signs() {
+    assert_eq!(
+        fold("return 7 // 2, -7 // 2, 7 // -2, -7 // -2"),
+        "return 3,-4,-4,3;\n"
+    );
+    assert_eq!(
+        fold("return 7 % 3, -7 % 3, 7 % -3, -7 % -3, 6 % -3"),
+        "return 1,2,-2,-1,0;\n"
+    );
+    assert_eq!(
+        fold("return 7.5 // 2, -7.5 // 2, 5.5 % -2, -5.5 % 2"),
+        "return 3.0,-4.0,-0.5,0.5;\n"
+    );
+}
+
+#[test]
+fn min_divided_by_minus_one() {
+    assert_eq!(
+        fold("return (-9223372036854775807 - 1) // -1, (-9223372036854775807 - 1) % -1"),
+        "return (-9223372036854775807 - 1),0;\n"
+    );
+}
+
+#[test]
+fn division_by_zero_is_left() {
+    assert_eq!(
+        fold("return 1 // 0, 1 % 0"),
+        "return (1) // (0),(1) % (0);\n"
+    );
+    // Floats don't raise errors
+    assert_eq!(
+        fold("return 1 / 0, -1 // 0.0, 0 / 0"),
+        "return math.huge,(-math.huge),(0/0);\n"
+    );
+}
+
+#[test]
+fn int_float_comparison() {
+    // 2^63 is above all integers even though it converts to the largest one
+    assert_eq!(
+        fold(
+            "local max, two_63 = 9223372036854775807, 9223372036854775808.0
+            return max < two_63, max == two_63"
+        ),
+        "local max,two_63 = 9223372036854775807,9.223372036854776e18;\nreturn true,false;\n"
+    );
+    assert_eq!(
+        fold(
+            "local min = -9223372036854775807 - 1
+            return min == -9223372036854775808.0, min > -9223372036854777856.0"
+        ),
+        "local min = (-9223372036854775807 - 1);\nreturn true,true;\n"
+    );
+    assert_eq!(
+        fold("return 1 == 1.0, 1 < 1.5, 2 <= 1.5, 1 == 0 / 0, 1 < 0 / 0"),
+        "return true,true,false,false,false;\n"
+    );
+    // Numbers are never equal to strings
+    assert_eq!(fold("return 1 == '1', 'a' .. 1"), "return false,\"a1\";\n");
+}
+
+#[test]
+fn and_or_truncate_calls() {
+    assert_eq!(
+        fold("return true and f(), false or f(), nil and f(), 1 or f()"),
+        "return (f()),(f()),nil,1;\n"
+    );
+    assert_eq!(fold("return false or f()"), "return (f());\n");
+    assert_eq!(
+        fold("return x and 1, x or 2"),
+        "return (x) and (1),(x) or (2);\n"
+    );
+}
+
+#[test]
+fn propagation() {
+    assert_eq!(
+        fold("local a, b = 2, 3.5 local c = a * b print(a, c)"),
+        "local a,b = 2,3.5;\nlocal c = 7.0;\nprint(2,7.0);\n"
+    );
+    // Assigned locals aren't propagated
+    assert_eq!(
+        fold("local a = 1 a = a + 1 print(a)"),
+        "local a = 1;\na = (a) + (1);\nprint(a);\n"
+    );
+    // Nor are the locals given a multiple result
+    assert_eq!(
+        fold("local a, b = f() print(b)"),
+        "local a,b = f();\nprint(b);\n"
+    );
+}
+
+#[test]
+fn propagation_stops_at_shadowing() {
+    assert_eq!(
+        fold("local a = 1 print(a) local a = g() print(a)"),
+        "local a = 1;\nprint(1);\nlocal a = g();\nprint(a);\n"
+    );
+    assert_eq!(
+        fold("local a = 1 local function f(a) return a end for a = 1, 2 do print(a) end"),
+        "local a = 1;\nlocal function f(a)\n  return a;\nend\nfor a = 1,2 do\n  print(a);\nend\n"
+    );
+    // The condition of `repeat` sees the locals of its body
+    assert_eq!(
+        fold("local a = 1 repeat local a = g() until a"),
+        "local a = 1;\nrepeat\n  local a = g();\nuntil a\n"
+    );
+}
+
+#[test]
+fn propagation_stops_at_closure_assignment() {
+    assert_eq!(
+        fold("local a = 1 local function f() a = 2 end f() print(a)"),
+        "local a = 1;\nlocal function f()\n  a = 2;\nend\nf();\nprint(a);\n"
+    );
+    assert_eq!(
+        fold("local a = 1 g(function() a = 2 end) print(a)"),
+        "local a = 1;\ng(function()\n  a = 2;\nend);\nprint(a);\n"
+    );
+}
+
+#[test]
+fn propagation_stops_at_rendered_code() {
+    let mut ctx = Context::parse(b"local a = 1", Syntax::default()).unwrap();
+    ctx.stat().rendered("a = 2\n".to_string());
+    ctx.append(Context::parse(b"print(a)", Syntax::default()).unwrap());
+    ctx.fold_constants();
+    let mut code = String::new();
+    ctx.render(&mut code).unwrap();
+    assert_eq!(code, "local a = 1;\na = 2\nprint(a);\n");
+}
+
+#[test]
+fn pruning() {
+    // The taken branch keeps its own scope
+    assert_eq!(
+        fold("if 1 == 2 then f() elseif true then local x = g() print(x) else h() end"),
+        "do\n  local x = g();\n  print(x);\nend\n"
+    );
+    assert_eq!(
+        fold("if false then f() else local x = g() end"),
+        "do\n  local x = g();\nend\n"
+    );
+    assert_eq!(fold("if nil then f() end"), "");
+    assert_eq!(
+        fold("if x then f() elseif false then g() else h() end"),
+        "if x then\n  f();\nelse\n  h();\nend\n"
+    );
+    assert_eq!(fold("while false do f() end"), "");
+    assert_eq!(
+        fold("local run = false while run do f() end"),
+        "local run = false;\n"
+    );
+}
@@ -0,0 +1,305 @@
+//! The embedded interpreter, checked against the output of the reference lua 5.3
+
+#![cfg(feature = "interpreter")]
+
+use cglua::interpreter::{Error, Lua, Value};
+use cglua::{BinOp, Context, ExprBuilder};
+
+/// Runs `code` and returns what it printed
+fn run(code: &str) -> String {
+    let mut lua = Lua::new();
+    if let Err(err) = lua.exec(code) {
+        panic!("{} failed: {}", code, err);
+    }
+    String::from_utf8(lua.stdout().to_vec()).unwrap()
+}
+
+#[test]
+fn print() {
+    assert_eq!(
+        run(r#"print(1, 2.0, -0.0, 1e100, 2^63, math.maxinteger, nil, true, "s")"#),
+        "1\t2.0\t-0.0\t1e+100\t9.2233720368548e+18\t9223372036854775807\tnil\ttrue\ts\n"
+    );
+    assert_eq!(
+        run("print(1e15, 1e16, 0.1, -1/0, 1/0, 123456789012.0)"),
+        "1e+15\t1e+16\t0.1\t-inf\tinf\t123456789012.0\n"
+    );
+    assert_eq!(run("print() io.write(1, 'a', 2.5)"), "\n1a2.5");
+    let mut lua = Lua::new();
+    lua.exec("io.stderr:write('err')").unwrap();
+    assert_eq!(lua.stdout(), b"");
+    assert_eq!(lua.stderr(), b"err");
+}
+
+#[test]
+fn string_format() {
+    assert_eq!(
+        run(r#"print(string.format("%5.2f|%-5d|%x|%X|%o|%s|%g|%e|%c|%%",
+            3.14159, 42, 255, 255, 8, true, 1e20, 12345.678, 65))"#),
+        " 3.14|42   |ff|FF|10|true|1e+20|1.234568e+04|A|%\n"
+    );
+    assert_eq!(
+        run(r#"print(string.format("%q", "a\nb\0"))"#),
+        "\"a\\\nb\\0\"\n"
+    );
+    assert_eq!(
+        run(
+            r#"print(string.format("%q", 1/3), string.format("%q", math.mininteger),
+            string.format("%10.3s|", "abcdef"))"#
+        ),
+        "0x1.5555555555555p-2\t0x8000000000000000\t       abc|\n"
+    );
+    assert_eq!(
+        run(
+            r#"print(string.rep("ab", 3, "-"), ("abc"):upper(), ("abc"):byte(1, -1),
+            string.char(72, 105), ("abcdef"):sub(-3, -2))"#
+        ),
+        "ab-ab-ab\tABC\t97\tHi\tde\n"
+    );
+}
+
+#[test]
+fn patterns() {
+    assert_eq!(
+        run(
+            r#"print(("hello world"):find("o w"), ("hello world"):find("l+"),
+            ("hello"):find("l", 1, true))"#
+        ),
+        "5\t3\t3\t3\n"
+    );
+    assert_eq!(
+        run(r#"print(string.match("key = value", "(%w+)%s*=%s*(%w+)"))
+            print(string.match("abc", "()b()"))"#),
+        "key\tvalue\n2\t3\n"
+    );
+    assert_eq!(
+        run(
+            r#"print(string.gsub("hello world", "o", "0"), string.gsub("abc", "%w", "%0%0"),
+            string.gsub("$x $y", "%$(%w+)", {x = 1}))"#
+        ),
+        "hell0 w0rld\taabbcc\t1 $y\t2\n"
+    );
+    assert_eq!(
+        run(r#"local words = {}
+            for w in string.gmatch("one two  three", "%a+") do words[#words + 1] = w end
+            print(table.concat(words, ","))"#),
+        "one,two,three\n"
+    );
+    assert_eq!(
+        run(r#"print(string.match("[[x]]", "%[(%b[])%]"),
+            string.match("  trim  ", "^%s*(.-)%s*$") .. "|",
+            string.find("THE (quick) fox", "%((%a+)%)"))"#),
+        "[x]\ttrim|\t5\t11\tquick\n"
+    );
+}
+
+#[test]
+fn math() {
+    assert_eq!(
+        run(
+            "print(math.floor(3.7), math.ceil(-3.7), math.floor(-0.0), math.max(1, 2.5), \
+             math.min(3, -1), math.abs(math.mininteger))"
+        ),
+        "3\t-3\t0\t2.5\t-1\t-9223372036854775808\n"
+    );
+    assert_eq!(
+        run(
+            r#"print(math.type(1), math.type(1.0), math.type("1"), math.tointeger(3.0),
+            math.tointeger(3.5), math.ult(1, -1))"#
+        ),
+        "integer\tfloat\tnil\t3\tnil\ttrue\n"
+    );
+    assert_eq!(
+        run(
+            "print(math.fmod(7, 3), math.fmod(-7, 3), math.fmod(7, -3.0), math.huge, \
+             math.pi, math.modf(-3.5))"
+        ),
+        "1\t-1\t1.0\tinf\t3.1415926535898\t-3\t-0.5\n"
+    );
+    assert_eq!(
+        run("print(math.sqrt(16), math.exp(0), math.log(1), math.log(8, 2), math.log(100, 10))"),
+        "4.0\t1.0\t0.0\t3.0\t2.0\n"
+    );
+}
+
+#[test]
+fn table() {
+    assert_eq!(
+        run("local t = {5, 2, 8, 1}
+             table.sort(t) print(table.concat(t, ' '))
+             table.sort(t, function(a, b) return a > b end) print(table.concat(t, ' '))
+             table.insert(t, 3) table.insert(t, 1, 0)
+             print(table.concat(t, ' '), table.remove(t), table.remove(t, 1), #t)"),
+        "1 2 5 8\n8 5 2 1\n0 8 5 2 1 3\t3\t0\t4\n"
+    );
+    assert_eq!(
+        run("print(table.unpack({1, 2, 3}))
+             print(select('#', table.unpack({1, nil, 3}, 1, 3)), table.pack(1, nil).n)"),
+        "1\t2\t3\n3\t2\n"
+    );
+    assert_eq!(
+        run("local mt = setmetatable({}, {
+               __index = function(_, k) return k .. '!' end,
+               __tostring = function() return 'T' end,
+             })
+             print(mt.x, tostring(mt), getmetatable('').__index == string, #{1, 2, 3}, next({}))"),
+        "x!\tT\ttrue\t3\tnil\n"
+    );
+}
+
+#[test]
+fn pcall_and_error() {
+    assert_eq!(
+        run("print(pcall(error, 'msg'))
+             print(pcall(error, 'msg', 0))
+             print(pcall(function() error('inner') end))
+             print(pcall(function() error({code = 7}) end) == false,
+                   select(2, pcall(error, {code = 7})).code)"),
+        "false\tmsg\nfalse\tmsg\nfalse\tchunk:3: inner\ntrue\t7\n"
+    );
+    assert_eq!(
+        run("print(pcall(function() local x = nil; return x.field end))
+             print(pcall(function() return 1 + {} end))
+             print(xpcall(function() error('e', 0) end, function(m) return 'handled ' .. m end))"),
+        "false\tchunk:1: attempt to index a nil value (local 'x')\n\
+         false\tchunk:2: attempt to perform arithmetic on a table value\n\
+         false\thandled e\n"
+    );
+
+    let mut lua = Lua::new();
+    match lua
+        .exec("local t = setmetatable({}, {__tostring = function() return 'custom' end}) error(t)")
+    {
+        Err(Error::Runtime(msg)) => assert_eq!(msg, "custom"),
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    match lua.exec("error('uncaught')") {
+        Err(Error::Runtime(msg)) => assert_eq!(msg, "chunk:1: uncaught"),
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    assert!(matches!(lua.exec("os.exit(3)"), Err(Error::Exit(3))));
+    assert!(matches!(
+        lua.exec("pcall(os.exit, false)"),
+        Err(Error::Exit(1))
+    ));
+    assert!(matches!(lua.exec("local = 1"), Err(Error::Syntax(_))));
+}
+
+#[test]
+fn integers_and_floats() {
+    assert_eq!(
+        run("print(7 // 2, -7 // 2, 7 % -3, -7 % 3, 7.0 // 2, -7.5 % 2, 1 // 0.0, -1 % math.huge)"),
+        "3\t-4\t-2\t2\t3.0\t0.5\tinf\tinf\n"
+    );
+    assert_eq!(
+        run(
+            "print(math.maxinteger + 1 == math.mininteger, math.mininteger // -1, \
+             math.mininteger % -1, 3 / 2, 4 / 2)"
+        ),
+        "true\t-9223372036854775808\t0\t1.5\t2.0\n"
+    );
+    assert_eq!(
+        run(
+            r#"print(1 == 1.0, math.maxinteger + 0.0 == math.maxinteger, 2^53 == 2^53 + 1,
+            "10" + 1, "3.0" * 2, 10 .. 20)"#
+        ),
+        "true\tfalse\ttrue\t11.0\t6.0\t1020\n"
+    );
+    assert_eq!(
+        run(
+            "print(1 < 1.5, -1 < math.mininteger + 0.0, math.maxinteger < 2^63, \
+             5 & 3, 5 | 3, 5 ~ 3, ~0, 1 << 63, 1 << 64, -1 >> 1)"
+        ),
+        "true\tfalse\ttrue\t1\t7\t6\t-1\t-9223372036854775808\t0\t9223372036854775807\n"
+    );
+    assert_eq!(
+        run("print(pcall(function() return 1 // 0 end))
+             print(pcall(function() return 1.5 | 0 end))"),
+        "false\tchunk:1: attempt to divide by zero\n\
+         false\tchunk:2: number has no integer representation\n"
+    );
+    assert_eq!(
+        run(
+            r#"print(tonumber("0x10"), tonumber("  12  "), tonumber("z", 36), tonumber("1e2"),
+            tonumber("x"), tonumber("10", 2), 0x7fffffffffffffff + 1, 9223372036854775808)"#
+        ),
+        "16\t12\t35\t100.0\tnil\t2\t-9223372036854775808\t9.2233720368548e+18\n"
+    );
+}
+
+#[test]
+fn host_functions() {
+    let mut lua = Lua::new();
+    lua.set_function("add", |_, args| match (&args[0], &args[1]) {
+        (Value::Int(a), Value::Int(b)) => Ok(vec![Value::Int(a + b)]),
+        _ => Err(Error::Runtime("integers expected".to_string())),
+    });
+    lua.set_stdin("line 1\nrest");
+    let res = lua
+        .exec("return add(40, 2), io.read('l'), io.read('a'), pcall(add, 1, 1.5)")
+        .unwrap();
+    assert_eq!(res[0].as_int(), Some(42));
+    assert_eq!(res[1].as_bytes(), Some(&b"line 1"[..]));
+    assert_eq!(res[2].as_bytes(), Some(&b"rest"[..]));
+    assert_eq!(res[4].as_bytes(), Some(&b"integers expected"[..]));
+}
+
+#[test]
+fn require() {
+    assert_eq!(
+        run(
+            "package.preload.m = function(name, extra) return {name = name, extra = extra} end
+             local m = require('m')
+             print(m.name, m.extra, require('m') == m, package.loaded.m == m,
+                   require('string') == string)
+             print(pcall(require, 'missing'))"
+        ),
+        "m\t:preload:\ttrue\ttrue\ttrue\n\
+         false\tmodule 'missing' not found:\n\
+         \tno field package.preload['missing']\n\
+         \tno file './missing.lua'\n\
+         \tno file './missing/init.lua'\n"
+    );
+}
+
+fn ident(name: &str) -> cglua::Value {
+    ExprBuilder.ident(name.to_string())
+}
+
+/// Runs a context built like the backend builds the code of a function
+#[test]
+fn run_context() {
+    let mut ctx = Context::new();
+    let fib = ctx.start_function("fib".to_string(), vec!["n".to_string()]);
+    ctx.stat().local(
+        vec!["a".to_string(), "b".to_string()],
+        vec![ExprBuilder.int(0), ExprBuilder.int(1)],
+    );
+    ctx.start_while(ExprBuilder.binop(BinOp::Gt, ident("n"), ExprBuilder.int(0)));
+    ctx.stat().assign_multi(
+        vec![ident("a").to_place(), ident("b").to_place()],
+        vec![
+            ident("b"),
+            ExprBuilder.binop(BinOp::Add, ident("a"), ident("b")),
+        ],
+    );
+    ctx.stat().assign(
+        ident("n").to_place(),
+        ExprBuilder.binop(BinOp::Sub, ident("n"), ExprBuilder.int(1)),
+    );
+    ctx.finish_block().unwrap();
+    ctx.stat().ret(vec![ident("a")]);
+    ctx.finish_block().unwrap();
+    let fib = ExprBuilder.get_place(fib);
+    ctx.stat().call(
+        ident("print"),
+        [
+            ExprBuilder.call(fib.clone(), [ExprBuilder.int(10)]),
+            ExprBuilder.call(fib, [ExprBuilder.int(90)]),
+        ],
+    );
+
+    let mut lua = Lua::new();
+    lua.run(&ctx).unwrap();
+    assert_eq!(lua.stdout(), b"55\t2880067194370816120\n");
+}
@@ -0,0 +1,200 @@
+//! Parsing lua source with `Context::parse`, checked by rendering the parsed context
+
+use std::path::Path;
+
+use cglua::{BinOp, Context, Error, ExprBuilder, Syntax, UnOp};
+
+fn render(ctx: &Context) -> String {
+    let mut code = String::new();
+    ctx.render(&mut code).unwrap();
+    code
+}
+
+/// Parses `source` and renders it
+fn reparse(source: &str) -> String {
+    match Context::parse(source.as_bytes(), Syntax::default()) {
+        Ok(ctx) => render(&ctx),
+        Err(err) => panic!("{}\nin\n{}", err, source),
+    }
+}
+
+/// Checks that rendering the parsed output of the renderer gives it back
+fn assert_round_trip(ctx: &Context) {
+    let code = render(ctx);
+    assert_eq!(reparse(&code), code);
+}
+
+#[test]
+fn builder_output() {
+    let mut ctx = Context::new();
+    let f = ctx.start_function("f".to_string(), vec!["a".to_string(), "...".to_string()]);
+    ctx.stat().local(
+        vec!["t".to_string(), "s".to_string()],
+        vec![
+            ExprBuilder.table([
+                (ExprBuilder.string("k".to_string()), ExprBuilder.int(1)),
+                (ExprBuilder.int(2), ExprBuilder.bool(true)),
+            ]),
+            ExprBuilder.bytes(b"\0\xff\n\"\\".to_vec()),
+        ],
+    );
+    ctx.start_if(ExprBuilder.binop(
+        BinOp::Or,
+        ExprBuilder.binop(
+            BinOp::Lt,
+            ExprBuilder.ident("a".to_string()),
+            ExprBuilder.int(0),
+        ),
+        ExprBuilder.unop(UnOp::Not, ExprBuilder.ident("s".to_string())),
+    ));
+    ctx.stat().assign_multi(
+        vec![
+            ExprBuilder.ident("a".to_string()).to_place(),
+            ExprBuilder
+                .table_access(
+                    ExprBuilder.ident("t".to_string()).to_place(),
+                    ExprBuilder.int(1),
+                )
+                .to_place(),
+        ],
+        vec![
+            ExprBuilder.binop(
+                BinOp::Mul,
+                ExprBuilder.binop(
+                    BinOp::Sub,
+                    ExprBuilder.ident("a".to_string()),
+                    ExprBuilder.int(1),
+                ),
+                ExprBuilder.unop(UnOp::Neg, ExprBuilder.ident("a".to_string())),
+            ),
+            ExprBuilder.list([ExprBuilder.ident("...".to_string())]),
+        ],
+    );
+    ctx.start_else_if(ExprBuilder.bool(false)).unwrap();
+    ctx.stat().ret(vec![]);
+    ctx.start_else().unwrap();
+    ctx.start_while(ExprBuilder.binop(
+        BinOp::BAnd,
+        ExprBuilder.ident("a".to_string()),
+        ExprBuilder.int(0xff),
+    ));
+    ctx.stat().brk();
+    ctx.finish_block().unwrap();
+    ctx.finish_block().unwrap();
+    ctx.start_raw_block();
+    ctx.stat().call(
+        ExprBuilder.ident("print".to_string()),
+        [ExprBuilder.unop(UnOp::Len, ExprBuilder.ident("s".to_string()))],
+    );
+    ctx.finish_block().unwrap();
+    ctx.stat().ret(vec![
+        ExprBuilder.ident("a".to_string()),
+        ExprBuilder.call(
+            ExprBuilder.ident("select".to_string()),
+            [ExprBuilder.int(2), ExprBuilder.ident("...".to_string())],
+        ),
+    ]);
+    ctx.finish_block().unwrap();
+    ctx.stat()
+        .call(ExprBuilder.get_place(f), [ExprBuilder.int(1)]);
+    assert_round_trip(&ctx);
+}
+
+#[test]
+fn literals() {
+    let mut ctx = Context::new();
+    ctx.stat().local(
+        vec!["x".to_string()],
+        vec![ExprBuilder.list([
+            ExprBuilder.int(i64::MIN),
+            ExprBuilder.int(i64::MAX),
+            ExprBuilder.int(-1),
+            ExprBuilder.double(0.1),
+            ExprBuilder.double(-0.0),
+            ExprBuilder.double(1e300),
+            ExprBuilder.double(5e-324),
+            ExprBuilder.double(f64::INFINITY),
+            ExprBuilder.double(f64::NEG_INFINITY),
+            ExprBuilder.double(f64::NAN),
+            ExprBuilder.double(2f64.powi(63)),
+            ExprBuilder.nil(),
+            ExprBuilder.bytes((0..=255).collect()),
+        ])],
+    );
+    assert_round_trip(&ctx);
+}
+
+#[test]
+fn source() {
+    let code = reparse(
+        r##"
+-- comments are dropped
+local a, b = 1, 2.5 --[[ long
+comment ]]
+for i = 1, 10, 2 do a = a + i end
+for k, v in pairs({x = 1, [2] = "y"}) do print(k, v) end
+repeat local x = a // 2 until x > 3 or a ~= b
+local function g(...) return select("#", ...), (...) end
+t = {g(1, 2)}
+t.x:method("s", ~a, -b, a >> 1 | 2 & 3 ~ 4, [[long
+string]])
+do ::top:: goto top end
+"##,
+    );
+    assert!(!code.contains("comment"), "{}", code);
+    assert_eq!(reparse(&code), code);
+}
+
+#[test]
+fn runtime_sources() {
+    let runtime = Path::new(env!("CARGO_MANIFEST_DIR")).join("../src/runtime");
+    let mut files = 0;
+    for entry in std::fs::read_dir(&runtime).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("lua".as_ref()) {
+            continue;
+        }
+        let source = std::fs::read(&path).unwrap();
+        let code = match Context::parse(&source, Syntax::default()) {
+            Ok(ctx) => render(&ctx),
+            Err(err) => panic!("{}: {}", path.display(), err),
+        };
+        assert_eq!(reparse(&code), code, "{}", path.display());
+        files += 1;
+    }
+    assert!(files > 0, "no lua files in {}", runtime.display());
+}
+
+#[test]
+fn syntax() {
+    let no_bitops = Syntax {
+        goto: true,
+        bitops: false,
+    };
+    assert!(Context::parse(b"x = a // 2", no_bitops).is_err());
+    assert!(Context::parse(b"x = a & 2", no_bitops).is_err());
+
+    let no_goto = Syntax {
+        goto: false,
+        bitops: true,
+    };
+    assert!(Context::parse(b"::top::", no_goto).is_err());
+    let code = render(&Context::parse(b"goto = 1 print(goto)", no_goto).unwrap());
+    assert_eq!(code, "goto = 1;\nprint(goto);\n");
+    assert!(Context::parse(code.as_bytes(), Syntax::default()).is_err());
+}
+
+#[test]
+fn errors() {
+    match Context::parse(b"local a = 1\nlocal b = = 2", Syntax::default()) {
+        Err(Error::Parse { line, .. }) => assert_eq!(line, 2),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("parsed invalid source"),
+    }
+    match Context::parse(b"while true do\nbreak\n", Syntax::default()) {
+        Err(Error::Parse { line, .. }) => assert_eq!(line, 3),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("parsed an unfinished block"),
+    }
+    assert!(Context::parse(b"break", Syntax::default()).is_err());
+}
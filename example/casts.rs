@@ -0,0 +1,139 @@
+//! Checks the numeric casts generated by the backend against the results of rustc's const
+//! evaluation for a matrix of source types, destination types and edge case values.
+//!
+//! The program panics on the first mismatch. It prints the bit pattern of every cast, which
+//! `./y.rs test` compares with the output of the program compiled by rustc.
+
+#[inline(never)]
+fn opaque<T>(v: T) -> T {
+    v
+}
+
+/// The bit pattern of a value, to compare NaNs and signed zeroes exactly.
+trait Bits: Copy + std::fmt::Debug {
+    fn bits(self) -> u128;
+}
+
+macro_rules! impl_bits {
+    ($($t:ty),*) => {
+        $(impl Bits for $t {
+            fn bits(self) -> u128 {
+                self as u128
+            }
+        })*
+    };
+}
+
+impl_bits!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, char);
+
+impl Bits for f32 {
+    fn bits(self) -> u128 {
+        self.to_bits() as u128
+    }
+}
+
+impl Bits for f64 {
+    fn bits(self) -> u128 {
+        self.to_bits() as u128
+    }
+}
+
+fn check<T: Bits>(what: &str, actual: T, expected: T) {
+    if actual.bits() != expected.bits() {
+        panic!("{}: got {:?}, expected {:?}", what, actual, expected);
+    }
+    println!("{} = {:#x}", what, actual.bits());
+}
+
+/// Casts each value to `$from` and then to each of the destination types, both at runtime and
+/// during const evaluation.
+macro_rules! check_casts {
+    ($from:ty => $to:tt: $($val:expr),* $(,)?) => {
+        $(check_casts!(@value $from, $val, $to);)*
+    };
+    (@value $from:ty, $val:expr, [$($to:ty),*]) => {
+        $({
+            const EXPECTED: $to = ($val as $from) as $to;
+            let actual = opaque($val as $from) as $to;
+            check(
+                concat!(stringify!($val), " as ", stringify!($from), " as ", stringify!($to)),
+                actual,
+                EXPECTED,
+            );
+        })*
+    };
+}
+
+macro_rules! check_int_casts {
+    ($($from:ty),*) => {
+        $(check_casts!(
+            $from => [u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64]:
+            0i128,
+            1i128,
+            -1i128,
+            127i128,
+            128i128,
+            255i128,
+            256i128,
+            -128i128,
+            -129i128,
+            0x7fff_ffffi128,
+            0x8000_0000i128,
+            0xffff_ffffi128,
+            -0x8000_0001i128,
+            (1i128 << 24) + 1,
+            (1i128 << 53) + 1,
+            0x7fff_ffff_ffff_ffffi128,
+            0x8000_0000_0000_0000i128,
+            0xffff_ffff_ffff_ffffi128,
+            0x1234_5678_9abc_def0_1234_5678_9abc_def0i128,
+            i128::MAX,
+            i128::MIN,
+        );)*
+    };
+}
+
+macro_rules! check_float_casts {
+    ($($from:ty),*) => {
+        $(check_casts!(
+            $from => [u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64]:
+            0.0f64,
+            -0.0f64,
+            0.5f64,
+            -0.5f64,
+            1.5f64,
+            2.5f64,
+            -1.5f64,
+            -1.0f64,
+            255.9f64,
+            256.0f64,
+            -128.9f64,
+            -129.0f64,
+            1e10f64,
+            -1e10f64,
+            9007199254740993.0f64,
+            9223372036854775807.0f64,
+            18446744073709551615.0f64,
+            1.7014118346046923e38f64,
+            3.4028235677973366e38f64,
+            1e300f64,
+            1.0000000596046448f64,
+            1e-40f64,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+        );)*
+    };
+}
+
+fn main() {
+    check_int_casts!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+    check_float_casts!(f32, f64);
+
+    check_casts!(u8 => [char]: 0u8, 65u8, 127u8, 128u8, 255u8);
+    check_casts!(char => [u8, u16, u32, u64, u128, i8, i16, i32, i64, i128]:
+        'a', 'é', '\u{10ffff}');
+    check_casts!(bool => [u8, u16, u32, u64, u128, i8, i16, i32, i64, i128]: false, true);
+}
@@ -0,0 +1,124 @@
+//! Checks the lowering of constants: scalars, slices and allocations with relocations to other
+//! statics and to functions, materialized in the lua memory when the program is loaded.
+//!
+//! `./y.rs test` compares the output with the program compiled by rustc.
+
+use std::fmt::Debug;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Point,
+    Circle { radius: f64 },
+    Rect(u16, u16),
+}
+
+struct Entry {
+    name: &'static str,
+    shape: Shape,
+    handler: fn(i64) -> i64,
+}
+
+/// An unsized struct, whose last field keeps the length of the slice
+struct Tagged<T: ?Sized> {
+    tag: u8,
+    data: T,
+}
+
+fn double(x: i64) -> i64 {
+    x * 2
+}
+
+fn negate(x: i64) -> i64 {
+    -x
+}
+
+const SMALL: u8 = 0xa5;
+const BIG: u128 = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+const NEGATIVE: i64 = i64::MIN + 7;
+const FLOATS: [f64; 4] = [1.5, -0.0, f64::MAX, f64::MIN_POSITIVE];
+const CHARS: [char; 3] = ['a', 'é', '🦀'];
+const TEXT: &str = "constant text";
+const BYTES: &[u8] = b"\x00\x01\xfe\xff";
+const NESTED: [[u16; 3]; 2] = [[1, 2, 3], [0xfffe, 0x8000, 0]];
+
+static COUNTS: [u32; 3] = [10, 20, 30];
+// Relocations to another static
+static COUNT_REFS: [&u32; 2] = [&COUNTS[2], &COUNTS[0]];
+static ENTRIES: &[Entry] = &[
+    Entry {
+        name: "double",
+        shape: Shape::Point,
+        handler: double,
+    },
+    Entry {
+        name: "negate",
+        shape: Shape::Circle { radius: 2.25 },
+        handler: negate,
+    },
+    Entry {
+        name: "rect",
+        shape: Shape::Rect(3, 4),
+        handler: double,
+    },
+];
+static TAGGED: &Tagged<[u8]> = &Tagged {
+    tag: 7,
+    data: [4, 5, 6],
+};
+static NAMES: &[&str] = &["first", "", "third"];
+
+#[inline(never)]
+fn opaque<T>(v: T) -> T {
+    v
+}
+
+fn show<T: Debug>(name: &str, value: T) {
+    println!("{}: {:?}", name, value);
+}
+
+/// Reads the fields of an unsized value behind a reference
+#[inline(never)]
+fn tagged_sum(tagged: &Tagged<[u8]>) -> u32 {
+    let data = &tagged.data;
+    u32::from(tagged.tag) + data.iter().map(|&b| u32::from(b)).sum::<u32>()
+}
+
+fn main() {
+    show("small", SMALL);
+    show("big", BIG);
+    show("negative", NEGATIVE);
+    for f in FLOATS.iter() {
+        println!("float: {:?} {:#x}", f, f.to_bits());
+    }
+    show("chars", CHARS);
+    show("text", TEXT);
+    show("text len", opaque(TEXT).len());
+    show("bytes", BYTES);
+    show("nested", NESTED);
+    show("nested element", NESTED[opaque(1)][opaque(0)]);
+
+    show("counts", COUNTS);
+    show("count refs", COUNT_REFS);
+    show(
+        "count ref is element",
+        std::ptr::eq(COUNT_REFS[0], &COUNTS[2]),
+    );
+
+    for entry in ENTRIES {
+        println!(
+            "{} {:?} {}",
+            entry.name,
+            entry.shape,
+            (entry.handler)(opaque(21))
+        );
+    }
+    show("entries equal", ENTRIES[0].shape == Shape::Point);
+
+    show("tagged tag", TAGGED.tag);
+    show("tagged data", &TAGGED.data);
+    show("tagged sum", tagged_sum(opaque(TAGGED)));
+
+    show("names", NAMES);
+    let lengths: Vec<usize> = NAMES.iter().map(|name| name.len()).collect();
+    show("name lengths", lengths);
+}
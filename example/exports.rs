@@ -0,0 +1,73 @@
+//! A lua module exporting functions of each supported type, loaded and checked by
+//! `example/exports_driver.lua`:
+//!
+//! ```text
+//! $cg_lua_dir/build/bin/cg_lua --crate-type cdylib example/exports.rs --out-dir build
+//! lua example/exports_driver.lua build
+//! ```
+
+use std::collections::HashMap;
+
+#[export_name = "lua_export.add"]
+pub fn add(a: i64, b: i32) -> i64 {
+    a + i64::from(b)
+}
+
+#[export_name = "lua_export.describe"]
+pub fn describe(flag: bool, c: char, x: f32) -> String {
+    format!("{} {} {}", flag, c.to_ascii_uppercase(), x * 2.0)
+}
+
+#[export_name = "lua_export.scale"]
+pub fn scale(values: Vec<f64>, k: f64) -> Vec<f64> {
+    values.into_iter().map(|v| v * k).collect()
+}
+
+#[export_name = "lua_export.lengths"]
+pub fn lengths(words: Vec<String>) -> Vec<usize> {
+    words.iter().map(|word| word.chars().count()).collect()
+}
+
+#[export_name = "lua_export.word_counts"]
+pub fn word_counts(text: &str) -> HashMap<String, u32> {
+    let mut counts = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word.to_string()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Looks the keys up in a map built by lua, which must have been hashed like rust does
+#[export_name = "lua_export.lookup"]
+pub fn lookup(map: HashMap<String, i64>, keys: Vec<String>) -> Vec<i64> {
+    keys.iter()
+        .map(|key| map.get(key).copied().unwrap_or(-1))
+        .collect()
+}
+
+#[export_name = "lua_export.lookup_int"]
+pub fn lookup_int(map: HashMap<u8, &str>, key: u8) -> String {
+    map.get(&key).map_or_else(String::new, |s| s.to_string())
+}
+
+/// Grows a map built by lua past its capacity
+#[export_name = "lua_export.extend"]
+pub fn extend(mut map: HashMap<i64, bool>, n: i64) -> HashMap<i64, bool> {
+    for i in 0..n {
+        map.entry(i).or_insert(i % 2 == 0);
+    }
+    map
+}
+
+#[export_name = "lua_export.letters"]
+pub fn letters(text: &str) -> HashMap<char, ()> {
+    text.chars()
+        .filter(|c| c.is_alphabetic())
+        .map(|c| (c, ()))
+        .collect()
+}
+
+#[export_name = "lua_export.empty"]
+pub fn empty() -> HashMap<String, String> {
+    HashMap::new()
+}
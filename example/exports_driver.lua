@@ -0,0 +1,57 @@
+-- Loads the module built from `example/exports.rs` and checks the values converted between lua and
+-- rust. The argument is the directory containing `exports.lua`.
+
+local dir = arg and arg[1] or "build"
+package.path = dir .. "/?.lua;" .. package.path
+
+local exports = require("exports")
+
+assert(exports.add(40, 2) == 42)
+assert(math.type(exports.add(1, 1)) == "integer")
+assert(not pcall(exports.add, 1, 1 << 40), "accepted an out of range i32")
+assert(exports.describe(true, "q", 1.25) == "true Q 2.5", exports.describe(true, "q", 1.25))
+
+local scaled = exports.scale({1, 2.5, -4}, 2)
+assert(#scaled == 3 and scaled[1] == 2.0 and scaled[2] == 5.0 and scaled[3] == -8.0)
+local lengths = exports.lengths({"a", "héllo", ""})
+assert(#lengths == 3 and lengths[1] == 1 and lengths[2] == 5 and lengths[3] == 0)
+
+local counts = exports.word_counts("the cat and the hat and the bat")
+local words = 0
+for _ in pairs(counts) do
+  words = words + 1
+end
+assert(words == 5)
+assert(counts.the == 3 and counts["and"] == 2 and counts.cat == 1 and counts.bat == 1)
+
+local map = {}
+for i = 1, 50 do
+  map["key" .. i] = i * i
+end
+local found = exports.lookup(map, {"key1", "key7", "key50", "missing", ""})
+assert(found[1] == 1 and found[2] == 49 and found[3] == 2500 and found[4] == -1 and found[5] == -1)
+assert(exports.lookup({}, {"key"})[1] == -1)
+
+assert(exports.lookup_int({[0] = "zero", [200] = "big", [7] = "seven"}, 200) == "big")
+assert(exports.lookup_int({[0] = "zero"}, 0) == "zero")
+assert(exports.lookup_int({[1] = "one"}, 2) == "")
+assert(not pcall(exports.lookup_int, {[256] = "x"}, 1), "accepted an out of range key")
+
+local extended = exports.extend({[-1] = true, [3] = false}, 100)
+local entries = 0
+for k, v in pairs(extended) do
+  entries = entries + 1
+  if k == -1 or k == 3 then
+    assert(v == (k == -1))
+  else
+    assert(v == (k % 2 == 0), k)
+  end
+end
+assert(entries == 101)
+
+local letters = exports.letters("a b, aé!")
+assert(letters.a == true and letters.b == true and letters["é"] == true and letters[","] == nil)
+
+assert(next(exports.empty()) == nil)
+
+print("ok")
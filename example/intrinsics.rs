@@ -0,0 +1,304 @@
+//! Checks the lowering of the integer, memory and atomic intrinsics, mostly through the methods of
+//! core which call them, on edge case values of every integer type.
+//!
+//! `./y.rs test` compares the output with the program compiled by rustc.
+
+#![feature(core_intrinsics)]
+
+use std::intrinsics;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{
+    AtomicBool, AtomicI64, AtomicPtr, AtomicU32, AtomicU8, AtomicUsize, Ordering,
+};
+
+#[inline(never)]
+fn opaque<T>(v: T) -> T {
+    v
+}
+
+macro_rules! bit_ops {
+    ($($t:ident: [$($v:expr),*];)*) => {
+        $(
+            for &v in [$($v),*].iter() {
+                let v: $t = opaque(v);
+                println!(
+                    "{} {:#x}: ones {} lz {} tz {} bswap {:#x} bitrev {:#x} rotl {:#x} rotr {:#x}",
+                    stringify!($t),
+                    v,
+                    v.count_ones(),
+                    v.leading_zeros(),
+                    v.trailing_zeros(),
+                    v.swap_bytes(),
+                    v.reverse_bits(),
+                    v.rotate_left(opaque(3)),
+                    v.rotate_right(opaque(13)),
+                );
+                if v != 0 {
+                    unsafe {
+                        println!(
+                            "  nonzero lz {} tz {}",
+                            intrinsics::ctlz_nonzero(v),
+                            intrinsics::cttz_nonzero(v),
+                        );
+                    }
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! arith_ops {
+    ($($t:ident: [$($v:expr),*];)*) => {
+        $(
+            let values = [$($v),*];
+            for &a in values.iter() {
+                for &b in values.iter() {
+                    let (a, b): ($t, $t) = (opaque(a), opaque(b));
+                    println!(
+                        "{} {} {}: sat {} {} {} ovf {:?} {:?} {:?} wrap {} {} div {:?} rem {:?}",
+                        stringify!($t),
+                        a,
+                        b,
+                        a.saturating_add(b),
+                        a.saturating_sub(b),
+                        a.saturating_mul(b),
+                        a.overflowing_add(b),
+                        a.overflowing_sub(b),
+                        a.overflowing_mul(b),
+                        a.wrapping_neg(),
+                        a.wrapping_shl(b as u32),
+                        a.checked_div(b),
+                        a.checked_rem(b),
+                    );
+                }
+            }
+        )*
+    };
+}
+
+/// Only the discriminants are compared
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum Choice {
+    A,
+    B(u8),
+    C { x: u32 },
+}
+
+fn integers() {
+    bit_ops! {
+        u8: [0, 1, 0x80, 0xff, 0x5a];
+        i8: [0, -1, i8::MIN, i8::MAX, 0x12];
+        u16: [0, 1, 0x8000, 0xffff, 0x1234];
+        i16: [-2, i16::MIN, i16::MAX];
+        u32: [0, 1, 0x8000_0000, u32::MAX, 0x1234_5678];
+        i32: [-1, i32::MIN, i32::MAX, 0x0f0f];
+        u64: [0, 1, 1 << 63, u64::MAX, 0x0123_4567_89ab_cdef];
+        i64: [-1, i64::MIN, i64::MAX, 0x100];
+        u128: [0, 1, 1 << 127, u128::MAX, 0x0123_4567_89ab_cdef_0000_0000_0000_0001];
+        i128: [-1, i128::MIN, i128::MAX, 1 << 64];
+        usize: [0, usize::MAX, 0xdead_beef];
+    }
+
+    arith_ops! {
+        u8: [0, 1, 7, 0x80, u8::MAX];
+        i8: [0, -1, 3, i8::MIN, i8::MAX];
+        u16: [0, 2, u16::MAX];
+        i16: [-1, 9, i16::MIN, i16::MAX];
+        u32: [0, 5, 0x8000_0000, u32::MAX];
+        i32: [0, -1, 7, i32::MIN, i32::MAX];
+        u64: [0, 3, 1 << 63, u64::MAX];
+        i64: [0, -1, 11, i64::MIN, i64::MAX];
+        u128: [0, 3, 1 << 127, u128::MAX];
+        i128: [0, -1, 5, i128::MIN, i128::MAX];
+    }
+
+    unsafe {
+        println!(
+            "exact_div {} {} {}",
+            intrinsics::exact_div(opaque(84u32), 4),
+            intrinsics::exact_div(opaque(-96i64), 8),
+            intrinsics::exact_div(opaque(1u128 << 100), 1 << 40),
+        );
+        println!(
+            "unchecked {} {} {} {} {}",
+            intrinsics::unchecked_add(opaque(100u8), 27),
+            intrinsics::unchecked_sub(opaque(-5i32), 7),
+            intrinsics::unchecked_mul(opaque(1u64 << 31), 1 << 31),
+            intrinsics::unchecked_shl(opaque(1i16), 14),
+            intrinsics::unchecked_shr(opaque(-64i64), 3),
+        );
+    }
+    println!(
+        "pow {} {:?}",
+        opaque(3u64).pow(20),
+        opaque(2i32).checked_pow(31)
+    );
+
+    let choices = [Choice::A, Choice::B(1), Choice::C { x: 2 }, Choice::B(9)];
+    for a in choices.iter() {
+        let same: Vec<bool> = choices
+            .iter()
+            .map(|b| mem::discriminant(a) == mem::discriminant(b))
+            .collect();
+        println!("discriminant {:?}", same);
+    }
+}
+
+fn memory() {
+    let mut buf = [0u8; 16];
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    unsafe {
+        // Overlapping copies move forward and backward
+        ptr::copy(buf.as_ptr(), buf.as_mut_ptr().add(3), 8);
+        println!("copy forward {:?}", buf);
+        ptr::copy(buf.as_ptr().add(5), buf.as_mut_ptr().add(1), 8);
+        println!("copy backward {:?}", buf);
+        let src = [0xaau8, 0xbb, 0xcc];
+        ptr::copy_nonoverlapping(src.as_ptr(), buf.as_mut_ptr().add(13), 3);
+        println!("copy_nonoverlapping {:?}", buf);
+        ptr::write_bytes(buf.as_mut_ptr().add(2), 0x7f, opaque(5));
+        println!("write_bytes {:?}", buf);
+
+        let mut words = [0u32; 4];
+        ptr::write_bytes(words.as_mut_ptr(), 0xa5, 2);
+        println!("write_bytes words {:#x?}", words);
+
+        let p = buf.as_mut_ptr().add(opaque(1));
+        ptr::write_unaligned(p as *mut u32, 0x1122_3344);
+        println!(
+            "unaligned {:#x} {:?}",
+            ptr::read_unaligned(buf.as_ptr().add(2) as *const u16),
+            buf
+        );
+        ptr::write_volatile(&mut words[3], 0xdead_beef);
+        println!("volatile {:#x}", ptr::read_volatile(&words[3]));
+
+        let end = buf.as_ptr().add(opaque(11));
+        println!("offset_from {}", end.offset_from(buf.as_ptr()));
+        let wide = [0u64; 6];
+        println!(
+            "offset_from u64 {}",
+            wide.as_ptr()
+                .add(opaque(5))
+                .offset_from(wide.as_ptr().add(1))
+        );
+    }
+
+    let (mut a, mut b) = ([1u16, 2, 3], [7u16, 8, 9]);
+    unsafe {
+        ptr::swap(&mut a[0], &mut b[2]);
+    }
+    mem::swap(&mut a[1], &mut b[1]);
+    let old = mem::replace(&mut a[2], 42);
+    println!("swap {:?} {:?} {}", a, b, old);
+
+    let slice: &[u32] = &[1, 2, 3, 4, 5];
+    let text = "héllo";
+    let dynamic: &dyn std::fmt::Debug = &(1u8, 2u64);
+    println!(
+        "size_of_val {} {} {} align_of_val {} {}",
+        mem::size_of_val(slice),
+        mem::size_of_val(text),
+        mem::size_of_val(dynamic),
+        mem::align_of_val(slice),
+        mem::align_of_val(dynamic),
+    );
+
+    let bits: u64 = unsafe { mem::transmute(opaque(-1.5f64)) };
+    let bytes: [u8; 4] = unsafe { mem::transmute(opaque(0x0102_0304u32)) };
+    println!("transmute {:#x} {:?}", bits, bytes);
+    println!(
+        "from bytes {:#x} {:#x}",
+        u32::from_be_bytes(bytes),
+        u64::from_le_bytes([1, 2, 3, 4, 5, 6, 7, 8])
+    );
+}
+
+fn atomics() {
+    let a = AtomicU32::new(5);
+    println!(
+        "u32 {} {} {} {} {} {} {}",
+        a.fetch_add(10, Ordering::SeqCst),
+        a.fetch_sub(3, Ordering::AcqRel),
+        a.fetch_and(0b1010, Ordering::Release),
+        a.fetch_or(0x100, Ordering::Relaxed),
+        a.fetch_xor(0xff, Ordering::SeqCst),
+        a.fetch_nand(0xf0f, Ordering::SeqCst),
+        a.load(Ordering::Acquire),
+    );
+    println!(
+        "u32 max min {} {} {}",
+        a.fetch_max(7, Ordering::SeqCst),
+        a.fetch_min(3, Ordering::SeqCst),
+        a.swap(u32::MAX, Ordering::SeqCst),
+    );
+    println!(
+        "u32 wrapping {} {}",
+        a.fetch_add(2, Ordering::SeqCst),
+        a.load(Ordering::SeqCst)
+    );
+
+    let b = AtomicU8::new(250);
+    println!(
+        "u8 {} {} {:?} {:?}",
+        b.fetch_add(10, Ordering::SeqCst),
+        b.load(Ordering::SeqCst),
+        b.compare_exchange(4, 9, Ordering::SeqCst, Ordering::SeqCst),
+        b.compare_exchange(1, 9, Ordering::SeqCst, Ordering::Relaxed),
+    );
+
+    let c = AtomicI64::new(-8);
+    println!(
+        "i64 {} {} {} {:?}",
+        c.fetch_max(-20, Ordering::SeqCst),
+        c.fetch_min(-20, Ordering::SeqCst),
+        c.fetch_sub(i64::MAX, Ordering::SeqCst),
+        c.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| v.checked_mul(2)),
+    );
+    let mut current = c.load(Ordering::Relaxed);
+    loop {
+        match c.compare_exchange_weak(current, current / 3, Ordering::SeqCst, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(v) => current = v,
+        }
+    }
+    println!("i64 {}", c.load(Ordering::SeqCst));
+
+    let flag = AtomicBool::new(false);
+    println!(
+        "bool {} {} {} {}",
+        flag.fetch_or(true, Ordering::SeqCst),
+        flag.fetch_and(false, Ordering::SeqCst),
+        flag.fetch_xor(true, Ordering::SeqCst),
+        flag.swap(false, Ordering::SeqCst),
+    );
+
+    let counter = AtomicUsize::new(0);
+    for i in 0..100 {
+        counter.fetch_add(i, Ordering::Relaxed);
+    }
+    println!("usize {}", counter.into_inner());
+
+    let mut values = [10i32, 20];
+    let p = AtomicPtr::new(&mut values[0] as *mut i32);
+    let old = p.swap(&mut values[1], Ordering::SeqCst);
+    let exchanged = p.compare_exchange(old, old, Ordering::SeqCst, Ordering::SeqCst);
+    unsafe {
+        println!(
+            "ptr {} {} {}",
+            *old,
+            *p.load(Ordering::SeqCst),
+            exchanged.is_err()
+        );
+    }
+}
+
+fn main() {
+    integers();
+    memory();
+    atomics();
+}
@@ -0,0 +1,49 @@
+//! A Neovim plugin exercising the `lua::nvim` bindings, checked against a stub `vim` table by
+//! `example/nvim_stub.lua`. It is built as both an executable, the script Neovim runs at startup,
+//! and a module, with the `nvim` feature of the `lua` crate:
+//!
+//! ```text
+//! $cg_lua_dir/build/bin/cg_lua --crate-type bin --crate-type cdylib -Cllvm-args=output=neovim \
+//!     --extern lua=path/to/liblua.rlib example/nvim_plugin.rs --out-dir build/nvim
+//! lua example/nvim_stub.lua build/nvim
+//! ```
+
+use lua::nvim::{self, LogLevel};
+use lua::LuaValue;
+
+fn on_write(args: &[LuaValue]) -> LuaValue {
+    let file = args
+        .first()
+        .and_then(LuaValue::as_table)
+        .and_then(|event| event.get("file").as_string())
+        .unwrap_or_default();
+    nvim::notify(&format!("wrote {}", file), LogLevel::Info);
+    LuaValue::nil()
+}
+
+#[export_name = "lua_export.setup"]
+pub fn setup(greeting: &str) -> i64 {
+    println!("{}", greeting);
+    nvim::command("set number");
+    nvim::create_autocmd(&["BufWritePost"], Some("*.rs"), on_write)
+}
+
+#[export_name = "lua_export.current_line"]
+pub fn current_line() -> String {
+    let line = nvim::api("nvim_get_current_line", &[]);
+    line.as_string().unwrap_or_default().to_uppercase()
+}
+
+#[export_name = "lua_export.expand"]
+pub fn expand(expr: &str) -> LuaValue {
+    nvim::call_function("expand", &[expr.into()])
+}
+
+#[export_name = "lua_export.fail"]
+pub fn fail() {
+    panic!("plugin failure");
+}
+
+fn main() {
+    println!("nvim_plugin loaded");
+}
@@ -0,0 +1,62 @@
+-- Loads the plugin built from `example/nvim_plugin.rs` into a stub `vim` table recording the calls
+-- made by the plugin, and checks them. The argument is the directory containing `plugin/` and
+-- `lua/`.
+
+local dir = arg and arg[1] or "build/nvim"
+package.path = dir .. "/lua/?/init.lua;" .. dir .. "/?.lua;" .. package.path
+
+local out, errors, notifications, commands, autocmds = {}, {}, {}, {}, {}
+
+vim = {
+  log = {levels = {TRACE = 0, DEBUG = 1, INFO = 2, WARN = 3, ERROR = 4}},
+  notify = function(msg, level)
+    notifications[#notifications + 1] = {msg, level}
+  end,
+  api = {
+    nvim_out_write = function(s)
+      out[#out + 1] = s
+    end,
+    nvim_err_write = function(s)
+      errors[#errors + 1] = s
+    end,
+    nvim_command = function(command)
+      commands[#commands + 1] = command
+    end,
+    nvim_get_current_line = function()
+      return "let x = 1;"
+    end,
+    nvim_create_autocmd = function(events, opts)
+      autocmds[#autocmds + 1] = {events = events, opts = opts}
+      return #autocmds
+    end,
+  },
+  fn = {
+    expand = function(expr)
+      assert(expr == "%:t")
+      return "main.rs"
+    end,
+  },
+}
+
+-- Neovim runs `plugin/nvim_plugin.lua` at startup
+require("plugin.nvim_plugin")
+assert(table.concat(out) == "nvim_plugin loaded\n", table.concat(out))
+
+local plugin = require("nvim_plugin")
+
+assert(plugin.setup("hello") == 1)
+assert(table.concat(out) == "nvim_plugin loaded\nhello\n", table.concat(out))
+assert(commands[1] == "set number")
+local autocmd = autocmds[1]
+assert(autocmd.events[1] == "BufWritePost" and autocmd.opts.pattern == "*.rs")
+
+autocmd.opts.callback({file = "src/main.rs"})
+assert(notifications[1][1] == "wrote src/main.rs" and notifications[1][2] == 2)
+
+assert(plugin.current_line() == "LET X = 1;")
+assert(plugin.expand("%:t") == "main.rs")
+
+local ok, err = pcall(plugin.fail)
+assert(not ok and tostring(err):find("plugin failure"), tostring(err))
+
+print("ok")
@@ -0,0 +1,66 @@
+//! Checks statics, `static mut` and `thread_local!` with destructors.
+//!
+//! The program ends with `std::process::exit`, which runs the destructors of the thread locals of
+//! the main thread like returning from `main` does. `./y.rs test` compares the output with the
+//! program compiled by rustc.
+
+use std::cell::{Cell, RefCell};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static GREETING: &str = "hello";
+static TABLE: [u16; 4] = [1, 1, 2, 3];
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+static mut COUNTER: u64 = 0;
+static mut LOG: [u8; 8] = [0; 8];
+
+struct Noisy(&'static str);
+
+impl Drop for Noisy {
+    fn drop(&mut self) {
+        println!("dropping {}", self.0);
+        // Destructors can still use the other thread locals
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+    }
+}
+
+thread_local! {
+    static DEPTH: Cell<u32> = Cell::new(0);
+    static NAMES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+    static FIRST: Noisy = Noisy("first");
+    static SECOND: RefCell<Option<Noisy>> = RefCell::new(None);
+}
+
+#[inline(never)]
+fn bump() -> u64 {
+    CALLS.fetch_add(1, Ordering::Relaxed);
+    unsafe {
+        COUNTER += 1;
+        LOG[(COUNTER % 8) as usize] = COUNTER as u8;
+        COUNTER
+    }
+}
+
+fn main() {
+    println!("{} {:?}", GREETING, TABLE);
+    println!("table sum {}", TABLE.iter().sum::<u16>());
+
+    for _ in 0..10 {
+        bump();
+    }
+    println!("calls {}", CALLS.load(Ordering::Relaxed));
+    let (counter, log) = unsafe { (COUNTER, LOG) };
+    println!("counter {} log {:?}", counter, log);
+
+    NAMES.with(|names| {
+        names.borrow_mut().push("a".to_string());
+        names.borrow_mut().push("b".to_string());
+    });
+    NAMES.with(|names| println!("names {:?}", names.borrow()));
+
+    FIRST.with(|first| println!("using {}", first.0));
+    SECOND.with(|second| *second.borrow_mut() = Some(Noisy("second")));
+    DEPTH.with(|depth| println!("depth {}", depth.get()));
+
+    process::exit(0);
+}
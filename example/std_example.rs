@@ -0,0 +1,207 @@
+//! Exercises common parts of std. `./y.rs test` compares its output when compiled by the backend
+//! with the output of the same program compiled by rustc.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+
+trait Shape {
+    fn area(&self) -> f64;
+    fn name(&self) -> String {
+        "shape".to_string()
+    }
+}
+
+struct Circle(f64);
+
+struct Rect {
+    w: f64,
+    h: f64,
+}
+
+impl Shape for Circle {
+    fn area(&self) -> f64 {
+        std::f64::consts::PI * self.0 * self.0
+    }
+
+    fn name(&self) -> String {
+        format!("circle({})", self.0)
+    }
+}
+
+impl Shape for Rect {
+    fn area(&self) -> f64 {
+        self.w * self.h
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Token {
+    Num(i64),
+    Op(char),
+    Ident(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Op(c) => write!(f, "{}", c),
+            Token::Ident(s) => f.write_str(s),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() {
+            let mut n = 0;
+            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                n = n * 10 + d as i64;
+                chars.next();
+            }
+            tokens.push(Token::Num(n));
+        } else if c.is_alphabetic() {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric()) {
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            return Err(format!("unexpected {:?}", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn collatz_len(mut n: u64) -> u32 {
+    let mut len = 1;
+    while n != 1 {
+        n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+        len += 1;
+    }
+    len
+}
+
+fn main() {
+    println!(
+        "Hello, {}! {} {:?} {:5}|{:<5}|{:^7}|",
+        "world", 42, "quoted", 7, 8, "mid"
+    );
+    println!(
+        "{:#x} {:#b} {:o} {:+} {:08.3} {:e}",
+        255, 5, 64, 3, -3.14159, 1234.5
+    );
+
+    let shapes: Vec<Box<dyn Shape>> =
+        vec![Box::new(Circle(1.5)), Box::new(Rect { w: 2.0, h: 3.5 })];
+    for shape in &shapes {
+        println!("{} has an area of {:.3}", shape.name(), shape.area());
+    }
+    let total: f64 = shapes.iter().map(|s| s.area()).sum();
+    println!("total {:.2}", total);
+
+    for source in &["x1 + 42 * (y - 7)", "x = 1"] {
+        match tokenize(source) {
+            Ok(tokens) => println!("{:?}", tokens),
+            Err(err) => println!("error: {}", err),
+        }
+    }
+    let tokens = tokenize("b + 10 * a - 2").unwrap();
+    let line: Vec<String> = tokens.iter().map(|t| t.to_string()).collect();
+    println!("{}", line.join(" "));
+    let mut sorted = tokens.clone();
+    sorted.sort();
+    println!("{:?}", sorted);
+
+    let mut words = BTreeMap::new();
+    for word in "the quick brown fox jumps over the lazy dog the end".split_whitespace() {
+        *words.entry(word).or_insert(0) += 1;
+    }
+    println!("{:?}", words);
+    let unique: HashSet<char> = "mississippi".chars().collect();
+    let mut unique: Vec<char> = unique.into_iter().collect();
+    unique.sort_unstable();
+    println!("{:?}", unique);
+
+    let mut queue: VecDeque<u32> = (1..=5).collect();
+    queue.rotate_left(2);
+    queue.push_front(0);
+    println!(
+        "{:?} {:?}",
+        queue,
+        queue.iter().rev().take(2).collect::<Vec<_>>()
+    );
+
+    let longest = (1..1000u64).max_by_key(|&n| collatz_len(n)).unwrap();
+    println!(
+        "longest collatz below 1000: {} ({} steps)",
+        longest,
+        collatz_len(longest)
+    );
+
+    let primes: Vec<u32> = (2..60u32).filter(|n| (2..*n).all(|d| n % d != 0)).collect();
+    println!("{:?}", primes);
+
+    let big: u128 = (1..=30u128).product();
+    println!("30! = {} ({} bits)", big, 128 - big.leading_zeros());
+    println!(
+        "{} {} {:?} {:?}",
+        i64::MAX.wrapping_add(1),
+        (-7i32).rem_euclid(3),
+        200u8.checked_add(100),
+        i32::MIN.overflowing_sub(1)
+    );
+    println!(
+        "{} {} {} {}",
+        2f64.sqrt(),
+        1e308 * 10.0,
+        f64::NAN.max(1.0),
+        (0.1f32 + 0.2f32) as f64
+    );
+
+    let text = String::from("Grüße, Jürgen ❤");
+    println!(
+        "{} {} {:?}",
+        text.len(),
+        text.chars().count(),
+        text.to_uppercase()
+    );
+    println!(
+        "{:?}",
+        text.char_indices()
+            .filter(|(_, c)| !c.is_ascii())
+            .collect::<Vec<_>>()
+    );
+    println!("{:?}", "a,b,,c".split(',').collect::<Vec<_>>());
+    println!(
+        "{:?}",
+        "  padded  "
+            .trim()
+            .parse::<i32>()
+            .map_err(|e| e.to_string())
+    );
+    println!("{:?}", "12345".parse::<u16>().ok().map(|n| n.swap_bytes()));
+
+    let mut fib = vec![0u64, 1];
+    while fib.len() < 20 {
+        let next = fib[fib.len() - 1] + fib[fib.len() - 2];
+        fib.push(next);
+    }
+    println!("{:?}", &fib[15..]);
+    let mut counter = {
+        let mut count = 0;
+        move || {
+            count += 1;
+            count
+        }
+    };
+    println!("{} {} {}", counter(), counter(), counter());
+}
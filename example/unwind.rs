@@ -0,0 +1,62 @@
+//! Checks that panics unwind when compiled with `-Cpanic=unwind`: destructors run while
+//! unwinding and `catch_unwind` returns the payload of the panic. The panic hook reports each
+//! panic on stderr, which is compared with the output of the native program.
+
+use std::cell::Cell;
+use std::panic;
+
+thread_local! {
+    static DROPPED: Cell<u32> = Cell::new(0);
+}
+
+struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        DROPPED.with(|dropped| dropped.set(dropped.get() + 1));
+    }
+}
+
+fn dropped() -> u32 {
+    DROPPED.with(|dropped| dropped.get())
+}
+
+#[inline(never)]
+fn panics(msg: &'static str) {
+    let _guard = Guard;
+    panic!("{}", msg);
+}
+
+#[inline(never)]
+fn index(v: &[u8], i: usize) -> u8 {
+    let _guard = Guard;
+    v[i]
+}
+
+fn main() {
+    // The hook runs before unwinding, the location of the panic is the same as natively
+    panic::set_hook(Box::new(|info| eprintln!("{}", info)));
+
+    let res = panic::catch_unwind(|| panics("boom"));
+    let payload = res.unwrap_err();
+    println!("caught {:?}", payload.downcast_ref::<String>());
+    println!("dropped {}", dropped());
+
+    let res = panic::catch_unwind(|| index(&[1, 2, 3], 5));
+    println!("out of bounds caught: {}", res.is_err());
+    println!("dropped {}", dropped());
+
+    let res = panic::catch_unwind(|| index(&[1, 2, 3], 1));
+    println!("in bounds: {:?}", res.ok());
+    println!("dropped {}", dropped());
+
+    // Nested panics are caught by the innermost `catch_unwind`
+    let res = panic::catch_unwind(|| {
+        let inner = panic::catch_unwind(|| panics("inner"));
+        println!("inner caught: {}", inner.is_err());
+        panics("outer");
+    });
+    let payload = res.unwrap_err();
+    println!("caught {:?}", payload.downcast_ref::<String>());
+    println!("dropped {}", dropped());
+}
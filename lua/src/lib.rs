@@ -0,0 +1,319 @@
+//! Access to the lua environment of programs compiled by rustc_codegen_lua
+//!
+//! Lua values stay in the lua state and are referred to by handles owned by [`LuaValue`]. The
+//! functions declared in `sys` are lowered by the backend to the lua operation they stand for, so
+//! this crate only works with the lua backend.
+//!
+//! ```ignore
+//! let print = lua::global("print");
+//! print.call(&["Hello from rust".into()]);
+//!
+//! let t = lua::LuaTable::new();
+//! t.set("answer", 42);
+//! lua::set_global("rust_table", t);
+//! ```
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+type Handle = usize;
+
+/// Calls the rust function `data` with the `nargs` values whose handles are at `args`, returning
+/// the handle of the result.
+type Trampoline = extern "C" fn(data: *const (), args: *const Handle, nargs: usize) -> Handle;
+
+#[cfg(feature = "nvim")]
+pub mod nvim;
+
+mod sys {
+    use super::{Handle, Trampoline};
+
+    extern "C" {
+        pub fn __cglua_global(name: *const u8, len: usize) -> Handle;
+        pub fn __cglua_set_global(name: *const u8, len: usize, value: Handle);
+        pub fn __cglua_new_table() -> Handle;
+        pub fn __cglua_clone(value: Handle) -> Handle;
+        pub fn __cglua_drop(value: Handle);
+        pub fn __cglua_from_int(value: i64) -> Handle;
+        pub fn __cglua_from_float(value: f64) -> Handle;
+        pub fn __cglua_from_bool(value: bool) -> Handle;
+        pub fn __cglua_from_str(ptr: *const u8, len: usize) -> Handle;
+        pub fn __cglua_type(value: Handle) -> u8;
+        pub fn __cglua_to_int(value: Handle) -> i64;
+        pub fn __cglua_to_float(value: Handle) -> f64;
+        pub fn __cglua_to_bool(value: Handle) -> bool;
+        pub fn __cglua_len(value: Handle) -> usize;
+        pub fn __cglua_copy_str(value: Handle, ptr: *mut u8);
+        pub fn __cglua_get(table: Handle, key: Handle) -> Handle;
+        pub fn __cglua_set(table: Handle, key: Handle, value: Handle);
+        pub fn __cglua_call(function: Handle, args: *const Handle, nargs: usize) -> Handle;
+        pub fn __cglua_function(trampoline: Trampoline, data: *const ()) -> Handle;
+    }
+}
+
+/// The type of a lua value, as returned by `type` except that numbers are split in integers and
+/// floats.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LuaType {
+    Nil,
+    Boolean,
+    Integer,
+    Float,
+    String,
+    Table,
+    Function,
+    Userdata,
+    Thread,
+}
+
+/// An owned reference to a lua value.
+#[repr(transparent)]
+pub struct LuaValue {
+    handle: Handle,
+}
+
+impl LuaValue {
+    pub const fn nil() -> Self {
+        LuaValue { handle: 0 }
+    }
+
+    fn from_handle(handle: Handle) -> Self {
+        LuaValue { handle }
+    }
+
+    pub fn type_of(&self) -> LuaType {
+        match unsafe { sys::__cglua_type(self.handle) } {
+            0 => LuaType::Nil,
+            1 => LuaType::Boolean,
+            2 => LuaType::Integer,
+            3 => LuaType::Float,
+            4 => LuaType::String,
+            5 => LuaType::Table,
+            6 => LuaType::Function,
+            7 => LuaType::Userdata,
+            8 => LuaType::Thread,
+            tag => unreachable!("invalid lua type tag {}", tag),
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.handle == 0
+    }
+
+    /// Whether lua considers this value true, which is the case of everything but `nil` and
+    /// `false`.
+    pub fn is_truthy(&self) -> bool {
+        unsafe { sys::__cglua_to_bool(self.handle) }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.type_of() {
+            LuaType::Boolean => Some(self.is_truthy()),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self.type_of() {
+            LuaType::Integer => Some(unsafe { sys::__cglua_to_int(self.handle) }),
+            _ => None,
+        }
+    }
+
+    /// The value of a number, converting integers to floats.
+    pub fn as_number(&self) -> Option<f64> {
+        match self.type_of() {
+            LuaType::Integer => Some(unsafe { sys::__cglua_to_int(self.handle) } as f64),
+            LuaType::Float => Some(unsafe { sys::__cglua_to_float(self.handle) }),
+            _ => None,
+        }
+    }
+
+    /// The bytes of a string, lua strings are not necessarily valid UTF-8.
+    pub fn as_bytes(&self) -> Option<Vec<u8>> {
+        if self.type_of() != LuaType::String {
+            return None;
+        }
+        let len = unsafe { sys::__cglua_len(self.handle) };
+        let mut bytes = Vec::with_capacity(len);
+        unsafe {
+            sys::__cglua_copy_str(self.handle, bytes.as_mut_ptr());
+            bytes.set_len(len);
+        }
+        Some(bytes)
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        String::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_table(&self) -> Option<LuaTable> {
+        match self.type_of() {
+            LuaType::Table => Some(LuaTable(self.clone())),
+            _ => None,
+        }
+    }
+
+    /// Calls this value with `args`, returning all the values it returned.
+    ///
+    /// Errors raised by the call are propagated like panics that can't be caught.
+    pub fn call(&self, args: &[LuaValue]) -> Vec<LuaValue> {
+        let results = LuaTable(LuaValue::from_handle(unsafe {
+            sys::__cglua_call(self.handle, args.as_ptr().cast(), args.len())
+        }));
+        let count = results.get("n").as_integer().unwrap_or(0);
+        (1..=count).map(|i| results.get(i)).collect()
+    }
+
+    /// A lua function calling `f` with its arguments and returning its result.
+    pub fn function(f: fn(&[LuaValue]) -> LuaValue) -> Self {
+        extern "C" fn trampoline(data: *const (), args: *const Handle, nargs: usize) -> Handle {
+            let f: fn(&[LuaValue]) -> LuaValue = unsafe { core::mem::transmute(data) };
+            // The handles of the arguments are owned by the caller
+            let args = unsafe { core::slice::from_raw_parts(args.cast::<LuaValue>(), nargs) };
+            let result = core::mem::ManuallyDrop::new(f(args));
+            result.handle
+        }
+        LuaValue::from_handle(unsafe { sys::__cglua_function(trampoline, f as *const ()) })
+    }
+}
+
+impl Default for LuaValue {
+    fn default() -> Self {
+        LuaValue::nil()
+    }
+}
+
+impl Clone for LuaValue {
+    fn clone(&self) -> Self {
+        LuaValue::from_handle(unsafe { sys::__cglua_clone(self.handle) })
+    }
+}
+
+impl Drop for LuaValue {
+    fn drop(&mut self) {
+        unsafe { sys::__cglua_drop(self.handle) }
+    }
+}
+
+impl fmt::Debug for LuaValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.type_of() {
+            LuaType::Nil => write!(f, "nil"),
+            LuaType::Boolean => write!(f, "{}", self.is_truthy()),
+            LuaType::Integer => write!(f, "{}", self.as_integer().unwrap()),
+            LuaType::Float => write!(f, "{:?}", self.as_number().unwrap()),
+            LuaType::String => write!(
+                f,
+                "{:?}",
+                String::from_utf8_lossy(&self.as_bytes().unwrap())
+            ),
+            ty => write!(f, "<{:?} {}>", ty, self.handle),
+        }
+    }
+}
+
+impl From<&LuaValue> for LuaValue {
+    fn from(value: &LuaValue) -> Self {
+        value.clone()
+    }
+}
+
+impl From<bool> for LuaValue {
+    fn from(value: bool) -> Self {
+        LuaValue::from_handle(unsafe { sys::__cglua_from_bool(value) })
+    }
+}
+
+impl From<i64> for LuaValue {
+    fn from(value: i64) -> Self {
+        LuaValue::from_handle(unsafe { sys::__cglua_from_int(value) })
+    }
+}
+
+impl From<i32> for LuaValue {
+    fn from(value: i32) -> Self {
+        LuaValue::from(i64::from(value))
+    }
+}
+
+impl From<f64> for LuaValue {
+    fn from(value: f64) -> Self {
+        LuaValue::from_handle(unsafe { sys::__cglua_from_float(value) })
+    }
+}
+
+impl From<&str> for LuaValue {
+    fn from(value: &str) -> Self {
+        LuaValue::from_handle(unsafe { sys::__cglua_from_str(value.as_ptr(), value.len()) })
+    }
+}
+
+impl From<String> for LuaValue {
+    fn from(value: String) -> Self {
+        LuaValue::from(value.as_str())
+    }
+}
+
+impl From<LuaTable> for LuaValue {
+    fn from(table: LuaTable) -> Self {
+        table.0
+    }
+}
+
+impl<T: Into<LuaValue>> From<Option<T>> for LuaValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or_else(LuaValue::nil, Into::into)
+    }
+}
+
+/// A lua table.
+#[derive(Clone, Debug)]
+pub struct LuaTable(LuaValue);
+
+impl LuaTable {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        LuaTable(LuaValue::from_handle(unsafe { sys::__cglua_new_table() }))
+    }
+
+    pub fn get(&self, key: impl Into<LuaValue>) -> LuaValue {
+        let key = key.into();
+        LuaValue::from_handle(unsafe { sys::__cglua_get(self.0.handle, key.handle) })
+    }
+
+    pub fn set(&self, key: impl Into<LuaValue>, value: impl Into<LuaValue>) {
+        let key = key.into();
+        let value = value.into();
+        unsafe { sys::__cglua_set(self.0.handle, key.handle, value.handle) }
+    }
+
+    /// The length of the table as given by the `#` operator.
+    pub fn len(&self) -> usize {
+        unsafe { sys::__cglua_len(self.0.handle) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_value(&self) -> &LuaValue {
+        &self.0
+    }
+}
+
+/// The global variable `name`.
+pub fn global(name: &str) -> LuaValue {
+    LuaValue::from_handle(unsafe { sys::__cglua_global(name.as_ptr(), name.len()) })
+}
+
+/// Sets the global variable `name` to `value`.
+pub fn set_global(name: &str, value: impl Into<LuaValue>) {
+    let value = value.into();
+    unsafe { sys::__cglua_set_global(name.as_ptr(), name.len(), value.handle) }
+}
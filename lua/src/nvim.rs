@@ -0,0 +1,73 @@
+//! Bindings to the lua API of Neovim, for plugins linked with `-Cllvm-args=output=neovim`
+//!
+//! ```ignore
+//! lua::nvim::create_autocmd(&["BufWritePost"], Some("*.rs"), |_| {
+//!     lua::nvim::notify("saved", lua::nvim::LogLevel::Info);
+//!     lua::LuaValue::nil()
+//! });
+//! ```
+
+use crate::{global, LuaTable, LuaValue};
+
+/// The levels of `vim.log.levels`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LogLevel {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+/// The field `name` of the `vim` global.
+fn vim(name: &str) -> LuaValue {
+    global("vim")
+        .as_table()
+        .expect("the `vim` global is not a table")
+        .get(name)
+}
+
+fn call_first(function: LuaValue, args: &[LuaValue]) -> LuaValue {
+    function.call(args).into_iter().next().unwrap_or_default()
+}
+
+/// Calls the API function `vim.api[function]`, returning its result.
+pub fn api(function: &str, args: &[LuaValue]) -> LuaValue {
+    let api = vim("api").as_table().expect("`vim.api` is not a table");
+    call_first(api.get(function), args)
+}
+
+/// Calls the vimscript function `vim.fn[function]`, returning its result.
+pub fn call_function(function: &str, args: &[LuaValue]) -> LuaValue {
+    let functions = vim("fn").as_table().expect("`vim.fn` is not a table");
+    call_first(functions.get(function), args)
+}
+
+/// Executes the Ex command `command`.
+pub fn command(command: &str) {
+    api("nvim_command", &[command.into()]);
+}
+
+/// Shows `message` to the user with `vim.notify`.
+pub fn notify(message: &str, level: LogLevel) {
+    vim("notify").call(&[message.into(), (level as i64).into()]);
+}
+
+/// Registers `callback` to run on `events` for buffers matching `pattern`, returning the id of the
+/// autocommand. The callback receives the event table of `nvim_create_autocmd`.
+pub fn create_autocmd(
+    events: &[&str],
+    pattern: Option<&str>,
+    callback: fn(&[LuaValue]) -> LuaValue,
+) -> i64 {
+    let event_list = LuaTable::new();
+    for (i, event) in events.iter().enumerate() {
+        event_list.set(i as i64 + 1, *event);
+    }
+    let opts = LuaTable::new();
+    opts.set("pattern", pattern);
+    opts.set("callback", LuaValue::function(callback));
+    api("nvim_create_autocmd", &[event_list.into(), opts.into()])
+        .as_integer()
+        .expect("`nvim_create_autocmd` didn't return an id")
+}
@@ -0,0 +1,455 @@
+//! Handling of everything related to the calling convention.
+//!
+//! Arguments are passed according to their layout: zero sized arguments are not passed at all,
+//! scalars and scalar pairs are passed as one or two lua values and everything else is passed as
+//! a pointer to a copy of the argument. Return values follow the same rules, except that values
+//! returned by reference are written to memory pointed to by an extra first parameter `ret`.
+
+use rustc_index::vec::IndexVec;
+use rustc_target::spec::abi::Abi as SpecAbi;
+
+use crate::analyze::SsaKind;
+use crate::prelude::*;
+
+/// Functions with more variables than this keep them in a table, as lua limits the number of
+/// locals of a function to 200.
+const MAX_LUA_VARS: usize = 150;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PassMode {
+    NoPass,
+    ByVal,
+    ByValPair,
+    ByRef,
+}
+
+pub(crate) fn get_pass_mode(layout: TyAndLayout<'_>) -> PassMode {
+    if layout.is_zst() {
+        return PassMode::NoPass;
+    }
+    match layout.abi {
+        Abi::Uninhabited => PassMode::NoPass,
+        Abi::Scalar(_) => PassMode::ByVal,
+        Abi::ScalarPair(_, _) => PassMode::ByValPair,
+        Abi::Vector { .. } | Abi::Aggregate { .. } => PassMode::ByRef,
+    }
+}
+
+/// Declares the lua parameters receiving an argument of type `layout`.
+fn declare_params(params: &mut Vec<String>, layout: TyAndLayout<'_>, name: String) -> Vec<Value> {
+    let names = match get_pass_mode(layout) {
+        PassMode::NoPass => vec![],
+        PassMode::ByVal | PassMode::ByRef => vec![name],
+        PassMode::ByValPair => vec![format!("{}_0", name), format!("{}_1", name)],
+    };
+    params.extend(names.iter().cloned());
+    names
+        .into_iter()
+        .map(|name| ExprBuilder.ident(name))
+        .collect()
+}
+
+/// The value of an argument received in the parameters `values`.
+fn cvalue_for_param<'tcx>(layout: TyAndLayout<'tcx>, mut values: Vec<Value>) -> CValue<'tcx> {
+    match get_pass_mode(layout) {
+        PassMode::NoPass => CValue::zst(layout),
+        PassMode::ByVal => CValue::by_val(values.pop().unwrap(), layout),
+        PassMode::ByValPair => {
+            let b = values.pop().unwrap();
+            let a = values.pop().unwrap();
+            CValue::by_val_pair(a, b, layout)
+        }
+        PassMode::ByRef => CValue::by_ref(values.pop().unwrap(), layout),
+    }
+}
+
+enum ArgKind<'tcx> {
+    Normal(TyAndLayout<'tcx>, Vec<Value>),
+    Spread(Vec<(TyAndLayout<'tcx>, Vec<Value>)>),
+}
+
+/// Assigns a place to every local and moves the arguments into them, returning the parameters of
+/// the lua function.
+pub(crate) fn codegen_fn_prelude<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    ssa_analyzed: &IndexVec<Local, SsaKind>,
+) -> Vec<String> {
+    let mir = fx.mir;
+    let mut params = vec![];
+
+    let ret_layout = fx.layout_of(fx.monomorphize(mir.local_decls[RETURN_PLACE].ty));
+    if get_pass_mode(ret_layout) == PassMode::ByRef {
+        params.push("ret".to_string());
+    }
+
+    let mut args = vec![];
+    for local in mir.args_iter() {
+        let arg_ty = fx.monomorphize(mir.local_decls[local].ty);
+
+        // Adapted from https://github.com/rust-lang/rust/blob/145155dc96757002c7b2e9de8489416e2fdbbd57/src/librustc_codegen_llvm/mir/mod.rs#L442-L482
+        if Some(local) == mir.spread_arg {
+            // This argument (e.g. the last argument in the "rust-call" ABI)
+            // is a tuple that was spread at the ABI level and now we have
+            // to reconstruct it into a tuple local variable, from multiple
+            // individual function arguments.
+            let tupled_arg_tys = match arg_ty.kind() {
+                ty::Tuple(ref tys) => tys,
+                _ => bug!("spread argument isn't a tuple?! but {:?}", arg_ty),
+            };
+
+            let mut fields = vec![];
+            for (i, field_ty) in tupled_arg_tys.types().enumerate() {
+                let layout = fx.layout_of(field_ty);
+                let values =
+                    declare_params(&mut params, layout, format!("a{}_{}", local.as_u32(), i));
+                fields.push((layout, values));
+            }
+            args.push((local, ArgKind::Spread(fields)));
+        } else {
+            let layout = fx.layout_of(arg_ty);
+            if layout.is_unsized() {
+                fx.tcx
+                    .sess
+                    .fatal("unsized function arguments are not supported by the lua backend");
+            }
+            let values = declare_params(&mut params, layout, format!("a{}", local.as_u32()));
+            args.push((local, ArgKind::Normal(layout, values)));
+        }
+    }
+
+    if fx.instance.def.requires_caller_location(fx.tcx) {
+        params.push("caller_location".to_string());
+        let caller_location_layout = fx.layout_of(fx.tcx.caller_location_ty());
+        fx.caller_location = Some(CValue::by_val(
+            ident("caller_location"),
+            caller_location_layout,
+        ));
+    }
+
+    let var_count = mir
+        .local_decls
+        .iter_enumerated()
+        .filter(|&(local, _)| ssa_analyzed[local] == SsaKind::Ssa)
+        .map(|(_, decl)| {
+            if lua_pair_type(fx.tcx, fx.monomorphize(decl.ty)) {
+                2
+            } else {
+                1
+            }
+        })
+        .sum::<usize>();
+    fx.vars_in_table = var_count + params.len() > MAX_LUA_VARS;
+
+    for (local, decl) in mir.local_decls.iter_enumerated() {
+        let layout = fx.layout_of(fx.monomorphize(decl.ty));
+        let arg = args.iter().find(|(arg_local, _)| *arg_local == local);
+
+        let place = if local == RETURN_PLACE && get_pass_mode(layout) == PassMode::ByRef {
+            CPlace::for_ptr(ident("ret"), layout)
+        } else if ssa_analyzed[local] == SsaKind::Ssa {
+            if lua_pair_type(fx.tcx, layout.ty) {
+                CPlace::new_var_pair(fx, local, layout)
+            } else {
+                CPlace::new_var(fx, local, layout)
+            }
+        } else {
+            match arg {
+                // The caller passes a copy it doesn't use anymore, so the argument can be used in
+                // place
+                Some((_, ArgKind::Normal(layout, values)))
+                    if get_pass_mode(*layout) == PassMode::ByRef =>
+                {
+                    CPlace::for_ptr(values[0].clone(), *layout)
+                }
+                _ => CPlace::new_stack_slot(fx, layout),
+            }
+        };
+        fx.local_map.push(place);
+    }
+
+    for (local, arg) in args {
+        let place = fx.get_local_place(local);
+        match arg {
+            ArgKind::Normal(layout, values) => {
+                if get_pass_mode(layout) == PassMode::ByRef
+                    && ssa_analyzed[local] == SsaKind::NotSsa
+                {
+                    continue;
+                }
+                place.write_cvalue(fx, cvalue_for_param(layout, values));
+            }
+            ArgKind::Spread(fields) => {
+                for (i, (layout, values)) in fields.into_iter().enumerate() {
+                    let field = place.clone().place_field(fx, mir::Field::new(i));
+                    field.write_cvalue(fx, cvalue_for_param(layout, values));
+                }
+            }
+        }
+    }
+
+    params
+}
+
+/// The lua arguments passing `arg`.
+///
+/// `is_owned` is set when the caller doesn't use the argument after the call, which allows
+/// passing it by reference without making a copy first.
+fn lua_args_for<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    arg: CValue<'tcx>,
+    is_owned: bool,
+) -> Vec<Value> {
+    match get_pass_mode(arg.layout()) {
+        PassMode::NoPass => vec![],
+        PassMode::ByVal => vec![arg.load_scalar(fx)],
+        PassMode::ByValPair => {
+            let (a, b) = arg.load_scalar_pair(fx);
+            vec![a, b]
+        }
+        PassMode::ByRef => {
+            if is_owned {
+                if let Some((ptr, None)) = arg.try_to_ptr() {
+                    return vec![ptr];
+                }
+            }
+            let copy = CPlace::new_stack_slot(fx, arg.layout());
+            copy.clone().write_cvalue(fx, arg);
+            vec![copy.to_ptr()]
+        }
+    }
+}
+
+pub(crate) fn codegen_terminator_call<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    span: Span,
+    func: &Operand<'tcx>,
+    args: &[Operand<'tcx>],
+    destination: Option<(Place<'tcx>, BasicBlock)>,
+    cleanup: Option<BasicBlock>,
+) {
+    let fn_ty = fx.monomorphize(func.ty(fx.mir, fx.tcx));
+    let fn_sig = fx
+        .tcx
+        .normalize_erasing_late_bound_regions(ParamEnv::reveal_all(), fn_ty.fn_sig(fx.tcx));
+
+    let destination = destination.map(|(place, bb)| (crate::base::codegen_place(fx, place), bb));
+
+    // Handle special calls like instrinsics and empty drop glue.
+    let instance = if let ty::FnDef(def_id, substs) = *fn_ty.kind() {
+        let instance = ty::Instance::resolve(fx.tcx, ParamEnv::reveal_all(), def_id, substs)
+            .unwrap()
+            .unwrap()
+            .polymorphize(fx.tcx);
+
+        if fx.tcx.symbol_name(instance).name.starts_with("llvm.") {
+            fx.tcx
+                .sess
+                .span_fatal(span, "llvm intrinsics are not supported by the lua backend");
+        }
+
+        match instance.def {
+            InstanceDef::Intrinsic(_) => {
+                crate::intrinsics::codegen_intrinsic_call(fx, instance, args, destination, span);
+                return;
+            }
+            InstanceDef::DropGlue(_, None) => {
+                // empty drop glue - a nop.
+                let (_, dest) = destination.expect("Non terminating drop_in_place_real???");
+                crate::base::codegen_jump(fx, dest);
+                return;
+            }
+            _ if crate::interop::is_interop_fn(fx.tcx, instance) => {
+                crate::interop::codegen_interop_call(fx, instance, args, destination, span);
+                return;
+            }
+            _ if crate::import::is_lua_import(fx.tcx, instance) => {
+                crate::import::codegen_import_call(fx, instance, args, destination, span);
+                return;
+            }
+            _ => Some(instance),
+        }
+    } else {
+        None
+    };
+
+    if fn_sig.c_variadic {
+        fx.tcx.sess.span_fatal(
+            span,
+            "calling variadic functions is not supported by the lua backend",
+        );
+    }
+
+    // Unpack arguments tuple for closures
+    let mut args = if fn_sig.abi == SpecAbi::RustCall {
+        assert_eq!(args.len(), 2, "rust-call abi requires two arguments");
+        let self_arg = codegen_operand_arg(fx, &args[0]);
+        let pack_arg = crate::base::codegen_operand(fx, &args[1]);
+
+        let tupled_arguments = match pack_arg.layout().ty.kind() {
+            ty::Tuple(ref tupled_arguments) => tupled_arguments,
+            _ => bug!("argument to function with \"rust-call\" ABI is not a tuple"),
+        };
+
+        let mut args = Vec::with_capacity(1 + tupled_arguments.len());
+        args.push(self_arg);
+        for i in 0..tupled_arguments.len() {
+            args.push((pack_arg.clone().value_field(fx, mir::Field::new(i)), false));
+        }
+        args
+    } else {
+        args.iter()
+            .map(|arg| codegen_operand_arg(fx, arg))
+            .collect::<Vec<_>>()
+    };
+
+    let callee = match instance {
+        // Trait object call
+        Some(Instance {
+            def: InstanceDef::Virtual(_, idx),
+            ..
+        }) => {
+            let (ptr, method) = crate::vtable::get_ptr_and_method_ref(fx, args[0].0.clone(), idx);
+            let ptr_layout = fx.layout_of(fx.tcx.mk_mut_ptr(fx.tcx.mk_unit()));
+            args[0] = (CValue::by_val(ptr, ptr_layout), false);
+            fn_ptr_target(method)
+        }
+
+        // Normal call
+        Some(instance) => fn_symbol(fx.tcx.symbol_name(instance).name),
+
+        // Indirect call
+        None => {
+            let func = crate::base::codegen_operand(fx, func).load_scalar(fx);
+            fn_ptr_target(func)
+        }
+    };
+
+    let ret_layout = match &destination {
+        Some((place, _)) => place.layout(),
+        None => fx.layout_of(fn_sig.output()),
+    };
+    let ret_mode = get_pass_mode(ret_layout);
+
+    let mut lua_args = vec![];
+    if ret_mode == PassMode::ByRef {
+        let ret_ptr = match &destination {
+            Some((place, _)) => place.to_ptr(),
+            None => CPlace::new_stack_slot(fx, ret_layout).to_ptr(),
+        };
+        lua_args.push(ret_ptr);
+    }
+    for (arg, is_owned) in args {
+        lua_args.extend(lua_args_for(fx, arg, is_owned));
+    }
+    if instance
+        .map(|inst| inst.def.requires_caller_location(fx.tcx))
+        .unwrap_or(false)
+    {
+        // Pass the caller location for `#[track_caller]`.
+        let caller_location = fx.get_caller_location(span).load_scalar(fx);
+        lua_args.push(caller_location);
+    }
+
+    let result_count = match (ret_mode, &destination) {
+        (PassMode::ByVal, Some(_)) => 1,
+        (PassMode::ByValPair, Some(_)) => 2,
+        _ => 0,
+    };
+    crate::base::codegen_call_with_unwind(
+        fx,
+        callee,
+        lua_args,
+        result_count,
+        cleanup,
+        |fx, mut results| match (ret_mode, destination) {
+            (PassMode::ByVal, Some((place, dest))) => {
+                let value = results.pop().unwrap();
+                place.write_cvalue(fx, CValue::by_val(value, ret_layout));
+                crate::base::codegen_jump(fx, dest);
+            }
+            (PassMode::ByValPair, Some((place, dest))) => {
+                let b = results.pop().unwrap();
+                let a = results.pop().unwrap();
+                place.write_cvalue(fx, CValue::by_val_pair(a, b, ret_layout));
+                crate::base::codegen_jump(fx, dest);
+            }
+            (_, Some((_, dest))) => crate::base::codegen_jump(fx, dest),
+            (_, None) => crate::base::codegen_trap(fx, "function marked as diverging returned"),
+        },
+    );
+}
+
+/// Calls the drop glue of the value in `drop_place`, continuing at `target`.
+pub(crate) fn codegen_drop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    span: Span,
+    drop_place: CPlace<'tcx>,
+    target: BasicBlock,
+    unwind: Option<BasicBlock>,
+) {
+    let ty = drop_place.layout().ty;
+    let drop_instance = Instance::resolve_drop_in_place(fx.tcx, ty).polymorphize(fx.tcx);
+
+    if let InstanceDef::DropGlue(_, None) = drop_instance.def {
+        // we don't actually need to drop anything
+        crate::base::codegen_jump(fx, target);
+        return;
+    }
+
+    let (callee, args) = match ty.kind() {
+        ty::Dynamic(..) => {
+            let (ptr, vtable) = drop_place.to_ptr_maybe_unsized();
+            let drop_fn = crate::vtable::drop_fn_of_obj(fx, vtable.unwrap());
+            (fn_ptr_target(drop_fn), vec![ptr])
+        }
+        _ => {
+            assert!(!matches!(drop_instance.def, InstanceDef::Virtual(_, _)));
+
+            let arg_layout = fx.layout_of(fx.tcx.mk_mut_ptr(ty));
+            let arg_value = drop_place.place_ref(fx, arg_layout);
+            let mut args = lua_args_for(fx, arg_value, false);
+            if drop_instance.def.requires_caller_location(fx.tcx) {
+                // Pass the caller location for `#[track_caller]`.
+                let caller_location = fx.get_caller_location(span).load_scalar(fx);
+                args.push(caller_location);
+            }
+            (fn_symbol(fx.tcx.symbol_name(drop_instance).name), args)
+        }
+    };
+
+    crate::base::codegen_call_with_unwind(fx, callee, args, 0, unwind, |fx, _| {
+        crate::base::codegen_jump(fx, target);
+    });
+}
+
+fn codegen_operand_arg<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    operand: &Operand<'tcx>,
+) -> (CValue<'tcx>, bool) {
+    let is_owned = matches!(operand, Operand::Move(_));
+    (crate::base::codegen_operand(fx, operand), is_owned)
+}
+
+/// `F[ptr]`, the function whose address is `ptr`
+fn fn_ptr_target(ptr: Value) -> Value {
+    ExprBuilder.table_access(ident("F").to_place(), ptr)
+}
+
+pub(crate) fn codegen_return(fx: &mut FunctionCx<'_, '_>) {
+    let ret_place = fx.get_local_place(RETURN_PLACE);
+    let values = match get_pass_mode(ret_place.layout()) {
+        PassMode::NoPass | PassMode::ByRef => vec![],
+        PassMode::ByVal => vec![ret_place.to_cvalue(fx).load_scalar(fx)],
+        PassMode::ByValPair => {
+            let (a, b) = ret_place.to_cvalue(fx).load_scalar_pair(fx);
+            vec![a, b]
+        }
+    };
+
+    // Popping the stack frame doesn't clear it, so the return values can still be read from it
+    fx.cx
+        .ctx
+        .stat()
+        .assign(rt_field("sp").to_place(), ident("sp"));
+    fx.cx.ctx.stat().ret(values);
+}
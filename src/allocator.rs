@@ -0,0 +1,69 @@
+//! Allocator shim
+//!
+//! The `__rust_alloc` family of functions forwards to the `#[global_allocator]` (`__rg_*`) or to
+//! the default allocator of std (`__rdl_*`).
+
+use rustc_ast::expand::allocator::{AllocatorKind, AllocatorTy, ALLOCATOR_METHODS};
+
+use crate::prelude::*;
+
+/// Defines the allocator shim functions in `ctx`.
+pub(crate) fn codegen(ctx: &mut Context, kind: AllocatorKind, has_alloc_error_handler: bool) {
+    for method in ALLOCATOR_METHODS {
+        let mut params = vec![];
+        for ty in method.inputs.iter() {
+            match *ty {
+                AllocatorTy::Layout => {
+                    params.push(format!("a{}", params.len())); // size
+                    params.push(format!("a{}", params.len())); // align
+                }
+                AllocatorTy::Ptr | AllocatorTy::Usize => params.push(format!("a{}", params.len())),
+                AllocatorTy::ResultPtr | AllocatorTy::Unit => panic!("invalid allocator arg"),
+            }
+        }
+        let returns = match method.output {
+            AllocatorTy::ResultPtr => true,
+            AllocatorTy::Unit => false,
+            AllocatorTy::Layout | AllocatorTy::Usize | AllocatorTy::Ptr => {
+                panic!("invalid allocator output")
+            }
+        };
+
+        let caller_name = format!("__rust_{}", method.name);
+        let callee_name = kind.fn_name(method.name);
+        codegen_forward(ctx, &caller_name, &callee_name, params, returns);
+    }
+
+    let callee_name = if has_alloc_error_handler {
+        "__rg_oom"
+    } else {
+        "__rdl_oom"
+    };
+    codegen_forward(
+        ctx,
+        "__rust_alloc_error_handler",
+        callee_name,
+        vec!["a0".to_string(), "a1".to_string()],
+        false,
+    );
+}
+
+/// Defines `caller_name` as a function calling `callee_name` with the same arguments.
+fn codegen_forward(
+    ctx: &mut Context,
+    caller_name: &str,
+    callee_name: &str,
+    params: Vec<String>,
+    returns: bool,
+) {
+    let args = params.iter().map(|param| ident(param)).collect::<Vec<_>>();
+
+    ctx.start_function_at(fn_symbol(caller_name).to_place(), params);
+    if returns {
+        ctx.stat()
+            .ret(vec![ExprBuilder.call(fn_symbol(callee_name), args)]);
+    } else {
+        ctx.stat().call(fn_symbol(callee_name), args);
+    }
+    ctx.finish_block().unwrap();
+}
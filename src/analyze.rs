@@ -0,0 +1,62 @@
+//! SSA analysis
+
+use rustc_index::vec::IndexVec;
+
+use crate::prelude::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SsaKind {
+    NotSsa,
+    Ssa,
+}
+
+/// Decides which locals can be stored in lua variables instead of memory.
+pub(crate) fn analyze(fx: &FunctionCx<'_, '_>) -> IndexVec<Local, SsaKind> {
+    let mut flag_map = fx
+        .mir
+        .local_decls
+        .iter()
+        .map(|local_decl| {
+            let ty = fx.monomorphize(local_decl.ty);
+            if lua_type(fx.tcx, ty) || lua_pair_type(fx.tcx, ty) {
+                SsaKind::Ssa
+            } else {
+                SsaKind::NotSsa
+            }
+        })
+        .collect::<IndexVec<Local, SsaKind>>();
+
+    for bb in fx.mir.basic_blocks().iter() {
+        for stmt in bb.statements.iter() {
+            if let StatementKind::Assign(place_and_rval) = &stmt.kind {
+                match &place_and_rval.1 {
+                    Rvalue::Ref(_, _, place) | Rvalue::AddressOf(_, place) => {
+                        // Borrowing through a pointer doesn't need the pointer itself to be
+                        // addressable
+                        if !place
+                            .projection
+                            .iter()
+                            .any(|elem| elem == ProjectionElem::Deref)
+                        {
+                            flag_map[place.local] = SsaKind::NotSsa;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Drop glue takes a pointer to the value to drop
+        if let TerminatorKind::Drop { place, .. } = &bb.terminator().kind {
+            if !place
+                .projection
+                .iter()
+                .any(|elem| elem == ProjectionElem::Deref)
+            {
+                flag_map[place.local] = SsaKind::NotSsa;
+            }
+        }
+    }
+
+    flag_map
+}
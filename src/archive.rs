@@ -0,0 +1,62 @@
+//! Reading and writing of the `ar` archives used for rlibs
+//!
+//! Only the common subset of the format is used: member names are short enough to fit in their
+//! header, so no symbol or name table is needed. rustc reads the `lib.rmeta` member with its own
+//! archive reader.
+
+use std::path::Path;
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const HEADER_LEN: usize = 60;
+
+pub(crate) fn write_archive(path: &Path, members: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    let mut out = MAGIC.to_vec();
+    for (name, data) in members {
+        assert!(
+            name.len() < 16,
+            "archive member name `{}` is too long",
+            name
+        );
+        let header = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            format!("{}/", name),
+            0,
+            0,
+            0,
+            644,
+            data.len()
+        );
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(b'\n');
+        }
+    }
+    std::fs::write(path, out)
+}
+
+/// The name and content of the members of an archive written by `write_archive`.
+pub(crate) fn read_archive(data: &[u8]) -> Result<Vec<(String, &[u8])>, String> {
+    let mut rest = data
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| "not an archive".to_string())?;
+    let mut members = vec![];
+    while !rest.is_empty() {
+        if rest.len() < HEADER_LEN {
+            return Err("truncated member header".to_string());
+        }
+        let (header, data) = rest.split_at(HEADER_LEN);
+        let name = String::from_utf8_lossy(&header[..16]);
+        let name = name.trim_end().trim_end_matches('/').to_string();
+        let size = std::str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|size| size.trim_end().parse::<usize>().ok())
+            .ok_or_else(|| format!("invalid size for member `{}`", name))?;
+        if data.len() < size {
+            return Err(format!("truncated member `{}`", name));
+        }
+        members.push((name, &data[..size]));
+        rest = &data[(size + size % 2).min(data.len())..];
+    }
+    Ok(members)
+}
@@ -0,0 +1,851 @@
+//! Codegen of a single function
+
+use std::time::Instant;
+
+use rustc_codegen_ssa::{ModuleCodegen, ModuleKind};
+use rustc_data_structures::stable_hasher::{HashStable, StableHasher};
+use rustc_hir::LangItem;
+use rustc_index::vec::IndexVec;
+use rustc_middle::mir::mono::MonoItem;
+use rustc_middle::ty::adjustment::PointerCast;
+use rustc_session::config::OptLevel;
+use rustc_span::symbol::Symbol;
+
+use crate::prelude::*;
+use crate::{BackendConfig, LuaContext};
+
+pub(crate) fn compile_codegen_unit(
+    tcx: TyCtxt<'_>,
+    cgu_name: Symbol,
+    config: BackendConfig,
+) -> (ModuleCodegen<LuaContext>, u64) {
+    let start_time = Instant::now();
+
+    let dep_node = tcx.codegen_unit(cgu_name).codegen_dep_node(tcx);
+    let (ModuleCodegenResult(module), _) = tcx.dep_graph.with_task(
+        dep_node,
+        tcx,
+        (cgu_name, config),
+        module_codegen,
+        rustc_middle::dep_graph::hash_result,
+    );
+
+    let time_to_codegen = start_time.elapsed();
+
+    // We assume that the cost to run the generated code through the linker is proportional to
+    // the time we needed for generating it.
+    let cost = time_to_codegen.as_secs() * 1_000_000_000 + time_to_codegen.subsec_nanos() as u64;
+
+    (module, cost)
+}
+
+struct ModuleCodegenResult(ModuleCodegen<LuaContext>);
+
+impl<HCX> HashStable<HCX> for ModuleCodegenResult {
+    fn hash_stable(&self, _: &mut HCX, _: &mut StableHasher) {
+        // do nothing
+    }
+}
+
+fn module_codegen(
+    tcx: TyCtxt<'_>,
+    (cgu_name, config): (Symbol, BackendConfig),
+) -> ModuleCodegenResult {
+    let cgu = tcx.codegen_unit(cgu_name);
+    let mono_items = cgu.items_in_deterministic_order(tcx);
+
+    let mut cx = CodegenCx::new(tcx, config);
+    for (mono_item, _) in mono_items {
+        match mono_item {
+            MonoItem::Fn(inst) => {
+                tcx.sess.time("codegen fn", || codegen_fn(&mut cx, inst));
+            }
+            MonoItem::Static(def_id) => crate::constant::codegen_static(tcx, &mut cx, def_id),
+            MonoItem::GlobalAsm(item_id) => {
+                let item = tcx.hir().item(item_id);
+                tcx.sess
+                    .span_fatal(item.span, "global_asm! is not supported by the lua backend");
+            }
+        }
+    }
+
+    crate::main_shim::maybe_create_entry_wrapper(tcx, &mut cx.ctx, cgu.is_primary());
+
+    let CodegenCx {
+        mut ctx,
+        constants_cx,
+        config,
+        ..
+    } = cx;
+    constants_cx.finalize(tcx, &mut ctx);
+    if tcx.sess.opts.optimize != OptLevel::No {
+        tcx.sess.time("fold constants", || ctx.fold_constants());
+        let removed = tcx.sess.time("remove dead code", || ctx.remove_dead_code());
+        if tcx.sess.verbose() {
+            tcx.sess.note_without_error(&format!(
+                "removed {} dead statements from {}",
+                removed, cgu_name
+            ));
+        }
+    }
+
+    ModuleCodegenResult(ModuleCodegen {
+        name: cgu_name.as_str().to_string(),
+        module_llvm: LuaContext { ctx },
+        kind: ModuleKind::Regular,
+    })
+}
+
+pub(crate) fn codegen_fn<'tcx>(cx: &mut CodegenCx<'tcx>, instance: Instance<'tcx>) {
+    let tcx = cx.tcx;
+
+    let mir = tcx.instance_mir(instance.def);
+    let symbol_name = tcx.symbol_name(instance);
+
+    let mut fx = FunctionCx {
+        cx,
+        tcx,
+        instance,
+        symbol_name,
+        mir,
+        local_map: IndexVec::with_capacity(mir.local_decls.len()),
+        caller_location: None,
+        vars_in_table: false,
+        vars: vec![],
+        tmp_count: 0,
+        frame_size: 0,
+        frame_align: 1,
+        exception: None,
+    };
+
+    // The body is generated first, as the size of the stack frame is only known afterwards
+    let outer = std::mem::take(&mut fx.cx.ctx);
+    let params = if let Some(params) = crate::panic::codegen_panic_override(&mut fx) {
+        params
+    } else if crate::constant::check_constants(&mut fx) {
+        let ssa_analyzed = crate::analyze::analyze(&fx);
+        let params = crate::abi::codegen_fn_prelude(&mut fx, &ssa_analyzed);
+        codegen_fn_content(&mut fx);
+        params
+    } else {
+        codegen_trap(&mut fx, "erroneous constant encountered");
+        vec![]
+    };
+    let body = std::mem::replace(&mut fx.cx.ctx, outer);
+
+    if fx.cx.config.source_map {
+        let span = tcx.def_span(instance.def_id());
+        let loc = tcx.sess.source_map().lookup_char_pos(span.lo());
+        fx.cx.ctx.stat().comment(format!(
+            "@ {}:{}:{} {}",
+            loc.file.name.prefer_remapped(),
+            loc.line,
+            loc.col_display + 1,
+            instance
+        ));
+    }
+    fx.cx
+        .ctx
+        .start_function_at(fn_symbol(symbol_name.name).to_place(), params);
+    if fx.vars_in_table {
+        fx.cx
+            .ctx
+            .stat()
+            .local(vec!["L".to_string()], vec![ExprBuilder.table(vec![])]);
+    } else if !fx.vars.is_empty() {
+        fx.cx.ctx.stat().local(fx.vars.clone(), vec![]);
+    }
+    fx.cx
+        .ctx
+        .stat()
+        .local(vec!["sp".to_string()], vec![rt_field("sp")]);
+    if fx.frame_size != 0 {
+        let frame = rt_call(
+            "alloca",
+            [int(fx.frame_size as i64), int(fx.frame_align as i64)],
+        );
+        fx.cx.ctx.stat().local(vec!["fp".to_string()], vec![frame]);
+    }
+    fx.cx.ctx.append(body);
+    fx.cx.ctx.finish_block().unwrap();
+
+    crate::export::maybe_codegen_export(&mut fx);
+}
+
+/// Basic blocks are dispatched on the `bb` variable by a loop around an `if` chain.
+fn codegen_fn_content(fx: &mut FunctionCx<'_, '_>) {
+    fx.cx.ctx.stat().local(vec!["bb".to_string()], vec![int(0)]);
+    fx.cx.ctx.start_while(ExprBuilder.bool(true));
+
+    let unwinding = unwinding_enabled(fx.tcx);
+    let mut first = true;
+    for (bb, bb_data) in fx.mir.basic_blocks().iter_enumerated() {
+        if bb_data.is_cleanup && !unwinding {
+            // Panics abort, so cleanup blocks are never entered
+            continue;
+        }
+
+        let cond = binop(LuaBinOp::Eq, ident("bb"), int(bb.as_u32() as i64));
+        if first {
+            fx.cx.ctx.start_if(cond);
+            first = false;
+        } else {
+            fx.cx.ctx.start_else_if(cond).unwrap();
+        }
+
+        for stmt in &bb_data.statements {
+            codegen_in_scope(fx, |fx| codegen_stmt(fx, stmt));
+        }
+        codegen_in_scope(fx, |fx| codegen_terminator(fx, bb_data.terminator()));
+    }
+
+    fx.cx.ctx.finish_block().unwrap();
+    fx.cx.ctx.finish_block().unwrap();
+}
+
+/// Generates code in a `do ... end` block when it needs temporaries, so that they don't count
+/// against the limit on the number of locals of the function.
+fn codegen_in_scope<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    f: impl FnOnce(&mut FunctionCx<'_, 'tcx>),
+) {
+    let outer = std::mem::take(&mut fx.cx.ctx);
+    let tmp_count = fx.tmp_count;
+    f(fx);
+    let inner = std::mem::replace(&mut fx.cx.ctx, outer);
+
+    if fx.tmp_count != tmp_count {
+        fx.cx.ctx.start_raw_block();
+        fx.cx.ctx.append(inner);
+        fx.cx.ctx.finish_block().unwrap();
+    } else {
+        fx.cx.ctx.append(inner);
+    }
+}
+
+pub(crate) fn codegen_jump(fx: &mut FunctionCx<'_, '_>, target: BasicBlock) {
+    fx.cx
+        .ctx
+        .stat()
+        .assign(ident("bb").to_place(), int(target.as_u32() as i64));
+}
+
+/// Calls `callee` with `args`, passing its first `result_count` results to `on_return`.
+///
+/// When panics unwind and the call has a `cleanup` block, the call is made through `pcall` and a
+/// panic jumps to the cleanup block after storing the error in [`FunctionCx::exception_var`].
+/// The code generated by `on_return` only runs when the call returns normally.
+pub(crate) fn codegen_call_with_unwind<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    callee: Value,
+    args: Vec<Value>,
+    result_count: usize,
+    cleanup: Option<BasicBlock>,
+    on_return: impl FnOnce(&mut FunctionCx<'_, 'tcx>, Vec<Value>),
+) {
+    let cleanup = match cleanup {
+        Some(cleanup) if unwinding_enabled(fx.tcx) => cleanup,
+        _ => {
+            let results = if result_count == 0 {
+                fx.cx.ctx.stat().call(callee, args);
+                vec![]
+            } else {
+                fx.tmp_multi(result_count, ExprBuilder.call(callee, args))
+            };
+            on_return(fx, results);
+            return;
+        }
+    };
+
+    // The frames of the functions being unwound are not popped, restore the stack pointer
+    let sp = fx.tmp(rt_field("sp"));
+    let pcall_args = std::iter::once(callee).chain(args).collect::<Vec<_>>();
+    let mut results = fx.tmp_multi(
+        1 + result_count.max(1),
+        ExprBuilder.call(ident("pcall"), pcall_args),
+    );
+    let ok = results.remove(0);
+
+    fx.cx.ctx.start_if(ExprBuilder.unop(LuaUnOp::Not, ok));
+    fx.cx.ctx.stat().assign(rt_field("sp").to_place(), sp);
+    let exception = fx.exception_var();
+    fx.cx.ctx.stat().assign(exception, results[0].clone());
+    codegen_jump(fx, cleanup);
+    fx.cx.ctx.start_else().unwrap();
+    results.truncate(result_count);
+    on_return(fx, results);
+    fx.cx.ctx.finish_block().unwrap();
+}
+
+/// Aborts the program with `msg`.
+pub(crate) fn codegen_trap(fx: &mut FunctionCx<'_, '_>, msg: &str) {
+    fx.cx
+        .ctx
+        .stat()
+        .call(rt_field("trap"), [ExprBuilder.string(msg.to_string())]);
+}
+
+fn codegen_terminator<'tcx>(fx: &mut FunctionCx<'_, 'tcx>, terminator: &Terminator<'tcx>) {
+    let source_info = terminator.source_info;
+    match &terminator.kind {
+        TerminatorKind::Goto { target } => codegen_jump(fx, *target),
+        TerminatorKind::Return => crate::abi::codegen_return(fx),
+        TerminatorKind::Assert {
+            cond,
+            expected,
+            msg,
+            target,
+            cleanup,
+        } => {
+            if !fx.tcx.sess.overflow_checks() {
+                if let mir::AssertKind::OverflowNeg(_) = *msg {
+                    codegen_jump(fx, *target);
+                    return;
+                }
+            }
+            let cond = codegen_operand(fx, cond).load_scalar(fx);
+            let failed = if *expected {
+                binop(LuaBinOp::Eq, cond, int(0))
+            } else {
+                int_to_bool(cond)
+            };
+
+            fx.cx.ctx.start_if(failed);
+            codegen_assert_panic(fx, msg, source_info.span, *cleanup);
+            fx.cx.ctx.start_else().unwrap();
+            codegen_jump(fx, *target);
+            fx.cx.ctx.finish_block().unwrap();
+        }
+        TerminatorKind::SwitchInt {
+            discr,
+            switch_ty,
+            targets,
+        } => {
+            let discr = codegen_operand(fx, discr).load_scalar(fx);
+            let discr = fx.tmp(discr);
+            let switch_ty = fx.monomorphize(switch_ty);
+            let (bits, _) = int_ty_bits(fx.tcx, switch_ty).unwrap();
+
+            let mut first = true;
+            for (value, target) in targets.iter() {
+                let value = lua_int_const(fx.tcx, switch_ty, value);
+                let cond = if bits == 128 {
+                    rt_call("eq128", [discr.clone(), value])
+                } else {
+                    binop(LuaBinOp::Eq, discr.clone(), value)
+                };
+                if first {
+                    fx.cx.ctx.start_if(cond);
+                    first = false;
+                } else {
+                    fx.cx.ctx.start_else_if(cond).unwrap();
+                }
+                codegen_jump(fx, target);
+            }
+
+            if first {
+                codegen_jump(fx, targets.otherwise());
+            } else {
+                fx.cx.ctx.start_else().unwrap();
+                codegen_jump(fx, targets.otherwise());
+                fx.cx.ctx.finish_block().unwrap();
+            }
+        }
+        TerminatorKind::Call {
+            func,
+            args,
+            destination,
+            fn_span,
+            cleanup,
+            from_hir_call: _,
+        } => {
+            fx.tcx.sess.time("codegen call", || {
+                crate::abi::codegen_terminator_call(
+                    fx,
+                    *fn_span,
+                    func,
+                    args,
+                    *destination,
+                    *cleanup,
+                )
+            });
+        }
+        TerminatorKind::InlineAsm { .. } => {
+            fx.tcx.sess.span_fatal(
+                source_info.span,
+                "inline assembly is not supported by the lua backend",
+            );
+        }
+        TerminatorKind::Resume => {
+            if unwinding_enabled(fx.tcx) {
+                let exception = fx.exception_var();
+                fx.cx
+                    .ctx
+                    .stat()
+                    .call(ident("error"), [ExprBuilder.get_place(exception), int(0)]);
+            } else {
+                codegen_trap(fx, "unwinding is not supported with -Cpanic=abort");
+            }
+        }
+        TerminatorKind::Abort => {
+            codegen_trap(fx, "panic in a function that cannot unwind");
+        }
+        TerminatorKind::Unreachable => {
+            codegen_trap(fx, "entered unreachable code");
+        }
+        TerminatorKind::Drop {
+            place,
+            target,
+            unwind,
+        } => {
+            let drop_place = codegen_place(fx, *place);
+            crate::abi::codegen_drop(fx, source_info.span, drop_place, *target, *unwind);
+        }
+        TerminatorKind::Yield { .. }
+        | TerminatorKind::FalseEdge { .. }
+        | TerminatorKind::FalseUnwind { .. }
+        | TerminatorKind::DropAndReplace { .. }
+        | TerminatorKind::GeneratorDrop => {
+            bug!("shouldn't exist at codegen {:?}", terminator);
+        }
+    }
+}
+
+fn codegen_stmt<'tcx>(fx: &mut FunctionCx<'_, 'tcx>, stmt: &Statement<'tcx>) {
+    match &stmt.kind {
+        StatementKind::SetDiscriminant {
+            place,
+            variant_index,
+        } => {
+            let place = codegen_place(fx, **place);
+            crate::discriminant::codegen_set_discriminant(fx, place, *variant_index);
+        }
+        StatementKind::Assign(to_place_and_rval) => {
+            let lval = codegen_place(fx, to_place_and_rval.0);
+            let dest_layout = lval.layout();
+            match &to_place_and_rval.1 {
+                Rvalue::Use(operand) => {
+                    let val = codegen_operand(fx, operand);
+                    lval.write_cvalue(fx, val);
+                }
+                Rvalue::Ref(_, _, place) | Rvalue::AddressOf(_, place) => {
+                    let place = codegen_place(fx, *place);
+                    let ref_ = place.place_ref(fx, lval.layout());
+                    lval.write_cvalue(fx, ref_);
+                }
+                Rvalue::ThreadLocalRef(def_id) => {
+                    // There is only one thread, so thread locals are ordinary statics
+                    let ptr = data_symbol(&crate::constant::static_symbol(fx.tcx, *def_id));
+                    lval.write_cvalue(fx, CValue::by_val(ptr, dest_layout));
+                }
+                Rvalue::BinaryOp(bin_op, lhs_rhs) => {
+                    let lhs = codegen_operand(fx, &lhs_rhs.0);
+                    let rhs = codegen_operand(fx, &lhs_rhs.1);
+
+                    let res = crate::num::codegen_binop(fx, *bin_op, lhs, rhs);
+                    lval.write_cvalue(fx, res);
+                }
+                Rvalue::CheckedBinaryOp(bin_op, lhs_rhs) => {
+                    let lhs = codegen_operand(fx, &lhs_rhs.0);
+                    let rhs = codegen_operand(fx, &lhs_rhs.1);
+
+                    let res = if !fx.tcx.sess.overflow_checks() {
+                        let val = crate::num::codegen_binop(fx, *bin_op, lhs, rhs).load_scalar(fx);
+                        CValue::by_val_pair(val, int(0), lval.layout())
+                    } else {
+                        crate::num::codegen_checked_int_binop(fx, *bin_op, lhs, rhs)
+                    };
+
+                    lval.write_cvalue(fx, res);
+                }
+                Rvalue::UnaryOp(un_op, operand) => {
+                    let operand = codegen_operand(fx, operand);
+                    let layout = operand.layout();
+                    let val = operand.load_scalar(fx);
+                    let res = match (un_op, layout.ty.kind()) {
+                        (UnOp::Not, ty::Bool) => binop(LuaBinOp::BXor, val, int(1)),
+                        (UnOp::Not, ty::Uint(_) | ty::Int(_)) => {
+                            crate::num::codegen_int_not(fx, layout.ty, val)
+                        }
+                        (UnOp::Neg, ty::Int(_)) => crate::num::codegen_int_neg(fx, layout.ty, val),
+                        (UnOp::Neg, ty::Float(_)) => ExprBuilder.unop(LuaUnOp::Neg, val),
+                        _ => unreachable!("un op {:?} for type {:?}", un_op, layout.ty),
+                    };
+                    lval.write_cvalue(fx, CValue::by_val(res, layout));
+                }
+                Rvalue::Cast(CastKind::Pointer(PointerCast::ReifyFnPointer), operand, to_ty) => {
+                    let from_ty = fx.monomorphize(operand.ty(&fx.mir.local_decls, fx.tcx));
+                    let to_layout = fx.layout_of(fx.monomorphize(to_ty));
+                    match *from_ty.kind() {
+                        ty::FnDef(def_id, substs) => {
+                            let instance = Instance::resolve_for_fn_ptr(
+                                fx.tcx,
+                                ParamEnv::reveal_all(),
+                                def_id,
+                                substs,
+                            )
+                            .unwrap()
+                            .polymorphize(fx.tcx);
+                            let func_addr = crate::constant::fn_ptr_ref(fx.tcx, instance);
+                            lval.write_cvalue(fx, CValue::by_val(func_addr, to_layout));
+                        }
+                        _ => bug!("Trying to ReifyFnPointer on non FnDef {:?}", from_ty),
+                    }
+                }
+                Rvalue::Cast(CastKind::Pointer(PointerCast::UnsafeFnPointer), operand, to_ty)
+                | Rvalue::Cast(CastKind::Pointer(PointerCast::MutToConstPointer), operand, to_ty)
+                | Rvalue::Cast(CastKind::Pointer(PointerCast::ArrayToPointer), operand, to_ty) => {
+                    let to_layout = fx.layout_of(fx.monomorphize(to_ty));
+                    let operand = codegen_operand(fx, operand);
+                    lval.write_cvalue(fx, operand.transmute(to_layout));
+                }
+                Rvalue::Cast(CastKind::Misc, operand, to_ty) => {
+                    let operand = codegen_operand(fx, operand);
+                    let from_ty = operand.layout().ty;
+                    let to_ty = fx.monomorphize(to_ty);
+
+                    fn is_fat_ptr<'tcx>(fx: &FunctionCx<'_, 'tcx>, ty: Ty<'tcx>) -> bool {
+                        ty.builtin_deref(true)
+                            .map(
+                                |TypeAndMut {
+                                     ty: pointee_ty,
+                                     mutbl: _,
+                                 }| {
+                                    has_ptr_meta(fx.tcx, pointee_ty)
+                                },
+                            )
+                            .unwrap_or(false)
+                    }
+
+                    if is_fat_ptr(fx, from_ty) {
+                        if is_fat_ptr(fx, to_ty) {
+                            // fat-ptr -> fat-ptr
+                            lval.write_cvalue(fx, operand.transmute(dest_layout));
+                        } else {
+                            // fat-ptr -> thin-ptr
+                            let (ptr, _extra) = operand.load_scalar_pair(fx);
+                            lval.write_cvalue(fx, CValue::by_val(ptr, dest_layout))
+                        }
+                    } else if let ty::Adt(adt_def, _substs) = from_ty.kind() {
+                        // enum -> discriminant value
+                        assert!(adt_def.is_enum());
+                        let discr_ty = from_ty.discriminant_ty(fx.tcx);
+                        let discr_layout = fx.layout_of(discr_ty);
+                        let discr = crate::discriminant::codegen_get_discriminant(
+                            fx,
+                            operand,
+                            discr_layout,
+                        )
+                        .load_scalar(fx);
+                        let res =
+                            crate::cast::codegen_int_or_float_cast(fx, discr, discr_ty, to_ty);
+                        lval.write_cvalue(fx, CValue::by_val(res, dest_layout));
+                    } else {
+                        let from = operand.load_scalar(fx);
+                        let res = crate::cast::codegen_int_or_float_cast(fx, from, from_ty, to_ty);
+                        lval.write_cvalue(fx, CValue::by_val(res, dest_layout));
+                    }
+                }
+                Rvalue::Cast(
+                    CastKind::Pointer(PointerCast::ClosureFnPointer(_)),
+                    operand,
+                    _to_ty,
+                ) => {
+                    let operand = codegen_operand(fx, operand);
+                    match *operand.layout().ty.kind() {
+                        ty::Closure(def_id, substs) => {
+                            let instance = Instance::resolve_closure(
+                                fx.tcx,
+                                def_id,
+                                substs,
+                                ty::ClosureKind::FnOnce,
+                            )
+                            .polymorphize(fx.tcx);
+                            let func_addr = crate::constant::fn_ptr_ref(fx.tcx, instance);
+                            lval.write_cvalue(fx, CValue::by_val(func_addr, dest_layout));
+                        }
+                        _ => bug!("{} cannot be cast to a fn ptr", operand.layout().ty),
+                    }
+                }
+                Rvalue::Cast(CastKind::Pointer(PointerCast::Unsize), operand, _to_ty) => {
+                    let operand = codegen_operand(fx, operand);
+                    crate::unsize::coerce_unsized_into(fx, operand, lval);
+                }
+                Rvalue::Discriminant(place) => {
+                    let place = codegen_place(fx, *place);
+                    let value = place.to_cvalue(fx);
+                    let discr =
+                        crate::discriminant::codegen_get_discriminant(fx, value, dest_layout);
+                    lval.write_cvalue(fx, discr);
+                }
+                Rvalue::Repeat(operand, times) => {
+                    let operand = codegen_operand(fx, operand);
+                    let times = fx
+                        .monomorphize(times)
+                        .eval_usize(fx.tcx, ParamEnv::reveal_all());
+                    let elem_size = operand.layout().size.bytes();
+                    if elem_size != 0 && times != 0 {
+                        let (src, _) = operand.force_stack(fx);
+                        fx.cx.ctx.stat().call(
+                            rt_field("memrep"),
+                            [lval.to_ptr(), src, int(elem_size as i64), int(times as i64)],
+                        );
+                    }
+                }
+                Rvalue::Len(place) => {
+                    let place = codegen_place(fx, *place);
+                    let usize_layout = fx.layout_of(fx.tcx.types.usize);
+                    let len = codegen_array_len(fx, place);
+                    lval.write_cvalue(fx, CValue::by_val(len, usize_layout));
+                }
+                Rvalue::NullaryOp(NullOp::Box, content_ty) => {
+                    let content_ty = fx.monomorphize(content_ty);
+                    let layout = fx.layout_of(content_ty);
+                    let llsize = int(layout.size.bytes() as i64);
+                    let llalign = int(layout.align.abi.bytes() as i64);
+                    let box_layout = fx.layout_of(fx.tcx.mk_box(content_ty));
+
+                    // Allocate space:
+                    let def_id = match fx.tcx.lang_items().require(LangItem::ExchangeMalloc) {
+                        Ok(id) => id,
+                        Err(s) => {
+                            fx.tcx
+                                .sess
+                                .fatal(&format!("allocation of `{}` {}", box_layout.ty, s));
+                        }
+                    };
+                    let instance = Instance::mono(fx.tcx, def_id).polymorphize(fx.tcx);
+                    let func = fn_symbol(fx.tcx.symbol_name(instance).name);
+                    let ptr = fx
+                        .tmp_multi(1, ExprBuilder.call(func, [llsize, llalign]))
+                        .pop()
+                        .unwrap();
+                    lval.write_cvalue(fx, CValue::by_val(ptr, box_layout));
+                }
+                Rvalue::NullaryOp(NullOp::SizeOf, ty) => {
+                    assert!(lval
+                        .layout()
+                        .ty
+                        .is_sized(fx.tcx.at(stmt.source_info.span), ParamEnv::reveal_all()));
+                    let ty_size = fx.layout_of(fx.monomorphize(ty)).size.bytes();
+                    let val = CValue::by_val(int(ty_size as i64), fx.layout_of(fx.tcx.types.usize));
+                    lval.write_cvalue(fx, val);
+                }
+                Rvalue::Aggregate(kind, operands) => match kind.as_ref() {
+                    AggregateKind::Array(_ty) => {
+                        for (i, operand) in operands.iter().enumerate() {
+                            let operand = codegen_operand(fx, operand);
+                            let to = lval.clone().place_index(fx, int(i as i64));
+                            to.write_cvalue(fx, operand);
+                        }
+                    }
+                    _ => {
+                        let (variant_index, variant_dest, active_field_index) = match **kind {
+                            AggregateKind::Adt(_, variant_index, _, _, active_field_index) => {
+                                let variant_dest = lval.clone().downcast_variant(fx, variant_index);
+                                (variant_index, variant_dest, active_field_index)
+                            }
+                            _ => (VariantIdx::from_u32(0), lval.clone(), None),
+                        };
+                        for (i, operand) in operands.iter().enumerate() {
+                            let operand = codegen_operand(fx, operand);
+                            let field_index = active_field_index.unwrap_or(i);
+                            let to = variant_dest
+                                .clone()
+                                .place_field(fx, mir::Field::new(field_index));
+                            to.write_cvalue(fx, operand);
+                        }
+                        crate::discriminant::codegen_set_discriminant(fx, lval, variant_index);
+                    }
+                },
+            }
+        }
+        StatementKind::StorageLive(_)
+        | StatementKind::StorageDead(_)
+        | StatementKind::Nop
+        | StatementKind::FakeRead(..)
+        | StatementKind::Retag { .. }
+        | StatementKind::AscribeUserType(..) => {}
+
+        StatementKind::LlvmInlineAsm(_) => {
+            fx.tcx.sess.span_fatal(
+                stmt.source_info.span,
+                "inline assembly is not supported by the lua backend",
+            );
+        }
+        StatementKind::Coverage { .. } => fx.tcx.sess.fatal("-Zcoverage is unimplemented"),
+        StatementKind::CopyNonOverlapping(inner) => {
+            let dst = codegen_operand(fx, &inner.dst);
+            let pointee = dst
+                .layout()
+                .ty
+                .builtin_deref(true)
+                .expect("Expected pointer")
+                .ty;
+            let elem_size = fx.layout_of(pointee).size.bytes();
+            let dst = dst.load_scalar(fx);
+            let src = codegen_operand(fx, &inner.src).load_scalar(fx);
+            let count = codegen_operand(fx, &inner.count).load_scalar(fx);
+            let bytes = if elem_size != 1 {
+                binop(LuaBinOp::Mul, count, int(elem_size as i64))
+            } else {
+                count
+            };
+            fx.cx.ctx.stat().call(rt_field("memcpy"), [dst, src, bytes]);
+        }
+    }
+}
+
+pub(crate) fn codegen_array_len<'tcx>(fx: &mut FunctionCx<'_, 'tcx>, place: CPlace<'tcx>) -> Value {
+    match *place.layout().ty.kind() {
+        ty::Array(_elem_ty, len) => {
+            let len = fx
+                .monomorphize(len)
+                .eval_usize(fx.tcx, ParamEnv::reveal_all()) as i64;
+            int(len)
+        }
+        ty::Slice(_elem_ty) => place
+            .to_ptr_maybe_unsized()
+            .1
+            .expect("Length metadata for slice place"),
+        _ => bug!("Rvalue::Len({:?})", place),
+    }
+}
+
+pub(crate) fn codegen_place<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    place: Place<'tcx>,
+) -> CPlace<'tcx> {
+    let mut cplace = fx.get_local_place(place.local);
+
+    for elem in place.projection {
+        match elem {
+            PlaceElem::Deref => {
+                cplace = cplace.place_deref(fx);
+            }
+            PlaceElem::Field(field, _ty) => {
+                cplace = cplace.place_field(fx, field);
+            }
+            PlaceElem::Index(local) => {
+                let index = fx.get_local_place(local).to_cvalue(fx).load_scalar(fx);
+                cplace = cplace.place_index(fx, index);
+            }
+            PlaceElem::ConstantIndex {
+                offset,
+                min_length: _,
+                from_end,
+            } => {
+                let offset = offset as i64;
+                let index = if !from_end {
+                    int(offset)
+                } else {
+                    let len = codegen_array_len(fx, cplace.clone());
+                    binop(LuaBinOp::Sub, len, int(offset))
+                };
+                cplace = cplace.place_index(fx, index);
+            }
+            PlaceElem::Subslice { from, to, from_end } => {
+                // These indices are generated by slice patterns.
+                // slice[from:-to] in Python terms.
+
+                match cplace.layout().ty.kind() {
+                    ty::Array(elem_ty, _len) => {
+                        assert!(!from_end, "array subslices are never `from_end`");
+                        let elem_layout = fx.layout_of(elem_ty);
+                        let ptr = cplace.to_ptr();
+                        cplace = CPlace::for_ptr(
+                            ptr_offset(ptr, (elem_layout.size.bytes() * from) as i64),
+                            fx.layout_of(fx.tcx.mk_array(elem_ty, to - from)),
+                        );
+                    }
+                    ty::Slice(elem_ty) => {
+                        assert!(from_end, "slice subslices should be `from_end`");
+                        let elem_layout = fx.layout_of(elem_ty);
+                        let (ptr, len) = cplace.to_ptr_maybe_unsized();
+                        let len = len.unwrap();
+                        cplace = CPlace::for_ptr_with_extra(
+                            ptr_offset(ptr, (elem_layout.size.bytes() * from) as i64),
+                            binop(LuaBinOp::Sub, len, int((from + to) as i64)),
+                            cplace.layout(),
+                        );
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            PlaceElem::Downcast(_adt_def, variant) => {
+                cplace = cplace.downcast_variant(fx, variant);
+            }
+        }
+    }
+
+    cplace
+}
+
+pub(crate) fn codegen_operand<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    operand: &Operand<'tcx>,
+) -> CValue<'tcx> {
+    match operand {
+        Operand::Move(place) | Operand::Copy(place) => {
+            let cplace = codegen_place(fx, *place);
+            cplace.to_cvalue(fx)
+        }
+        Operand::Constant(const_) => crate::constant::codegen_constant(fx, const_),
+    }
+}
+
+fn codegen_assert_panic<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    msg: &AssertKind<Operand<'tcx>>,
+    span: Span,
+    cleanup: Option<BasicBlock>,
+) {
+    let location = fx.get_caller_location(span).load_scalar(fx);
+
+    let (lang_item, args) = match msg {
+        AssertKind::BoundsCheck { len, index } => {
+            let len = codegen_operand(fx, len).load_scalar(fx);
+            let index = codegen_operand(fx, index).load_scalar(fx);
+            (LangItem::PanicBoundsCheck, vec![index, len, location])
+        }
+        _ => {
+            let (msg_ptr, msg_len) = crate::constant::codegen_const_str(fx, msg.description());
+            (LangItem::Panic, vec![msg_ptr, msg_len, location])
+        }
+    };
+
+    codegen_panic_inner(fx, lang_item, args, span, cleanup);
+}
+
+pub(crate) fn codegen_panic<'tcx>(fx: &mut FunctionCx<'_, 'tcx>, msg_str: &str, span: Span) {
+    let location = fx.get_caller_location(span).load_scalar(fx);
+    let (msg_ptr, msg_len) = crate::constant::codegen_const_str(fx, msg_str);
+    codegen_panic_inner(
+        fx,
+        LangItem::Panic,
+        vec![msg_ptr, msg_len, location],
+        span,
+        None,
+    );
+}
+
+fn codegen_panic_inner<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    lang_item: LangItem,
+    args: Vec<Value>,
+    span: Span,
+    cleanup: Option<BasicBlock>,
+) {
+    let def_id = fx
+        .tcx
+        .lang_items()
+        .require(lang_item)
+        .unwrap_or_else(|s| fx.tcx.sess.span_fatal(span, &s));
+
+    let instance = Instance::mono(fx.tcx, def_id).polymorphize(fx.tcx);
+    let symbol_name = fx.tcx.symbol_name(instance).name;
+
+    codegen_call_with_unwind(fx, fn_symbol(symbol_name), args, 0, cleanup, |fx, _| {
+        codegen_trap(fx, "panic function returned");
+    });
+}
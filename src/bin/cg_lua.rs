@@ -36,8 +36,12 @@ impl rustc_driver::Callbacks for LuaPassesCallback {
         // If a --prints=... option has been given, we don't print the "total"
         // time because it will mess up the --prints output. See #64339.
 
-        config.opts.cg.panic = Some(PanicStrategy::Abort);
-        config.opts.debugging_opts.panic_abort_tests = true;
+        // Unwinding is opt-in with `-Cpanic=unwind`
+        if config.opts.cg.panic.is_none() {
+            config.opts.cg.panic = Some(PanicStrategy::Abort);
+        }
+        config.opts.debugging_opts.panic_abort_tests =
+            config.opts.cg.panic == Some(PanicStrategy::Abort);
         config.opts.maybe_sysroot = Some(config.opts.maybe_sysroot.clone().unwrap_or_else(|| {
             std::env::current_exe()
                 .unwrap()
@@ -33,8 +33,12 @@ impl rustc_driver::Callbacks for LuaPassesCallbacks {
             return;
         }
 
-        config.opts.cg.panic = Some(PanicStrategy::Abort);
-        config.opts.debugging_opts.panic_abort_tests = true;
+        // Unwinding is opt-in with `-Cpanic=unwind`
+        if config.opts.cg.panic.is_none() {
+            config.opts.cg.panic = Some(PanicStrategy::Abort);
+        }
+        config.opts.debugging_opts.panic_abort_tests =
+            config.opts.cg.panic == Some(PanicStrategy::Abort);
         config.opts.maybe_sysroot = Some(
             std::env::current_exe()
                 .unwrap()
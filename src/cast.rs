@@ -0,0 +1,92 @@
+//! Various number casting functions
+
+use crate::num::wrap_int;
+use crate::prelude::*;
+
+/// Casts the integer `from` of `from_bits` bits to an integer of `to_bits` bits, truncating or
+/// extending it as rust's `as` does.
+pub(crate) fn codegen_intcast(
+    fx: &mut FunctionCx<'_, '_>,
+    from: Value,
+    (from_bits, from_signed): (u64, bool),
+    (to_bits, to_signed): (u64, bool),
+) -> Value {
+    match (from_bits, to_bits) {
+        // Both halves are kept as is, the sign only matters when extending
+        (128, 128) => from,
+        (128, _) => {
+            let from = fx.tmp(from);
+            let lo = ExprBuilder.table_access(from.to_place(), int(1));
+            wrap_int(lo, to_bits, to_signed)
+        }
+        (_, 128) => rt_call("to128", [from, ExprBuilder.bool(from_signed)]),
+        _ => {
+            // 64 bit integers are stored as their two's complement bit pattern, which is what
+            // extending a narrower integer to 64 bits produces.
+            let fits = to_bits == 64
+                || (from_signed == to_signed && to_bits >= from_bits)
+                || (!from_signed && to_signed && to_bits > from_bits);
+            if fits {
+                from
+            } else {
+                wrap_int(from, to_bits, to_signed)
+            }
+        }
+    }
+}
+
+/// Casts between integers and floats, including the saturating float to integer casts.
+pub(crate) fn codegen_int_or_float_cast<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    from: Value,
+    from_ty: Ty<'tcx>,
+    to_ty: Ty<'tcx>,
+) -> Value {
+    let from_int = int_ty_bits(fx.tcx, from_ty);
+    let to_int = int_ty_bits(fx.tcx, to_ty);
+
+    match (from_int, to_int) {
+        (Some(from_int), Some(to_int)) => codegen_intcast(fx, from, from_int, to_int),
+        (Some((from_bits, from_signed)), None) => {
+            // int -> float
+            let to_f32 = to_ty == fx.tcx.types.f32;
+            let mantissa = if to_f32 { 24 } else { 53 };
+            if from_bits == 128 {
+                rt_call(
+                    "itof128",
+                    [from, ExprBuilder.bool(from_signed), int(mantissa)],
+                )
+            } else if from_bits < 64 || (from_signed && !to_f32) {
+                // The conversion of lua rounds to the nearest double, which is exact for integers
+                // narrower than 64 bits, leaving a single rounding to f32.
+                let res = binop(LuaBinOp::Add, from, ExprBuilder.double(0.0));
+                if to_f32 && from_bits > 24 {
+                    rt_call("fround", [res])
+                } else {
+                    res
+                }
+            } else {
+                rt_call("itof", [from, ExprBuilder.bool(from_signed), int(mantissa)])
+            }
+        }
+        (None, Some((to_bits, to_signed))) => {
+            // float -> int, saturating with NaN mapping to 0
+            if to_bits == 128 {
+                rt_call("ftoi128", [from, ExprBuilder.bool(to_signed)])
+            } else {
+                rt_call(
+                    "ftoi",
+                    [from, int(to_bits as i64), ExprBuilder.bool(to_signed)],
+                )
+            }
+        }
+        (None, None) => {
+            // float -> float
+            if from_ty == fx.tcx.types.f64 && to_ty == fx.tcx.types.f32 {
+                rt_call("fround", [from])
+            } else {
+                from
+            }
+        }
+    }
+}
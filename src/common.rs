@@ -0,0 +1,393 @@
+use rustc_index::vec::IndexVec;
+use rustc_middle::ty::layout::{LayoutError, TyAndLayout};
+use rustc_target::abi::{Integer, Primitive};
+use rustc_target::spec::{HasTargetSpec, PanicStrategy, Target};
+
+use crate::constant::ConstantCx;
+use crate::prelude::*;
+use crate::BackendConfig;
+
+/// Per codegen unit state
+pub(crate) struct CodegenCx<'tcx> {
+    pub(crate) tcx: TyCtxt<'tcx>,
+    pub(crate) config: BackendConfig,
+    pub(crate) ctx: Context,
+    pub(crate) constants_cx: ConstantCx,
+}
+
+impl<'tcx> CodegenCx<'tcx> {
+    pub(crate) fn new(tcx: TyCtxt<'tcx>, config: BackendConfig) -> Self {
+        CodegenCx {
+            tcx,
+            config,
+            ctx: Context::new(),
+            constants_cx: ConstantCx::new(),
+        }
+    }
+}
+
+/// Per function state
+pub(crate) struct FunctionCx<'m, 'tcx: 'm> {
+    pub(crate) cx: &'m mut CodegenCx<'tcx>,
+    pub(crate) tcx: TyCtxt<'tcx>,
+
+    pub(crate) instance: Instance<'tcx>,
+    pub(crate) symbol_name: SymbolName<'tcx>,
+    pub(crate) mir: &'tcx Body<'tcx>,
+
+    pub(crate) local_map: IndexVec<Local, CPlace<'tcx>>,
+
+    /// When `#[track_caller]` is used, the implicit caller location is stored in this variable.
+    pub(crate) caller_location: Option<CValue<'tcx>>,
+
+    /// Whether locals live in a table instead of lua locals, see [`FunctionCx::new_var`].
+    pub(crate) vars_in_table: bool,
+    pub(crate) vars: Vec<String>,
+    /// Number of temporaries declared by the statement currently being generated.
+    pub(crate) tmp_count: usize,
+
+    /// Size and alignment of the stack frame holding the locals that live in memory, which is
+    /// allocated once on entry and addressed through `fp`.
+    pub(crate) frame_size: u64,
+    pub(crate) frame_align: u64,
+
+    /// The variable holding the error being unwound while cleanup blocks run, see
+    /// [`FunctionCx::exception_var`].
+    pub(crate) exception: Option<LuaPlace>,
+}
+
+impl<'tcx> LayoutOf for FunctionCx<'_, 'tcx> {
+    type Ty = Ty<'tcx>;
+    type TyAndLayout = TyAndLayout<'tcx>;
+
+    fn layout_of(&self, ty: Ty<'tcx>) -> TyAndLayout<'tcx> {
+        RevealAllLayoutCx(self.tcx).layout_of(ty)
+    }
+}
+
+impl<'tcx> layout::HasTyCtxt<'tcx> for FunctionCx<'_, 'tcx> {
+    fn tcx<'b>(&'b self) -> TyCtxt<'tcx> {
+        self.tcx
+    }
+}
+
+impl<'tcx> rustc_target::abi::HasDataLayout for FunctionCx<'_, 'tcx> {
+    fn data_layout(&self) -> &rustc_target::abi::TargetDataLayout {
+        &self.tcx.data_layout
+    }
+}
+
+impl<'tcx> layout::HasParamEnv<'tcx> for FunctionCx<'_, 'tcx> {
+    fn param_env(&self) -> ParamEnv<'tcx> {
+        ParamEnv::reveal_all()
+    }
+}
+
+impl<'tcx> HasTargetSpec for FunctionCx<'_, 'tcx> {
+    fn target_spec(&self) -> &Target {
+        &self.tcx.sess.target
+    }
+}
+
+impl<'tcx> FunctionCx<'_, 'tcx> {
+    pub(crate) fn monomorphize<T>(&self, value: T) -> T
+    where
+        T: TypeFoldable<'tcx> + Copy,
+    {
+        self.instance.subst_mir_and_normalize_erasing_regions(
+            self.tcx,
+            ty::ParamEnv::reveal_all(),
+            value,
+        )
+    }
+
+    pub(crate) fn pointer_size(&self) -> u64 {
+        self.tcx.data_layout.pointer_size.bytes()
+    }
+
+    pub(crate) fn get_local_place(&mut self, local: Local) -> CPlace<'tcx> {
+        self.local_map[local].clone()
+    }
+
+    /// Allocates a variable living for the whole function.
+    ///
+    /// Lua limits the number of locals of a function to 200, so functions with many locals store
+    /// them in the `L` table instead.
+    pub(crate) fn new_var(&mut self, name: String) -> LuaPlace {
+        if self.vars_in_table {
+            let index = self.vars.len() as i64 + 1;
+            self.vars.push(name);
+            let table = self.cx.ctx.declare("L".to_string());
+            ExprBuilder
+                .table_access(table, ExprBuilder.int(index))
+                .to_place()
+        } else {
+            self.vars.push(name.clone());
+            self.cx.ctx.declare(name)
+        }
+    }
+
+    /// The variable storing the error caught by a call with a cleanup block, which `Resume`
+    /// raises again once the cleanup is done.
+    pub(crate) fn exception_var(&mut self) -> LuaPlace {
+        if let Some(exception) = &self.exception {
+            return exception.clone();
+        }
+        let exception = self.new_var("exc".to_string());
+        self.exception = Some(exception.clone());
+        exception
+    }
+
+    /// Reserves space in the stack frame of the function, returning its address.
+    pub(crate) fn stack_slot(&mut self, size: u64, align: u64) -> Value {
+        let offset = (self.frame_size + align - 1) / align * align;
+        self.frame_size = offset + size;
+        self.frame_align = self.frame_align.max(align);
+        ptr_offset(ident("fp"), offset as i64)
+    }
+
+    /// Stores `value` in a fresh local scoped to the statement being generated.
+    pub(crate) fn tmp(&mut self, value: Value) -> Value {
+        if value.is_trivial() {
+            return value;
+        }
+        let name = self.fresh_tmp_name();
+        self.cx.ctx.stat().local(vec![name.clone()], vec![value]);
+        ExprBuilder.ident(name)
+    }
+
+    /// Stores all the results of the call `value` in fresh locals.
+    pub(crate) fn tmp_multi(&mut self, count: usize, value: Value) -> Vec<Value> {
+        let names = (0..count)
+            .map(|_| self.fresh_tmp_name())
+            .collect::<Vec<_>>();
+        self.cx.ctx.stat().local(names.clone(), vec![value]);
+        names
+            .into_iter()
+            .map(|name| ExprBuilder.ident(name))
+            .collect()
+    }
+
+    fn fresh_tmp_name(&mut self) -> String {
+        self.tmp_count += 1;
+        format!("t{}", self.tmp_count)
+    }
+
+    pub(crate) fn get_caller_location(&mut self, span: Span) -> CValue<'tcx> {
+        if let Some(loc) = &self.caller_location {
+            // `#[track_caller]` is used; return caller location instead of current location.
+            return loc.clone();
+        }
+
+        let topmost = span.ctxt().outer_expn().expansion_cause().unwrap_or(span);
+        let caller = self.tcx.sess.source_map().lookup_char_pos(topmost.lo());
+        let const_loc = self.tcx.const_caller_location((
+            rustc_span::symbol::Symbol::intern(
+                &caller.file.name.prefer_remapped().to_string_lossy(),
+            ),
+            caller.line as u32,
+            caller.col_display as u32 + 1,
+        ));
+        crate::constant::codegen_const_value(self, const_loc, self.tcx.caller_location_ty())
+    }
+}
+
+pub(crate) struct RevealAllLayoutCx<'tcx>(pub(crate) TyCtxt<'tcx>);
+
+impl<'tcx> LayoutOf for RevealAllLayoutCx<'tcx> {
+    type Ty = Ty<'tcx>;
+    type TyAndLayout = TyAndLayout<'tcx>;
+
+    fn layout_of(&self, ty: Ty<'tcx>) -> TyAndLayout<'tcx> {
+        assert!(!ty.still_further_specializable());
+        self.0
+            .layout_of(ParamEnv::reveal_all().and(ty))
+            .unwrap_or_else(|e| {
+                if let LayoutError::SizeOverflow(_) = e {
+                    self.0.sess.fatal(&e.to_string())
+                } else {
+                    bug!("failed to get layout for `{}`: {}", ty, e)
+                }
+            })
+    }
+}
+
+impl<'tcx> layout::HasTyCtxt<'tcx> for RevealAllLayoutCx<'tcx> {
+    fn tcx<'b>(&'b self) -> TyCtxt<'tcx> {
+        self.0
+    }
+}
+
+impl<'tcx> rustc_target::abi::HasDataLayout for RevealAllLayoutCx<'tcx> {
+    fn data_layout(&self) -> &rustc_target::abi::TargetDataLayout {
+        &self.0.data_layout
+    }
+}
+
+impl<'tcx> layout::HasParamEnv<'tcx> for RevealAllLayoutCx<'tcx> {
+    fn param_env(&self) -> ParamEnv<'tcx> {
+        ParamEnv::reveal_all()
+    }
+}
+
+impl<'tcx> HasTargetSpec for RevealAllLayoutCx<'tcx> {
+    fn target_spec(&self) -> &Target {
+        &self.0.sess.target
+    }
+}
+
+/// Can values of `ty` be kept in a single lua variable
+pub(crate) fn lua_type<'tcx>(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>) -> bool {
+    match ty.kind() {
+        ty::Bool | ty::Char | ty::Int(_) | ty::Uint(_) | ty::Float(_) | ty::FnPtr(_) => true,
+        ty::RawPtr(TypeAndMut { ty: pointee_ty, .. }) | ty::Ref(_, pointee_ty, _) => {
+            !has_ptr_meta(tcx, pointee_ty)
+        }
+        _ => false,
+    }
+}
+
+/// Can values of `ty` be kept in a pair of lua variables
+pub(crate) fn lua_pair_type<'tcx>(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>) -> bool {
+    match ty.kind() {
+        ty::Tuple(substs) if substs.len() == 2 => {
+            substs.types().all(|ty| lua_type(tcx, ty))
+                && matches!(
+                    RevealAllLayoutCx(tcx).layout_of(ty).abi,
+                    Abi::ScalarPair(..)
+                )
+        }
+        ty::RawPtr(TypeAndMut { ty: pointee_ty, .. }) | ty::Ref(_, pointee_ty, _) => {
+            has_ptr_meta(tcx, pointee_ty)
+        }
+        _ => false,
+    }
+}
+
+/// Is a pointer to this type a fat ptr?
+pub(crate) fn has_ptr_meta<'tcx>(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>) -> bool {
+    let ptr_ty = tcx.mk_ptr(TypeAndMut {
+        ty,
+        mutbl: rustc_hir::Mutability::Not,
+    });
+    match &tcx
+        .layout_of(ParamEnv::reveal_all().and(ptr_ty))
+        .unwrap()
+        .abi
+    {
+        Abi::Scalar(_) => false,
+        Abi::ScalarPair(_, _) => true,
+        abi => unreachable!("Abi of ptr to {:?} is {:?}???", ty, abi),
+    }
+}
+
+/// Number of bits of an integer primitive, if it is one.
+pub(crate) fn int_bits(primitive: Primitive) -> Option<(u64, bool)> {
+    match primitive {
+        Primitive::Int(int, signed) => Some((int.size().bits(), signed)),
+        Primitive::Pointer => None,
+        Primitive::F32 | Primitive::F64 => None,
+    }
+}
+
+/// Reads `ty` as an integer type, returning its width and signedness.
+pub(crate) fn int_ty_bits<'tcx>(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>) -> Option<(u64, bool)> {
+    match ty.kind() {
+        ty::Int(int) => Some((Integer::from_int_ty(&tcx, *int).size().bits(), true)),
+        ty::Uint(uint) => Some((Integer::from_uint_ty(&tcx, *uint).size().bits(), false)),
+        ty::Bool => Some((8, false)),
+        ty::Char => Some((32, false)),
+        ty::RawPtr(..) | ty::Ref(..) | ty::FnPtr(..) => {
+            Some((tcx.data_layout.pointer_size.bits(), false))
+        }
+        _ => None,
+    }
+}
+
+/// Whether panics unwind the stack, running cleanup blocks, instead of aborting.
+pub(crate) fn unwinding_enabled(tcx: TyCtxt<'_>) -> bool {
+    tcx.sess.panic_strategy() == PanicStrategy::Unwind
+}
+
+pub(crate) fn ident(name: &str) -> Value {
+    ExprBuilder.ident(name.to_string())
+}
+
+/// `rt.<name>`, a field of the runtime library
+pub(crate) fn rt_field(name: &str) -> Value {
+    ExprBuilder.table_access(ident("rt").to_place(), ExprBuilder.string(name.to_string()))
+}
+
+/// Calls the runtime helper `rt.<name>`
+pub(crate) fn rt_call<I: IntoIterator<Item = Value>>(name: &str, args: I) -> Value {
+    ExprBuilder.call(rt_field(name), args)
+}
+
+/// `S["<symbol>"]`, the function defined under `symbol`
+pub(crate) fn fn_symbol(symbol: &str) -> Value {
+    ExprBuilder.table_access(
+        ident("S").to_place(),
+        ExprBuilder.string(symbol.to_string()),
+    )
+}
+
+/// `D["<symbol>"]`, the address of the data defined under `symbol`
+pub(crate) fn data_symbol(symbol: &str) -> Value {
+    ExprBuilder.table_access(
+        ident("D").to_place(),
+        ExprBuilder.string(symbol.to_string()),
+    )
+}
+
+pub(crate) fn int(value: i64) -> Value {
+    ExprBuilder.int(value)
+}
+
+pub(crate) fn binop(op: LuaBinOp, lhs: Value, rhs: Value) -> Value {
+    ExprBuilder.binop(op, lhs, rhs)
+}
+
+/// `ptr + offset`, folding constant offsets
+pub(crate) fn ptr_offset(ptr: Value, offset: i64) -> Value {
+    if offset == 0 {
+        return ptr;
+    }
+    match ptr.as_int() {
+        Some(base) => int(base.wrapping_add(offset)),
+        None => binop(LuaBinOp::Add, ptr, int(offset)),
+    }
+}
+
+/// Converts a lua boolean to the 0/1 integer representation of `bool`.
+pub(crate) fn bool_to_int(cond: Value) -> Value {
+    binop(LuaBinOp::Or, binop(LuaBinOp::And, cond, int(1)), int(0))
+}
+
+/// Converts a `bool` back to a lua boolean usable as a condition.
+pub(crate) fn int_to_bool(value: Value) -> Value {
+    binop(LuaBinOp::Ne, value, int(0))
+}
+
+/// The lua representation of the integer `bits` of type `ty`.
+///
+/// Integers narrower than 64 bits are stored as their mathematical value, 64 bit integers use
+/// lua's wrapping integers and 128 bit integers are `{lo, hi}` tables.
+pub(crate) fn lua_int_const<'tcx>(tcx: TyCtxt<'tcx>, ty: Ty<'tcx>, bits: u128) -> Value {
+    let (size, signed) = int_ty_bits(tcx, ty).unwrap_or_else(|| bug!("not an int: {:?}", ty));
+    lua_int(bits, size, signed)
+}
+
+pub(crate) fn lua_int(bits: u128, size: u64, signed: bool) -> Value {
+    match size {
+        128 => ExprBuilder.list([int(bits as u64 as i64), int((bits >> 64) as u64 as i64)]),
+        64 => int(bits as u64 as i64),
+        _ => {
+            let bits = bits & ((1u128 << size) - 1);
+            if signed && bits >> (size - 1) != 0 {
+                int(bits as i64 - (1i64 << size))
+            } else {
+                int(bits as i64)
+            }
+        }
+    }
+}
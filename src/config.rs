@@ -0,0 +1,222 @@
+//! Options of the backend
+//!
+//! Options are given with `-Cllvm-args=<key>=<value>`, the last occurrence of a key winning. The
+//! `CG_LUA_<KEY>` environment variables, such as `CG_LUA_MINIFY=true`, are used for the keys which
+//! aren't passed on the command line.
+
+use std::str::FromStr;
+
+use crate::dialect::{Dialect, LuaFeatures};
+
+/// How the crate is run, `mode=aot|jit|jit-lazy`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CodegenMode {
+    /// Lua files are written by the linker
+    Aot,
+    /// The crate is compiled and run in memory
+    Jit,
+    /// The crate is run in memory, compiling its functions when they are first called
+    JitLazy,
+}
+
+impl FromStr for CodegenMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aot" => Ok(CodegenMode::Aot),
+            "jit" => Ok(CodegenMode::Jit),
+            "jit-lazy" => Ok(CodegenMode::JitLazy),
+            _ => Err(format!("unknown codegen mode `{}`", s)),
+        }
+    }
+}
+
+/// The kind of linked output, `output=default|neovim`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Lua scripts and modules
+    Default,
+    /// A Neovim plugin
+    Neovim,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(OutputMode::Default),
+            "neovim" => Ok(OutputMode::Neovim),
+            _ => Err(format!("unknown output mode `{}`", s)),
+        }
+    }
+}
+
+/// How the results of integer arithmetic are kept in the range of their type,
+/// `integers=wrapping|unchecked`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IntegerStrategy {
+    /// Integers narrower than 64 bits are masked after each operation which can overflow
+    Wrapping,
+    /// Additions, subtractions, multiplications and left shifts of integers narrower than 64 bits
+    /// are not masked, which is faster but only correct for code in which they never overflow
+    Unchecked,
+}
+
+impl FromStr for IntegerStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrapping" => Ok(IntegerStrategy::Wrapping),
+            "unchecked" => Ok(IntegerStrategy::Unchecked),
+            _ => Err(format!("unknown integer strategy `{}`", s)),
+        }
+    }
+}
+
+/// The keys of the options, which are also the names of their environment variables without the
+/// `CG_LUA_` prefix, in uppercase
+const KEYS: &[&str] = &[
+    "mode",
+    "output",
+    "dialect",
+    "native_cpu",
+    "integers",
+    "minify",
+    "source_map",
+    "embed_runtime",
+];
+
+#[derive(Clone, Debug)]
+pub struct BackendConfig {
+    /// Whether the crate is written to lua files or run in memory
+    ///
+    /// Defaults to `aot`, `cargo jit` and `cargo lazy-jit` select the jit modes.
+    pub codegen_mode: CodegenMode,
+
+    /// The arguments of the program in the jit modes
+    ///
+    /// Only set with the `CG_LUA_JIT_ARGS` environment variable, split at whitespace.
+    pub jit_args: Vec<String>,
+
+    /// The kind of linked output, defaults to `default`
+    pub output_mode: OutputMode,
+
+    /// The dialect used when `-Ctarget-cpu` isn't given, `dialect=<cpu>`
+    ///
+    /// Takes the values of `-Ctarget-cpu`, including `native`. Defaults to the cpu of the target.
+    pub cpu: Option<&'static str>,
+
+    /// The dialect of `-Ctarget-cpu=native` when no lua 5.3 or 5.4 interpreter is found,
+    /// `native_cpu=<cpu>`
+    ///
+    /// Defaults to `lua53`.
+    pub native_cpu: Option<Dialect>,
+
+    /// The dialect selected by `-Ctarget-cpu` or `cpu`, set by `init`
+    pub dialect: Dialect,
+
+    /// The features of `dialect` with the toggles of `-Ctarget-feature`, set by `init`
+    pub features: LuaFeatures,
+
+    /// How integer arithmetic wraps, defaults to `wrapping`
+    pub integers: IntegerStrategy,
+
+    /// Removes the indentation, empty lines and comments from the linked code
+    ///
+    /// The source locations written with `source_map` are kept. Defaults to `false`.
+    pub minify: bool,
+
+    /// Writes the rust source location of each function in a `-- @` comment before it
+    ///
+    /// Defaults to `false`.
+    pub source_map: bool,
+
+    /// Whether linked programs and modules contain the runtime
+    ///
+    /// Otherwise the runtime is written to `cg_lua_rt.lua` next to them and loaded with
+    /// `require("cg_lua_rt")`, so that it is shared by all the crates loaded in a lua state.
+    /// Defaults to `true`.
+    pub embed_runtime: bool,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            codegen_mode: CodegenMode::Aot,
+            jit_args: {
+                let args = std::env::var("CG_LUA_JIT_ARGS").unwrap_or_else(|_| String::new());
+                args.split_whitespace().map(|arg| arg.to_owned()).collect()
+            },
+            output_mode: OutputMode::Default,
+            cpu: None,
+            native_cpu: None,
+            dialect: Dialect::Lua53,
+            features: Dialect::Lua53.features(),
+            integers: IntegerStrategy::Wrapping,
+            minify: false,
+            source_map: false,
+            embed_runtime: true,
+        }
+    }
+}
+
+impl BackendConfig {
+    /// Parses the options from the environment and `-Cllvm-args`, which take precedence
+    pub fn from_opts(opts: &[String]) -> Result<Self, String> {
+        let mut config = BackendConfig::default();
+
+        for key in KEYS {
+            let var = format!("CG_LUA_{}", key.to_uppercase());
+            if let Ok(value) = std::env::var(&var) {
+                config
+                    .set(key, &value)
+                    .map_err(|err| format!("{} (in the environment variable {})", err, var))?;
+            }
+        }
+
+        for opt in opts {
+            match opt.split_once('=') {
+                Some((key, value)) => config.set(key, value)?,
+                None => {
+                    return Err(format!(
+                        "invalid option `{}`, options are given as `-Cllvm-args=<key>=<value>`",
+                        opt
+                    ))
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid value `{}` for {}, expected a boolean", value, key))
+        }
+
+        match key {
+            "mode" => self.codegen_mode = value.parse()?,
+            "output" => self.output_mode = value.parse()?,
+            "dialect" if value == "native" => self.cpu = Some("native"),
+            "dialect" => self.cpu = Some(value.parse::<Dialect>()?.name()),
+            "native_cpu" => self.native_cpu = Some(value.parse()?),
+            "integers" => self.integers = value.parse()?,
+            "minify" => self.minify = parse_bool(key, value)?,
+            "source_map" => self.source_map = parse_bool(key, value)?,
+            "embed_runtime" => self.embed_runtime = parse_bool(key, value)?,
+            _ => {
+                return Err(format!(
+                    "unknown option `{}`, the options are {}",
+                    key,
+                    KEYS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+}
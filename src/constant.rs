@@ -0,0 +1,325 @@
+//! Handling of `const`s, promoteds and the allocations they point to
+//!
+//! Allocations are not given an address at compile time. Each codegen unit instead registers the
+//! bytes and relocations of the allocations it uses with `rt.data`, and `rt.link` lays them out in
+//! memory once every codegen unit of the program has been loaded. Anonymous allocations are named
+//! after a hash of their content, so identical constants used by several codegen units are only
+//! materialized once.
+
+use std::hash::Hash;
+
+use rustc_data_structures::fingerprint::Fingerprint;
+use rustc_data_structures::fx::{FxHashMap, FxHashSet};
+use rustc_data_structures::stable_hasher::StableHasher;
+use rustc_errors::ErrorReported;
+use rustc_middle::mir::interpret::{
+    AllocId, Allocation, ConstValue, ErrorHandled, GlobalAlloc, Scalar as InterpScalar,
+};
+use rustc_middle::ty::ConstKind;
+use rustc_target::abi::Primitive;
+
+use crate::prelude::*;
+
+pub(crate) struct ConstantCx {
+    todo: Vec<AllocId>,
+    /// Data already defined by this codegen unit
+    done: FxHashSet<String>,
+    names: FxHashMap<AllocId, String>,
+}
+
+impl ConstantCx {
+    pub(crate) fn new() -> Self {
+        ConstantCx {
+            todo: vec![],
+            done: FxHashSet::default(),
+            names: FxHashMap::default(),
+        }
+    }
+
+    /// Emits the definitions of all the allocations referenced by the codegen unit.
+    pub(crate) fn finalize(mut self, tcx: TyCtxt<'_>, ctx: &mut Context) {
+        while let Some(alloc_id) = self.todo.pop() {
+            let name = self.alloc_name(tcx, alloc_id);
+            if !self.done.insert(name.clone()) {
+                continue;
+            }
+
+            let alloc = match tcx.get_global_alloc(alloc_id).unwrap() {
+                GlobalAlloc::Memory(alloc) => alloc,
+                GlobalAlloc::Function(_) | GlobalAlloc::Static(_) => unreachable!(),
+            };
+            self.define_data(tcx, ctx, name, alloc);
+        }
+    }
+
+    /// A reference to the address of the allocation `alloc_id`
+    pub(crate) fn data_ref(&mut self, tcx: TyCtxt<'_>, alloc_id: AllocId) -> Value {
+        let name = self.alloc_name(tcx, alloc_id);
+        if let GlobalAlloc::Memory(_) = tcx.get_global_alloc(alloc_id).unwrap() {
+            self.todo.push(alloc_id);
+        }
+        data_symbol(&name)
+    }
+
+    /// The name under which the allocation is registered in `D`.
+    ///
+    /// Statics use their symbol name, anonymous allocations a hash of their content.
+    fn alloc_name(&mut self, tcx: TyCtxt<'_>, alloc_id: AllocId) -> String {
+        if let Some(name) = self.names.get(&alloc_id) {
+            return name.clone();
+        }
+
+        let name = match tcx.get_global_alloc(alloc_id).unwrap() {
+            GlobalAlloc::Static(def_id) => static_symbol(tcx, def_id),
+            GlobalAlloc::Function(instance) => {
+                bug!("function {:?} is not a data allocation", instance)
+            }
+            GlobalAlloc::Memory(alloc) => {
+                let mut hasher = StableHasher::new();
+                alloc.align.bytes().hash(&mut hasher);
+                alloc
+                    .inspect_with_uninit_and_ptr_outside_interpreter(0..alloc.len())
+                    .hash(&mut hasher);
+                for &(offset, reloc) in alloc.relocations().iter() {
+                    offset.bytes().hash(&mut hasher);
+                    self.reloc_target(tcx, reloc).hash(&mut hasher);
+                }
+                let hash: Fingerprint = hasher.finish();
+                format!("alloc.{}", hash.to_hex())
+            }
+        };
+
+        self.names.insert(alloc_id, name.clone());
+        name
+    }
+
+    /// The kind (`d` for data, `f` for functions) and name of the target of a relocation
+    fn reloc_target(&mut self, tcx: TyCtxt<'_>, reloc: AllocId) -> (&'static str, String) {
+        match tcx.get_global_alloc(reloc).unwrap() {
+            GlobalAlloc::Function(instance) => ("f", tcx.symbol_name(instance).name.to_string()),
+            GlobalAlloc::Memory(_) => {
+                self.todo.push(reloc);
+                ("d", self.alloc_name(tcx, reloc))
+            }
+            GlobalAlloc::Static(def_id) => ("d", static_symbol(tcx, def_id)),
+        }
+    }
+
+    /// Registers `alloc` under `name`, to be materialized by `rt.link`.
+    pub(crate) fn define_data(
+        &mut self,
+        tcx: TyCtxt<'_>,
+        ctx: &mut Context,
+        name: String,
+        alloc: &Allocation,
+    ) {
+        let bytes = alloc
+            .inspect_with_uninit_and_ptr_outside_interpreter(0..alloc.len())
+            .to_vec();
+
+        let mut relocs = vec![];
+        for &(offset, reloc) in alloc.relocations().iter() {
+            let (kind, target) = self.reloc_target(tcx, reloc);
+            relocs.push(int(offset.bytes() as i64));
+            relocs.push(ExprBuilder.string(kind.to_string()));
+            relocs.push(ExprBuilder.string(target));
+        }
+
+        ctx.stat().call(
+            rt_field("data"),
+            [
+                ExprBuilder.string(name),
+                int(alloc.align.bytes() as i64),
+                ExprBuilder.bytes(bytes),
+                ExprBuilder.list(relocs),
+            ],
+        );
+    }
+}
+
+/// Defines the storage of the static `def_id`, initialized with the result of its const-eval.
+///
+/// Lua code runs on a single thread, so `#[thread_local]` statics get the same storage as other
+/// statics.
+pub(crate) fn codegen_static(tcx: TyCtxt<'_>, cx: &mut CodegenCx<'_>, def_id: DefId) {
+    assert!(!tcx.is_foreign_item(def_id));
+
+    let alloc = match tcx.eval_static_initializer(def_id) {
+        Ok(alloc) => alloc,
+        // The error has already been reported
+        Err(_) => return,
+    };
+
+    let name = static_symbol(tcx, def_id);
+    cx.constants_cx.define_data(tcx, &mut cx.ctx, name, alloc);
+}
+
+pub(crate) fn static_symbol(tcx: TyCtxt<'_>, def_id: DefId) -> String {
+    tcx.symbol_name(Instance::mono(tcx, def_id))
+        .name
+        .to_string()
+}
+
+/// Checks that all the constants required by the function can be evaluated.
+pub(crate) fn check_constants(fx: &mut FunctionCx<'_, '_>) -> bool {
+    let mut all_constants_ok = true;
+    for constant in &fx.mir.required_consts {
+        let const_ = match fx.monomorphize(constant.literal) {
+            ConstantKind::Ty(ct) => ct,
+            ConstantKind::Val(..) => continue,
+        };
+        match const_.val {
+            ConstKind::Value(_) => {}
+            ConstKind::Unevaluated(unevaluated) => {
+                if let Err(err) =
+                    fx.tcx
+                        .const_eval_resolve(ParamEnv::reveal_all(), unevaluated, None)
+                {
+                    all_constants_ok = false;
+                    match err {
+                        ErrorHandled::Reported(ErrorReported) | ErrorHandled::Linted => {
+                            fx.tcx
+                                .sess
+                                .span_err(constant.span, "erroneous constant encountered");
+                        }
+                        ErrorHandled::TooGeneric => {
+                            span_bug!(
+                                constant.span,
+                                "codegen encountered polymorphic constant: {:?}",
+                                err
+                            );
+                        }
+                    }
+                }
+            }
+            ConstKind::Param(_)
+            | ConstKind::Infer(_)
+            | ConstKind::Bound(_, _)
+            | ConstKind::Placeholder(_)
+            | ConstKind::Error(_) => unreachable!("{:?}", const_),
+        }
+    }
+    all_constants_ok
+}
+
+pub(crate) fn codegen_constant<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    constant: &Constant<'tcx>,
+) -> CValue<'tcx> {
+    let const_ = match fx.monomorphize(constant.literal) {
+        ConstantKind::Ty(ct) => ct,
+        ConstantKind::Val(val, ty) => return codegen_const_value(fx, val, ty),
+    };
+    let const_val = match const_.val {
+        ConstKind::Value(const_val) => const_val,
+        ConstKind::Unevaluated(uv) if fx.tcx.is_static(uv.def.did) => {
+            assert!(uv.substs(fx.tcx).is_empty());
+            assert!(uv.promoted.is_none());
+
+            let layout = fx.layout_of(const_.ty);
+            let ptr = data_symbol(&static_symbol(fx.tcx, uv.def.did));
+            return CPlace::for_ptr(ptr, layout).to_cvalue(fx);
+        }
+        ConstKind::Unevaluated(unevaluated) => {
+            match fx
+                .tcx
+                .const_eval_resolve(ParamEnv::reveal_all(), unevaluated, None)
+            {
+                Ok(const_val) => const_val,
+                Err(_) => {
+                    span_bug!(
+                        constant.span,
+                        "erroneous constant not captured by required_consts"
+                    );
+                }
+            }
+        }
+        ConstKind::Param(_)
+        | ConstKind::Infer(_)
+        | ConstKind::Bound(_, _)
+        | ConstKind::Placeholder(_)
+        | ConstKind::Error(_) => unreachable!("{:?}", const_),
+    };
+
+    codegen_const_value(fx, const_val, const_.ty)
+}
+
+pub(crate) fn codegen_const_value<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    const_val: ConstValue<'tcx>,
+    ty: Ty<'tcx>,
+) -> CValue<'tcx> {
+    let layout = fx.layout_of(ty);
+    assert!(!layout.is_unsized(), "sized const value");
+
+    if layout.is_zst() {
+        return CValue::zst(layout);
+    }
+
+    match const_val {
+        ConstValue::Scalar(x) => match x {
+            InterpScalar::Int(int) => {
+                let bits = int.assert_bits(layout.size);
+                CValue::by_val(scalar_to_lua(fx, layout, bits), layout)
+            }
+            InterpScalar::Ptr(ptr, _size) => {
+                let (alloc_id, offset) = ptr.into_parts(); // we know the `offset` is relative
+                let base = match fx.tcx.get_global_alloc(alloc_id).unwrap() {
+                    GlobalAlloc::Memory(_) => fx.cx.constants_cx.data_ref(fx.tcx, alloc_id),
+                    GlobalAlloc::Function(instance) => fn_ptr_ref(fx.tcx, instance),
+                    GlobalAlloc::Static(def_id) => {
+                        assert!(fx.tcx.is_static(def_id));
+                        data_symbol(&static_symbol(fx.tcx, def_id))
+                    }
+                };
+                CValue::by_val(ptr_offset(base, offset.bytes() as i64), layout)
+            }
+        },
+        ConstValue::ByRef { alloc, offset } => CValue::by_ref(
+            ptr_offset(pointer_for_allocation(fx, alloc), offset.bytes() as i64),
+            layout,
+        ),
+        ConstValue::Slice { data, start, end } => {
+            let ptr = ptr_offset(pointer_for_allocation(fx, data), start as i64);
+            let len = int(end.checked_sub(start).unwrap() as i64);
+            CValue::by_val_pair(ptr, len, layout)
+        }
+    }
+}
+
+/// The lua number for the scalar `bits` of the given layout
+pub(crate) fn scalar_to_lua(fx: &FunctionCx<'_, '_>, layout: TyAndLayout<'_>, bits: u128) -> Value {
+    let primitive = match layout.abi {
+        Abi::Scalar(ref scalar) => scalar.value,
+        _ => bug!("scalar constant of non scalar type {:?}", layout.ty),
+    };
+    match primitive {
+        Primitive::Int(integer, signed) => lua_int(bits, integer.size().bits(), signed),
+        Primitive::F32 => ExprBuilder.double(f32::from_bits(bits as u32) as f64),
+        Primitive::F64 => ExprBuilder.double(f64::from_bits(bits as u64)),
+        Primitive::Pointer => lua_int(bits, fx.pointer_size() * 8, false),
+    }
+}
+
+/// The address of the function `instance`, which is allocated when first requested.
+pub(crate) fn fn_ptr_ref<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> Value {
+    rt_call(
+        "fnptr",
+        [ExprBuilder.string(tcx.symbol_name(instance).name.to_string())],
+    )
+}
+
+pub(crate) fn pointer_for_allocation<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    alloc: &'tcx Allocation,
+) -> Value {
+    let alloc_id = fx.tcx.create_memory_alloc(alloc);
+    fx.cx.constants_cx.data_ref(fx.tcx, alloc_id)
+}
+
+/// A `&'static str` pointing to `s`, as a pointer and a length
+pub(crate) fn codegen_const_str(fx: &mut FunctionCx<'_, '_>, s: &str) -> (Value, Value) {
+    let alloc_id = fx.tcx.allocate_bytes(s.as_bytes());
+    let ptr = fx.cx.constants_cx.data_ref(fx.tcx, alloc_id);
+    (ptr, int(s.len() as i64))
+}
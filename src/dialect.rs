@@ -0,0 +1,253 @@
+//! Lua dialects and their features, selected with `-Ctarget-cpu` and `-Ctarget-feature`
+//!
+//! The dialects are lua 5.1 to 5.4, LuaJIT and Luau, but the generated code needs the integers and
+//! bitwise operators of lua 5.3, so only `lua53` and `lua54` are supported: selecting another
+//! dialect is an error naming what it lacks. Each dialect has a set of features, which the
+//! `features` of the target spec and then `-Ctarget-feature=+utf8,-goto` style toggles change in
+//! order, so `+<feature>` enables a feature disabled by the target or by an earlier toggle. Without
+//! `utf8`, the runtime brings its own `utf8` library, and `goto` is never needed, it is only
+//! reported to `cfg`. `integers` and `bitops` can't be disabled and features which the dialect
+//! lacks can't be enabled.
+
+use std::lazy::SyncOnceCell;
+use std::process::Command;
+use std::str::FromStr;
+
+/// A lua implementation, the `-Ctarget-cpu` of the target
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Lua51,
+    Lua52,
+    Lua53,
+    Lua54,
+    LuaJit,
+    Luau,
+}
+
+const DIALECTS: &[Dialect] = &[
+    Dialect::Lua51,
+    Dialect::Lua52,
+    Dialect::Lua53,
+    Dialect::Lua54,
+    Dialect::LuaJit,
+    Dialect::Luau,
+];
+
+impl Dialect {
+    pub fn name(self) -> &'static str {
+        match self {
+            Dialect::Lua51 => "lua51",
+            Dialect::Lua52 => "lua52",
+            Dialect::Lua53 => "lua53",
+            Dialect::Lua54 => "lua54",
+            Dialect::LuaJit => "luajit",
+            Dialect::Luau => "luau",
+        }
+    }
+
+    /// The features of the dialect before the target spec and `-Ctarget-feature` are applied
+    ///
+    /// LuaJIT and Luau only have the bitwise operations of the `bit` and `bit32` libraries, on 32
+    /// bit integers stored in floats, which isn't the `bitops` feature.
+    pub fn features(self) -> LuaFeatures {
+        let none = LuaFeatures {
+            bitops: false,
+            goto: false,
+            integers: false,
+            utf8: false,
+        };
+        match self {
+            Dialect::Lua51 => none,
+            Dialect::Lua52 | Dialect::LuaJit => LuaFeatures { goto: true, ..none },
+            Dialect::Lua53 | Dialect::Lua54 => LuaFeatures {
+                bitops: true,
+                goto: true,
+                integers: true,
+                utf8: true,
+            },
+            Dialect::Luau => LuaFeatures { utf8: true, ..none },
+        }
+    }
+
+    /// Checks that the generated code can run on the dialect, which needs the native integers and
+    /// bitwise operators of lua 5.3
+    pub(crate) fn check_supported(self) -> Result<(), String> {
+        let features = self.features();
+        let missing: Vec<_> = [
+            (features.integers, "64 bit integers"),
+            (features.bitops, "bitwise operators"),
+        ]
+        .iter()
+        .filter(|(available, _)| !available)
+        .map(|(_, missing)| *missing)
+        .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "the `{}` dialect is unsupported: the generated code needs native {}, which it lacks, \
+             use `-Ctarget-cpu=lua53` or `-Ctarget-cpu=lua54`",
+            self.name(),
+            missing.join(" and ")
+        ))
+    }
+
+    /// Recognizes the version printed by `lua -v` or `luajit -v`
+    fn from_version(version: &str) -> Option<Self> {
+        if version.starts_with("LuaJIT ") {
+            return Some(Dialect::LuaJit);
+        }
+        let version = version.strip_prefix("Lua 5.")?;
+        match version.as_bytes().first()? {
+            b'1' => Some(Dialect::Lua51),
+            b'2' => Some(Dialect::Lua52),
+            b'3' => Some(Dialect::Lua53),
+            b'4' => Some(Dialect::Lua54),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DIALECTS
+            .iter()
+            .copied()
+            .find(|dialect| dialect.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = DIALECTS.iter().map(|dialect| dialect.name()).collect();
+                format!(
+                    "unknown target cpu `{}`, the cpus are native, {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// The dialect of the first interpreter found in `PATH` which runs a supported dialect, probed
+/// once. Finding only other interpreters, such as lua 5.1 or LuaJIT, is an error.
+pub(crate) fn probe_interpreter() -> Result<Option<Dialect>, String> {
+    static PROBED: SyncOnceCell<Result<Option<Dialect>, String>> = SyncOnceCell::new();
+    PROBED
+        .get_or_init(|| {
+            let mut unsupported = vec![];
+            for interpreter in ["lua", "lua5.4", "lua5.3", "luajit"] {
+                let output = match Command::new(interpreter).arg("-v").output() {
+                    Ok(output) => output,
+                    Err(_) => continue,
+                };
+                // Lua 5.1 prints its version on the standard error
+                let version = if output.stdout.is_empty() {
+                    output.stderr
+                } else {
+                    output.stdout
+                };
+                let version = String::from_utf8_lossy(&version).trim().to_string();
+                match Dialect::from_version(&version) {
+                    Some(dialect) if dialect.check_supported().is_ok() => return Ok(Some(dialect)),
+                    _ => unsupported.push(format!("`{}` is {}", interpreter, version)),
+                }
+            }
+            if unsupported.is_empty() {
+                return Ok(None);
+            }
+            Err(format!(
+                "`-Ctarget-cpu=native` found no lua 5.3 or 5.4 interpreter in `PATH` ({}), select \
+                 the dialect with `-Ctarget-cpu` or `-Cllvm-args=native_cpu=<cpu>`",
+                unsupported.join(", ")
+            ))
+        })
+        .clone()
+}
+
+/// The names of the features, as given to `-Ctarget-feature`
+const FEATURES: &[&str] = &["bitops", "goto", "integers", "utf8"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LuaFeatures {
+    /// The bitwise operators
+    pub bitops: bool,
+    /// `goto` and labels
+    pub goto: bool,
+    /// The 64 bit integer subtype of numbers
+    pub integers: bool,
+    /// The `utf8` library
+    pub utf8: bool,
+}
+
+impl LuaFeatures {
+    fn feature_mut(&mut self, name: &str) -> Result<&mut bool, String> {
+        match name {
+            "bitops" => Ok(&mut self.bitops),
+            "goto" => Ok(&mut self.goto),
+            "integers" => Ok(&mut self.integers),
+            "utf8" => Ok(&mut self.utf8),
+            _ => Err(format!(
+                "unknown target feature `{}`, the features are {}",
+                name,
+                FEATURES.join(", ")
+            )),
+        }
+    }
+
+    /// Applies the comma separated `+<feature>` and `-<feature>` toggles of the target spec or
+    /// `-Ctarget-feature`, the last toggle of a feature winning
+    pub fn apply_toggles(&mut self, toggles: &str) -> Result<(), String> {
+        for toggle in toggles.split(',').filter(|toggle| !toggle.is_empty()) {
+            let (enable, name) = if let Some(name) = toggle.strip_prefix('+') {
+                (true, name)
+            } else if let Some(name) = toggle.strip_prefix('-') {
+                (false, name)
+            } else {
+                return Err(format!(
+                    "invalid target feature `{}`, features are enabled with `+<feature>` and \
+                     disabled with `-<feature>`",
+                    toggle
+                ));
+            };
+            *self.feature_mut(name)? = enable;
+        }
+        Ok(())
+    }
+
+    /// The features in the order of `FEATURES`
+    fn flags(self) -> [bool; 4] {
+        [self.bitops, self.goto, self.integers, self.utf8]
+    }
+
+    /// The names of the enabled features
+    pub fn enabled(self) -> Vec<&'static str> {
+        FEATURES
+            .iter()
+            .zip(self.flags())
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Checks that `dialect` has these features and that the generated code can run with them
+    pub(crate) fn check_supported(self, dialect: Dialect) -> Result<(), String> {
+        let available = dialect.features().flags();
+        for ((name, enabled), available) in FEATURES.iter().zip(self.flags()).zip(available) {
+            if enabled && !available {
+                return Err(format!(
+                    "`{}` doesn't have the `{}` target feature",
+                    dialect.name(),
+                    name
+                ));
+            }
+        }
+        for (name, enabled) in [("integers", self.integers), ("bitops", self.bitops)] {
+            if !enabled {
+                return Err(format!(
+                    "the lua backend needs the `{}` target feature, which can't be disabled",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
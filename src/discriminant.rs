@@ -0,0 +1,164 @@
+//! Handling of enum discriminants
+//!
+//! Adapted from <https://github.com/rust-lang/rust/blob/d760df5aea483aae041c9a241e7acacf48f75035/src/librustc_codegen_ssa/mir/place.rs>
+
+use rustc_target::abi::{Int, TagEncoding, Variants};
+
+use crate::prelude::*;
+
+pub(crate) fn codegen_set_discriminant<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    place: CPlace<'tcx>,
+    variant_index: VariantIdx,
+) {
+    let layout = place.layout();
+    if layout.for_variant(fx, variant_index).abi.is_uninhabited() {
+        return;
+    }
+    match layout.variants {
+        Variants::Single { index } => {
+            assert_eq!(index, variant_index);
+        }
+        Variants::Multiple {
+            tag: _,
+            tag_field,
+            tag_encoding: TagEncoding::Direct,
+            variants: _,
+        } => {
+            let ptr = place.place_field(fx, mir::Field::new(tag_field));
+            let to = layout
+                .ty
+                .discriminant_for_variant(fx.tcx, variant_index)
+                .unwrap()
+                .val;
+            let to = crate::constant::scalar_to_lua(fx, ptr.layout(), to);
+            let discr = CValue::by_val(to, ptr.layout());
+            ptr.write_cvalue(fx, discr);
+        }
+        Variants::Multiple {
+            tag: _,
+            tag_field,
+            tag_encoding:
+                TagEncoding::Niche {
+                    dataful_variant,
+                    ref niche_variants,
+                    niche_start,
+                },
+            variants: _,
+        } => {
+            if variant_index != dataful_variant {
+                let niche = place.place_field(fx, mir::Field::new(tag_field));
+                let niche_value = variant_index.as_u32() - niche_variants.start().as_u32();
+                let niche_value = u128::from(niche_value).wrapping_add(niche_start);
+                let niche_llval = crate::constant::scalar_to_lua(fx, niche.layout(), niche_value);
+                let niche_llval = CValue::by_val(niche_llval, niche.layout());
+                niche.write_cvalue(fx, niche_llval);
+            }
+        }
+    }
+}
+
+pub(crate) fn codegen_get_discriminant<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    value: CValue<'tcx>,
+    dest_layout: TyAndLayout<'tcx>,
+) -> CValue<'tcx> {
+    let layout = value.layout();
+
+    if layout.abi == Abi::Uninhabited {
+        fx.cx.ctx.stat().call(
+            rt_field("trap"),
+            [ExprBuilder
+                .string("[panic] Tried to get discriminant for uninhabited type.".to_string())],
+        );
+        return CValue::by_val(lua_int_const(fx.tcx, dest_layout.ty, 0), dest_layout);
+    }
+
+    let (tag_scalar, tag_field, tag_encoding) = match &layout.variants {
+        Variants::Single { index } => {
+            let discr_val = layout
+                .ty
+                .discriminant_for_variant(fx.tcx, *index)
+                .map_or(u128::from(index.as_u32()), |discr| discr.val);
+            return CValue::by_val(
+                lua_int_const(fx.tcx, dest_layout.ty, discr_val),
+                dest_layout,
+            );
+        }
+        Variants::Multiple {
+            tag,
+            tag_field,
+            tag_encoding,
+            variants: _,
+        } => (tag.clone(), *tag_field, tag_encoding),
+    };
+
+    let (dest_bits, _) = int_ty_bits(fx.tcx, dest_layout.ty).unwrap();
+    let (tag_bits, tag_signed) = match tag_scalar.value {
+        Int(int, signed) => (int.size().bits(), signed),
+        _ => (fx.pointer_size() * 8, false),
+    };
+
+    // Read the tag/niche-encoded discriminant from memory.
+    let tag = value.value_field(fx, mir::Field::new(tag_field));
+    let tag = tag.load_scalar(fx);
+
+    // Decode the discriminant (specifically if it's niche-encoded).
+    match *tag_encoding {
+        TagEncoding::Direct => {
+            // Narrow tags hold the mathematical value of the discriminant, so only a change to or
+            // from the 128 bit representation is needed
+            let val = match (tag_bits == 128, dest_bits == 128) {
+                (false, true) => rt_call("to128", [tag, ExprBuilder.bool(tag_signed)]),
+                (true, false) => ExprBuilder.table_access(tag.to_place(), int(1)),
+                _ => tag,
+            };
+            CValue::by_val(val, dest_layout)
+        }
+        TagEncoding::Niche {
+            dataful_variant,
+            ref niche_variants,
+            niche_start,
+        } => {
+            if tag_bits == 128 {
+                fx.tcx
+                    .sess
+                    .fatal("128 bit niche encoded enums are not supported yet");
+            }
+
+            // relative_discr = tag - niche_start, wrapping in the width of the tag
+            let relative_discr = crate::num::wrap_int(
+                binop(LuaBinOp::Sub, tag, lua_int(niche_start, tag_bits, false)),
+                tag_bits,
+                false,
+            );
+            let relative_discr = fx.tmp(relative_discr);
+            let relative_max = niche_variants.end().as_u32() - niche_variants.start().as_u32();
+            let is_niche = crate::num::codegen_icmp(
+                BinOp::Le,
+                tag_bits,
+                false,
+                relative_discr.clone(),
+                int(i64::from(relative_max)),
+            );
+
+            let niche_discr = binop(
+                LuaBinOp::Add,
+                relative_discr,
+                int(i64::from(niche_variants.start().as_u32())),
+            );
+            let dataful_discr = int(i64::from(dataful_variant.as_u32()));
+            let discr = binop(
+                LuaBinOp::Or,
+                binop(LuaBinOp::And, is_niche, niche_discr),
+                dataful_discr,
+            );
+            let discr = if dest_bits == 128 {
+                rt_call("to128", [discr, ExprBuilder.bool(false)])
+            } else {
+                discr
+            };
+            CValue::by_val(discr, dest_layout)
+        }
+    }
+}
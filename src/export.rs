@@ -0,0 +1,320 @@
+//! Rust functions callable from lua
+//!
+//! A function with `#[export_name = "lua_export.<name>"]` gets a lua wrapper stored as `<name>` in
+//! `rt.module`, the table returned by a linked library. The wrapper converts the lua arguments to
+//! the parameter types of the function and its return value back to lua, with `rt.from_lua` and
+//! `rt.to_lua` interpreting a descriptor of each type built here.
+
+use rustc_target::abi::Primitive;
+
+use crate::abi::{get_pass_mode, PassMode};
+use crate::prelude::*;
+use crate::value_and_place::{load_primitive, scalar_pair_calculate_b_offset, store_primitive};
+
+pub(crate) const PREFIX: &str = "lua_export.";
+
+/// Whether `def_id` is the item `name` of the crate `krate`.
+fn is_item(tcx: TyCtxt<'_>, def_id: DefId, krate: &str, name: &str) -> bool {
+    tcx.crate_name(def_id.krate).as_str() == krate && tcx.item_name(def_id).as_str() == name
+}
+
+/// Whether `ty` is `alloc::alloc::Global`, the allocator used by `rt.from_lua` and `rt.to_lua`.
+fn is_global_alloc(tcx: TyCtxt<'_>, ty: Ty<'_>) -> bool {
+    match ty.kind() {
+        ty::Adt(adt_def, _) => is_item(tcx, adt_def.did, "alloc", "Global"),
+        _ => false,
+    }
+}
+
+/// Whether `ty` is `std::collections::hash_map::RandomState`, the hasher `rt.from_lua` fills.
+fn is_random_state(tcx: TyCtxt<'_>, ty: Ty<'_>) -> bool {
+    match ty.kind() {
+        ty::Adt(adt_def, _) => is_item(tcx, adt_def.did, "std", "RandomState"),
+        _ => false,
+    }
+}
+
+/// Whether `layout` can be the key of a map, whose hash `rt.from_lua` computes from the bytes its
+/// `Hash` impl writes.
+fn is_map_key(tcx: TyCtxt<'_>, layout: TyAndLayout<'_>) -> bool {
+    match *layout.ty.kind() {
+        ty::Bool | ty::Char => true,
+        ty::Int(_) | ty::Uint(_) => layout.size.bits() <= 64,
+        ty::Ref(_, inner, _) => *inner.kind() == ty::Str,
+        ty::Adt(adt_def, _) => is_item(tcx, adt_def.did, "alloc", "String"),
+        _ => false,
+    }
+}
+
+/// The offset of the field reached by following the field names of `path` from `layout`.
+fn field_offset<'tcx>(
+    fx: &FunctionCx<'_, 'tcx>,
+    mut layout: TyAndLayout<'tcx>,
+    path: &[&str],
+) -> i64 {
+    let mut offset = Size::ZERO;
+    for name in path {
+        let adt_def = match layout.ty.kind() {
+            ty::Adt(adt_def, _) => adt_def,
+            _ => bug!("{} has no fields", layout.ty),
+        };
+        let idx = adt_def
+            .non_enum_variant()
+            .fields
+            .iter()
+            .position(|field| field.ident.name.as_str() == *name)
+            .unwrap_or_else(|| bug!("{} has no field {}", layout.ty, name));
+        offset += layout.fields.offset(idx);
+        layout = layout.field(fx, idx);
+    }
+    offset.bytes() as i64
+}
+
+/// The `{ptr_offset, cap_offset, len_offset}` of the fields of the `Vec` at `prefix` in `layout`.
+fn vec_offsets<'tcx>(
+    fx: &FunctionCx<'_, 'tcx>,
+    layout: TyAndLayout<'tcx>,
+    prefix: &[&str],
+) -> Vec<Value> {
+    [&["buf", "ptr", "pointer"][..], &["buf", "cap"], &["len"]]
+        .iter()
+        .map(|path| {
+            let path = prefix
+                .iter()
+                .chain(path.iter())
+                .copied()
+                .collect::<Vec<_>>();
+            int(field_offset(fx, layout, &path))
+        })
+        .collect()
+}
+
+/// The descriptor of `layout` understood by `rt.from_lua` and `rt.to_lua`.
+pub(crate) fn type_descriptor<'tcx>(
+    fx: &FunctionCx<'_, 'tcx>,
+    layout: TyAndLayout<'tcx>,
+    span: Span,
+) -> Value {
+    let tcx = fx.tcx;
+    let kind = |kind: &str| ExprBuilder.string(kind.to_string());
+    let size = int(layout.size.bytes() as i64);
+
+    let fields = match *layout.ty.kind() {
+        ty::Tuple(tys) if tys.is_empty() => vec![kind("unit")],
+        ty::Never => vec![kind("unit")],
+        ty::Bool => vec![kind("bool")],
+        ty::Char => vec![kind("char")],
+        ty::Int(_) | ty::Uint(_) if layout.size.bits() <= 64 => {
+            vec![kind("int"), size, ExprBuilder.bool(layout.ty.is_signed())]
+        }
+        ty::Float(_) => vec![kind("float"), size],
+        ty::Ref(_, inner, _) if *inner.kind() == ty::Str => vec![kind("str")],
+        ty::Adt(adt_def, _)
+            if is_item(tcx, adt_def.did, "lua", "LuaValue")
+                || is_item(tcx, adt_def.did, "lua", "LuaTable") =>
+        {
+            vec![kind("value")]
+        }
+        ty::Adt(adt_def, _) if is_item(tcx, adt_def.did, "alloc", "String") => {
+            let mut fields = vec![kind("string")];
+            fields.extend(vec_offsets(fx, layout, &["vec"]));
+            fields
+        }
+        ty::Adt(adt_def, substs)
+            if is_item(tcx, adt_def.did, "alloc", "Vec")
+                && is_global_alloc(tcx, substs.type_at(1)) =>
+        {
+            let elem = fx.layout_of(substs.type_at(0));
+            let mut fields = vec![
+                kind("vec"),
+                type_descriptor(fx, elem, span),
+                int(elem.size.bytes() as i64),
+                int(elem.align.abi.bytes() as i64),
+            ];
+            fields.extend(vec_offsets(fx, layout, &[]));
+            fields
+        }
+        ty::Adt(adt_def, substs)
+            if is_item(tcx, adt_def.did, "std", "HashMap")
+                && is_random_state(tcx, substs.type_at(2)) =>
+        {
+            let key = fx.layout_of(substs.type_at(0));
+            let value = fx.layout_of(substs.type_at(1));
+            if !is_map_key(tcx, key) {
+                tcx.sess.span_fatal(
+                    span,
+                    &format!(
+                        "`{}` can't be the key of a map passed between rust and lua, the keys \
+                         can be `bool`, `char`, integers up to 64 bits, `&str` and `String`",
+                        key.ty
+                    ),
+                );
+            }
+            // The buckets of the table hold `(K, V)` tuples
+            let elem = fx.layout_of(tcx.intern_tup(&[key.ty, value.ty]));
+            let mut fields = vec![
+                kind("map"),
+                type_descriptor(fx, key, span),
+                type_descriptor(fx, value, span),
+                int(elem.fields.offset(0).bytes() as i64),
+                int(elem.fields.offset(1).bytes() as i64),
+                int(elem.size.bytes() as i64),
+                int(elem.align.abi.bytes() as i64),
+            ];
+            let paths: [&[&str]; 6] = [
+                &["base", "hash_builder", "k0"],
+                &["base", "hash_builder", "k1"],
+                &["base", "table", "table", "bucket_mask"],
+                &["base", "table", "table", "ctrl", "pointer"],
+                &["base", "table", "table", "growth_left"],
+                &["base", "table", "table", "items"],
+            ];
+            fields.extend(paths.iter().map(|path| int(field_offset(fx, layout, path))));
+            fields
+        }
+        _ => tcx.sess.span_fatal(
+            span,
+            &format!(
+                "`{}` can't be passed between rust and lua, the supported types are `bool`, \
+                 `char`, integers up to 64 bits, floats, `&str`, `String`, `Vec<T>`, \
+                 `HashMap<K, V>`, `lua::LuaValue` and `lua::LuaTable`",
+                layout.ty
+            ),
+        ),
+    };
+    ExprBuilder.list(fields)
+}
+
+/// The scalar components of the pass mode of `layout` with their offset.
+fn scalars(fx: &FunctionCx<'_, '_>, layout: TyAndLayout<'_>) -> Vec<(i64, Primitive)> {
+    match (get_pass_mode(layout), &layout.abi) {
+        (PassMode::ByVal, Abi::Scalar(scalar)) => vec![(0, scalar.value)],
+        (PassMode::ByValPair, Abi::ScalarPair(a, b)) => {
+            let b_offset = scalar_pair_calculate_b_offset(fx.tcx, a, b).bytes() as i64;
+            vec![(0, a.value), (b_offset, b.value)]
+        }
+        _ => vec![],
+    }
+}
+
+/// Defines the lua wrapper of the function being codegened when it is exported to lua.
+pub(crate) fn maybe_codegen_export(fx: &mut FunctionCx<'_, '_>) {
+    let name = match fx.symbol_name.name.strip_prefix(PREFIX) {
+        Some(name) => name,
+        None => return,
+    };
+    if fx.instance.def.requires_caller_location(fx.tcx) || fx.mir.spread_arg.is_some() {
+        fx.tcx.sess.span_fatal(
+            fx.mir.span,
+            "functions exported to lua must use the rust ABI without `#[track_caller]`",
+        );
+    }
+
+    let mut params = vec!["temps".to_string()];
+    let mut args = vec![];
+    let mut conversions = vec![];
+    for local in fx.mir.args_iter() {
+        let layout = fx.layout_of(fx.monomorphize(fx.mir.local_decls[local].ty));
+        let param = format!("a{}", params.len() - 1);
+        let ptr_name = format!("p{}", params.len() - 1);
+        let ptr = ident(&ptr_name);
+        conversions.push((
+            ptr_name,
+            type_descriptor(fx, layout, fx.mir.span),
+            ident(&param),
+            layout,
+        ));
+        params.push(param);
+
+        match get_pass_mode(layout) {
+            PassMode::NoPass => {}
+            PassMode::ByRef => args.push(ptr),
+            PassMode::ByVal | PassMode::ByValPair => {
+                for (offset, primitive) in scalars(fx, layout) {
+                    args.push(load_primitive(
+                        fx,
+                        ptr_offset(ptr.clone(), offset),
+                        primitive,
+                    ));
+                }
+            }
+        }
+    }
+    let ret_layout = fx.layout_of(fx.monomorphize(fx.mir.local_decls[RETURN_PLACE].ty));
+    let ret_desc = type_descriptor(fx, ret_layout, fx.mir.span);
+    let ret_mode = get_pass_mode(ret_layout);
+    let ret = ident("ret");
+    if ret_mode == PassMode::ByRef {
+        args.insert(0, ret.clone());
+    }
+
+    let entry = ExprBuilder
+        .table_access(
+            rt_field("module").to_place(),
+            ExprBuilder.string(name.to_string()),
+        )
+        .to_place();
+    fx.cx.ctx.start_function_at(entry, params);
+    for (ptr_name, desc, value, layout) in conversions {
+        fx.cx.ctx.stat().local(
+            vec![ptr_name.clone()],
+            vec![rt_call(
+                "alloca",
+                [
+                    int(layout.size.bytes() as i64),
+                    int(layout.align.abi.bytes() as i64),
+                ],
+            )],
+        );
+        fx.cx.ctx.stat().call(
+            rt_field("from_lua"),
+            [desc, value, ident(&ptr_name), ident("temps")],
+        );
+    }
+    if ret_mode != PassMode::NoPass {
+        fx.cx.ctx.stat().local(
+            vec!["ret".to_string()],
+            vec![rt_call(
+                "alloca",
+                [
+                    int(ret_layout.size.bytes() as i64),
+                    int(ret_layout.align.abi.bytes() as i64),
+                ],
+            )],
+        );
+    }
+
+    let function = fn_symbol(fx.symbol_name.name);
+    let ret_scalars = scalars(fx, ret_layout);
+    if ret_scalars.is_empty() {
+        fx.cx.ctx.stat().call(function, args);
+    } else {
+        let results = (0..ret_scalars.len())
+            .map(|i| format!("r{}", i))
+            .collect::<Vec<_>>();
+        fx.cx
+            .ctx
+            .stat()
+            .local(results.clone(), vec![ExprBuilder.call(function, args)]);
+        for ((offset, primitive), result) in ret_scalars.into_iter().zip(results) {
+            store_primitive(
+                fx,
+                ptr_offset(ret.clone(), offset),
+                primitive,
+                ident(&result),
+            );
+        }
+    }
+    if ret_mode != PassMode::NoPass {
+        fx.cx
+            .ctx
+            .stat()
+            .ret(vec![rt_call("to_lua", [ret_desc, ret])]);
+    }
+    fx.cx.ctx.finish_block().unwrap();
+
+    fx.cx
+        .ctx
+        .stat()
+        .call(rt_field("export"), [ExprBuilder.string(name.to_string())]);
+}
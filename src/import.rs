@@ -0,0 +1,95 @@
+//! Calls to lua functions declared in `extern` blocks
+//!
+//! The functions of an `extern` block with `#[link(name = "<lib>")]` are the fields named after
+//! their link name of the lua library `<lib>`, which `rt.lib` resolves to a global table or a
+//! module loaded with `require`. The arguments are converted to lua values by `rt.to_lua` and the
+//! result back by `rt.from_lua`, using the same type descriptors as exported functions.
+
+use rustc_span::Symbol;
+
+use crate::base::{codegen_jump, codegen_operand, codegen_trap};
+use crate::export::type_descriptor;
+use crate::prelude::*;
+
+/// The name of the lua library providing the foreign function `def_id`, if it has one.
+fn lua_library(tcx: TyCtxt<'_>, def_id: DefId) -> Option<Symbol> {
+    if !tcx.is_foreign_item(def_id) {
+        return None;
+    }
+    let foreign_modules = tcx.foreign_modules(def_id.krate);
+    tcx.native_libraries(def_id.krate)
+        .iter()
+        .find(|lib| {
+            lib.foreign_module
+                .and_then(|module| foreign_modules.get(&module))
+                .map_or(false, |module| module.foreign_items.contains(&def_id))
+        })?
+        .name
+}
+
+/// Whether `instance` is a function of a lua library.
+pub(crate) fn is_lua_import<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> bool {
+    lua_library(tcx, instance.def_id()).is_some()
+}
+
+pub(crate) fn codegen_import_call<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    instance: Instance<'tcx>,
+    args: &[mir::Operand<'tcx>],
+    destination: Option<(CPlace<'tcx>, BasicBlock)>,
+    span: Span,
+) {
+    let tcx = fx.tcx;
+    let library = lua_library(tcx, instance.def_id()).unwrap();
+    if tcx.fn_sig(instance.def_id()).c_variadic() {
+        tcx.sess
+            .span_fatal(span, "variadic lua functions are not supported");
+    }
+
+    let args = args
+        .iter()
+        .map(|arg| {
+            let arg = codegen_operand(fx, arg);
+            let desc = type_descriptor(fx, arg.layout(), span);
+            if arg.layout().is_zst() {
+                return ExprBuilder.nil();
+            }
+            // Arguments are moved into the call, so their buffers are freed by `rt.to_lua`
+            let (ptr, _) = arg.force_stack(fx);
+            rt_call("to_lua", [desc, ptr])
+        })
+        .collect::<Vec<_>>();
+
+    let function = ExprBuilder.table_access(
+        rt_call("lib", [ExprBuilder.string(library.to_string())]).to_place(),
+        ExprBuilder.string(tcx.symbol_name(instance).name.to_string()),
+    );
+
+    let (ret, target) = match destination {
+        Some((ret, target)) if !ret.layout().is_zst() => (ret, target),
+        destination => {
+            fx.cx.ctx.stat().call(function, args);
+            match destination {
+                Some((_, target)) => codegen_jump(fx, target),
+                None => codegen_trap(fx, "lua function marked as diverging returned"),
+            }
+            return;
+        }
+    };
+    let ret_layout = ret.layout();
+    let desc = type_descriptor(fx, ret_layout, span);
+    // Borrowed results, like `&str`, are leaked as there is no point at which to free them
+    let slot = CPlace::new_stack_slot(fx, ret_layout);
+    fx.cx.ctx.stat().call(
+        rt_field("from_lua"),
+        [
+            desc,
+            ExprBuilder.call(function, args),
+            slot.to_ptr(),
+            ExprBuilder.table([]),
+        ],
+    );
+    let value = slot.to_cvalue(fx);
+    ret.write_cvalue(fx, value);
+    codegen_jump(fx, target);
+}
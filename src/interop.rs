@@ -0,0 +1,103 @@
+//! Lowering of the functions of the `lua` crate giving access to the lua environment
+//!
+//! The crate declares `__cglua_*` foreign functions taking and returning handles to lua values,
+//! which index the `V` table of the runtime. Calls to them are replaced by the lua operation they
+//! stand for.
+
+use crate::base::{codegen_jump, codegen_operand};
+use crate::prelude::*;
+
+const PREFIX: &str = "__cglua_";
+
+/// Whether `instance` is one of the functions implemented by `codegen_interop_call`.
+pub(crate) fn is_interop_fn<'tcx>(tcx: TyCtxt<'tcx>, instance: Instance<'tcx>) -> bool {
+    tcx.is_foreign_item(instance.def_id()) && tcx.symbol_name(instance).name.starts_with(PREFIX)
+}
+
+/// `V[handle]`, the lua value referred to by `handle`
+fn lua_value(handle: Value) -> Value {
+    ExprBuilder.table_access(ident("V").to_place(), handle)
+}
+
+/// `_G[name]`, the global named by the string at `ptr`
+fn lua_global(ptr: Value, len: Value) -> Value {
+    ExprBuilder.table_access(ident("_G").to_place(), rt_call("load_str", [ptr, len]))
+}
+
+fn lua_ref(value: Value) -> Value {
+    rt_call("ref", [value])
+}
+
+pub(crate) fn codegen_interop_call<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    instance: Instance<'tcx>,
+    args: &[mir::Operand<'tcx>],
+    destination: Option<(CPlace<'tcx>, BasicBlock)>,
+    span: Span,
+) {
+    let symbol = fx.tcx.symbol_name(instance).name;
+    let args = args
+        .iter()
+        .map(|arg| {
+            let arg = codegen_operand(fx, arg);
+            arg.load_scalar(fx)
+        })
+        .collect::<Vec<_>>();
+    let arg = |i: usize| args[i].clone();
+
+    let result = match &symbol[PREFIX.len()..] {
+        "global" => Some(lua_ref(lua_global(arg(0), arg(1)))),
+        "set_global" => {
+            fx.cx
+                .ctx
+                .stat()
+                .assign(lua_global(arg(0), arg(1)).to_place(), lua_value(arg(2)));
+            None
+        }
+        "new_table" => Some(lua_ref(ExprBuilder.table([]))),
+        "clone" => Some(lua_ref(lua_value(arg(0)))),
+        "drop" => {
+            fx.cx.ctx.stat().call(rt_field("unref"), [arg(0)]);
+            None
+        }
+        "from_int" | "from_float" => Some(lua_ref(arg(0))),
+        "from_bool" => Some(lua_ref(int_to_bool(arg(0)))),
+        "from_str" => Some(lua_ref(rt_call("load_str", [arg(0), arg(1)]))),
+        "type" => Some(rt_call("type_tag", [lua_value(arg(0))])),
+        "to_int" | "to_float" => Some(lua_value(arg(0))),
+        "to_bool" => Some(bool_to_int(lua_value(arg(0)))),
+        "len" => Some(ExprBuilder.unop(LuaUnOp::Len, lua_value(arg(0)))),
+        "copy_str" => {
+            fx.cx
+                .ctx
+                .stat()
+                .call(rt_field("store_str"), [arg(1), lua_value(arg(0))]);
+            None
+        }
+        "get" => {
+            let table = lua_value(arg(0)).to_place();
+            Some(lua_ref(ExprBuilder.table_access(table, lua_value(arg(1)))))
+        }
+        "set" => {
+            let table = lua_value(arg(0)).to_place();
+            let place = ExprBuilder
+                .table_access(table, lua_value(arg(1)))
+                .to_place();
+            fx.cx.ctx.stat().assign(place, lua_value(arg(2)));
+            None
+        }
+        "call" => Some(rt_call("call", [lua_value(arg(0)), arg(1), arg(2)])),
+        "function" => Some(lua_ref(rt_call("lua_function", [arg(0), arg(1)]))),
+        _ => fx.tcx.sess.span_fatal(
+            span,
+            &format!("`{}` is not a function of the lua interop crate", symbol),
+        ),
+    };
+
+    let (ret, target) = destination.expect("lua interop functions return");
+    if let Some(result) = result {
+        let layout = ret.layout();
+        ret.write_cvalue(fx, CValue::by_val(result, layout));
+    }
+    codegen_jump(fx, target);
+}
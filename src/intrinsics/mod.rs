@@ -0,0 +1,586 @@
+//! Codegen of intrinsics. This includes `extern "rust-intrinsic"` functions.
+//!
+//! Every supported intrinsic is lowered to a lua expression or a call to a runtime helper.
+//! Intrinsics that are not supported yet are reported as a compile error naming them.
+
+use rustc_middle::ty::subst::SubstsRef;
+use rustc_span::symbol::{sym, Symbol};
+
+use crate::base::{codegen_jump, codegen_operand, codegen_panic, codegen_trap};
+use crate::num::{math, wrap_int};
+use crate::prelude::*;
+
+fn report_unsupported_intrinsic(tcx: TyCtxt<'_>, intrinsic: Symbol, span: Span) -> ! {
+    tcx.sess.span_fatal(
+        span,
+        &format!(
+            "the `{}` intrinsic is not supported by the lua backend yet",
+            intrinsic
+        ),
+    );
+}
+
+pub(crate) fn codegen_intrinsic_call<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    instance: Instance<'tcx>,
+    args: &[mir::Operand<'tcx>],
+    destination: Option<(CPlace<'tcx>, BasicBlock)>,
+    span: Span,
+) {
+    let intrinsic = fx.tcx.item_name(instance.def_id());
+    let substs = instance.substs;
+
+    let (ret, target) = match destination {
+        Some(destination) => destination,
+        None => {
+            match intrinsic {
+                sym::abort => {
+                    fx.cx.ctx.stat().call(rt_field("abort"), []);
+                }
+                sym::transmute => codegen_trap(fx, "transmuting to uninhabited type"),
+                sym::unreachable => codegen_trap(fx, "entered unreachable code"),
+                _ => report_unsupported_intrinsic(fx.tcx, intrinsic, span),
+            }
+            return;
+        }
+    };
+
+    if intrinsic.as_str().starts_with("simd_") {
+        fx.tcx
+            .sess
+            .span_fatal(span, "simd intrinsics are not supported by the lua backend");
+    }
+
+    let args = args
+        .iter()
+        .map(|arg| codegen_operand(fx, arg))
+        .collect::<Vec<_>>();
+
+    if intrinsic.as_str().starts_with("atomic_") {
+        codegen_atomic_intrinsic(fx, intrinsic, substs, args, ret, span);
+    } else if let Some(res) = codegen_float_intrinsic(fx, intrinsic, &args) {
+        let layout = ret.layout();
+        ret.write_cvalue(fx, CValue::by_val(res, layout));
+    } else {
+        codegen_regular_intrinsic(fx, instance, intrinsic, substs, args, ret, span);
+    }
+
+    codegen_jump(fx, target);
+}
+
+/// `count` elements of `elem_size` bytes, in bytes
+fn byte_count(count: Value, elem_size: u64) -> Value {
+    match elem_size {
+        1 => count,
+        _ => binop(LuaBinOp::Mul, count, int(elem_size as i64)),
+    }
+}
+
+fn codegen_regular_intrinsic<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    instance: Instance<'tcx>,
+    intrinsic: Symbol,
+    substs: SubstsRef<'tcx>,
+    args: Vec<CValue<'tcx>>,
+    ret: CPlace<'tcx>,
+    span: Span,
+) {
+    let ret_layout = ret.layout();
+    let usize_layout = fx.layout_of(fx.tcx.types.usize);
+
+    match intrinsic {
+        sym::assume | sym::forget => {}
+        sym::likely | sym::unlikely => {
+            ret.write_cvalue(fx, args[0].clone());
+        }
+        sym::breakpoint => codegen_trap(fx, "breakpoint"),
+        sym::copy | sym::copy_nonoverlapping => {
+            let elem_size = fx.layout_of(substs.type_at(0)).size.bytes();
+            let src = args[0].clone().load_scalar(fx);
+            let dst = args[1].clone().load_scalar(fx);
+            let count = args[2].clone().load_scalar(fx);
+            fx.cx
+                .ctx
+                .stat()
+                .call(rt_field("memcpy"), [dst, src, byte_count(count, elem_size)]);
+        }
+        sym::volatile_copy_memory | sym::volatile_copy_nonoverlapping_memory => {
+            // NOTE: the volatile variants have the src and dst swapped
+            let elem_size = fx.layout_of(substs.type_at(0)).size.bytes();
+            let dst = args[0].clone().load_scalar(fx);
+            let src = args[1].clone().load_scalar(fx);
+            let count = args[2].clone().load_scalar(fx);
+            fx.cx
+                .ctx
+                .stat()
+                .call(rt_field("memcpy"), [dst, src, byte_count(count, elem_size)]);
+        }
+        sym::write_bytes | sym::volatile_set_memory => {
+            let elem_size = fx.layout_of(substs.type_at(0)).size.bytes();
+            let dst = args[0].clone().load_scalar(fx);
+            let val = args[1].clone().load_scalar(fx);
+            let count = args[2].clone().load_scalar(fx);
+            fx.cx
+                .ctx
+                .stat()
+                .call(rt_field("memset"), [dst, val, byte_count(count, elem_size)]);
+        }
+        sym::size_of_val | sym::min_align_of_val => {
+            let layout = fx.layout_of(substs.type_at(0));
+            let (size, align) = if layout.is_unsized() {
+                let (_ptr, info) = args[0].clone().load_scalar_pair(fx);
+                crate::unsize::size_and_align_of_dst(fx, layout, info)
+            } else {
+                (
+                    int(layout.size.bytes() as i64),
+                    int(layout.align.abi.bytes() as i64),
+                )
+            };
+            let res = if intrinsic == sym::size_of_val {
+                size
+            } else {
+                align
+            };
+            ret.write_cvalue(fx, CValue::by_val(res, usize_layout));
+        }
+        sym::size_of
+        | sym::pref_align_of
+        | sym::min_align_of
+        | sym::needs_drop
+        | sym::type_id
+        | sym::type_name
+        | sym::variant_count => {
+            let const_val = fx
+                .tcx
+                .const_eval_instance(ParamEnv::reveal_all(), instance, None)
+                .unwrap();
+            let val = crate::constant::codegen_const_value(fx, const_val, ret_layout.ty);
+            ret.write_cvalue(fx, val);
+        }
+        sym::ctpop
+        | sym::ctlz
+        | sym::ctlz_nonzero
+        | sym::cttz
+        | sym::cttz_nonzero
+        | sym::bswap
+        | sym::bitreverse => {
+            let (bits, signed) = int_ty_bits(fx.tcx, args[0].layout().ty).unwrap();
+            let val = args[0].clone().load_scalar(fx);
+            let name = match intrinsic {
+                sym::ctpop => "ctpop",
+                sym::ctlz | sym::ctlz_nonzero => "ctlz",
+                sym::cttz | sym::cttz_nonzero => "cttz",
+                sym::bswap => "bswap",
+                sym::bitreverse => "bitreverse",
+                _ => unreachable!(),
+            };
+            let res = if bits == 128 {
+                rt_call(&format!("{}128", name), [val])
+            } else {
+                rt_call(name, [val, int(bits as i64)])
+            };
+            let res = match intrinsic {
+                // The result has the type of the operand, but is a count
+                sym::ctpop | sym::ctlz | sym::ctlz_nonzero | sym::cttz | sym::cttz_nonzero
+                    if bits == 128 =>
+                {
+                    rt_call("to128", [res, ExprBuilder.bool(false)])
+                }
+                _ => wrap_int(res, bits, signed),
+            };
+            ret.write_cvalue(fx, CValue::by_val(res, ret_layout));
+        }
+        sym::rotate_left | sym::rotate_right => {
+            let (bits, signed) = int_ty_bits(fx.tcx, args[0].layout().ty).unwrap();
+            let x = args[0].clone().load_scalar(fx);
+            let y = args[1].clone().load_scalar(fx);
+            let res = if bits == 128 {
+                let name = if intrinsic == sym::rotate_left {
+                    "rotl128"
+                } else {
+                    "rotr128"
+                };
+                let y = fx.tmp(y);
+                rt_call(name, [x, ExprBuilder.table_access(y.to_place(), int(1))])
+            } else {
+                let name = if intrinsic == sym::rotate_left {
+                    "rotl"
+                } else {
+                    "rotr"
+                };
+                wrap_int(rt_call(name, [x, y, int(bits as i64)]), bits, signed)
+            };
+            ret.write_cvalue(fx, CValue::by_val(res, ret_layout));
+        }
+        sym::saturating_add | sym::saturating_sub => {
+            let (bits, signed) = int_ty_bits(fx.tcx, args[0].layout().ty).unwrap();
+            let lhs = args[0].clone().load_scalar(fx);
+            let rhs = args[1].clone().load_scalar(fx);
+            let res = match (intrinsic == sym::saturating_add, bits) {
+                (true, 128) => rt_call("sat_add128", [lhs, rhs, ExprBuilder.bool(signed)]),
+                (false, 128) => rt_call("sat_sub128", [lhs, rhs, ExprBuilder.bool(signed)]),
+                (true, _) => rt_call(
+                    "sat_add",
+                    [lhs, rhs, int(bits as i64), ExprBuilder.bool(signed)],
+                ),
+                (false, _) => rt_call(
+                    "sat_sub",
+                    [lhs, rhs, int(bits as i64), ExprBuilder.bool(signed)],
+                ),
+            };
+            ret.write_cvalue(fx, CValue::by_val(res, ret_layout));
+        }
+        sym::unchecked_add
+        | sym::unchecked_sub
+        | sym::unchecked_mul
+        | sym::unchecked_div
+        | sym::exact_div
+        | sym::unchecked_rem
+        | sym::unchecked_shl
+        | sym::unchecked_shr
+        | sym::wrapping_add
+        | sym::wrapping_sub
+        | sym::wrapping_mul => {
+            let bin_op = match intrinsic {
+                sym::unchecked_add | sym::wrapping_add => BinOp::Add,
+                sym::unchecked_sub | sym::wrapping_sub => BinOp::Sub,
+                sym::unchecked_mul | sym::wrapping_mul => BinOp::Mul,
+                sym::unchecked_div | sym::exact_div => BinOp::Div,
+                sym::unchecked_rem => BinOp::Rem,
+                sym::unchecked_shl => BinOp::Shl,
+                sym::unchecked_shr => BinOp::Shr,
+                _ => unreachable!(),
+            };
+            let res = crate::num::codegen_int_binop(fx, bin_op, args[0].clone(), args[1].clone());
+            ret.write_cvalue(fx, res);
+        }
+        sym::add_with_overflow | sym::sub_with_overflow | sym::mul_with_overflow => {
+            let bin_op = match intrinsic {
+                sym::add_with_overflow => BinOp::Add,
+                sym::sub_with_overflow => BinOp::Sub,
+                sym::mul_with_overflow => BinOp::Mul,
+                _ => unreachable!(),
+            };
+            let res =
+                crate::num::codegen_checked_int_binop(fx, bin_op, args[0].clone(), args[1].clone());
+            ret.write_cvalue(fx, res);
+        }
+        sym::fadd_fast | sym::fsub_fast | sym::fmul_fast | sym::fdiv_fast | sym::frem_fast => {
+            let bin_op = match intrinsic {
+                sym::fadd_fast => BinOp::Add,
+                sym::fsub_fast => BinOp::Sub,
+                sym::fmul_fast => BinOp::Mul,
+                sym::fdiv_fast => BinOp::Div,
+                sym::frem_fast => BinOp::Rem,
+                _ => unreachable!(),
+            };
+            let res = crate::num::codegen_float_binop(fx, bin_op, args[0].clone(), args[1].clone());
+            ret.write_cvalue(fx, res);
+        }
+        sym::discriminant_value => {
+            let ptr = args[0].clone().load_scalar(fx);
+            let val = CPlace::for_ptr(ptr, fx.layout_of(substs.type_at(0))).to_cvalue(fx);
+            let discr = crate::discriminant::codegen_get_discriminant(fx, val, ret_layout);
+            ret.write_cvalue(fx, discr);
+        }
+        sym::transmute => {
+            let res = codegen_transmute(fx, args[0].clone(), ret_layout);
+            ret.write_cvalue(fx, res);
+        }
+        sym::ptr_offset_from => {
+            let pointee_size = fx.layout_of(substs.type_at(0)).size.bytes();
+            let ptr = args[0].clone().load_scalar(fx);
+            let base = args[1].clone().load_scalar(fx);
+            let diff_bytes = binop(LuaBinOp::Sub, ptr, base);
+            // The difference is an exact multiple of the pointee size
+            let diff = match pointee_size {
+                1 => diff_bytes,
+                _ => binop(LuaBinOp::IDiv, diff_bytes, int(pointee_size as i64)),
+            };
+            ret.write_cvalue(fx, CValue::by_val(diff, ret_layout));
+        }
+        sym::offset | sym::arith_offset => {
+            let res =
+                crate::num::codegen_ptr_binop(fx, BinOp::Offset, args[0].clone(), args[1].clone());
+            ret.write_cvalue(fx, res);
+        }
+        sym::ptr_guaranteed_eq | sym::ptr_guaranteed_ne => {
+            let bin_op = if intrinsic == sym::ptr_guaranteed_eq {
+                BinOp::Eq
+            } else {
+                BinOp::Ne
+            };
+            let res = crate::num::codegen_ptr_binop(fx, bin_op, args[0].clone(), args[1].clone());
+            ret.write_cvalue(fx, res);
+        }
+        sym::caller_location => {
+            let caller_location = fx.get_caller_location(span);
+            ret.write_cvalue(fx, caller_location);
+        }
+        sym::volatile_load | sym::unaligned_volatile_load => {
+            let ptr = args[0].clone().load_scalar(fx);
+            let val = CPlace::for_ptr(ptr, fx.layout_of(substs.type_at(0))).to_cvalue(fx);
+            ret.write_cvalue(fx, val);
+        }
+        sym::volatile_store | sym::unaligned_volatile_store | sym::nontemporal_store => {
+            let ptr = args[0].clone().load_scalar(fx);
+            let val = args[1].clone();
+            let dest = CPlace::for_ptr(ptr, val.layout());
+            dest.write_cvalue(fx, val);
+        }
+        sym::raw_eq => {
+            let size = fx.layout_of(substs.type_at(0)).size.bytes();
+            let lhs = args[0].clone().load_scalar(fx);
+            let rhs = args[1].clone().load_scalar(fx);
+            let is_eq = if size == 0 {
+                int(1)
+            } else {
+                bool_to_int(rt_call("memeq", [lhs, rhs, int(size as i64)]))
+            };
+            ret.write_cvalue(fx, CValue::by_val(is_eq, ret_layout));
+        }
+        sym::assert_inhabited | sym::assert_zero_valid | sym::assert_uninit_valid => {
+            let layout = fx.layout_of(substs.type_at(0));
+            if layout.abi.is_uninhabited() {
+                codegen_panic(
+                    fx,
+                    &format!("attempted to instantiate uninhabited type `{}`", layout.ty),
+                    span,
+                );
+            } else if intrinsic == sym::assert_zero_valid && !layout.might_permit_raw_init(fx, true)
+            {
+                codegen_panic(
+                    fx,
+                    &format!(
+                        "attempted to zero-initialize type `{}`, which is invalid",
+                        layout.ty
+                    ),
+                    span,
+                );
+            } else if intrinsic == sym::assert_uninit_valid
+                && !layout.might_permit_raw_init(fx, false)
+            {
+                codegen_panic(
+                    fx,
+                    &format!(
+                        "attempted to leave type `{}` uninitialized, which is invalid",
+                        layout.ty
+                    ),
+                    span,
+                );
+            }
+        }
+        sym::r#try => {
+            let f = args[0].clone().load_scalar(fx);
+            let data = args[1].clone().load_scalar(fx);
+            let data = fx.tmp(data);
+            let try_fn = ExprBuilder.table_access(ident("F").to_place(), f);
+
+            if !unwinding_enabled(fx.tcx) {
+                // The closure can't panic without aborting the program, so the catch function is
+                // never called
+                fx.cx.ctx.stat().call(try_fn, [data]);
+                ret.write_cvalue(fx, CValue::by_val(int(0), ret_layout));
+                return;
+            }
+
+            let catch_fn = args[2].clone().load_scalar(fx);
+            let catch_fn = ExprBuilder.table_access(ident("F").to_place(), catch_fn);
+            let sp = fx.tmp(rt_field("sp"));
+            let mut results =
+                fx.tmp_multi(2, ExprBuilder.call(ident("pcall"), [try_fn, data.clone()]));
+            let exception = results.pop().unwrap();
+            let ok = results.pop().unwrap();
+            let caught = fx.tmp(int(0));
+
+            fx.cx.ctx.start_if(ExprBuilder.unop(LuaUnOp::Not, ok));
+            fx.cx.ctx.stat().assign(rt_field("sp").to_place(), sp);
+            // `rt.catch` raises errors that are not rust panics again, like aborts
+            fx.cx
+                .ctx
+                .stat()
+                .call(catch_fn, [data, rt_call("catch", [exception])]);
+            fx.cx.ctx.stat().assign(caught.to_place(), int(1));
+            fx.cx.ctx.finish_block().unwrap();
+
+            ret.write_cvalue(fx, CValue::by_val(caught, ret_layout));
+        }
+        _ => report_unsupported_intrinsic(fx.tcx, intrinsic, span),
+    }
+}
+
+/// Reinterprets the bytes of `from` as a value of type `layout`.
+fn codegen_transmute<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    from: CValue<'tcx>,
+    layout: TyAndLayout<'tcx>,
+) -> CValue<'tcx> {
+    let int_like = |primitive: rustc_target::abi::Primitive| match primitive {
+        rustc_target::abi::Primitive::Int(integer, signed) => Some((integer.size().bits(), signed)),
+        rustc_target::abi::Primitive::Pointer => Some((fx.pointer_size() * 8, false)),
+        rustc_target::abi::Primitive::F32 | rustc_target::abi::Primitive::F64 => None,
+    };
+
+    if let (Abi::Scalar(src), Abi::Scalar(dst)) = (&from.layout().abi, &layout.abi) {
+        match (int_like(src.value), int_like(dst.value)) {
+            // Integers of the same size only differ by the sign extension of their lua value
+            (Some((src_bits, src_signed)), Some((dst_bits, dst_signed)))
+                if src_bits == dst_bits =>
+            {
+                let val = from.load_scalar(fx);
+                let val = if src_signed != dst_signed {
+                    wrap_int(val, dst_bits, dst_signed)
+                } else {
+                    val
+                };
+                return CValue::by_val(val, layout);
+            }
+            (None, None) if src.value == dst.value => return from.transmute(layout),
+            _ => {}
+        }
+    }
+
+    // Go through memory for everything else, like reinterpreting the bits of floats
+    let (ptr, _) = from.force_stack(fx);
+    CValue::by_ref(ptr, layout)
+}
+
+/// Lowers the float math intrinsics to the `math` library or runtime helpers, returning `None`
+/// for other intrinsics.
+///
+/// The result of inexact `f32` operations is rounded back to single precision.
+fn codegen_float_intrinsic<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    intrinsic: Symbol,
+    args: &[CValue<'tcx>],
+) -> Option<Value> {
+    let (function, exact) = match intrinsic {
+        sym::sqrtf32 | sym::sqrtf64 => (math("sqrt"), false),
+        sym::powif32 | sym::powif64 | sym::powf32 | sym::powf64 => (rt_field("pow"), false),
+        sym::sinf32 | sym::sinf64 => (math("sin"), false),
+        sym::cosf32 | sym::cosf64 => (math("cos"), false),
+        sym::expf32 | sym::expf64 => (math("exp"), false),
+        sym::exp2f32 | sym::exp2f64 => (rt_field("exp2"), false),
+        sym::logf32 | sym::logf64 => (math("log"), false),
+        sym::log2f32 | sym::log2f64 => (rt_field("log2"), false),
+        sym::log10f32 | sym::log10f64 => (rt_field("log10"), false),
+        // The runtime rounds the fused result of f32 operands to single precision itself
+        sym::fmaf32 => (rt_field("fmaf32"), true),
+        sym::fmaf64 => (rt_field("fma"), true),
+        sym::fabsf32 | sym::fabsf64 => (math("abs"), true),
+        sym::copysignf32 | sym::copysignf64 => (rt_field("copysign"), true),
+        sym::minnumf32 | sym::minnumf64 => (rt_field("minnum"), true),
+        sym::maxnumf32 | sym::maxnumf64 => (rt_field("maxnum"), true),
+        sym::floorf32 | sym::floorf64 => (rt_field("floor"), true),
+        sym::ceilf32 | sym::ceilf64 => (rt_field("ceil"), true),
+        sym::truncf32 | sym::truncf64 => (rt_field("trunc"), true),
+        sym::roundf32 | sym::roundf64 => (rt_field("round"), true),
+        sym::rintf32 | sym::rintf64 | sym::nearbyintf32 | sym::nearbyintf64 => {
+            (rt_field("rint"), true)
+        }
+        _ => return None,
+    };
+
+    let is_f32 = args[0].layout().ty == fx.tcx.types.f32;
+    let args = args
+        .iter()
+        .map(|arg| arg.clone().load_scalar(fx))
+        .collect::<Vec<_>>();
+    let res = ExprBuilder.call(function, args);
+    if is_f32 && !exact {
+        Some(rt_call("fround", [res]))
+    } else {
+        Some(res)
+    }
+}
+
+/// Lua code runs on a single thread, so atomic operations are plain memory accesses.
+fn codegen_atomic_intrinsic<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    intrinsic: Symbol,
+    substs: SubstsRef<'tcx>,
+    args: Vec<CValue<'tcx>>,
+    ret: CPlace<'tcx>,
+    span: Span,
+) {
+    let name = intrinsic.as_str();
+    let op = name["atomic_".len()..].split('_').next().unwrap();
+
+    if let "fence" | "singlethreadfence" = op {
+        return;
+    }
+
+    let layout = fx.layout_of(substs.type_at(0));
+    let ptr = args[0].clone().load_scalar(fx);
+    let ptr = fx.tmp(ptr);
+    let place = CPlace::for_ptr(ptr, layout);
+
+    if op == "store" {
+        place.write_cvalue(fx, args[1].clone());
+        return;
+    }
+
+    let old = place.to_cvalue(fx).load_scalar(fx);
+    let old = fx.tmp(old);
+    let old_val = CValue::by_val(old.clone(), layout);
+    if op == "load" {
+        ret.write_cvalue(fx, old_val);
+        return;
+    }
+
+    let (bits, signed) = int_ty_bits(fx.tcx, layout.ty).unwrap();
+    let src = args[1].clone();
+    let new = match op {
+        "xchg" => src.load_scalar(fx),
+        "cxchg" | "cxchgweak" => {
+            let test_old = src.load_scalar(fx);
+            let new = args[2].clone().load_scalar(fx);
+            let is_eq = crate::num::codegen_icmp(BinOp::Eq, bits, signed, old.clone(), test_old);
+            let is_eq = fx.tmp(bool_to_int(is_eq));
+
+            fx.cx.ctx.start_if(int_to_bool(is_eq.clone()));
+            place.write_cvalue(fx, CValue::by_val(new, layout));
+            fx.cx.ctx.finish_block().unwrap();
+
+            let ret_layout = ret.layout();
+            ret.write_cvalue(fx, CValue::by_val_pair(old, is_eq, ret_layout));
+            return;
+        }
+        "xadd" | "xsub" | "and" | "or" | "xor" => {
+            let bin_op = match op {
+                "xadd" => BinOp::Add,
+                "xsub" => BinOp::Sub,
+                "and" => BinOp::BitAnd,
+                "or" => BinOp::BitOr,
+                "xor" => BinOp::BitXor,
+                _ => unreachable!(),
+            };
+            crate::num::codegen_int_binop(fx, bin_op, old_val.clone(), src).load_scalar(fx)
+        }
+        "nand" => {
+            let and = crate::num::codegen_int_binop(fx, BinOp::BitAnd, old_val.clone(), src)
+                .load_scalar(fx);
+            crate::num::codegen_int_not(fx, layout.ty, and)
+        }
+        "max" | "min" | "umax" | "umin" => {
+            let src = src.load_scalar(fx);
+            let src = fx.tmp(src);
+            let signed = op == "max" || op == "min";
+            let cmp_op = if op == "max" || op == "umax" {
+                BinOp::Gt
+            } else {
+                BinOp::Lt
+            };
+            let keep_old = crate::num::codegen_icmp(cmp_op, bits, signed, old.clone(), src.clone());
+            binop(
+                LuaBinOp::Or,
+                binop(LuaBinOp::And, keep_old, old.clone()),
+                src,
+            )
+        }
+        _ => report_unsupported_intrinsic(fx.tcx, intrinsic, span),
+    };
+
+    place.write_cvalue(fx, CValue::by_val(new, layout));
+    ret.write_cvalue(fx, old_val);
+}
@@ -0,0 +1,244 @@
+//! The jit modes, running the crate in an embedded lua interpreter instead of writing lua files
+//!
+//! `cargo jit` passes `-Cllvm-args=mode=jit`: the local crate is compiled in memory, linked with
+//! the code of the rlibs of its dependencies and run right away, the compiler exiting with the exit
+//! code of the program. Arguments are given to the program with `CG_LUA_JIT_ARGS`, see
+//! [`BackendConfig::jit_args`].
+//!
+//! Only the local crate is compiled in memory: the code of the dependencies, std included, is read
+//! from the lua modules stored in their rlibs. The jit modes therefore need a sysroot built by
+//! `./y.rs build` with the same version of the backend, as rlibs compiled against another version
+//! of the runtime are rejected.
+//!
+//! `cargo lazy-jit` passes `-Cllvm-args=mode=jit-lazy`, which only compiles the statics and the
+//! entry point upfront. Each function of the local crate starts as a trampoline in `S` which asks
+//! the compiler for its code on the first call, loads it and replaces itself with it. The
+//! functions are found again by their symbol name in `LAZY_INSTANCES`.
+
+use std::cell::RefCell;
+
+use rustc_data_structures::fx::FxHashMap;
+use rustc_hir::def_id::{CrateNum, LOCAL_CRATE};
+use rustc_middle::middle::dependency_format::Linkage;
+use rustc_middle::mir::mono::MonoItem;
+use rustc_session::config::CrateType;
+
+use cglua::interpreter::{Error, Lua, Table, Value as LuaValue};
+
+use crate::link::Runtime;
+use crate::prelude::*;
+use crate::{BackendConfig, CodegenMode};
+
+/// Defines the trampolines of lazily compiled functions. The native function compiling them is
+/// only reachable through this chunk.
+const LAZY_PRELUDE: &str = r#"local jit_compile = __cg_lua_jit_compile
+__cg_lua_jit_compile = nil
+local function jit_stub(name)
+  local f
+  S[name] = function(...)
+    f = f or jit_compile(name, rt)
+    return f(...)
+  end
+end
+"#;
+
+thread_local! {
+    /// The functions compiled on their first call, by symbol name. They are stored without their
+    /// lifetime and lifted back to the `TyCtxt` when they are compiled, which happens while the
+    /// `TyCtxt` they come from is still alive.
+    static LAZY_INSTANCES: RefCell<FxHashMap<String, Instance<'static>>> =
+        RefCell::new(FxHashMap::default());
+}
+
+pub(crate) fn run_jit(tcx: TyCtxt<'_>, config: &BackendConfig) -> ! {
+    if !tcx.sess.opts.output_types.should_codegen() {
+        tcx.sess.fatal("JIT mode doesn't work with `cargo check`");
+    }
+
+    if !tcx.sess.crate_types().contains(&CrateType::Executable) {
+        tcx.sess.fatal("can't jit non-executable crate");
+    }
+
+    let lazy = config.codegen_mode == CodegenMode::JitLazy;
+    let mut code = crate::link::runtime_header(tcx.sess, config.features, Runtime::Full);
+    if lazy {
+        code.push_str(LAZY_PRELUDE);
+    }
+
+    let ctx = tcx
+        .sess
+        .time("codegen crate", || codegen_local_crate(tcx, config, lazy));
+    tcx.sess.abort_if_errors();
+    let mut local = String::new();
+    ctx.render(&mut local)
+        .expect("rendering to a string can't fail");
+    crate::link::push_module(&mut code, local.as_bytes());
+    push_dependencies(tcx, &mut code);
+    code.push_str(crate::link::EXECUTABLE_FOOTER);
+
+    let crate_name = tcx.crate_name(LOCAL_CRATE).to_string();
+    let mut lua = Lua::new();
+    lua.inherit_stdio();
+    lua.globals()
+        .set("arg", jit_args(&crate_name, &config.jit_args));
+    if lazy {
+        let config = config.clone();
+        lua.set_function("__cg_lua_jit_compile", move |lua, args| {
+            compile_lazily(lua, args, &config)
+        });
+    }
+
+    println!(
+        "Rustc codegen lua will JIT run the executable, because -Cllvm-args=mode={} was passed",
+        if lazy { "jit-lazy" } else { "jit" }
+    );
+
+    let res = lua
+        .load(&crate_name, code.as_bytes())
+        .and_then(|main| lua.call(&main, vec![]));
+    let exit_code = match res {
+        Ok(_) => 0,
+        Err(Error::Exit(code)) => code,
+        Err(err) => tcx
+            .sess
+            .fatal(&format!("error running {}: {}", crate_name, err)),
+    };
+    use std::io::Write;
+    let _ = std::io::stdout().flush();
+    std::process::exit(exit_code);
+}
+
+/// The `arg` table of the program: the crate name followed by the arguments
+fn jit_args(crate_name: &str, jit_args: &[String]) -> Table {
+    let args = Table::new();
+    args.set(0i64, crate_name);
+    for (i, arg) in jit_args.iter().enumerate() {
+        args.set(i as i64 + 1, arg.as_str());
+    }
+    args
+}
+
+/// Compiles the mono items of all the codegen units of the local crate into a single module. In
+/// lazy mode, functions are replaced by trampolines.
+fn codegen_local_crate(tcx: TyCtxt<'_>, config: &BackendConfig, lazy: bool) -> Context {
+    let (_, cgus) = tcx.collect_and_partition_mono_items(());
+    let mut cx = CodegenCx::new(tcx, config.clone());
+    for cgu in cgus {
+        for (mono_item, _) in cgu.items_in_deterministic_order(tcx) {
+            match mono_item {
+                MonoItem::Fn(inst) if lazy => {
+                    let name = tcx.symbol_name(inst).name.to_string();
+                    // Safety: only used through `tcx.lift`, which checks that it belongs to `tcx`
+                    let inst =
+                        unsafe { std::mem::transmute::<Instance<'_>, Instance<'static>>(inst) };
+                    LAZY_INSTANCES
+                        .with(|instances| instances.borrow_mut().insert(name.clone(), inst));
+                    cx.ctx
+                        .stat()
+                        .call(ident("jit_stub"), [ExprBuilder.string(name)]);
+                }
+                MonoItem::Fn(inst) => {
+                    tcx.sess
+                        .time("codegen fn", || crate::base::codegen_fn(&mut cx, inst));
+                }
+                MonoItem::Static(def_id) => crate::constant::codegen_static(tcx, &mut cx, def_id),
+                MonoItem::GlobalAsm(item_id) => {
+                    let item = tcx.hir().item(item_id);
+                    tcx.sess
+                        .span_fatal(item.span, "global_asm! is not supported by the lua backend");
+                }
+            }
+        }
+    }
+
+    crate::main_shim::maybe_create_entry_wrapper(tcx, &mut cx.ctx, true);
+    if let Some(kind) = tcx.allocator_kind(()) {
+        crate::allocator::codegen(&mut cx.ctx, kind, tcx.lang_items().oom().is_some());
+    }
+
+    let CodegenCx {
+        mut ctx,
+        constants_cx,
+        ..
+    } = cx;
+    constants_cx.finalize(tcx, &mut ctx);
+    ctx
+}
+
+/// Appends the code of the rlibs of all the dependencies. `cargo jit` prefers dylibs, but as they
+/// can't be loaded the crates linked through them are taken from their rlibs too.
+fn push_dependencies(tcx: TyCtxt<'_>, code: &mut String) {
+    let formats = tcx.dependency_formats(());
+    let linkages = formats
+        .iter()
+        .find(|(ty, _)| *ty == CrateType::Executable)
+        .map(|(_, linkages)| &linkages[..])
+        .unwrap_or(&[]);
+    for (i, linkage) in linkages.iter().enumerate() {
+        if *linkage == Linkage::NotLinked {
+            continue;
+        }
+        let cnum = CrateNum::new(i + 1);
+        let source = tcx.used_crate_source(cnum);
+        let rlib = source
+            .rlib
+            .as_ref()
+            .map(|(path, _)| path)
+            .unwrap_or_else(|| {
+                tcx.sess.fatal(&format!(
+                    "no rlib found for crate `{}`",
+                    tcx.crate_name(cnum)
+                ))
+            });
+        crate::link::push_rlib(tcx.sess, code, rlib);
+    }
+}
+
+/// Called by the trampoline of the function `name` the first time it runs: compiles the function,
+/// runs its code with the runtime `rt` and returns the defined function.
+fn compile_lazily(
+    lua: &mut Lua,
+    args: Vec<LuaValue>,
+    config: &BackendConfig,
+) -> Result<Vec<LuaValue>, Error> {
+    let (name, rt) = match &args[..] {
+        [LuaValue::Str(name), LuaValue::Table(rt)] => {
+            (String::from_utf8_lossy(name).into_owned(), rt.clone())
+        }
+        _ => return Err(Error::Runtime("invalid arguments to the jit".to_string())),
+    };
+
+    let code = rustc_middle::ty::tls::with(|tcx| {
+        let instance = LAZY_INSTANCES.with(|instances| instances.borrow().get(&name).copied())?;
+        let instance = tcx.lift(instance)?;
+        let mut cx = CodegenCx::new(tcx, config.clone());
+        tcx.sess
+            .time("codegen fn", || crate::base::codegen_fn(&mut cx, instance));
+        let CodegenCx {
+            mut ctx,
+            constants_cx,
+            ..
+        } = cx;
+        constants_cx.finalize(tcx, &mut ctx);
+
+        let mut code = format!(
+            "local rt = ...\n\
+             local PTR_SIZE = {}\n\
+             local S, D, F, V = rt.S, rt.D, rt.F, rt.V\n",
+            tcx.sess.target.pointer_width / 8,
+        );
+        ctx.render(&mut code)
+            .expect("rendering to a string can't fail");
+        code.push_str("\nrt.link()\n");
+        Some(code)
+    })
+    .ok_or_else(|| Error::Runtime(format!("no function {} to compile", name)))?;
+
+    let chunk = lua.load(&name, code.as_bytes())?;
+    lua.call(&chunk, vec![LuaValue::Table(rt.clone())])?;
+    let function = rt
+        .get("S")
+        .as_table()
+        .map_or(LuaValue::Nil, |symbols| symbols.get(name.as_str()));
+    Ok(vec![function])
+}
@@ -1,13 +1,16 @@
-#![feature(rustc_private)]
+#![feature(rustc_private, once_cell)]
 
 extern crate rustc_ast;
 extern crate rustc_codegen_ssa;
 extern crate rustc_data_structures;
 extern crate rustc_errors;
+extern crate rustc_hir;
+extern crate rustc_index;
 extern crate rustc_middle;
 extern crate rustc_session;
 extern crate rustc_span;
 extern crate rustc_symbol_mangling;
+extern crate rustc_target;
 
 #[allow(unused_extern_crates)]
 extern crate rustc_driver;
@@ -15,15 +18,17 @@ extern crate rustc_driver;
 use rustc_ast::expand::allocator::AllocatorKind;
 use rustc_codegen_ssa::{
     back::{
-        lto::{LtoModuleCodegen, SerializedModule, ThinModule},
-        write::{CodegenContext, FatLTOInput, ModuleConfig, TargetMachineFactoryFn},
+        lto::{LtoModuleCodegen, SerializedModule, ThinModule, ThinShared},
+        write::{
+            CodegenContext, FatLTOInput, ModuleConfig, OngoingCodegen, TargetMachineFactoryFn,
+        },
     },
     base::codegen_crate,
     traits::{
         CodegenBackend, ExtraBackendMethods, ModuleBufferMethods, ThinBufferMethods,
         WriteBackendMethods,
     },
-    CodegenResults, CompiledModule, ModuleCodegen,
+    CodegenResults, CompiledModule, ModuleCodegen, ModuleKind,
 };
 use rustc_data_structures::fx::FxHashMap;
 use rustc_errors::{ErrorReported, Handler};
@@ -33,19 +38,116 @@ use rustc_middle::{
     ty::TyCtxt,
 };
 use rustc_session::{
-    config::{OptLevel, OutputFilenames},
+    config::{OptLevel, OutputFilenames, OutputType},
     Session,
 };
 use rustc_span::{fatal_error::FatalError, Symbol};
 
 use std::any::Any;
+use std::ffi::CString;
+use std::io::Write;
+use std::lazy::SyncOnceCell;
+use std::path::Path;
 use std::sync::Arc;
 
-#[derive(Clone)]
-pub struct LuaCodegenBackend;
+pub use crate::config::*;
+pub use crate::dialect::{Dialect, LuaFeatures};
+
+mod abi;
+mod allocator;
+mod analyze;
+mod archive;
+mod base;
+mod cast;
+mod common;
+mod config;
+mod constant;
+mod dialect;
+mod discriminant;
+mod export;
+mod import;
+mod interop;
+mod intrinsics;
+mod jit;
+mod link;
+mod main_shim;
+mod num;
+mod panic;
+mod runtime;
+mod unsize;
+mod value_and_place;
+mod vtable;
+
+mod prelude {
+    pub(crate) use rustc_hir::def_id::DefId;
+    pub(crate) use rustc_middle::bug;
+    pub(crate) use rustc_middle::mir::{self, *};
+    pub(crate) use rustc_middle::span_bug;
+    pub(crate) use rustc_middle::ty::layout::{self, TyAndLayout};
+    pub(crate) use rustc_middle::ty::{
+        self, Instance, InstanceDef, ParamEnv, SymbolName, Ty, TyCtxt, TypeAndMut, TypeFoldable,
+    };
+    pub(crate) use rustc_span::Span;
+    pub(crate) use rustc_target::abi::{Abi, LayoutOf, Scalar, Size, VariantIdx};
+
+    pub(crate) use rustc_index::vec::Idx;
+
+    pub(crate) use cglua::{
+        BinOp as LuaBinOp, Context, ExprBuilder, Place as LuaPlace, UnOp as LuaUnOp, Value,
+    };
+
+    pub(crate) use crate::common::*;
+    pub(crate) use crate::value_and_place::{CPlace, CPlaceInner, CValue};
+}
+
+#[derive(Clone, Default)]
+pub struct LuaCodegenBackend {
+    /// Parsed from the options of the session by `init`
+    config: Arc<SyncOnceCell<BackendConfig>>,
+}
+
+impl LuaCodegenBackend {
+    fn config(&self) -> &BackendConfig {
+        self.config
+            .get()
+            .expect("the backend config is parsed by `init`")
+    }
+}
 
 impl CodegenBackend for LuaCodegenBackend {
-    fn init(&self, _sess: &Session) {}
+    fn init(&self, sess: &Session) {
+        let mut config = BackendConfig::from_opts(&sess.opts.cg.llvm_args)
+            .unwrap_or_else(|err| sess.fatal(&err));
+        let dialect = target_cpu(sess, &config)
+            .parse::<Dialect>()
+            .and_then(|dialect| dialect.check_supported().map(|()| dialect))
+            .unwrap_or_else(|err| sess.fatal(&err));
+        // The features of the target spec only apply to the lua target, not to the host
+        let target_features = if sess.target.arch == "lua" {
+            &sess.target.features[..]
+        } else {
+            ""
+        };
+        let mut features = dialect.features();
+        features
+            .apply_toggles(target_features)
+            .and_then(|()| features.apply_toggles(&sess.opts.cg.target_feature))
+            .and_then(|()| features.check_supported(dialect))
+            .unwrap_or_else(|err| sess.fatal(&err));
+        config.dialect = dialect;
+        config.features = features;
+        // A config set earlier, by another call or by the driver, is kept
+        let _ = self.config.set(config);
+    }
+
+    fn target_features(&self, _sess: &Session) -> Vec<Symbol> {
+        self.config()
+            .features
+            .enabled()
+            .into_iter()
+            .map(Symbol::intern)
+            .collect()
+    }
 
     fn codegen_crate<'tcx>(
         &self,
@@ -53,7 +155,11 @@ impl CodegenBackend for LuaCodegenBackend {
         metadata: EncodedMetadata,
         need_metadata_module: bool,
     ) -> Box<dyn Any> {
-        let target_cpu = target_cpu(tcx.sess);
+        if self.config().codegen_mode != CodegenMode::Aot {
+            jit::run_jit(tcx, self.config());
+        }
+
+        let target_cpu = target_cpu(tcx.sess, self.config());
         let res = codegen_crate(
             self.clone(),
             tcx,
@@ -69,27 +175,105 @@ impl CodegenBackend for LuaCodegenBackend {
 
     fn join_codegen(
         &self,
-        _ongoing_codegen: Box<dyn Any>,
-        _sess: &Session,
+        ongoing_codegen: Box<dyn Any>,
+        sess: &Session,
     ) -> Result<(CodegenResults, FxHashMap<WorkProductId, WorkProduct>), ErrorReported> {
-        todo!("join codegen")
+        Ok(ongoing_codegen
+            .downcast::<OngoingCodegen<LuaCodegenBackend>>()
+            .expect("Expected LuaCodegenBackend's OngoingCodegen, found Box<Any>")
+            .join(sess))
     }
 
     fn link(
         &self,
-        _sess: &Session,
-        _codegen_results: CodegenResults,
-        _outputs: &OutputFilenames,
+        sess: &Session,
+        codegen_results: CodegenResults,
+        outputs: &OutputFilenames,
     ) -> Result<(), ErrorReported> {
-        todo!("link")
+        link::link(sess, &codegen_results, outputs, self.config());
+        Ok(())
+    }
+}
+
+/// The lua code generated for a codegen unit
+pub struct LuaContext {
+    pub(crate) ctx: cglua::Context,
+}
+
+impl LuaContext {
+    /// The form in which rustc keeps modules for LTO and incremental reuse, which is loaded again
+    /// without parsing lua code
+    fn serialize(&self) -> Vec<u8> {
+        self.ctx.to_bytes()
+    }
+
+    /// Loads the module `name` serialized by `serialize`
+    fn deserialize(
+        cgcx: &CodegenContext<LuaCodegenBackend>,
+        name: String,
+        data: &[u8],
+    ) -> Result<ModuleCodegen<Self>, FatalError> {
+        match cglua::Context::from_bytes(data) {
+            Ok(ctx) => Ok(ModuleCodegen {
+                name,
+                module_llvm: LuaContext { ctx },
+                kind: ModuleKind::Regular,
+            }),
+            Err(err) => {
+                let handler = cgcx.create_diag_handler();
+                handler.err(&format!("error loading the lua module {}: {}", name, err));
+                Err(FatalError)
+            }
+        }
+    }
+
+    /// Writes the code to `out`, rendering it one top-level statement at a time
+    fn write_to(&self, out: &Path) -> std::io::Result<()> {
+        /// Forwards the rendered code to a file, keeping the io error `fmt::Error` can't carry
+        struct FileWriter {
+            file: std::io::BufWriter<std::fs::File>,
+            error: Option<std::io::Error>,
+        }
+
+        impl std::fmt::Write for FileWriter {
+            fn write_str(&mut self, s: &str) -> std::fmt::Result {
+                self.file.write_all(s.as_bytes()).map_err(|err| {
+                    self.error = Some(err);
+                    std::fmt::Error
+                })
+            }
+        }
+
+        let mut writer = FileWriter {
+            file: std::io::BufWriter::new(std::fs::File::create(out)?),
+            error: None,
+        };
+        if self.ctx.render(&mut writer).is_err() {
+            let err = writer.error.take();
+            return Err(err.expect("only io errors stop the rendering"));
+        }
+        writer.file.flush()
     }
 }
 
-pub struct LuaContext {}
+/// Puts the code of modules together, as lua modules can't be optimized across each other
+fn merge_modules<I>(modules: I) -> Option<ModuleCodegen<LuaContext>>
+where
+    I: IntoIterator<Item = ModuleCodegen<LuaContext>>,
+{
+    let mut modules = modules.into_iter();
+    let mut merged = modules.next()?;
+    for module in modules {
+        merged.module_llvm.ctx.append(module.module_llvm.ctx);
+    }
+    Some(merged)
+}
 
 impl ExtraBackendMethods for LuaCodegenBackend {
     fn new_metadata<'tcx>(&self, _tcx: TyCtxt<'tcx>, _mod_name: &str) -> Self::Module {
-        LuaContext {}
+        LuaContext {
+            ctx: cglua::Context::new(),
+        }
     }
 
     fn write_compressed_metadata<'tcx>(
@@ -98,25 +282,25 @@ impl ExtraBackendMethods for LuaCodegenBackend {
         _metadata: &EncodedMetadata,
         _module: &mut Self::Module,
     ) {
-        unimplemented!("write metadata")
+        // Rust dylibs can't be linked against, so the lua module they produce has no metadata
     }
 
     fn codegen_allocator<'tcx>(
         &self,
         _tcx: TyCtxt<'tcx>,
-        _mods: &mut Self::Module,
-        _kind: AllocatorKind,
-        _has_alloc_error_handler: bool,
+        mods: &mut Self::Module,
+        kind: AllocatorKind,
+        has_alloc_error_handler: bool,
     ) {
-        unimplemented!("allocator codegen")
+        allocator::codegen(&mut mods.ctx, kind, has_alloc_error_handler);
     }
 
     fn compile_codegen_unit<'tcx>(
         &self,
-        _tcx: TyCtxt<'tcx>,
-        _cgu_name: Symbol,
+        tcx: TyCtxt<'tcx>,
+        cgu_name: Symbol,
     ) -> (ModuleCodegen<Self::Module>, u64) {
-        unimplemented!("compile codegen units")
+        base::compile_codegen_unit(tcx, cgu_name, self.config().clone())
     }
 
     fn target_machine_factory(
@@ -127,8 +311,8 @@ impl ExtraBackendMethods for LuaCodegenBackend {
         Arc::new(|_| Ok(()))
     }
 
-    fn target_cpu<'b>(&self, _sess: &'b Session) -> &'b str {
-        unimplemented!()
+    fn target_cpu<'b>(&self, sess: &'b Session) -> &'b str {
+        target_cpu(sess, self.config())
     }
 
     fn tune_cpu<'b>(&self, _sess: &'b Session) -> Option<&'b str> {
@@ -136,19 +320,21 @@ impl ExtraBackendMethods for LuaCodegenBackend {
     }
 }
 
-pub struct ModuleBuffer;
+/// A module serialized by `LuaContext::serialize`
+pub struct ModuleBuffer(Vec<u8>);
 
 impl ModuleBufferMethods for ModuleBuffer {
     fn data(&self) -> &[u8] {
-        unimplemented!()
+        &self.0
     }
 }
 
-pub struct ThinBuffer;
+/// A module serialized by `LuaContext::serialize`
+pub struct ThinBuffer(Vec<u8>);
 
 impl ThinBufferMethods for ThinBuffer {
     fn data(&self) -> &[u8] {
-        unimplemented!()
+        &self.0
     }
 }
 
@@ -160,20 +346,59 @@ impl WriteBackendMethods for LuaCodegenBackend {
     type ThinData = ();
     type ThinBuffer = ThinBuffer;
 
+    /// Fat LTO only puts all the modules in one
     fn run_fat_lto(
-        _cgcx: &CodegenContext<Self>,
-        _modules: Vec<FatLTOInput<Self>>,
-        _cached_modules: Vec<(SerializedModule<Self::ModuleBuffer>, WorkProduct)>,
+        cgcx: &CodegenContext<Self>,
+        modules: Vec<FatLTOInput<Self>>,
+        cached_modules: Vec<(SerializedModule<Self::ModuleBuffer>, WorkProduct)>,
     ) -> Result<LtoModuleCodegen<Self>, FatalError> {
-        panic!("fat lto in lua has not a lot of meaning");
+        let modules = modules.into_iter().map(|module| match module {
+            FatLTOInput::Serialized { name, buffer } => {
+                LuaContext::deserialize(cgcx, name, buffer.data())
+            }
+            FatLTOInput::InMemory(module) => Ok(module),
+        });
+        let cached = cached_modules.into_iter().map(|(module, work_product)| {
+            LuaContext::deserialize(cgcx, work_product.cgu_name, module.data())
+        });
+        let modules = modules.chain(cached).collect::<Result<Vec<_>, _>>()?;
+        Ok(LtoModuleCodegen::Fat {
+            module: merge_modules(modules),
+            _serialized_bitcode: Vec::new(),
+        })
     }
 
+    /// Thin LTO, which rustc uses for optimized builds with several codegen units, leaves the
+    /// modules as they are. Cached modules are reused without being loaded.
     fn run_thin_lto(
         _cgcx: &CodegenContext<Self>,
-        _modules: Vec<(String, Self::ThinBuffer)>,
-        _cached_modules: Vec<(SerializedModule<Self::ModuleBuffer>, WorkProduct)>,
+        modules: Vec<(String, Self::ThinBuffer)>,
+        cached_modules: Vec<(SerializedModule<Self::ModuleBuffer>, WorkProduct)>,
     ) -> Result<(Vec<LtoModuleCodegen<Self>>, Vec<WorkProduct>), FatalError> {
-        panic!("thin lto in lua has not a lot of meaning");
+        let (names, thin_buffers): (Vec<_>, Vec<_>) = modules.into_iter().unzip();
+        let module_names = names
+            .into_iter()
+            .map(|name| CString::new(name).expect("codegen unit names have no nul byte"))
+            .collect();
+        let shared = Arc::new(ThinShared {
+            data: (),
+            thin_buffers,
+            serialized_modules: Vec::new(),
+            module_names,
+        });
+        let modules = (0..shared.thin_buffers.len())
+            .map(|idx| {
+                LtoModuleCodegen::Thin(ThinModule {
+                    shared: shared.clone(),
+                    idx,
+                })
+            })
+            .collect();
+        let copy_jobs = cached_modules
+            .into_iter()
+            .map(|(_, work_product)| work_product)
+            .collect();
+        Ok((modules, copy_jobs))
     }
 
     fn print_pass_timings(&self) {
@@ -190,27 +415,43 @@ impl WriteBackendMethods for LuaCodegenBackend {
     }
 
     unsafe fn optimize_thin(
-        _cgcx: &CodegenContext<Self>,
-        _thin: &mut ThinModule<Self>,
+        cgcx: &CodegenContext<Self>,
+        thin: &mut ThinModule<Self>,
     ) -> Result<ModuleCodegen<Self::Module>, FatalError> {
-        todo!()
+        LuaContext::deserialize(cgcx, thin.name().to_string(), thin.data())
     }
 
     unsafe fn codegen(
-        _cgcx: &CodegenContext<Self>,
-        _diag_handler: &Handler,
-        _module: ModuleCodegen<Self::Module>,
+        cgcx: &CodegenContext<Self>,
+        diag_handler: &Handler,
+        module: ModuleCodegen<Self::Module>,
         _config: &ModuleConfig,
     ) -> Result<CompiledModule, FatalError> {
-        todo!("write::codegen")
+        let obj_out = cgcx
+            .output_filenames
+            .temp_path(OutputType::Object, Some(&module.name));
+        if let Err(err) = module.module_llvm.write_to(&obj_out) {
+            diag_handler.err(&format!("error writing lua module: {}", err));
+            return Err(FatalError);
+        }
+
+        Ok(CompiledModule {
+            name: module.name,
+            kind: module.kind,
+            object: Some(obj_out),
+            dwarf_object: None,
+            bytecode: None,
+        })
     }
 
-    fn prepare_thin(_module: ModuleCodegen<Self::Module>) -> (String, Self::ThinBuffer) {
-        unimplemented!()
+    fn prepare_thin(module: ModuleCodegen<Self::Module>) -> (String, Self::ThinBuffer) {
+        let buffer = ThinBuffer(module.module_llvm.serialize());
+        (module.name, buffer)
     }
 
-    fn serialize_module(_module: ModuleCodegen<Self::Module>) -> (String, Self::ModuleBuffer) {
-        unimplemented!()
+    fn serialize_module(module: ModuleCodegen<Self::Module>) -> (String, Self::ModuleBuffer) {
+        let buffer = ModuleBuffer(module.module_llvm.serialize());
+        (module.name, buffer)
     }
 
     fn run_lto_pass_manager(
@@ -224,27 +465,45 @@ impl WriteBackendMethods for LuaCodegenBackend {
 
     fn run_link(
         _cgcx: &CodegenContext<Self>,
-        _diag_handler: &Handler,
-        _modules: Vec<ModuleCodegen<Self::Module>>,
+        diag_handler: &Handler,
+        modules: Vec<ModuleCodegen<Self::Module>>,
     ) -> Result<ModuleCodegen<Self::Module>, FatalError> {
-        todo!("run link")
+        merge_modules(modules).ok_or_else(|| {
+            diag_handler.err("no lua modules to link");
+            FatalError
+        })
     }
 }
 
-fn handle_native(name: &str) -> &str {
+/// The dialect used when neither `-Ctarget-cpu`, the `dialect` option nor the target selects one
+const DEFAULT_DIALECT: Dialect = Dialect::Lua53;
+
+/// `native` is the dialect of the lua 5.3 or 5.4 interpreter found in `PATH`, or `native_cpu` when
+/// there is none. Finding only interpreters of other versions is an error without `native_cpu`.
+fn handle_native<'a>(name: &'a str, native_cpu: Option<Dialect>) -> Result<&'a str, String> {
     if name != "native" {
-        return name;
+        return Ok(name);
     }
 
-    todo!("handle native")
+    let probed = match crate::dialect::probe_interpreter() {
+        Ok(probed) => probed,
+        Err(_) if native_cpu.is_some() => None,
+        Err(err) => return Err(err),
+    };
+    Ok(probed.or(native_cpu).unwrap_or(DEFAULT_DIALECT).name())
 }
 
-pub fn target_cpu(sess: &Session) -> &str {
-    let name = sess.opts.cg.target_cpu.as_ref().unwrap_or(&sess.target.cpu);
-    handle_native(name)
+/// The dialect name given with `-Ctarget-cpu` or the `dialect` option, or the cpu of the target
+/// when it is a dialect, as it isn't when the backend compiles for targets such as the host
+pub fn target_cpu<'a>(sess: &'a Session, config: &BackendConfig) -> &'a str {
+    match sess.opts.cg.target_cpu.as_deref().or(config.cpu) {
+        Some(name) => handle_native(name, config.native_cpu).unwrap_or_else(|err| sess.fatal(&err)),
+        None if sess.target.cpu.parse::<Dialect>().is_ok() => &sess.target.cpu,
+        None => DEFAULT_DIALECT.name(),
+    }
 }
 
 #[no_mangle]
 pub fn __rustc_codegen_backend() -> Box<dyn CodegenBackend> {
-    Box::new(LuaCodegenBackend)
+    Box::new(LuaCodegenBackend::default())
 }
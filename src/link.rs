@@ -0,0 +1,355 @@
+//! Linking of the lua code of a crate and its dependencies
+//!
+//! Rlibs are archives of the crate metadata and of the lua code of each codegen unit. The other
+//! crate types put the code of the local crate and of all the statically linked crates together:
+//!
+//! * executables start with the runtime, link the data symbols and run `main`
+//! * `cdylib` and `dylib` start the same way but return `rt.module`, the table of the functions
+//!   exported to lua, so that they can be loaded with `require`
+//! * staticlibs, `lib<crate>.lua`, only contain the code of the crates, which expects the locals
+//!   defined by `runtime_header` to be in scope. The header, with the whole runtime, is written
+//!   next to the staticlib with the `.rt.lua` extension. Build tools concatenate the staticlibs
+//!   after it and call `rt.link()` once all the code has run.
+//!
+//! Outside of Neovim plugins, executables and modules are both written to `<crate>.lua`, so
+//! building them, or a `cdylib` and a `dylib`, in one compilation is an error.
+//!
+//! With `-Cllvm-args=output=neovim`, the output is a Neovim plugin: executables are written to
+//! `plugin/<crate>.lua`, which Neovim runs at startup, and modules to `lua/<crate>/init.lua`, to be
+//! loaded with `require("<crate>")`. The runtime is adapted to Neovim by `NEOVIM_HOST`.
+//!
+//! With `-Cllvm-args=embed_runtime=false`, programs and modules `require` the runtime from
+//! `cg_lua_rt.lua`, which is written next to them. Otherwise programs and modules only embed the
+//! parts of the runtime they use, see `crate::runtime`.
+//!
+//! Rlibs record the version of the runtime their code was compiled against, crates compiled
+//! against another version can't be linked.
+
+use std::path::Path;
+
+use rustc_codegen_ssa::CodegenResults;
+use rustc_hir::def_id::CrateNum;
+use rustc_middle::middle::dependency_format::Linkage;
+use rustc_session::config::{CrateType, OutputFilenames, OutputType};
+use rustc_session::output::out_filename;
+use rustc_session::Session;
+
+use crate::archive::{read_archive, write_archive};
+use crate::prelude::*;
+use crate::{BackendConfig, LuaFeatures, OutputMode};
+
+const METADATA_FILENAME: &str = "lib.rmeta";
+
+/// The rlib member containing the runtime version
+const RUNTIME_VERSION_FILENAME: &str = "lua.version";
+
+/// Runs `main` once all the code has run, exiting with its exit code
+pub(crate) const EXECUTABLE_FOOTER: &str = r#"rt.link()
+local code = S["main"](0, 0)
+if code ~= 0 then
+  rt.exit(code)
+end
+"#;
+
+const MODULE_FOOTER: &str = "rt.link()\nreturn rt.module\n";
+
+/// The extension of the runtime header written next to staticlibs
+const STATICLIB_HEADER_EXTENSION: &str = "rt.lua";
+
+/// The module name of the runtime when it isn't embedded
+const RUNTIME_MODULE: &str = "cg_lua_rt";
+
+/// How linked code gets the runtime
+pub(crate) enum Runtime<'a> {
+    /// The whole runtime is embedded
+    Full,
+    /// The runtime is embedded without the parts which the code doesn't use
+    UsedBy(&'a str),
+    /// The runtime is loaded from the `cg_lua_rt` module
+    Shared,
+}
+
+pub(crate) fn link(
+    sess: &Session,
+    codegen_results: &CodegenResults,
+    outputs: &OutputFilenames,
+    config: &BackendConfig,
+) {
+    if !sess.opts.output_types.contains_key(&OutputType::Exe) {
+        return;
+    }
+
+    let mode = config.output_mode;
+    let crate_name = codegen_results.crate_info.local_crate_name.as_str();
+    let outs: Vec<_> = sess
+        .crate_types()
+        .iter()
+        .map(|&crate_type| {
+            let out = match (mode, crate_type) {
+                (OutputMode::Neovim, CrateType::Executable) => outputs
+                    .out_directory
+                    .join("plugin")
+                    .join(format!("{}.lua", crate_name)),
+                (OutputMode::Neovim, CrateType::Dylib | CrateType::Cdylib) => outputs
+                    .out_directory
+                    .join("lua")
+                    .join(&*crate_name)
+                    .join("init.lua"),
+                _ => out_filename(sess, crate_type, outputs, &crate_name),
+            };
+            (crate_type, out)
+        })
+        .collect();
+    for (i, (crate_type, out)) in outs.iter().enumerate() {
+        if let Some((other, _)) = outs[..i].iter().find(|(_, other)| other == out) {
+            sess.fatal(&format!(
+                "the `{}` and `{}` crate types would both be written to {}, build them separately",
+                other,
+                crate_type,
+                out.display()
+            ));
+        }
+    }
+
+    for (crate_type, out) in outs {
+        let res = match crate_type {
+            CrateType::Rlib => write_rlib(sess, codegen_results, &out),
+            CrateType::Executable | CrateType::Dylib | CrateType::Cdylib | CrateType::Staticlib => {
+                let code = link_code(sess, codegen_results, crate_type, config);
+                write_code(&out, &code, config)
+            }
+            CrateType::ProcMacro => sess.fatal("proc macros can't be compiled to lua"),
+        };
+        if let Err(err) = res {
+            sess.fatal(&format!("error writing {}: {}", out.display(), err));
+        }
+
+        if crate_type == CrateType::Staticlib {
+            let out = out.with_extension(STATICLIB_HEADER_EXTENSION);
+            let code = runtime_header(sess, config.features, Runtime::Full);
+            if let Err(err) = write_code(&out, &code, config) {
+                sess.fatal(&format!("error writing {}: {}", out.display(), err));
+            }
+        }
+    }
+
+    let requires_runtime = sess.crate_types().iter().any(|crate_type| {
+        matches!(
+            crate_type,
+            CrateType::Executable | CrateType::Dylib | CrateType::Cdylib
+        )
+    });
+    if !config.embed_runtime && requires_runtime {
+        let dir = match mode {
+            OutputMode::Default => outputs.out_directory.clone(),
+            OutputMode::Neovim => outputs.out_directory.join("lua"),
+        };
+        let out = dir.join(format!("{}.lua", RUNTIME_MODULE));
+        let code = format!(
+            "local PTR_SIZE = {}\n{}{}",
+            sess.target.pointer_width / 8,
+            runtime_libraries(config.features),
+            crate::runtime::runtime(None)
+        );
+        if let Err(err) = write_code(&out, &code, config) {
+            sess.fatal(&format!("error writing {}: {}", out.display(), err));
+        }
+    }
+}
+
+fn write_code(out: &Path, code: &str, config: &BackendConfig) -> std::io::Result<()> {
+    let code = if config.minify {
+        minify(code)
+    } else {
+        code.to_string()
+    };
+    out.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(out, code))
+}
+
+/// Removes the indentation, empty lines and comments, except for source locations. Comments are
+/// only removed when they take a whole line, and lines never start inside a string, as the
+/// generated code escapes line breaks and the runtime has no long strings.
+fn minify(code: &str) -> String {
+    let mut minified = String::with_capacity(code.len() / 2);
+    for line in code.lines() {
+        let line = line.trim_start();
+        if line.is_empty() || (line.starts_with("--") && !line.starts_with("-- @")) {
+            continue;
+        }
+        minified.push_str(line);
+        minified.push('\n');
+    }
+    minified
+}
+
+fn read_module(sess: &Session, path: &Path) -> Vec<u8> {
+    std::fs::read(path)
+        .unwrap_or_else(|err| sess.fatal(&format!("error reading {}: {}", path.display(), err)))
+}
+
+fn write_rlib(sess: &Session, codegen_results: &CodegenResults, out: &Path) -> std::io::Result<()> {
+    let mut members = vec![
+        (
+            METADATA_FILENAME.to_string(),
+            codegen_results.metadata.raw_data.clone(),
+        ),
+        (
+            RUNTIME_VERSION_FILENAME.to_string(),
+            crate::runtime::RUNTIME_VERSION.to_string().into_bytes(),
+        ),
+    ];
+    let objects = codegen_results
+        .modules
+        .iter()
+        .filter_map(|module| module.object.as_ref());
+    for (i, object) in objects.enumerate() {
+        members.push((format!("{}.lua", i), read_module(sess, object)));
+    }
+    write_archive(out, &members)
+}
+
+/// The replacements of the standard libraries used by the runtime which the dialect lacks
+fn runtime_libraries(features: LuaFeatures) -> &'static str {
+    if features.utf8 {
+        ""
+    } else {
+        crate::runtime::UTF8_LIBRARY
+    }
+}
+
+/// The start of linked programs and modules, defining the locals used by the generated code.
+///
+/// A shared runtime is checked to have the version of the embedded one.
+pub(crate) fn runtime_header(
+    sess: &Session,
+    features: LuaFeatures,
+    runtime: Runtime<'_>,
+) -> String {
+    let used_by = match runtime {
+        Runtime::Full => None,
+        Runtime::UsedBy(code) => Some(code),
+        Runtime::Shared => {
+            return format!(
+                "local PTR_SIZE = {ptr_size}\n\
+                 local rt = require(\"{module}\")\n\
+                 if rt.version ~= {version} then\n  \
+                 error(\"{module} is not version {version} of the runtime\", 0)\n\
+                 end\n\
+                 local S, D, F, V = rt.S, rt.D, rt.F, rt.V\n",
+                ptr_size = sess.target.pointer_width / 8,
+                module = RUNTIME_MODULE,
+                version = crate::runtime::RUNTIME_VERSION,
+            );
+        }
+    };
+    let runtime = format!(
+        "{}local rt = (function()\n{}end)()",
+        runtime_libraries(features),
+        crate::runtime::runtime(used_by)
+    );
+    format!(
+        "local PTR_SIZE = {}\n\
+         {}\n\
+         local S, D, F, V = rt.S, rt.D, rt.F, rt.V\n",
+        sess.target.pointer_width / 8,
+        runtime,
+    )
+}
+
+pub(crate) fn push_module(code: &mut String, module: &[u8]) {
+    code.push_str("do\n");
+    code.push_str(&String::from_utf8_lossy(module));
+    code.push_str("\nend\n");
+}
+
+/// Appends the lua code of each codegen unit of the rlib at `path`
+pub(crate) fn push_rlib(sess: &Session, code: &mut String, path: &Path) {
+    let data = read_module(sess, path);
+    let members = read_archive(&data)
+        .unwrap_or_else(|err| sess.fatal(&format!("error reading {}: {}", path.display(), err)));
+    let version = members
+        .iter()
+        .find(|(name, _)| name == RUNTIME_VERSION_FILENAME)
+        .and_then(|(_, version)| std::str::from_utf8(version).ok()?.parse::<u32>().ok());
+    if version != Some(crate::runtime::RUNTIME_VERSION) {
+        sess.fatal(&format!(
+            "{} was compiled against another version of the lua runtime, rebuild it with this \
+             version of the lua backend",
+            path.display()
+        ));
+    }
+    for (name, module) in members {
+        if name != METADATA_FILENAME && name != RUNTIME_VERSION_FILENAME {
+            push_module(code, module);
+        }
+    }
+}
+
+fn link_code(
+    sess: &Session,
+    codegen_results: &CodegenResults,
+    crate_type: CrateType,
+    config: &BackendConfig,
+) -> String {
+    let crate_info = &codegen_results.crate_info;
+    // The header is added once the rest of the code is known, so that the runtime can be trimmed
+    let mut code = String::new();
+    if crate_type != CrateType::Staticlib && config.output_mode == OutputMode::Neovim {
+        code.push_str(crate::runtime::NEOVIM_HOST);
+    }
+
+    let local_modules = codegen_results
+        .modules
+        .iter()
+        .chain(&codegen_results.allocator_module);
+    for module in local_modules {
+        if let Some(object) = &module.object {
+            push_module(&mut code, &read_module(sess, object));
+        }
+    }
+
+    let linkages = crate_info
+        .dependency_formats
+        .iter()
+        .find(|(ty, _)| *ty == crate_type)
+        .map(|(_, linkages)| &linkages[..])
+        .unwrap_or(&[]);
+    for (i, linkage) in linkages.iter().enumerate() {
+        let cnum = CrateNum::new(i + 1);
+        match linkage {
+            Linkage::NotLinked | Linkage::IncludedFromDylib => {}
+            Linkage::Static => {
+                let rlib = crate_info.used_crate_source[&cnum]
+                    .rlib
+                    .as_ref()
+                    .map(|(path, _)| path)
+                    .unwrap_or_else(|| {
+                        sess.fatal(&format!(
+                            "no rlib found for crate `{}`",
+                            crate_info.crate_name[&cnum]
+                        ))
+                    });
+                push_rlib(sess, &mut code, rlib);
+            }
+            Linkage::Dynamic => sess.fatal(&format!(
+                "crate `{}` is a dylib, which can't be linked by the lua backend",
+                crate_info.crate_name[&cnum]
+            )),
+        }
+    }
+
+    match crate_type {
+        CrateType::Executable => code.push_str(EXECUTABLE_FOOTER),
+        CrateType::Dylib | CrateType::Cdylib => code.push_str(MODULE_FOOTER),
+        CrateType::Staticlib => return code,
+        _ => {}
+    }
+    let runtime = if config.embed_runtime {
+        Runtime::UsedBy(&code)
+    } else {
+        Runtime::Shared
+    };
+    runtime_header(sess, config.features, runtime) + &code
+}
@@ -0,0 +1,67 @@
+use rustc_hir::LangItem;
+use rustc_middle::ty::subst::GenericArg;
+use rustc_session::config::EntryFnType;
+
+use crate::prelude::*;
+
+/// Create the `main` function which will initialize the rust runtime and call
+/// users main function.
+///
+/// The call is wrapped in `rt.start`, which reports panics and turns them into the exit code 101.
+pub(crate) fn maybe_create_entry_wrapper(tcx: TyCtxt<'_>, ctx: &mut Context, is_primary_cgu: bool) {
+    let (main_def_id, is_main_fn) = match tcx.entry_fn(()) {
+        Some((def_id, entry_ty)) => (
+            def_id,
+            match entry_ty {
+                EntryFnType::Main => true,
+                EntryFnType::Start => false,
+            },
+        ),
+        None => return,
+    };
+
+    if !is_primary_cgu {
+        return;
+    }
+
+    let main_instance = Instance::mono(tcx, main_def_id).polymorphize(tcx);
+    let argc = ident("argc");
+    let argv = ident("argv");
+
+    let args = if is_main_fn {
+        let start_def_id = tcx.require_lang_item(LangItem::Start, None);
+        let main_ret_ty = tcx.fn_sig(main_def_id).output();
+        // Given that `main()` has no arguments,
+        // then its return type cannot have
+        // late-bound regions, since late-bound
+        // regions must appear in the argument
+        // listing.
+        let main_ret_ty = tcx.erase_regions(main_ret_ty.no_bound_vars().unwrap());
+        let start_instance = Instance::resolve(
+            tcx,
+            ParamEnv::reveal_all(),
+            start_def_id,
+            tcx.intern_substs(&[GenericArg::from(main_ret_ty)]),
+        )
+        .unwrap()
+        .unwrap()
+        .polymorphize(tcx);
+
+        vec![
+            fn_symbol(tcx.symbol_name(start_instance).name),
+            crate::constant::fn_ptr_ref(tcx, main_instance),
+            argc,
+            argv,
+        ]
+    } else {
+        // `#[start]` functions take `argc` and `argv` directly
+        vec![fn_symbol(tcx.symbol_name(main_instance).name), argc, argv]
+    };
+
+    ctx.start_function_at(
+        fn_symbol("main").to_place(),
+        vec!["argc".to_string(), "argv".to_string()],
+    );
+    ctx.stat().ret(vec![rt_call("start", args)]);
+    ctx.finish_block().unwrap();
+}
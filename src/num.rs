@@ -0,0 +1,451 @@
+//! Various operations on integer and floating-point numbers
+
+use crate::prelude::*;
+use crate::IntegerStrategy;
+
+/// `math.<name>` from the lua standard library
+pub(crate) fn math(name: &str) -> Value {
+    ExprBuilder.table_access(
+        ident("math").to_place(),
+        ExprBuilder.string(name.to_string()),
+    )
+}
+
+/// Brings `value` back in the range of an integer of `bits` bits.
+///
+/// 64 bit integers wrap on their own, narrower integers are masked and sign extended.
+pub(crate) fn wrap_int(value: Value, bits: u64, signed: bool) -> Value {
+    if bits >= 64 {
+        return value;
+    }
+    let mask = int(((1u128 << bits) - 1) as i64);
+    if signed {
+        let half = int(1 << (bits - 1));
+        binop(
+            LuaBinOp::Sub,
+            binop(
+                LuaBinOp::BAnd,
+                binop(LuaBinOp::Add, value, half.clone()),
+                mask,
+            ),
+            half,
+        )
+    } else {
+        binop(LuaBinOp::BAnd, value, mask)
+    }
+}
+
+/// The lua condition comparing two integers of `bits` bits.
+pub(crate) fn codegen_icmp(
+    bin_op: BinOp,
+    bits: u64,
+    signed: bool,
+    lhs: Value,
+    rhs: Value,
+) -> Value {
+    let lt = |lhs: Value, rhs: Value| match (bits, signed) {
+        (128, true) => rt_call("slt128", [lhs, rhs]),
+        (128, false) => rt_call("ult128", [lhs, rhs]),
+        (64, false) => ExprBuilder.call(math("ult"), [lhs, rhs]),
+        _ => binop(LuaBinOp::Lt, lhs, rhs),
+    };
+    let not = |cond: Value| ExprBuilder.unop(LuaUnOp::Not, cond);
+
+    if bits == 128 {
+        match bin_op {
+            BinOp::Eq => rt_call("eq128", [lhs, rhs]),
+            BinOp::Ne => not(rt_call("eq128", [lhs, rhs])),
+            BinOp::Lt => lt(lhs, rhs),
+            BinOp::Le => not(lt(rhs, lhs)),
+            BinOp::Gt => lt(rhs, lhs),
+            BinOp::Ge => not(lt(lhs, rhs)),
+            _ => unreachable!("{:?} is not a comparison", bin_op),
+        }
+    } else if bits == 64 && !signed {
+        match bin_op {
+            BinOp::Eq => binop(LuaBinOp::Eq, lhs, rhs),
+            BinOp::Ne => binop(LuaBinOp::Ne, lhs, rhs),
+            BinOp::Lt => lt(lhs, rhs),
+            BinOp::Le => not(lt(rhs, lhs)),
+            BinOp::Gt => lt(rhs, lhs),
+            BinOp::Ge => not(lt(lhs, rhs)),
+            _ => unreachable!("{:?} is not a comparison", bin_op),
+        }
+    } else {
+        let op = match bin_op {
+            BinOp::Eq => LuaBinOp::Eq,
+            BinOp::Ne => LuaBinOp::Ne,
+            BinOp::Lt => LuaBinOp::Lt,
+            BinOp::Le => LuaBinOp::Le,
+            BinOp::Gt => LuaBinOp::Gt,
+            BinOp::Ge => LuaBinOp::Ge,
+            _ => unreachable!("{:?} is not a comparison", bin_op),
+        };
+        binop(op, lhs, rhs)
+    }
+}
+
+pub(crate) fn codegen_binop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    bin_op: BinOp,
+    in_lhs: CValue<'tcx>,
+    in_rhs: CValue<'tcx>,
+) -> CValue<'tcx> {
+    match bin_op {
+        BinOp::Eq | BinOp::Lt | BinOp::Le | BinOp::Ne | BinOp::Ge | BinOp::Gt => {
+            match in_lhs.layout().ty.kind() {
+                ty::Bool | ty::Uint(_) | ty::Int(_) | ty::Char => {
+                    let (bits, signed) = int_ty_bits(fx.tcx, in_lhs.layout().ty).unwrap();
+                    let lhs = in_lhs.load_scalar(fx);
+                    let rhs = in_rhs.load_scalar(fx);
+
+                    let cond = codegen_icmp(bin_op, bits, signed, lhs, rhs);
+                    return CValue::by_val(bool_to_int(cond), fx.layout_of(fx.tcx.types.bool));
+                }
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+
+    match in_lhs.layout().ty.kind() {
+        ty::Bool => crate::num::codegen_bool_binop(fx, bin_op, in_lhs, in_rhs),
+        ty::Uint(_) | ty::Int(_) => crate::num::codegen_int_binop(fx, bin_op, in_lhs, in_rhs),
+        ty::Float(_) => crate::num::codegen_float_binop(fx, bin_op, in_lhs, in_rhs),
+        ty::RawPtr(..) | ty::FnPtr(..) => crate::num::codegen_ptr_binop(fx, bin_op, in_lhs, in_rhs),
+        _ => unreachable!(
+            "{:?}({:?}, {:?})",
+            bin_op,
+            in_lhs.layout().ty,
+            in_rhs.layout().ty
+        ),
+    }
+}
+
+pub(crate) fn codegen_bool_binop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    bin_op: BinOp,
+    in_lhs: CValue<'tcx>,
+    in_rhs: CValue<'tcx>,
+) -> CValue<'tcx> {
+    let lhs = in_lhs.load_scalar(fx);
+    let rhs = in_rhs.load_scalar(fx);
+
+    let op = match bin_op {
+        BinOp::BitXor => LuaBinOp::BXor,
+        BinOp::BitAnd => LuaBinOp::BAnd,
+        BinOp::BitOr => LuaBinOp::BOr,
+        _ => unreachable!("{:?}({:?}, {:?})", bin_op, in_lhs, in_rhs),
+    };
+
+    CValue::by_val(binop(op, lhs, rhs), fx.layout_of(fx.tcx.types.bool))
+}
+
+/// The shift amount `rhs` masked to the width of the shifted integer.
+fn shift_amount(fx: &FunctionCx<'_, '_>, rhs: Value, rhs_ty: Ty<'_>, bits: u64) -> Value {
+    let (rhs_bits, _) = int_ty_bits(fx.tcx, rhs_ty).unwrap();
+    let rhs = if rhs_bits == 128 {
+        ExprBuilder.table_access(rhs.to_place(), int(1))
+    } else {
+        rhs
+    };
+    binop(LuaBinOp::BAnd, rhs, int(bits as i64 - 1))
+}
+
+pub(crate) fn codegen_int_binop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    bin_op: BinOp,
+    in_lhs: CValue<'tcx>,
+    in_rhs: CValue<'tcx>,
+) -> CValue<'tcx> {
+    if bin_op != BinOp::Shl && bin_op != BinOp::Shr {
+        assert_eq!(
+            in_lhs.layout().ty,
+            in_rhs.layout().ty,
+            "int binop requires lhs and rhs of same type"
+        );
+    }
+
+    let (bits, signed) = int_ty_bits(fx.tcx, in_lhs.layout().ty).unwrap();
+    let rhs_ty = in_rhs.layout().ty;
+    let lhs = in_lhs.load_scalar(fx);
+    let rhs = in_rhs.load_scalar(fx);
+
+    let val = if bits == 128 {
+        match bin_op {
+            BinOp::Add => rt_call("add128", [lhs, rhs]),
+            BinOp::Sub => rt_call("sub128", [lhs, rhs]),
+            BinOp::Mul => rt_call("mul128", [lhs, rhs]),
+            BinOp::Div if signed => rt_call("sdiv128", [lhs, rhs]),
+            BinOp::Div => rt_call("udiv128", [lhs, rhs]),
+            BinOp::Rem if signed => rt_call("srem128", [lhs, rhs]),
+            BinOp::Rem => rt_call("urem128", [lhs, rhs]),
+            BinOp::BitXor => rt_call("bxor128", [lhs, rhs]),
+            BinOp::BitAnd => rt_call("band128", [lhs, rhs]),
+            BinOp::BitOr => rt_call("bor128", [lhs, rhs]),
+            BinOp::Shl => rt_call("shl128", [lhs, shift_amount(fx, rhs, rhs_ty, bits)]),
+            BinOp::Shr if signed => rt_call("ashr128", [lhs, shift_amount(fx, rhs, rhs_ty, bits)]),
+            BinOp::Shr => rt_call("lshr128", [lhs, shift_amount(fx, rhs, rhs_ty, bits)]),
+            _ => unreachable!("{:?}({:?}, {:?})", bin_op, in_lhs, in_rhs),
+        }
+    } else {
+        // Results which can overflow, unless the integer strategy assumes they don't
+        let integers = fx.cx.config.integers;
+        let wrap = |value| match integers {
+            IntegerStrategy::Wrapping => wrap_int(value, bits, signed),
+            IntegerStrategy::Unchecked => value,
+        };
+        match bin_op {
+            BinOp::Add => wrap(binop(LuaBinOp::Add, lhs, rhs)),
+            BinOp::Sub => wrap(binop(LuaBinOp::Sub, lhs, rhs)),
+            BinOp::Mul => wrap(binop(LuaBinOp::Mul, lhs, rhs)),
+            BinOp::Div if signed => wrap_int(rt_call("sdiv", [lhs, rhs]), bits, signed),
+            BinOp::Div if bits == 64 => rt_call("udiv", [lhs, rhs]),
+            BinOp::Div => binop(LuaBinOp::IDiv, lhs, rhs),
+            BinOp::Rem if signed => rt_call("srem", [lhs, rhs]),
+            BinOp::Rem if bits == 64 => rt_call("urem", [lhs, rhs]),
+            BinOp::Rem => binop(LuaBinOp::Mod, lhs, rhs),
+            BinOp::BitXor => binop(LuaBinOp::BXor, lhs, rhs),
+            BinOp::BitAnd => binop(LuaBinOp::BAnd, lhs, rhs),
+            BinOp::BitOr => binop(LuaBinOp::BOr, lhs, rhs),
+            BinOp::Shl => {
+                let rhs = shift_amount(fx, rhs, rhs_ty, bits);
+                wrap(binop(LuaBinOp::Shl, lhs, rhs))
+            }
+            BinOp::Shr if signed => rt_call("ashr", [lhs, shift_amount(fx, rhs, rhs_ty, bits)]),
+            BinOp::Shr => binop(LuaBinOp::Shr, lhs, shift_amount(fx, rhs, rhs_ty, bits)),
+            _ => unreachable!("{:?}({:?}, {:?})", bin_op, in_lhs, in_rhs),
+        }
+    };
+
+    CValue::by_val(val, in_lhs.layout())
+}
+
+pub(crate) fn codegen_checked_int_binop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    bin_op: BinOp,
+    in_lhs: CValue<'tcx>,
+    in_rhs: CValue<'tcx>,
+) -> CValue<'tcx> {
+    if bin_op != BinOp::Shl && bin_op != BinOp::Shr {
+        assert_eq!(
+            in_lhs.layout().ty,
+            in_rhs.layout().ty,
+            "checked int binop requires lhs and rhs of same type"
+        );
+    }
+
+    let lhs_layout = in_lhs.layout();
+    let (bits, signed) = int_ty_bits(fx.tcx, lhs_layout.ty).unwrap();
+    let out_layout = fx.layout_of(fx.tcx.mk_tup([lhs_layout.ty, fx.tcx.types.bool].iter()));
+
+    let (res, has_overflow) = match bin_op {
+        BinOp::Add | BinOp::Sub | BinOp::Mul => {
+            let lhs = in_lhs.load_scalar(fx);
+            let rhs = in_rhs.load_scalar(fx);
+            let name = match (bin_op, bits) {
+                (BinOp::Add, 128) => "oadd128",
+                (BinOp::Sub, 128) => "osub128",
+                (BinOp::Mul, 128) => "omul128",
+                (BinOp::Add, _) => "oadd",
+                (BinOp::Sub, _) => "osub",
+                (BinOp::Mul, _) => "omul",
+                _ => unreachable!(),
+            };
+            let call = if bits == 128 {
+                rt_call(name, [lhs, rhs, ExprBuilder.bool(signed)])
+            } else {
+                rt_call(name, [lhs, rhs, int(bits as i64), ExprBuilder.bool(signed)])
+            };
+            let mut results = fx.tmp_multi(2, call);
+            let has_overflow = results.pop().unwrap();
+            (results.pop().unwrap(), bool_to_int(has_overflow))
+        }
+        BinOp::Shl | BinOp::Shr => {
+            let (rhs_bits, _) = int_ty_bits(fx.tcx, in_rhs.layout().ty).unwrap();
+            let rhs = in_rhs.load_scalar(fx);
+            let rhs = fx.tmp(rhs);
+            let in_rhs = CValue::by_val(rhs.clone(), in_rhs.layout());
+            let val = codegen_int_binop(fx, bin_op, in_lhs, in_rhs).load_scalar(fx);
+
+            // Negative shift amounts are huge when compared as unsigned
+            let max_shift = int(bits as i64 - 1);
+            let has_overflow = if rhs_bits == 128 {
+                binop(
+                    LuaBinOp::Or,
+                    binop(
+                        LuaBinOp::Ne,
+                        ExprBuilder.table_access(rhs.clone().to_place(), int(2)),
+                        int(0),
+                    ),
+                    ExprBuilder.call(
+                        math("ult"),
+                        [max_shift, ExprBuilder.table_access(rhs.to_place(), int(1))],
+                    ),
+                )
+            } else {
+                ExprBuilder.call(math("ult"), [max_shift, rhs])
+            };
+            (val, bool_to_int(has_overflow))
+        }
+        _ => bug!(
+            "binop {:?} on checked int/uint lhs: {:?} rhs: {:?}",
+            bin_op,
+            in_lhs,
+            in_rhs
+        ),
+    };
+
+    CValue::by_val_pair(res, has_overflow, out_layout)
+}
+
+/// `!value` for an integer of type `ty`
+pub(crate) fn codegen_int_not<'tcx>(
+    fx: &FunctionCx<'_, 'tcx>,
+    ty: Ty<'tcx>,
+    value: Value,
+) -> Value {
+    let (bits, signed) = int_ty_bits(fx.tcx, ty).unwrap();
+    if bits == 128 {
+        rt_call("bnot128", [value])
+    } else if signed {
+        // The complement of a sign extended value is still sign extended
+        ExprBuilder.unop(LuaUnOp::BNot, value)
+    } else {
+        wrap_int(ExprBuilder.unop(LuaUnOp::BNot, value), bits, signed)
+    }
+}
+
+/// `-value` for an integer of type `ty`
+pub(crate) fn codegen_int_neg<'tcx>(
+    fx: &FunctionCx<'_, 'tcx>,
+    ty: Ty<'tcx>,
+    value: Value,
+) -> Value {
+    let (bits, signed) = int_ty_bits(fx.tcx, ty).unwrap();
+    if bits == 128 {
+        rt_call("neg128", [value])
+    } else {
+        wrap_int(ExprBuilder.unop(LuaUnOp::Neg, value), bits, signed)
+    }
+}
+
+pub(crate) fn codegen_float_binop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    bin_op: BinOp,
+    in_lhs: CValue<'tcx>,
+    in_rhs: CValue<'tcx>,
+) -> CValue<'tcx> {
+    assert_eq!(in_lhs.layout().ty, in_rhs.layout().ty);
+
+    let lhs = in_lhs.load_scalar(fx);
+    let rhs = in_rhs.load_scalar(fx);
+    let op = match bin_op {
+        BinOp::Add => LuaBinOp::Add,
+        BinOp::Sub => LuaBinOp::Sub,
+        BinOp::Mul => LuaBinOp::Mul,
+        BinOp::Div => LuaBinOp::Div,
+        BinOp::Rem => {
+            let res = ExprBuilder.call(math("fmod"), [lhs, rhs]);
+            return CValue::by_val(res, in_lhs.layout());
+        }
+        BinOp::Eq | BinOp::Lt | BinOp::Le | BinOp::Ne | BinOp::Ge | BinOp::Gt => {
+            let op = match bin_op {
+                BinOp::Eq => LuaBinOp::Eq,
+                BinOp::Lt => LuaBinOp::Lt,
+                BinOp::Le => LuaBinOp::Le,
+                BinOp::Ne => LuaBinOp::Ne,
+                BinOp::Ge => LuaBinOp::Ge,
+                BinOp::Gt => LuaBinOp::Gt,
+                _ => unreachable!(),
+            };
+            let val = bool_to_int(binop(op, lhs, rhs));
+            return CValue::by_val(val, fx.layout_of(fx.tcx.types.bool));
+        }
+        _ => unreachable!("{:?}({:?}, {:?})", bin_op, in_lhs, in_rhs),
+    };
+
+    let res = binop(op, lhs, rhs);
+    // Lua computes in double precision, round back to the precision of `f32`
+    let res = if in_lhs.layout().ty == fx.tcx.types.f32 {
+        rt_call("fround", [res])
+    } else {
+        res
+    };
+    CValue::by_val(res, in_lhs.layout())
+}
+
+pub(crate) fn codegen_ptr_binop<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    bin_op: BinOp,
+    in_lhs: CValue<'tcx>,
+    in_rhs: CValue<'tcx>,
+) -> CValue<'tcx> {
+    let is_thin_ptr = in_lhs
+        .layout()
+        .ty
+        .builtin_deref(true)
+        .map(|TypeAndMut { ty, mutbl: _ }| !has_ptr_meta(fx.tcx, ty))
+        .unwrap_or(true);
+    let ptr_bits = fx.pointer_size() * 8;
+
+    if is_thin_ptr {
+        match bin_op {
+            BinOp::Offset => {
+                let pointee_ty = in_lhs.layout().ty.builtin_deref(true).unwrap().ty;
+                let pointee_size = fx.layout_of(pointee_ty).size.bytes() as i64;
+                let base_layout = in_lhs.layout();
+                let base = in_lhs.load_scalar(fx);
+                let offset = in_rhs.load_scalar(fx);
+                let ptr_diff = match offset.as_int() {
+                    Some(offset) => int(offset.wrapping_mul(pointee_size)),
+                    None if pointee_size == 1 => offset,
+                    None => binop(LuaBinOp::Mul, offset, int(pointee_size)),
+                };
+                let res = match ptr_diff.as_int() {
+                    Some(diff) => ptr_offset(base, diff),
+                    None => binop(LuaBinOp::Add, base, ptr_diff),
+                };
+                CValue::by_val(wrap_int(res, ptr_bits, false), base_layout)
+            }
+            _ => {
+                let lhs = in_lhs.load_scalar(fx);
+                let rhs = in_rhs.load_scalar(fx);
+                let cond = codegen_icmp(bin_op, ptr_bits, false, lhs, rhs);
+                CValue::by_val(bool_to_int(cond), fx.layout_of(fx.tcx.types.bool))
+            }
+        }
+    } else {
+        let (lhs_ptr, lhs_extra) = in_lhs.load_scalar_pair(fx);
+        let (rhs_ptr, rhs_extra) = in_rhs.load_scalar_pair(fx);
+        let lhs_ptr = fx.tmp(lhs_ptr);
+        let rhs_ptr = fx.tmp(rhs_ptr);
+        let lhs_extra = fx.tmp(lhs_extra);
+        let rhs_extra = fx.tmp(rhs_extra);
+
+        let ptr_eq = binop(LuaBinOp::Eq, lhs_ptr.clone(), rhs_ptr.clone());
+        let extra_eq = binop(LuaBinOp::Eq, lhs_extra.clone(), rhs_extra.clone());
+
+        let res = match bin_op {
+            BinOp::Eq => binop(LuaBinOp::And, ptr_eq, extra_eq),
+            BinOp::Ne => ExprBuilder.unop(LuaUnOp::Not, binop(LuaBinOp::And, ptr_eq, extra_eq)),
+            BinOp::Lt | BinOp::Le | BinOp::Ge | BinOp::Gt => {
+                let strict_op = match bin_op {
+                    BinOp::Lt | BinOp::Le => BinOp::Lt,
+                    _ => BinOp::Gt,
+                };
+                // (lhs_ptr <strict_op> rhs_ptr) or (lhs_ptr == rhs_ptr and lhs_extra <op> rhs_extra)
+                let ptr_cmp = codegen_icmp(strict_op, ptr_bits, false, lhs_ptr, rhs_ptr);
+                let extra_cmp = codegen_icmp(bin_op, ptr_bits, false, lhs_extra, rhs_extra);
+                binop(
+                    LuaBinOp::Or,
+                    ptr_cmp,
+                    binop(LuaBinOp::And, ptr_eq, extra_cmp),
+                )
+            }
+            _ => panic!("bin_op {:?} on ptr", bin_op),
+        };
+
+        CValue::by_val(bool_to_int(res), fx.layout_of(fx.tcx.types.bool))
+    }
+}
@@ -0,0 +1,133 @@
+//! Codegen of the panic handler and the panic runtime
+//!
+//! With `-Cpanic=abort`, the `panic_impl` lang item (`rust_begin_unwind` for std) is replaced by a
+//! function formatting the panic message with `core::fmt::write` and raising it as a lua error
+//! with `rt.panic`. This skips the panic machinery of std, so hooks installed with
+//! `std::panic::set_hook` don't run and the panic count isn't updated: the lua error is the only
+//! report of the panic.
+//!
+//! With `-Cpanic=unwind`, std reports the panic itself and the functions of the `panic_unwind`
+//! crate starting and catching a panic are replaced by `rt.start_panic` and `rt.panic_cleanup`,
+//! which carry the `Box<dyn Any + Send>` payload of the panic in a lua error.
+
+use rustc_hir::def_id::{CRATE_DEF_INDEX, LOCAL_CRATE};
+use rustc_span::symbol::sym;
+
+use crate::prelude::*;
+
+/// Finds `core::fmt::write`, which is not a lang item.
+fn fmt_write_def_id(tcx: TyCtxt<'_>) -> Option<DefId> {
+    let core = tcx
+        .crates(())
+        .iter()
+        .copied()
+        .find(|&cnum| tcx.crate_name(cnum) == sym::core)?;
+    let mut def_id = DefId {
+        krate: core,
+        index: CRATE_DEF_INDEX,
+    };
+    for name in [sym::fmt, sym::write] {
+        def_id = tcx
+            .item_children(def_id)
+            .iter()
+            .find(|child| child.ident.name == name)?
+            .res
+            .opt_def_id()?;
+    }
+    Some(def_id)
+}
+
+fn field_by_name<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    place: CPlace<'tcx>,
+    name: &str,
+) -> CPlace<'tcx> {
+    let adt_def = match place.layout().ty.kind() {
+        ty::Adt(adt_def, _) => adt_def,
+        _ => bug!("{} has no fields", place.layout().ty),
+    };
+    let idx = adt_def
+        .non_enum_variant()
+        .fields
+        .iter()
+        .position(|field| field.ident.name.as_str() == name)
+        .unwrap_or_else(|| bug!("{} has no field {}", place.layout().ty, name));
+    place.place_field(fx, mir::Field::new(idx))
+}
+
+/// Generates the body of the functions implemented by the backend instead of their MIR,
+/// returning the parameters of the lua function.
+pub(crate) fn codegen_panic_override(fx: &mut FunctionCx<'_, '_>) -> Option<Vec<String>> {
+    let tcx = fx.tcx;
+    if !unwinding_enabled(tcx) {
+        if Some(fx.instance.def_id()) == tcx.lang_items().panic_impl() {
+            return Some(codegen_panic_impl(fx));
+        }
+        return None;
+    }
+
+    if tcx.crate_name(LOCAL_CRATE).as_str() != "panic_unwind" {
+        return None;
+    }
+    let helper = match fx.symbol_name.name {
+        "__rust_start_panic" => "start_panic",
+        "__rust_panic_cleanup" => "panic_cleanup",
+        _ => return None,
+    };
+    fx.cx
+        .ctx
+        .stat()
+        .ret(vec![rt_call(helper, [ident("payload")])]);
+    Some(vec!["payload".to_string()])
+}
+
+/// The body of `panic_impl` in abort mode. It replaces `rust_begin_unwind` of std entirely, which
+/// means the panic hook is never called.
+fn codegen_panic_impl(fx: &mut FunctionCx<'_, '_>) -> Vec<String> {
+    let info_local = fx.mir.args_iter().next().unwrap();
+    let info_ty = fx.monomorphize(fx.mir.local_decls[info_local].ty);
+    let info_layout = fx.layout_of(info_ty.builtin_deref(true).unwrap().ty);
+    let info = CPlace::for_ptr(ident("info"), info_layout);
+
+    // `message: Option<&fmt::Arguments>` is `None` for panics with a non string payload
+    let message_ptr = field_by_name(fx, info.clone(), "message")
+        .to_cvalue(fx)
+        .load_scalar(fx);
+    let message_ptr = fx.tmp(message_ptr);
+    let message = fx.tmp(ExprBuilder.string("Box<dyn Any>".to_string()));
+    let fmt_write = fmt_write_def_id(fx.tcx).expect("`core::fmt::write` not found");
+    let fmt_write = fn_symbol(fx.tcx.symbol_name(Instance::mono(fx.tcx, fmt_write)).name);
+    fx.cx
+        .ctx
+        .start_if(binop(LuaBinOp::Ne, message_ptr.clone(), int(0)));
+    fx.cx.ctx.stat().assign(
+        message.to_place(),
+        rt_call("format", [fmt_write, message_ptr]),
+    );
+    fx.cx.ctx.finish_block().unwrap();
+
+    let location = field_by_name(fx, info, "location").place_deref(fx);
+    let location_ptr = fx.tmp(location.to_ptr());
+    let location = CPlace::for_ptr(location_ptr, location.layout());
+    let (file_ptr, file_len) = field_by_name(fx, location.clone(), "file")
+        .to_cvalue(fx)
+        .load_scalar_pair(fx);
+    let line = field_by_name(fx, location.clone(), "line")
+        .to_cvalue(fx)
+        .load_scalar(fx);
+    let col = field_by_name(fx, location, "col")
+        .to_cvalue(fx)
+        .load_scalar(fx);
+
+    fx.cx.ctx.stat().call(
+        rt_field("panic"),
+        [
+            message,
+            rt_call("load_str", [file_ptr, file_len]),
+            line,
+            col,
+        ],
+    );
+
+    vec!["info".to_string()]
+}
@@ -0,0 +1,253 @@
+//! The lua runtime library used by the generated code
+//!
+//! The runtime is a chunk evaluating to the `rt` table. It expects `PTR_SIZE`, the size in bytes
+//! of a pointer on the target, to be in scope.
+//!
+//! # Memory model
+//!
+//! Memory is a table `rt.M` mapping each address to the byte stored there, pointers are plain lua
+//! integers. Functions live in the `rt.S` table indexed by their symbol name, while the address of
+//! each data symbol (statics and constant allocations) is stored in `rt.D` by `rt.link`. Lua values
+//! used by rust code through the `lua` crate are kept in `rt.V` and referred to by their index.
+//!
+//! Integers narrower than 64 bits are stored as their mathematical value, 64 bit integers wrap like
+//! lua integers do and 128 bit integers are `{lo, hi}` tables of 64 bit halves.
+//!
+//! # Sources
+//!
+//! The runtime is maintained as the lua files of `src/runtime`, which run in the order of
+//! `RUNTIME_SOURCES`. Each top-level statement of these files is an item, together with the
+//! comments right before it. Linked programs only embed the items they reach: those without a
+//! definition, like `if io then ... end`, and those defining a `local`, a field of `rt` or a
+//! function of `S` which the program or another embedded item uses. Uses are found by scanning the
+//! code for identifiers, `rt.<field>` accesses and strings, so they are over-approximated but never
+//! missed. Definitions are only recognized at the start of items, the runtime sticks to the forms
+//! `local <names> = ...`, `local function <name>`, `function rt.<field>`, `rt.<field> = ...` and
+//! `S["<symbol>"] = ...` for them.
+//!
+//! `RUNTIME_VERSION` is bumped whenever the interface between the generated code and the runtime
+//! changes, so that code compiled against another runtime is rejected instead of misbehaving.
+
+use std::collections::{HashMap, HashSet};
+
+/// The version of the interface between the generated code and the runtime
+pub(crate) const RUNTIME_VERSION: u32 = 1;
+
+const RUNTIME_SOURCES: &[&str] = &[
+    include_str!("runtime/memory.lua"),
+    include_str!("runtime/int.lua"),
+    include_str!("runtime/float.lua"),
+    include_str!("runtime/symbols.lua"),
+    include_str!("runtime/panic.lua"),
+    include_str!("runtime/value.lua"),
+    include_str!("runtime/sys.lua"),
+    include_str!("runtime/export.lua"),
+    include_str!("runtime/lib.lua"),
+];
+
+/// Adapts the runtime to Neovim, whose message area replaces the standard output and error.
+/// Panics are reported with `vim.notify` and never exit Neovim.
+pub(crate) const NEOVIM_HOST: &str = include_str!("runtime/neovim.lua");
+
+/// The functions of the `utf8` library used by the runtime, for dialects without it. It is a local
+/// defined before the runtime, which uses it as an upvalue.
+pub(crate) const UTF8_LIBRARY: &str = include_str!("runtime/utf8.lua");
+
+/// The runtime chunk, evaluating to the `rt` table. With `used_by`, the items which the code can't
+/// reach are left out.
+pub(crate) fn runtime(used_by: Option<&str>) -> String {
+    let items: Vec<Item<'_>> = RUNTIME_SOURCES
+        .iter()
+        .flat_map(|source| items(source))
+        .collect();
+    let kept = match used_by {
+        Some(code) => reachable(&items, code),
+        None => vec![true; items.len()],
+    };
+
+    let mut runtime = String::new();
+    for (item, _) in items.iter().zip(kept).filter(|(_, kept)| *kept) {
+        runtime.push_str(item.code);
+    }
+    runtime.push_str(&format!("rt.version = {}\nreturn rt\n", RUNTIME_VERSION));
+    runtime
+}
+
+/// Something defined by the runtime
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Name<'a> {
+    /// A local of the runtime chunk
+    Local(&'a str),
+    /// A field of `rt`
+    Field(&'a str),
+    /// A function of `S`, which is used by the code mentioning its symbol in a string
+    Symbol(&'a str),
+}
+
+/// A top-level statement of the runtime
+struct Item<'a> {
+    /// The code of the statement, starting with the comments right before it
+    code: &'a str,
+    defs: Vec<Name<'a>>,
+}
+
+/// Splits a runtime source into items. An item starts with each line which isn't indented and
+/// doesn't end a block, the comment lines right before it being part of it.
+fn items(source: &str) -> Vec<Item<'_>> {
+    let mut items = Vec::new();
+    let mut current: Option<(usize, &str)> = None;
+    let mut comments = None;
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let starts_item = !line.starts_with(char::is_whitespace)
+            && !["--", "end", "else", "until", "}", ")"]
+                .iter()
+                .any(|prefix| line.starts_with(prefix));
+        if line.starts_with("--") {
+            comments.get_or_insert(offset);
+        } else if starts_item {
+            // The first item also gets the comments at the start of the file
+            let start = match current {
+                Some((item_start, first_line)) => {
+                    let start = comments.unwrap_or(offset);
+                    items.push(Item {
+                        code: &source[item_start..start],
+                        defs: definitions(first_line),
+                    });
+                    start
+                }
+                None => 0,
+            };
+            current = Some((start, line));
+            comments = None;
+        } else {
+            comments = None;
+        }
+        offset += line.len();
+    }
+    if let Some((item_start, first_line)) = current {
+        items.push(Item {
+            code: &source[item_start..],
+            defs: definitions(first_line),
+        });
+    }
+    items
+}
+
+/// The names defined by an item starting with `line`
+fn definitions(line: &str) -> Vec<Name<'_>> {
+    if let Some(rest) = line.strip_prefix("local function ") {
+        return vec![Name::Local(name_prefix(rest))];
+    }
+    if let Some(rest) = line.strip_prefix("function rt.") {
+        return vec![Name::Field(name_prefix(rest))];
+    }
+    if let Some(rest) = line.strip_prefix("S[\"") {
+        let symbol = rest.split('"').next().unwrap_or(rest);
+        return vec![Name::Symbol(symbol)];
+    }
+    let (targets, local) = match line.strip_prefix("local ") {
+        Some(rest) => (rest, true),
+        None => (line, false),
+    };
+    let targets = targets.split(" = ").next().unwrap_or("");
+    targets
+        .split(',')
+        .map(str::trim)
+        .filter_map(|target| {
+            if local {
+                Some(Name::Local(target)).filter(|_| is_name(target))
+            } else {
+                target
+                    .strip_prefix("rt.")
+                    .filter(|field| is_name(field))
+                    .map(Name::Field)
+            }
+        })
+        .collect()
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty() && name_prefix(s).len() == s.len() && !s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn name_prefix(s: &str) -> &str {
+    let len = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(s.len());
+    &s[..len]
+}
+
+/// Calls `f` with the names which `code` may use: its identifiers, the fields of `rt` it accesses
+/// and the contents of its strings. Comments are skipped.
+fn for_each_use<'a>(code: &'a str, mut f: impl FnMut(Name<'a>)) {
+    let bytes = code.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = code[i..].find('\n').map_or(bytes.len(), |len| i + len);
+            }
+            quote @ (b'"' | b'\'') => {
+                let start = i + 1;
+                i = start;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                f(Name::Symbol(&code[start..i.min(bytes.len())]));
+                i += 1;
+            }
+            c if c.is_ascii_alphanumeric() || c == b'_' => {
+                let name = name_prefix(&code[i..]);
+                i += name.len();
+                if c.is_ascii_digit() {
+                    continue;
+                }
+                f(Name::Local(name));
+                if name == "rt" && bytes.get(i) == Some(&b'.') {
+                    let field = name_prefix(&code[i + 1..]);
+                    if is_name(field) {
+                        f(Name::Field(field));
+                        i += 1 + field.len();
+                    }
+                }
+            }
+            _ => i += 1,
+        }
+    }
+}
+
+/// Which items are needed by `code`, which can't use the locals of the runtime
+fn reachable<'a>(items: &[Item<'a>], code: &'a str) -> Vec<bool> {
+    let mut definers: HashMap<Name<'a>, Vec<usize>> = HashMap::new();
+    for (i, item) in items.iter().enumerate() {
+        for &def in &item.defs {
+            definers.entry(def).or_default().push(i);
+        }
+    }
+
+    let mut used = HashSet::new();
+    let mut pending = Vec::new();
+    let mut use_names = |code: &'a str, locals: bool, pending: &mut Vec<Name<'a>>| {
+        for_each_use(code, |name| {
+            if (locals || !matches!(name, Name::Local(_))) && used.insert(name) {
+                pending.push(name);
+            }
+        })
+    };
+    use_names(code, false, &mut pending);
+    let mut kept: Vec<bool> = items.iter().map(|item| item.defs.is_empty()).collect();
+    for (item, _) in items.iter().zip(&kept).filter(|(_, kept)| **kept) {
+        use_names(item.code, true, &mut pending);
+    }
+
+    while let Some(name) = pending.pop() {
+        for &i in definers.get(&name).map_or(&[][..], |items| &items[..]) {
+            if !kept[i] {
+                kept[i] = true;
+                use_names(items[i].code, true, &mut pending);
+            }
+        }
+    }
+    kept
+}
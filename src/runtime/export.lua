@@ -0,0 +1,350 @@
+-- Exported functions
+--
+-- Rust functions exported with `#[export_name = "lua_export.<name>"]` are added to `rt.module`,
+-- the table returned by a linked library. Their arguments and return value are converted by
+-- `rt.from_lua` and `rt.to_lua` according to a descriptor of the rust type:
+--
+--   {"unit"}, {"bool"}, {"char"}, {"int", size, signed}, {"float", size}, {"str"} for `&str`,
+--   {"value"} for the handle of a `lua::LuaValue` or `lua::LuaTable`,
+--   {"string", ptr_offset, cap_offset, len_offset},
+--   {"vec", elem, elem_size, elem_align, ptr_offset, cap_offset, len_offset} and
+--   {"map", key, value, key_offset, value_offset, elem_size, elem_align, k0_offset, k1_offset,
+--    bucket_mask_offset, ctrl_offset, growth_left_offset, items_offset} for `HashMap<K, V>`
+
+rt.module = {}
+
+local function expected(what, v)
+  error(string.format("expected %s, got %s", what, type(v)), 0)
+end
+
+local function rust_alloc(size, align)
+  if size == 0 then
+    return align
+  end
+  return S["__rust_alloc"](size, align)
+end
+
+-- Maps
+--
+-- A `HashMap` is a hashbrown table of `(K, V)` elements: `buckets` control bytes at `ctrl`,
+-- followed by a copy of the first group of them, and the elements stored before `ctrl`, the one of
+-- bucket `i` at `ctrl - (i + 1) * elem_size`. The control byte of an empty bucket is 0xff, the one
+-- of a full bucket the top 7 bits of the hash of its key. The keys are hashed with SipHash-1-3
+-- keyed by the `RandomState` of the map, over the bytes which their `Hash` impl writes.
+
+-- The width of the groups of control bytes probed together by the generic implementation of
+-- hashbrown, which targets without SSE2 use
+local GROUP_WIDTH = PTR_SIZE
+
+local function rotl(x, b)
+  return (x << b) | (x >> (64 - b))
+end
+
+local function sip_round(v0, v1, v2, v3)
+  v0 = v0 + v1
+  v1 = rotl(v1, 13) ~ v0
+  v0 = rotl(v0, 32)
+  v2 = v2 + v3
+  v3 = rotl(v3, 16) ~ v2
+  v0 = v0 + v3
+  v3 = rotl(v3, 21) ~ v0
+  v2 = v2 + v1
+  v1 = rotl(v1, 17) ~ v2
+  v2 = rotl(v2, 32)
+  return v0, v1, v2, v3
+end
+
+-- The SipHash-1-3 of the list of bytes `bytes` with the keys `k0` and `k1`, like `DefaultHasher`
+local function sip13(k0, k1, bytes)
+  local v0, v1 = k0 ~ 0x736f6d6570736575, k1 ~ 0x646f72616e646f6d
+  local v2, v3 = k0 ~ 0x6c7967656e657261, k1 ~ 0x7465646279746573
+  local n = #bytes
+  local full = n - n % 8
+  for i = 1, full + 1, 8 do
+    -- The last word holds the remaining bytes and the length
+    local m = i > full and (n & 0xff) << 56 or 0
+    for j = 0, math.min(7, n - i) do
+      m = m | (bytes[i + j] << (8 * j))
+    end
+    v3 = v3 ~ m
+    v0, v1, v2, v3 = sip_round(v0, v1, v2, v3)
+    v0 = v0 ~ m
+  end
+  v2 = v2 ~ 0xff
+  for _ = 1, 3 do
+    v0, v1, v2, v3 = sip_round(v0, v1, v2, v3)
+  end
+  return v0 ~ v1 ~ v2 ~ v3
+end
+
+-- The bytes which the `Hash` impl of the key described by `desc` at `p` writes to the hasher
+local function key_bytes(desc, p)
+  local kind, bytes = desc[1], {}
+  if kind == "str" or kind == "string" then
+    local buf, len
+    if kind == "str" then
+      buf, len = rt.load(p, PTR_SIZE), rt.load(p + PTR_SIZE, PTR_SIZE)
+    else
+      buf, len = rt.load(p + desc[2], PTR_SIZE), rt.load(p + desc[4], PTR_SIZE)
+    end
+    for i = 1, len do
+      bytes[i] = M[buf + i - 1]
+    end
+    -- `str` ends its hash with 0xff, so that ("a", "b") and ("ab", "") differ
+    bytes[len + 1] = 0xff
+  else
+    local size = kind == "int" and desc[2] or kind == "char" and 4 or 1
+    for i = 1, size do
+      bytes[i] = M[p + i - 1]
+    end
+  end
+  return bytes
+end
+
+-- The number of buckets of a table created with capacity `cap`
+local function capacity_to_buckets(cap)
+  if cap < 8 then
+    return cap < 4 and 4 or 8
+  end
+  local buckets, adjusted = 1, cap * 8 // 7
+  while buckets < adjusted do
+    buckets = buckets * 2
+  end
+  return buckets
+end
+
+-- The size and alignment of the allocation of a table and the offset of its control bytes
+local function table_layout(elem_size, elem_align, buckets)
+  local ctrl_align = math.max(elem_align, GROUP_WIDTH)
+  local ctrl_offset = align_up(elem_size * buckets, ctrl_align)
+  return ctrl_offset + buckets + GROUP_WIDTH, ctrl_align, ctrl_offset
+end
+
+-- The bucket where hashbrown inserts a new element with the hash `hash`
+local function find_insert_slot(ctrl, mask, hash)
+  local pos, stride = hash & mask, 0
+  while true do
+    for i = 0, GROUP_WIDTH - 1 do
+      if M[ctrl + pos + i] >= 0x80 then
+        local slot = (pos + i) & mask
+        if M[ctrl + slot] < 0x80 then
+          -- In tables smaller than a group, the empty bytes after the copy of the first group
+          -- lead to full buckets
+          for j = 0, GROUP_WIDTH - 1 do
+            if M[ctrl + j] >= 0x80 then
+              return j
+            end
+          end
+        end
+        return slot
+      end
+    end
+    stride = stride + GROUP_WIDTH
+    pos = (pos + stride) & mask
+  end
+end
+
+-- Stores the lua value `v` as the rust value described by `desc` at `p`. Temporary allocations
+-- backing borrowed values are appended to `temps`.
+function rt.from_lua(desc, v, p, temps)
+  local kind = desc[1]
+  if kind == "unit" then
+    return
+  elseif kind == "bool" then
+    if type(v) ~= "boolean" then
+      expected("boolean", v)
+    end
+    M[p] = v and 1 or 0
+  elseif kind == "char" then
+    if type(v) ~= "string" or utf8.len(v) ~= 1 then
+      expected("a single character string", v)
+    end
+    rt.store(p, 4, utf8.codepoint(v))
+  elseif kind == "int" then
+    local size, signed = desc[2], desc[3]
+    local i = math.tointeger(v)
+    if not i then
+      expected("integer", v)
+    end
+    local in_range
+    if size == 8 then
+      in_range = signed or i >= 0
+    elseif signed then
+      in_range = i >= -(1 << (size * 8 - 1)) and i < 1 << (size * 8 - 1)
+    else
+      in_range = i >= 0 and i < 1 << (size * 8)
+    end
+    if not in_range then
+      error(string.format("integer %d out of range for a %d bit integer", i, size * 8), 0)
+    end
+    rt.store(p, size, i)
+  elseif kind == "float" then
+    if type(v) ~= "number" then
+      expected("number", v)
+    end
+    if desc[2] == 4 then
+      rt.store_f32(p, v + 0.0)
+    else
+      rt.store_f64(p, v + 0.0)
+    end
+  elseif kind == "str" then
+    if type(v) ~= "string" or not utf8.len(v) then
+      expected("UTF-8 string", v)
+    end
+    local buf = rt.alloc(#v, 1)
+    store_bytes(buf, v)
+    temps[#temps + 1] = {buf, #v}
+    rt.store(p, PTR_SIZE, buf)
+    rt.store(p + PTR_SIZE, PTR_SIZE, #v)
+  elseif kind == "value" then
+    rt.store(p, PTR_SIZE, rt.ref(v))
+  elseif kind == "string" then
+    if type(v) ~= "string" or not utf8.len(v) then
+      expected("UTF-8 string", v)
+    end
+    local buf = rust_alloc(#v, 1)
+    store_bytes(buf, v)
+    rt.store(p + desc[2], PTR_SIZE, buf)
+    rt.store(p + desc[3], PTR_SIZE, #v)
+    rt.store(p + desc[4], PTR_SIZE, #v)
+  elseif kind == "vec" then
+    if type(v) ~= "table" then
+      expected("table", v)
+    end
+    local elem, size, align = desc[2], desc[3], desc[4]
+    local n = #v
+    local buf = rust_alloc(n * size, align)
+    for i = 1, n do
+      rt.from_lua(elem, v[i], buf + (i - 1) * size, temps)
+    end
+    rt.store(p + desc[5], PTR_SIZE, buf)
+    rt.store(p + desc[6], PTR_SIZE, n)
+    rt.store(p + desc[7], PTR_SIZE, n)
+  elseif kind == "map" then
+    if type(v) ~= "table" then
+      expected("table", v)
+    end
+    local key, value, key_offset, value_offset = desc[2], desc[3], desc[4], desc[5]
+    local size, align = desc[6], desc[7]
+    local n = 0
+    for _ in pairs(v) do
+      n = n + 1
+    end
+    local buckets = capacity_to_buckets(n)
+    local mask = buckets - 1
+    local len, ctrl_align, ctrl_offset = table_layout(size, align, buckets)
+    local ctrl = rust_alloc(len, ctrl_align) + ctrl_offset
+    rt.memset(ctrl, 0xff, buckets + GROUP_WIDTH)
+    local k0, k1 = math.random(0, math.maxinteger), math.random(0, math.maxinteger)
+    -- Each element is converted on the stack, then moved to the bucket given by its hash
+    local elem = rt.alloca(size, align)
+    for k, val in pairs(v) do
+      rt.from_lua(key, k, elem + key_offset, temps)
+      rt.from_lua(value, val, elem + value_offset, temps)
+      local hash = sip13(k0, k1, key_bytes(key, elem + key_offset))
+      local slot = find_insert_slot(ctrl, mask, hash)
+      local h2 = hash >> 57
+      M[ctrl + slot] = h2
+      M[ctrl + ((slot - GROUP_WIDTH) & mask) + GROUP_WIDTH] = h2
+      rt.memcpy(ctrl - (slot + 1) * size, elem, size)
+    end
+    rt.store(p + desc[8], 8, k0)
+    rt.store(p + desc[9], 8, k1)
+    rt.store(p + desc[10], PTR_SIZE, mask)
+    rt.store(p + desc[11], PTR_SIZE, ctrl)
+    -- The capacity of the buckets, which keeps an eighth of them empty in larger tables
+    local capacity = mask < 8 and mask or buckets // 8 * 7
+    rt.store(p + desc[12], PTR_SIZE, capacity - n)
+    rt.store(p + desc[13], PTR_SIZE, n)
+  else
+    error("invalid type descriptor " .. tostring(kind))
+  end
+end
+
+-- Loads the rust value described by `desc` at `p` as a lua value. The value is moved out of rust
+-- memory: owned buffers are deallocated and handles released.
+function rt.to_lua(desc, p)
+  local kind = desc[1]
+  if kind == "unit" then
+    return nil
+  elseif kind == "bool" then
+    return M[p] ~= 0
+  elseif kind == "char" then
+    return utf8.char(rt.load(p, 4))
+  elseif kind == "int" then
+    return rt.load(p, desc[2], desc[3])
+  elseif kind == "float" then
+    if desc[2] == 4 then
+      return rt.load_f32(p)
+    end
+    return rt.load_f64(p)
+  elseif kind == "str" then
+    return load_string(rt.load(p, PTR_SIZE), rt.load(p + PTR_SIZE, PTR_SIZE))
+  elseif kind == "value" then
+    local h = rt.load(p, PTR_SIZE)
+    local v = V[h]
+    rt.unref(h)
+    return v
+  elseif kind == "string" then
+    local buf, cap = rt.load(p + desc[2], PTR_SIZE), rt.load(p + desc[3], PTR_SIZE)
+    local s = load_string(buf, rt.load(p + desc[4], PTR_SIZE))
+    if cap ~= 0 then
+      S["__rust_dealloc"](buf, cap, 1)
+    end
+    return s
+  elseif kind == "vec" then
+    local elem, size, align = desc[2], desc[3], desc[4]
+    local buf, cap = rt.load(p + desc[5], PTR_SIZE), rt.load(p + desc[6], PTR_SIZE)
+    local t = {}
+    for i = 1, rt.load(p + desc[7], PTR_SIZE) do
+      t[i] = rt.to_lua(elem, buf + (i - 1) * size)
+    end
+    if cap ~= 0 and size ~= 0 then
+      S["__rust_dealloc"](buf, cap * size, align)
+    end
+    return t
+  elseif kind == "map" then
+    local key, value, key_offset, value_offset = desc[2], desc[3], desc[4], desc[5]
+    local size, align = desc[6], desc[7]
+    local mask, ctrl = rt.load(p + desc[10], PTR_SIZE), rt.load(p + desc[11], PTR_SIZE)
+    local t = {}
+    for i = 0, mask do
+      if M[ctrl + i] < 0x80 then
+        local elem = ctrl - (i + 1) * size
+        local k = rt.to_lua(key, elem + key_offset)
+        local val = rt.to_lua(value, elem + value_offset)
+        -- Keys with `()` values, as in sets, are kept
+        if val == nil then
+          val = true
+        end
+        t[k] = val
+      end
+    end
+    -- Empty maps without allocation share a static group of empty control bytes
+    if mask ~= 0 then
+      local len, ctrl_align, ctrl_offset = table_layout(size, align, mask + 1)
+      S["__rust_dealloc"](ctrl - ctrl_offset, len, ctrl_align)
+    end
+    return t
+  end
+  error("invalid type descriptor " .. tostring(kind))
+end
+
+-- Wraps the function converting its arguments and calling the rust function `rt.module[name]`,
+-- which receives a table collecting the temporary allocations to free once the call returns.
+function rt.export(name)
+  local f = rt.module[name]
+  rt.module[name] = function(...)
+    local sp, temps = rt.sp, {}
+    local ok, res = pcall(f, temps, ...)
+    rt.sp = sp
+    for _, temp in ipairs(temps) do
+      rt.free(temp[1], temp[2])
+    end
+    if not ok then
+      error(res, 0)
+    end
+    return res
+  end
+end
+
@@ -0,0 +1,372 @@
+-- Floats
+--
+-- All floats are lua numbers, f32 values are doubles that are exactly representable as f32.
+-- Operations that can produce extra precision round their result with `rt.fround`.
+
+local huge = math.huge
+
+local function signbit(x)
+  return x < 0 or (x == 0 and 1 / x < 0)
+end
+
+local function fround_soft(x)
+  if x ~= x or x == 0 or x == huge or x == -huge then
+    return x
+  end
+  local a = math.abs(x)
+  local e = math.floor(math.log(a) / math.log(2))
+  -- The logarithm may be off by one close to powers of two
+  if 2.0 ^ e > a then
+    e = e - 1
+  elseif 2.0 ^ (e + 1) <= a then
+    e = e + 1
+  end
+  -- Subnormals all share the smallest exponent
+  if e < -126 then
+    e = -126
+  end
+  local ulp = 2.0 ^ (e - 23)
+  local q = a / ulp
+  local r = math.floor(q)
+  local d = q - r
+  if d > 0.5 or (d == 0.5 and r % 2 == 1) then
+    r = r + 1
+  end
+  local res = r * ulp
+  if res >= 2.0 ^ 128 then
+    res = huge
+  end
+  if x < 0 then
+    return -res
+  end
+  return res
+end
+
+-- Rounds `x` to the nearest f32, ties to even
+if string.pack then
+  function rt.fround(x)
+    return (string.unpack("<f", string.pack("<f", x)))
+  end
+else
+  rt.fround = fround_soft
+end
+
+-- math.floor and math.ceil return integers when they can, which loses the sign of zero
+local function to_float(r, x)
+  r = r + 0.0
+  if r == 0 and signbit(x) then
+    return -0.0
+  end
+  return r
+end
+
+function rt.floor(x)
+  if x ~= x or x == huge or x == -huge then
+    return x
+  end
+  return to_float(math.floor(x), x)
+end
+
+function rt.ceil(x)
+  if x ~= x or x == huge or x == -huge then
+    return x
+  end
+  return to_float(math.ceil(x), x)
+end
+
+function rt.trunc(x)
+  if x >= 0 then
+    return rt.floor(x)
+  end
+  return rt.ceil(x)
+end
+
+-- Rounds half way cases away from zero
+function rt.round(x)
+  local t = rt.trunc(x)
+  if math.abs(x - t) >= 0.5 then
+    t = to_float(t + (x > 0 and 1 or -1), x)
+  end
+  return t
+end
+
+-- Rounds half way cases to even
+function rt.rint(x)
+  local f = rt.floor(x)
+  if f ~= f or f == huge or f == -huge then
+    return f
+  end
+  local d = x - f
+  if d > 0.5 or (d == 0.5 and f % 2 ~= 0) then
+    f = f + 1
+  end
+  return to_float(f, x)
+end
+
+function rt.copysign(a, b)
+  local m = math.abs(a)
+  if signbit(b) then
+    return -m
+  end
+  return m
+end
+
+-- Returns the other operand if one of them is NaN
+function rt.minnum(a, b)
+  if a ~= a then
+    return b
+  elseif b ~= b or a < b then
+    return a
+  end
+  return b
+end
+
+function rt.maxnum(a, b)
+  if a ~= a then
+    return b
+  elseif b ~= b or a > b then
+    return a
+  end
+  return b
+end
+
+function rt.pow(a, b)
+  return a ^ b
+end
+
+function rt.exp2(x)
+  return 2.0 ^ x
+end
+
+-- Lua 5.1 ignores the base of `math.log`
+local log_has_base = math.log(8, 2) == 3
+
+function rt.log2(x)
+  if log_has_base then
+    return math.log(x, 2)
+  end
+  local r = math.log(x) / math.log(2)
+  -- Keep the result of powers of two exact
+  local i = math.floor(r + 0.5)
+  if 2.0 ^ i == x then
+    return i + 0.0
+  end
+  return r
+end
+
+function rt.log10(x)
+  if log_has_base then
+    return math.log(x, 10)
+  end
+  return math.log10(x)
+end
+
+-- Splits a finite float into its sign, an integer mantissa and an exponent
+local function float_parts(x)
+  local bits = string.unpack("<i8", string.pack("<d", x))
+  local e = (bits >> 52) & 0x7ff
+  local m = bits & 0xfffffffffffff
+  if e == 0 then
+    e = 1
+  else
+    m = m | (1 << 52)
+  end
+  return bits < 0, m, e - 1075
+end
+
+-- Rounds `v * 2^e`, `v` being a non-zero 128 bit integer, to the nearest float of `bits` bits,
+-- ties to even
+local function round_float(neg, v, e, bits)
+  local mant, emin, max = 53, -1074, huge
+  if bits == 32 then
+    mant, emin, max = 24, -149, 2.0 ^ 128
+  end
+  local q = math.max(e + 128 - rt.ctlz128(v) - mant, emin)
+  local r = 0
+  if q <= e then
+    -- The value fits in the mantissa
+    r, q = v[1], e
+  elseif q - e < 128 then
+    local shift = q - e
+    local t = rt.lshr128(v, shift)
+    local rem = rt.sub128(v, rt.shl128(t, shift))
+    local half = rt.shl128({1, 0}, shift - 1)
+    r = t[1]
+    if rt.ult128(half, rem) or (rt.eq128(rem, half) and r & 1 == 1) then
+      r = r + 1
+    end
+  end
+  -- Scaled in two steps so that neither power of two overflows
+  local res = r * 2.0 ^ (q // 2) * 2.0 ^ (q - q // 2)
+  if res >= max then
+    res = huge
+  end
+  if neg then
+    return -res
+  end
+  return res
+end
+
+-- Shifts the 128 bit integer `v` left until its highest bit is bit 125
+local function normalize(v, e)
+  local shift = rt.ctlz128(v) - 2
+  return rt.shl128(v, shift), e - shift
+end
+
+-- Computes `a * b + c` with a single rounding to a float of `bits` bits. The exact sum is
+-- computed with 128 bit integers, the smaller operand only keeping a sticky bit when it is too
+-- far below the larger one.
+local function fma(a, b, c, bits)
+  if a - a ~= 0 or b - b ~= 0 or a == 0 or b == 0 then
+    -- The product is either not finite or exact
+    return a * b + c
+  elseif c - c ~= 0 then
+    -- The exact product is finite, even when `a * b` overflows
+    return c
+  elseif c == 0 then
+    -- The product of two f32 values is exact
+    if bits == 32 then
+      return rt.fround(a * b)
+    end
+    return a * b
+  end
+  local na, ma, ea = float_parts(a)
+  local nb, mb, eb = float_parts(b)
+  local nc, mc, ec = float_parts(c)
+  local lo, hi = mul64(ma, mb)
+  local x, xe = normalize({lo, hi}, ea + eb)
+  local y, ye = normalize({mc, 0}, ec)
+  local xneg, yneg = na ~= nb, nc
+  if xe < ye then
+    x, xe, xneg, y, ye, yneg = y, ye, yneg, x, xe, xneg
+  end
+  local d = xe - ye
+  local sticky = 0
+  if d >= 128 then
+    y, sticky = {0, 0}, 1
+  elseif d > 0 then
+    local t = rt.lshr128(y, d)
+    if not rt.eq128(rt.shl128(t, d), y) then
+      sticky = 1
+    end
+    y = t
+  end
+  local r, neg
+  if xneg == yneg then
+    r, neg = rt.add128(x, y), xneg
+  elseif rt.ult128(x, y) then
+    r, neg = rt.sub128(y, x), yneg
+  else
+    r, neg = rt.sub128(rt.sub128(x, y), {sticky, 0}), xneg
+  end
+  if rt.eq128(r, {0, 0}) then
+    return 0.0
+  end
+  r[1] = r[1] | sticky
+  return round_float(neg, r, xe, bits)
+end
+
+function rt.fma(a, b, c)
+  return fma(a, b, c, 64)
+end
+
+function rt.fmaf32(a, b, c)
+  return fma(a, b, c, 32)
+end
+
+-- Casts
+
+-- Rounds the unsigned integer `hi * 2^64 + lo` to the nearest float with `mant` significant bits,
+-- ties to even
+local function utof(lo, hi, mant)
+  local n
+  if hi ~= 0 then
+    n = 128 - rt.ctlz(hi, 64)
+  else
+    n = 64 - rt.ctlz(lo, 64)
+  end
+  if n <= mant then
+    return lo + 0.0
+  end
+  local shift = n - mant
+  local v = {lo, hi}
+  local r = rt.lshr128(v, shift)[1]
+  local rem = rt.sub128(v, rt.shl128({r, 0}, shift))
+  local half = rt.shl128({1, 0}, shift - 1)
+  if rt.ult128(half, rem) or (rt.eq128(rem, half) and r & 1 == 1) then
+    r = r + 1
+  end
+  local res = r * 2.0 ^ shift
+  if mant == 24 and res >= 2.0 ^ 128 then
+    return huge
+  end
+  return res
+end
+
+-- Converts a 64 bit integer to a float with `mant` significant bits
+function rt.itof(v, signed, mant)
+  if signed and v < 0 then
+    return -utof(-v, 0, mant)
+  end
+  return utof(v, 0, mant)
+end
+
+function rt.itof128(v, signed, mant)
+  if signed and v[2] < 0 then
+    local a = rt.neg128(v)
+    return -utof(a[1], a[2], mant)
+  end
+  return utof(v[1], v[2], mant)
+end
+
+-- Converts an integral float in `[0, 2^64)` to the bit pattern of an u64
+local function ftou64(f)
+  if f >= 2.0 ^ 63 then
+    return math.tointeger(f - 2.0 ^ 64)
+  end
+  return math.tointeger(f)
+end
+
+-- Converts a float to an integer of `bits` bits, saturating at the bounds and mapping NaN to 0
+function rt.ftoi(x, bits, signed)
+  if x ~= x then
+    return 0
+  end
+  local lo = signed and -(2.0 ^ (bits - 1)) or 0.0
+  local hi = signed and 2.0 ^ (bits - 1) or 2.0 ^ bits
+  if x <= lo then
+    return int_min(bits, signed)
+  elseif x >= hi then
+    return int_max(bits, signed)
+  end
+  x = rt.trunc(x)
+  if signed then
+    return math.tointeger(x)
+  end
+  return ftou64(x)
+end
+
+function rt.ftoi128(x, signed)
+  if x ~= x then
+    return {0, 0}
+  end
+  if signed then
+    if x <= -(2.0 ^ 127) then
+      return {0, math.mininteger}
+    elseif x >= 2.0 ^ 127 then
+      return {-1, math.maxinteger}
+    end
+  elseif x <= 0 then
+    return {0, 0}
+  elseif x >= 2.0 ^ 128 then
+    return {-1, -1}
+  end
+  local a = rt.trunc(math.abs(x))
+  local hi = rt.floor(a / 2.0 ^ 64)
+  local r = {ftou64(a - hi * 2.0 ^ 64), ftou64(hi)}
+  if x < 0 then
+    return rt.neg128(r)
+  end
+  return r
+end
+
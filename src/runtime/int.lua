@@ -0,0 +1,460 @@
+-- Integers
+
+function rt.wrap(v, bits, signed)
+  local half = 1 << (bits - 1)
+  if signed then
+    return ((v + half) & ((half << 1) - 1)) - half
+  else
+    return v & ((half << 1) - 1)
+  end
+end
+
+function rt.ashr(v, n)
+  if v >= 0 then
+    return v >> n
+  end
+  return ~((~v) >> n)
+end
+
+function rt.sdiv(a, b)
+  local q = a // b
+  if q < 0 and q * b ~= a then
+    q = q + 1
+  end
+  return q
+end
+
+function rt.srem(a, b)
+  return a - rt.sdiv(a, b) * b
+end
+
+function rt.udiv(n, d)
+  if d < 0 then
+    if ult(n, d) then
+      return 0
+    else
+      return 1
+    end
+  end
+  if n >= 0 then
+    return n // d
+  end
+  local q = ((n >> 1) // d) << 1
+  local r = n - q * d
+  if not ult(r, d) then
+    q = q + 1
+  end
+  return q
+end
+
+function rt.urem(n, d)
+  return n - rt.udiv(n, d) * d
+end
+
+-- Unsigned 64x64 -> 128 bit multiplication, returns the low and high halves
+local function mul64(a, b)
+  local a0, a1 = a & 0xffffffff, a >> 32
+  local b0, b1 = b & 0xffffffff, b >> 32
+  local p00, p01, p10, p11 = a0 * b0, a0 * b1, a1 * b0, a1 * b1
+  local mid = (p00 >> 32) + (p01 & 0xffffffff) + (p10 & 0xffffffff)
+  local lo = (p00 & 0xffffffff) | (mid << 32)
+  local hi = p11 + (p01 >> 32) + (p10 >> 32) + (mid >> 32)
+  return lo, hi
+end
+rt.mul64 = mul64
+
+-- Checked operations return the wrapped result and whether it overflowed
+
+local function checked(r, bits, signed)
+  local w = rt.wrap(r, bits, signed)
+  return w, w ~= r
+end
+
+function rt.oadd(a, b, bits, signed)
+  if bits < 64 then
+    return checked(a + b, bits, signed)
+  end
+  local r = a + b
+  if signed then
+    return r, ((a ~ r) & (b ~ r)) < 0
+  end
+  return r, ult(r, a)
+end
+
+function rt.osub(a, b, bits, signed)
+  if bits < 64 then
+    return checked(a - b, bits, signed)
+  end
+  local r = a - b
+  if signed then
+    return r, ((a ~ b) & (a ~ r)) < 0
+  end
+  return r, ult(a, b)
+end
+
+function rt.omul(a, b, bits, signed)
+  if bits <= 32 then
+    return checked(a * b, bits, signed)
+  end
+  if not signed then
+    local lo, hi = mul64(a, b)
+    return lo, hi ~= 0
+  end
+  local p = rt.mul128({a, a >> 63 == 1 and -1 or 0}, {b, b >> 63 == 1 and -1 or 0})
+  return p[1], p[2] ~= (p[1] < 0 and -1 or 0)
+end
+
+-- Bit manipulation, the operand holds an integer of `bits` bits which may be sign extended. The
+-- results are not sign extended.
+
+local function mask(v, bits)
+  if bits == 64 then
+    return v
+  end
+  return v & ((1 << bits) - 1)
+end
+
+function rt.ctpop(v, bits)
+  v = mask(v, bits)
+  local n = 0
+  while v ~= 0 do
+    v = v & (v - 1)
+    n = n + 1
+  end
+  return n
+end
+
+function rt.ctlz(v, bits)
+  v = mask(v, bits)
+  local n = bits
+  while v ~= 0 do
+    v = v >> 1
+    n = n - 1
+  end
+  return n
+end
+
+function rt.cttz(v, bits)
+  v = mask(v, bits)
+  if v == 0 then
+    return bits
+  end
+  local n = 0
+  while v & 1 == 0 do
+    v = v >> 1
+    n = n + 1
+  end
+  return n
+end
+
+function rt.bswap(v, bits)
+  v = mask(v, bits)
+  local r = 0
+  for _ = 1, bits // 8 do
+    r = (r << 8) | (v & 0xff)
+    v = v >> 8
+  end
+  return r
+end
+
+function rt.bitreverse(v, bits)
+  v = mask(v, bits)
+  local r = 0
+  for _ = 1, bits do
+    r = (r << 1) | (v & 1)
+    v = v >> 1
+  end
+  return r
+end
+
+function rt.rotl(v, n, bits)
+  v = mask(v, bits)
+  n = n % bits
+  if n == 0 then
+    return v
+  end
+  return mask((v << n) | (v >> (bits - n)), bits)
+end
+
+function rt.rotr(v, n, bits)
+  return rt.rotl(v, bits - n % bits, bits)
+end
+
+local function int_max(bits, signed)
+  if signed then
+    return (1 << (bits - 1)) - 1
+  elseif bits == 64 then
+    return -1
+  end
+  return (1 << bits) - 1
+end
+
+local function int_min(bits, signed)
+  if signed then
+    return -(1 << (bits - 1))
+  end
+  return 0
+end
+
+function rt.sat_add(a, b, bits, signed)
+  local r, overflow = rt.oadd(a, b, bits, signed)
+  if not overflow then
+    return r
+  elseif signed and b < 0 then
+    return int_min(bits, signed)
+  end
+  return int_max(bits, signed)
+end
+
+function rt.sat_sub(a, b, bits, signed)
+  local r, overflow = rt.osub(a, b, bits, signed)
+  if not overflow then
+    return r
+  elseif signed and b < 0 then
+    return int_max(bits, signed)
+  end
+  return int_min(bits, signed)
+end
+
+-- 128 bit integers
+
+local function sext(v)
+  return v < 0 and -1 or 0
+end
+
+-- Widens a 64 bit integer to 128 bits
+function rt.to128(v, signed)
+  return {v, signed and sext(v) or 0}
+end
+
+function rt.eq128(a, b)
+  return a[1] == b[1] and a[2] == b[2]
+end
+
+function rt.ult128(a, b)
+  if a[2] ~= b[2] then
+    return ult(a[2], b[2])
+  end
+  return ult(a[1], b[1])
+end
+
+function rt.slt128(a, b)
+  if a[2] ~= b[2] then
+    return a[2] < b[2]
+  end
+  return ult(a[1], b[1])
+end
+
+function rt.add128(a, b)
+  local lo = a[1] + b[1]
+  return {lo, a[2] + b[2] + (ult(lo, a[1]) and 1 or 0)}
+end
+
+function rt.sub128(a, b)
+  return {a[1] - b[1], a[2] - b[2] - (ult(a[1], b[1]) and 1 or 0)}
+end
+
+function rt.neg128(a)
+  return rt.sub128({0, 0}, a)
+end
+
+function rt.mul128(a, b)
+  local lo, hi = mul64(a[1], b[1])
+  return {lo, hi + a[1] * b[2] + a[2] * b[1]}
+end
+
+function rt.band128(a, b)
+  return {a[1] & b[1], a[2] & b[2]}
+end
+
+function rt.bor128(a, b)
+  return {a[1] | b[1], a[2] | b[2]}
+end
+
+function rt.bxor128(a, b)
+  return {a[1] ~ b[1], a[2] ~ b[2]}
+end
+
+function rt.bnot128(a)
+  return {~a[1], ~a[2]}
+end
+
+function rt.shl128(a, n)
+  n = n & 127
+  if n == 0 then
+    return a
+  elseif n >= 64 then
+    return {0, a[1] << (n - 64)}
+  end
+  return {a[1] << n, (a[2] << n) | (a[1] >> (64 - n))}
+end
+
+function rt.lshr128(a, n)
+  n = n & 127
+  if n == 0 then
+    return a
+  elseif n >= 64 then
+    return {a[2] >> (n - 64), 0}
+  end
+  return {(a[1] >> n) | (a[2] << (64 - n)), a[2] >> n}
+end
+
+function rt.ashr128(a, n)
+  n = n & 127
+  if n == 0 then
+    return a
+  elseif n >= 64 then
+    return {rt.ashr(a[2], n - 64), sext(a[2])}
+  end
+  return {(a[1] >> n) | (a[2] << (64 - n)), rt.ashr(a[2], n)}
+end
+
+function rt.udivmod128(a, b)
+  if b[1] == 0 and b[2] == 0 then
+    error("attempt to divide by zero")
+  end
+  local q, r = {0, 0}, {0, 0}
+  for i = 127, 0, -1 do
+    r = rt.shl128(r, 1)
+    if i >= 64 then
+      r[1] = r[1] | ((a[2] >> (i - 64)) & 1)
+    else
+      r[1] = r[1] | ((a[1] >> i) & 1)
+    end
+    if not rt.ult128(r, b) then
+      r = rt.sub128(r, b)
+      if i >= 64 then
+        q[2] = q[2] | (1 << (i - 64))
+      else
+        q[1] = q[1] | (1 << i)
+      end
+    end
+  end
+  return q, r
+end
+
+function rt.udiv128(a, b)
+  return (rt.udivmod128(a, b))
+end
+
+function rt.urem128(a, b)
+  local _, r = rt.udivmod128(a, b)
+  return r
+end
+
+local function abs128(a)
+  if a[2] < 0 then
+    return rt.neg128(a)
+  end
+  return a
+end
+
+function rt.sdiv128(a, b)
+  local q = rt.udivmod128(abs128(a), abs128(b))
+  if (a[2] < 0) ~= (b[2] < 0) then
+    return rt.neg128(q)
+  end
+  return q
+end
+
+function rt.srem128(a, b)
+  local _, r = rt.udivmod128(abs128(a), abs128(b))
+  if a[2] < 0 then
+    return rt.neg128(r)
+  end
+  return r
+end
+
+function rt.oadd128(a, b, signed)
+  local r = rt.add128(a, b)
+  if signed then
+    return r, ((a[2] ~ r[2]) & (b[2] ~ r[2])) < 0
+  end
+  return r, rt.ult128(r, a)
+end
+
+function rt.osub128(a, b, signed)
+  local r = rt.sub128(a, b)
+  if signed then
+    return r, ((a[2] ~ b[2]) & (a[2] ~ r[2])) < 0
+  end
+  return r, rt.ult128(a, b)
+end
+
+function rt.omul128(a, b, signed)
+  local r = rt.mul128(a, b)
+  if (a[1] == 0 and a[2] == 0) or (b[1] == 0 and b[2] == 0) then
+    return r, false
+  end
+  if signed then
+    local min = {0, math.mininteger}
+    if (rt.eq128(a, {-1, -1}) and rt.eq128(b, min)) or (rt.eq128(b, {-1, -1}) and rt.eq128(a, min)) then
+      return r, true
+    end
+    return r, not rt.eq128(rt.sdiv128(r, a), b)
+  end
+  return r, not rt.eq128(rt.udiv128(r, a), b)
+end
+
+function rt.ctpop128(a)
+  return rt.ctpop(a[1], 64) + rt.ctpop(a[2], 64)
+end
+
+function rt.ctlz128(a)
+  if a[2] ~= 0 then
+    return rt.ctlz(a[2], 64)
+  end
+  return 64 + rt.ctlz(a[1], 64)
+end
+
+function rt.cttz128(a)
+  if a[1] ~= 0 then
+    return rt.cttz(a[1], 64)
+  end
+  return 64 + rt.cttz(a[2], 64)
+end
+
+function rt.bswap128(a)
+  return {rt.bswap(a[2], 64), rt.bswap(a[1], 64)}
+end
+
+function rt.bitreverse128(a)
+  return {rt.bitreverse(a[2], 64), rt.bitreverse(a[1], 64)}
+end
+
+function rt.rotl128(a, n)
+  n = n & 127
+  if n == 0 then
+    return a
+  end
+  return rt.bor128(rt.shl128(a, n), rt.lshr128(a, 128 - n))
+end
+
+function rt.rotr128(a, n)
+  return rt.rotl128(a, (128 - (n & 127)) & 127)
+end
+
+function rt.sat_add128(a, b, signed)
+  local r, overflow = rt.oadd128(a, b, signed)
+  if not overflow then
+    return r
+  elseif not signed then
+    return {-1, -1}
+  elseif b[2] < 0 then
+    return {0, math.mininteger}
+  end
+  return {-1, math.maxinteger}
+end
+
+function rt.sat_sub128(a, b, signed)
+  local r, overflow = rt.osub128(a, b, signed)
+  if not overflow then
+    return r
+  elseif not signed then
+    return {0, 0}
+  elseif b[2] < 0 then
+    return {-1, math.maxinteger}
+  end
+  return {0, math.mininteger}
+end
+
@@ -0,0 +1,33 @@
+-- Lua libraries
+--
+-- Foreign functions of an `extern` block with `#[link(name = "<lib>")]` are fields of `rt.lib(lib)`.
+-- The library is `_G`, a global table when a global is named after the first component of `lib`,
+-- the next components indexing into it, or otherwise the module `lib` loaded with `require`.
+
+local libs = {}
+
+function rt.lib(name)
+  local lib = libs[name]
+  if lib then
+    return lib
+  end
+  if name == "_G" then
+    lib = _G
+  else
+    local first, rest = name:match("^([^.]*)(.*)$")
+    lib = _G[first]
+    if lib ~= nil then
+      for field in rest:gmatch("[^.]+") do
+        lib = lib[field]
+        if lib == nil then
+          error("lua library " .. name .. " not found", 0)
+        end
+      end
+    else
+      lib = require(name)
+    end
+  end
+  libs[name] = lib
+  return lib
+end
+
@@ -0,0 +1,157 @@
+local rt = {}
+local M, S, D, F = {}, {}, {}, {}
+rt.M, rt.S, rt.D, rt.F = M, S, D, F
+
+local ult = math.ult
+
+local function align_up(v, align)
+  return (v + align - 1) // align * align
+end
+rt.align_up = align_up
+
+-- Allocations
+
+local STACK_BASE = 0x40000000
+local brk = 0x1000
+local free_lists = {}
+rt.sp = STACK_BASE
+
+function rt.alloc(size, align)
+  if size == 0 then
+    return align
+  end
+  local list = free_lists[size]
+  if list then
+    for i = #list, 1, -1 do
+      local p = list[i]
+      if p % align == 0 then
+        table.remove(list, i)
+        return p
+      end
+    end
+  end
+  local p = align_up(brk, align)
+  brk = p + size
+  if brk > STACK_BASE then
+    error("out of memory")
+  end
+  return p
+end
+
+function rt.free(p, size)
+  if size == 0 then
+    return
+  end
+  for i = p, p + size - 1 do
+    M[i] = nil
+  end
+  local list = free_lists[size]
+  if not list then
+    list = {}
+    free_lists[size] = list
+  end
+  list[#list + 1] = p
+end
+
+function rt.alloca(size, align)
+  local p = align_up(rt.sp, align)
+  rt.sp = p + size
+  return p
+end
+
+-- Memory accesses
+
+function rt.load(p, n, signed)
+  local v = 0
+  for i = p + n - 1, p, -1 do
+    v = (v << 8) | (M[i] or 0)
+  end
+  if signed and n < 8 then
+    local sign = 1 << (n * 8 - 1)
+    if v & sign ~= 0 then
+      v = v - (sign << 1)
+    end
+  end
+  return v
+end
+
+function rt.store(p, n, v)
+  for i = p, p + n - 1 do
+    M[i] = v & 0xff
+    v = v >> 8
+  end
+end
+
+local function load_bytes(p, n)
+  local bytes = {}
+  for i = 1, n do
+    bytes[i] = M[p + i - 1] or 0
+  end
+  return string.char(table.unpack(bytes))
+end
+
+local function store_bytes(p, s)
+  for i = 1, #s do
+    M[p + i - 1] = s:byte(i)
+  end
+end
+
+function rt.load_f32(p)
+  return (string.unpack("<f", load_bytes(p, 4)))
+end
+
+function rt.load_f64(p)
+  return (string.unpack("<d", load_bytes(p, 8)))
+end
+
+function rt.store_f32(p, v)
+  store_bytes(p, string.pack("<f", v))
+end
+
+function rt.store_f64(p, v)
+  store_bytes(p, string.pack("<d", v))
+end
+
+function rt.load128(p)
+  return {rt.load(p, 8), rt.load(p + 8, 8)}
+end
+
+function rt.store128(p, v)
+  rt.store(p, 8, v[1])
+  rt.store(p + 8, 8, v[2])
+end
+
+function rt.memcpy(dst, src, n)
+  if dst <= src then
+    for i = 0, n - 1 do
+      M[dst + i] = M[src + i]
+    end
+  else
+    for i = n - 1, 0, -1 do
+      M[dst + i] = M[src + i]
+    end
+  end
+end
+
+function rt.memeq(a, b, n)
+  for i = 0, n - 1 do
+    if (M[a + i] or 0) ~= (M[b + i] or 0) then
+      return false
+    end
+  end
+  return true
+end
+
+function rt.memset(dst, v, n)
+  for i = dst, dst + n - 1 do
+    M[i] = v
+  end
+end
+
+-- Copies the `size` bytes at `src` `count` times after `dst`
+function rt.memrep(dst, src, size, count)
+  for i = 0, count - 1 do
+    rt.memcpy(dst + i * size, src, size)
+  end
+end
+
@@ -0,0 +1,33 @@
+local files = rt.files
+files[0] = nil
+files[1] = {
+  write = function(_, s)
+    vim.api.nvim_out_write(s)
+    return true
+  end,
+  flush = function()
+    return true
+  end,
+}
+files[2] = {
+  write = function(_, s)
+    vim.api.nvim_err_write(s)
+    return true
+  end,
+  flush = function()
+    return true
+  end,
+}
+
+function rt.report(msg)
+  vim.notify(msg, vim.log.levels.ERROR)
+end
+
+function rt.exit(code)
+  return code
+end
+
+S["__lua_exit"] = function(code)
+  rt.run_tls_dtors()
+  error(setmetatable({message = "exited with code " .. code, exit = code}, rt.Panic), 0)
+end
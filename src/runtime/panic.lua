@@ -0,0 +1,161 @@
+-- Panics
+--
+-- Panics and aborts raise a lua error whose value is a `rt.Panic` table with the `message` and,
+-- for panics, the `file`, `line` and `col` of the panic location. Aborts have `abort` set instead.
+-- When panics unwind, std reports the panic before it starts and the error carries the
+-- `payload` of the panic, a `{ptr, vtable}` pair for the `Box<dyn Any + Send>` passed to
+-- `catch_unwind`.
+
+local Panic = {}
+Panic.__index = Panic
+rt.Panic = Panic
+
+function Panic.__tostring(p)
+  if p.abort then
+    return "fatal runtime error: " .. p.message
+  elseif not p.file then
+    return "panicked at '" .. p.message .. "'"
+  end
+  return string.format("panicked at '%s', %s:%d:%d", p.message, p.file, p.line, p.col)
+end
+
+function rt.panic(message, file, line, col)
+  error(setmetatable({message = message, file = file, line = line, col = col}, Panic), 0)
+end
+
+function rt.abort()
+  error(setmetatable({message = "aborted", abort = true}, Panic), 0)
+end
+
+function rt.trap(msg)
+  error(setmetatable({message = msg, abort = true}, Panic), 0)
+end
+
+-- `__rust_start_panic`, `payload` points to the `&mut dyn BoxMeUp` owning the payload
+function rt.start_panic(payload)
+  local data = rt.load(payload, PTR_SIZE)
+  local vtable = rt.load(payload + PTR_SIZE, PTR_SIZE)
+  -- `take_box` is the first method of `BoxMeUp`
+  local take_box = F[rt.load(vtable + 3 * PTR_SIZE, PTR_SIZE)]
+  local ptr, meta = take_box(data)
+  error(setmetatable({message = "Box<dyn Any>", payload = {ptr, meta}}, Panic), 0)
+end
+
+local caught = {}
+
+-- Catches the error `e` raised through the `try` intrinsic, returning the pointer passed to its
+-- catch function. Errors that are not unwinding rust panics are raised again.
+function rt.catch(e)
+  if getmetatable(e) ~= Panic or not e.payload then
+    error(e, 0)
+  end
+  local p = rt.alloc(1, 1)
+  caught[p] = e
+  return p
+end
+
+-- `__rust_panic_cleanup`, returns the payload of the panic caught by `rt.catch`
+function rt.panic_cleanup(p)
+  local e = caught[p]
+  caught[p] = nil
+  rt.free(p, 1)
+  return e.payload[1], e.payload[2]
+end
+
+function rt.load_str(p, n)
+  return load_bytes(p, n)
+end
+
+-- Formatting of panic messages goes through a `&mut dyn core::fmt::Write` whose methods append
+-- to a lua table. The data pointer is a one byte allocation identifying the table.
+local writers = {}
+local writer_vtable
+
+local function get_writer_vtable(fmt_write)
+  if writer_vtable then
+    return writer_vtable
+  end
+  local function write_str(w, p, n)
+    local buf = writers[w]
+    buf[#buf + 1] = load_bytes(p, n)
+    return 0
+  end
+  local function write_char(w, c)
+    local buf = writers[w]
+    buf[#buf + 1] = utf8.char(c)
+    return 0
+  end
+  local function write_fmt(w, args)
+    return fmt_write(w, writer_vtable, args)
+  end
+  -- drop_in_place, size, align, then the methods of the trait in declaration order
+  local entries = {0, 1, 1, write_str, write_char, write_fmt}
+  writer_vtable = rt.alloc(#entries * PTR_SIZE, PTR_SIZE)
+  for i, entry in ipairs(entries) do
+    if type(entry) == "function" then
+      local p = rt.alloc(1, 1)
+      F[p] = entry
+      entry = p
+    end
+    rt.store(writer_vtable + (i - 1) * PTR_SIZE, PTR_SIZE, entry)
+  end
+  return writer_vtable
+end
+
+-- Formats the `fmt::Arguments` at `args` using `core::fmt::write`, passed as `fmt_write`
+function rt.format(fmt_write, args)
+  local buf = {}
+  local w = rt.alloc(1, 1)
+  writers[w] = buf
+  fmt_write(w, get_writer_vtable(fmt_write), args)
+  writers[w] = nil
+  rt.free(w, 1)
+  return table.concat(buf)
+end
+
+-- Reports an error ending the program, hosts without a standard error override it
+function rt.report(msg)
+  if io then
+    io.stderr:write(msg, "\n")
+  end
+end
+
+-- Ends the program with the exit code `code`, `err` being the error that ended it if any. When the
+-- host can't exit, the error is raised again.
+function rt.exit(code, err)
+  if os and os.exit then
+    os.exit(code)
+  end
+  if err ~= nil then
+    error(err, 0)
+  end
+  return code
+end
+
+-- Calls `f`, the entry point of a rust program, returning its exit code. A panic is reported like
+-- the default panic hook of std does and ends the program with code 101.
+function rt.start(f, ...)
+  local ok, res = pcall(f, ...)
+  if ok then
+    rt.run_tls_dtors()
+    return res
+  end
+  local code = 101
+  if getmetatable(res) == Panic then
+    if res.payload then
+      -- Already reported by the panic hook of std
+    elseif res.exit then
+      code = res.exit
+    elseif res.abort then
+      code = 134
+      rt.report(tostring(res))
+    else
+      rt.report("thread 'main' " .. tostring(res) .. "\n" ..
+        "note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace")
+    end
+  else
+    rt.report("thread 'main' panicked at '" .. tostring(res) .. "'")
+  end
+  return rt.exit(code, res)
+end
+
@@ -0,0 +1,84 @@
+-- Data and function symbols
+
+local pending = {}
+local defined = {}
+local fnptrs = {}
+
+-- Registers an allocation, `relocs` is a flat list of `offset, kind, target` triples where `kind`
+-- is "d" for data symbols and "f" for functions. The bytes at `offset` contain the addend.
+function rt.data(name, align, bytes, relocs)
+  if defined[name] then
+    return
+  end
+  defined[name] = true
+  pending[#pending + 1] = {name, align, bytes, relocs}
+end
+
+function rt.fnptr(name)
+  local p = fnptrs[name]
+  if not p then
+    local f = S[name]
+    if not f then
+      error("undefined symbol " .. name)
+    end
+    p = rt.alloc(1, 1)
+    F[p] = f
+    fnptrs[name] = p
+  end
+  return p
+end
+
+-- Gives an address to all the data registered since the last call and resolves their relocations
+function rt.link()
+  local defs = pending
+  pending = {}
+  for _, def in ipairs(defs) do
+    D[def[1]] = rt.alloc(#def[3], def[2])
+  end
+  for _, def in ipairs(defs) do
+    local p, relocs = D[def[1]], def[4]
+    store_bytes(p, def[3])
+    for i = 1, #relocs, 3 do
+      local offset, kind, target = relocs[i], relocs[i + 1], relocs[i + 2]
+      local base
+      if kind == "f" then
+        base = rt.fnptr(target)
+      else
+        base = D[target]
+        if not base then
+          error("undefined symbol " .. target)
+        end
+      end
+      rt.store(p + offset, PTR_SIZE, base + rt.load(p + offset, PTR_SIZE))
+    end
+  end
+end
+
+-- Thread locals
+--
+-- Lua code runs on a single thread, so thread local statics are plain data symbols and only their
+-- destructors need special handling: they run when `main` returns or the program exits with
+-- `std::process::exit`.
+
+local tls_dtors = {}
+
+function rt.register_tls_dtor(dtor, obj)
+  tls_dtors[#tls_dtors + 1] = {dtor, obj}
+end
+
+function rt.run_tls_dtors()
+  -- Destructors may access other thread locals and register new destructors
+  while #tls_dtors > 0 do
+    local dtors = tls_dtors
+    tls_dtors = {}
+    for i = #dtors, 1, -1 do
+      F[dtors[i][1]](dtors[i][2])
+    end
+  end
+end
+
+S["__cxa_thread_atexit_impl"] = function(dtor, obj, _dso_handle)
+  rt.register_tls_dtor(dtor, obj)
+  return 0
+end
+
@@ -0,0 +1,305 @@
+-- System interface
+--
+-- The `lua` platform of std reaches the host through these functions, which use the standard
+-- library of lua when it is available. Failures return a negated error code, the `errno` value
+-- reported by the io library or `EIO` when it didn't give one. Functions producing a string return
+-- its length and keep it until `__lua_result` copies it to memory.
+
+local EIO, EBADF = 5, 9
+local error_messages = {
+  [2] = "No such file or directory",
+  [5] = "Input/output error",
+  [9] = "Bad file descriptor",
+  [13] = "Permission denied",
+  [17] = "File exists",
+}
+local result
+
+local function set_result(s)
+  result = s
+  return #s
+end
+
+local function io_error(msg, code)
+  code = code or EIO
+  if msg then
+    error_messages[code] = msg:gsub("^.-: ", "", 1)
+  end
+  return -code
+end
+
+-- Loads `n` bytes as a string in chunks, as `table.unpack` is limited by the size of the stack
+local function load_string(p, n)
+  local chunks = {}
+  for i = 0, n - 1, 4096 do
+    chunks[#chunks + 1] = load_bytes(p + i, math.min(4096, n - i))
+  end
+  return table.concat(chunks)
+end
+
+-- Open files indexed by their file descriptor, reads from stdin are line buffered
+local files = {}
+local next_fd = 3
+local stdin_buf = ""
+if io then
+  files[0], files[1], files[2] = io.stdin, io.stdout, io.stderr
+end
+rt.files = files
+
+S["__lua_result"] = function(p)
+  store_bytes(p, result)
+  result = nil
+end
+
+S["__lua_alloc"] = function(size, align)
+  return rt.alloc(size, align)
+end
+
+S["__lua_dealloc"] = function(p, size)
+  rt.free(p, size)
+end
+
+S["__lua_argc"] = function()
+  if type(arg) ~= "table" then
+    return 0
+  end
+  return #arg + 1
+end
+
+S["__lua_arg"] = function(i)
+  return set_result(tostring(arg[i]))
+end
+
+S["__lua_getenv"] = function(p, n)
+  local v = os and os.getenv and os.getenv(load_string(p, n))
+  if not v then
+    return -1
+  end
+  return set_result(v)
+end
+
+S["__lua_strerror"] = function(code)
+  local msg = error_messages[code]
+  if not msg then
+    return -1
+  end
+  return set_result(msg)
+end
+
+S["__lua_open"] = function(p, n, mode_p, mode_n)
+  if not io then
+    return -EBADF
+  end
+  local f, msg, code = io.open(load_string(p, n), load_string(mode_p, mode_n))
+  if not f then
+    return io_error(msg, code)
+  end
+  local fd = next_fd
+  next_fd = next_fd + 1
+  files[fd] = f
+  return fd
+end
+
+S["__lua_close"] = function(fd)
+  local f = files[fd]
+  files[fd] = nil
+  if f then
+    f:close()
+  end
+  return 0
+end
+
+S["__lua_read"] = function(fd, p, n)
+  local f = files[fd]
+  if not f then
+    return -EBADF
+  end
+  local s, msg, code
+  if fd == 0 then
+    -- Reading `n` bytes from stdin would block until they are all available
+    if stdin_buf == "" then
+      s, msg, code = f:read("L")
+      stdin_buf = s or ""
+    end
+    s, stdin_buf = stdin_buf:sub(1, n), stdin_buf:sub(n + 1)
+  else
+    s, msg, code = f:read(n)
+  end
+  if msg then
+    return io_error(msg, code)
+  end
+  s = s or ""
+  store_bytes(p, s)
+  return #s
+end
+
+S["__lua_write"] = function(fd, p, n)
+  local f = files[fd]
+  if not f then
+    return -EBADF
+  end
+  local ok, msg, code = f:write(load_string(p, n))
+  if not ok then
+    return io_error(msg, code)
+  end
+  return n
+end
+
+S["__lua_flush"] = function(fd)
+  local f = files[fd]
+  if not f then
+    return -EBADF
+  end
+  local ok, msg, code = f:flush()
+  if not ok then
+    return io_error(msg, code)
+  end
+  return 0
+end
+
+local whences = {[0] = "set", "cur", "end"}
+
+S["__lua_seek"] = function(fd, whence, offset)
+  local f = files[fd]
+  if not f then
+    return -EBADF
+  end
+  local pos, msg, code = f:seek(whences[whence], offset)
+  if not pos then
+    return io_error(msg, code)
+  end
+  return pos
+end
+
+S["__lua_remove"] = function(p, n)
+  if not (os and os.remove) then
+    return -EBADF
+  end
+  local ok, msg, code = os.remove(load_string(p, n))
+  if not ok then
+    return io_error(msg, code)
+  end
+  return 0
+end
+
+S["__lua_rename"] = function(from_p, from_n, to_p, to_n)
+  if not (os and os.rename) then
+    return -EBADF
+  end
+  local ok, msg, code = os.rename(load_string(from_p, from_n), load_string(to_p, to_n))
+  if not ok then
+    return io_error(msg, code)
+  end
+  return 0
+end
+
+S["__lua_clock"] = function()
+  return os.clock() + 0.0
+end
+
+S["__lua_time"] = function()
+  return math.floor(os.time())
+end
+
+-- Like `exit` of the C library, `std::process::exit` runs the destructors of the thread locals
+S["__lua_exit"] = function(code)
+  rt.run_tls_dtors()
+  if os and os.exit then
+    os.exit(code)
+  end
+  error(setmetatable({message = "exited with code " .. code, exit = code}, Panic), 0)
+end
+
+-- The functions of the C math library used by std, f32 results are rounded by the `f` variants
+
+local exp, log = math.exp, math.log
+
+local function expm1(x)
+  if math.abs(x) < 1e-5 then
+    return x + x * x / 2 + x * x * x / 6
+  end
+  return exp(x) - 1
+end
+
+local function log1p(x)
+  if math.abs(x) < 1e-4 then
+    return x - x * x / 2 + x * x * x / 3
+  end
+  return log(1 + x)
+end
+
+local function cbrt(x)
+  if x == 0 or x ~= x or x == huge or x == -huge then
+    return x
+  end
+  local a = math.abs(x)
+  local y = a ^ (1 / 3)
+  y = y - (y * y * y - a) / (3 * y * y)
+  if x < 0 then
+    return -y
+  end
+  return y
+end
+
+local function hypot(a, b)
+  a, b = math.abs(a), math.abs(b)
+  if a == huge or b == huge then
+    return huge
+  end
+  if a < b then
+    a, b = b, a
+  end
+  if a == 0 or a ~= a or b ~= b then
+    return a + b
+  end
+  local r = b / a
+  return a * math.sqrt(1 + r * r)
+end
+
+local function fdim(a, b)
+  if a ~= a or b ~= b then
+    return a + b
+  end
+  return math.max(a - b, 0.0)
+end
+
+local cmath = {
+  acos = math.acos,
+  asin = math.asin,
+  atan = math.atan,
+  atan2 = function(y, x)
+    return math.atan(y, x)
+  end,
+  cbrt = cbrt,
+  cosh = function(x)
+    return (exp(x) + exp(-x)) / 2
+  end,
+  expm1 = expm1,
+  fdim = fdim,
+  hypot = hypot,
+  log1p = log1p,
+  sinh = function(x)
+    if math.abs(x) < 1e-5 then
+      return x + x * x * x / 6
+    end
+    return (exp(x) - exp(-x)) / 2
+  end,
+  tan = math.tan,
+  tanh = function(x)
+    if x > 20 then
+      return 1.0
+    elseif x < -20 then
+      return -1.0
+    end
+    local e = expm1(2 * x)
+    return e / (e + 2)
+  end,
+}
+
+for name, f in pairs(cmath) do
+  S[name] = f
+  S[name .. "f"] = function(...)
+    return rt.fround(f(...))
+  end
+end
+
@@ -0,0 +1,63 @@
+local utf8 = utf8 or (function()
+  -- Decodes the character at `i`, returning its code point and the index of the next one
+  local function decode(s, i)
+    local c = s:byte(i)
+    if c < 0x80 then
+      return c, i + 1
+    end
+    local n = c >= 0xf0 and 3 or c >= 0xe0 and 2 or c >= 0xc0 and 1
+    if not n or c > 0xf4 then
+      return nil
+    end
+    local code = c & (0x3f >> n)
+    for j = i + 1, i + n do
+      local b = s:byte(j)
+      if not b or b & 0xc0 ~= 0x80 then
+        return nil
+      end
+      code = code << 6 | b & 0x3f
+    end
+    return code, i + n + 1
+  end
+
+  local lib = {}
+
+  function lib.char(...)
+    local chars = {}
+    for i, c in ipairs({...}) do
+      if c < 0x80 then
+        chars[i] = string.char(c)
+      elseif c < 0x800 then
+        chars[i] = string.char(0xc0 | c >> 6, 0x80 | c & 0x3f)
+      elseif c < 0x10000 then
+        chars[i] = string.char(0xe0 | c >> 12, 0x80 | c >> 6 & 0x3f, 0x80 | c & 0x3f)
+      else
+        chars[i] = string.char(0xf0 | c >> 18, 0x80 | c >> 12 & 0x3f, 0x80 | c >> 6 & 0x3f,
+          0x80 | c & 0x3f)
+      end
+    end
+    return table.concat(chars)
+  end
+
+  function lib.len(s)
+    local i, n = 1, 0
+    while i <= #s do
+      local code, next = decode(s, i)
+      if not code then
+        return nil, i
+      end
+      i, n = next, n + 1
+    end
+    return n
+  end
+
+  function lib.codepoint(s, i)
+    local code = decode(s, i or 1)
+    if not code then
+      error("invalid UTF-8 code", 2)
+    end
+    return code
+  end
+
+  return lib
+end)()
@@ -0,0 +1,88 @@
+-- Lua values
+--
+-- Rust code refers to lua values through handles indexing `rt.V`, the handle 0 being nil. The
+-- `lua` crate owns the handles and releases them with `rt.unref`.
+
+local V = {}
+rt.V = V
+local free_handles = {}
+local next_handle = 1
+
+function rt.ref(v)
+  if v == nil then
+    return 0
+  end
+  local h = table.remove(free_handles)
+  if not h then
+    h = next_handle
+    next_handle = h + 1
+  end
+  V[h] = v
+  return h
+end
+
+function rt.unref(h)
+  if h ~= 0 then
+    V[h] = nil
+    free_handles[#free_handles + 1] = h
+  end
+end
+
+local type_tags = {
+  ["nil"] = 0,
+  boolean = 1,
+  number = 2,
+  string = 4,
+  table = 5,
+  ["function"] = 6,
+  userdata = 7,
+  thread = 8,
+}
+
+-- The `lua::LuaType` of `v`, floats being 3
+function rt.type_tag(v)
+  if math.type(v) == "float" then
+    return 3
+  end
+  return type_tags[type(v)]
+end
+
+-- Calls `f` with the `n` values whose handles are at `args`, returning a handle to the
+-- `table.pack` of the results
+function rt.call(f, args, n)
+  local values = {}
+  for i = 1, n do
+    values[i] = V[rt.load(args + (i - 1) * PTR_SIZE, PTR_SIZE)]
+  end
+  return rt.ref(table.pack(f(table.unpack(values, 1, n))))
+end
+
+function rt.store_str(p, s)
+  store_bytes(p, s)
+end
+
+-- A lua function calling the rust function pointer `trampoline` with `data` and the handles of its
+-- arguments, the trampoline returning the handle of the result
+function rt.lua_function(trampoline, data)
+  local f = F[trampoline]
+  return function(...)
+    local n = select("#", ...)
+    local sp = rt.sp
+    local args = rt.alloca(n * PTR_SIZE, PTR_SIZE)
+    for i = 1, n do
+      rt.store(args + (i - 1) * PTR_SIZE, PTR_SIZE, rt.ref((select(i, ...))))
+    end
+    local ok, res = pcall(f, data, args, n)
+    for i = 1, n do
+      rt.unref(rt.load(args + (i - 1) * PTR_SIZE, PTR_SIZE))
+    end
+    rt.sp = sp
+    if not ok then
+      error(res, 0)
+    end
+    local v = V[res]
+    rt.unref(res)
+    return v
+  end
+end
+
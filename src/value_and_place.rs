@@ -0,0 +1,557 @@
+//! Definition of [`CValue`] and [`CPlace`]
+
+use rustc_target::abi::Primitive;
+
+use crate::prelude::*;
+
+/// Lua code reading a value of type `primitive` from memory at `ptr`.
+pub(crate) fn load_primitive(fx: &FunctionCx<'_, '_>, ptr: Value, primitive: Primitive) -> Value {
+    match primitive {
+        Primitive::Int(integer, _) if integer.size().bits() == 128 => rt_call("load128", [ptr]),
+        Primitive::Int(integer, signed) => rt_call(
+            "load",
+            [
+                ptr,
+                int(integer.size().bytes() as i64),
+                ExprBuilder.bool(signed),
+            ],
+        ),
+        Primitive::F32 => rt_call("load_f32", [ptr]),
+        Primitive::F64 => rt_call("load_f64", [ptr]),
+        Primitive::Pointer => rt_call(
+            "load",
+            [ptr, int(fx.pointer_size() as i64), ExprBuilder.bool(false)],
+        ),
+    }
+}
+
+/// Writes `value`, of type `primitive`, to memory at `ptr`.
+pub(crate) fn store_primitive(
+    fx: &mut FunctionCx<'_, '_>,
+    ptr: Value,
+    primitive: Primitive,
+    value: Value,
+) {
+    let (function, args) = match primitive {
+        Primitive::Int(integer, _) if integer.size().bits() == 128 => {
+            ("store128", vec![ptr, value])
+        }
+        Primitive::Int(integer, _) => (
+            "store",
+            vec![ptr, int(integer.size().bytes() as i64), value],
+        ),
+        Primitive::F32 => ("store_f32", vec![ptr, value]),
+        Primitive::F64 => ("store_f64", vec![ptr, value]),
+        Primitive::Pointer => ("store", vec![ptr, int(fx.pointer_size() as i64), value]),
+    };
+    fx.cx.ctx.stat().call(rt_field(function), args);
+}
+
+pub(crate) fn scalar_pair_calculate_b_offset(
+    tcx: TyCtxt<'_>,
+    a_scalar: &Scalar,
+    b_scalar: &Scalar,
+) -> Size {
+    a_scalar
+        .value
+        .size(&tcx)
+        .align_to(b_scalar.value.align(&tcx).abi)
+}
+
+fn codegen_field<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    base: Value,
+    extra: Option<Value>,
+    layout: TyAndLayout<'tcx>,
+    field: mir::Field,
+) -> (Value, TyAndLayout<'tcx>) {
+    let field_offset = layout.fields.offset(field.index());
+    let field_layout = layout.field(&*fx, field.index());
+
+    let simple = |_fx: &mut FunctionCx<'_, '_>| {
+        (
+            ptr_offset(base.clone(), field_offset.bytes() as i64),
+            field_layout,
+        )
+    };
+
+    if let Some(extra) = extra {
+        if !field_layout.is_unsized() {
+            return simple(fx);
+        }
+        match field_layout.ty.kind() {
+            ty::Slice(..) | ty::Str | ty::Foreign(..) => simple(fx),
+            ty::Adt(def, _) if def.repr.packed() => {
+                assert_eq!(layout.align.abi.bytes(), 1);
+                simple(fx)
+            }
+            _ => {
+                // We have to align the offset for DST's
+                let unaligned_offset = field_offset.bytes();
+                let (_, unsized_align) =
+                    crate::unsize::size_and_align_of_dst(fx, field_layout, extra);
+
+                let offset = rt_call("align_up", [int(unaligned_offset as i64), unsized_align]);
+                (binop(LuaBinOp::Add, base, offset), field_layout)
+            }
+        }
+    } else {
+        simple(fx)
+    }
+}
+
+/// A read-only value
+#[derive(Debug, Clone)]
+pub(crate) struct CValue<'tcx>(CValueInner, TyAndLayout<'tcx>);
+
+#[derive(Debug, Clone)]
+enum CValueInner {
+    ByRef(Value, Option<Value>),
+    ByVal(Value),
+    ByValPair(Value, Value),
+}
+
+impl<'tcx> CValue<'tcx> {
+    pub(crate) fn by_ref(ptr: Value, layout: TyAndLayout<'tcx>) -> CValue<'tcx> {
+        CValue(CValueInner::ByRef(ptr, None), layout)
+    }
+
+    pub(crate) fn by_ref_unsized(
+        ptr: Value,
+        meta: Value,
+        layout: TyAndLayout<'tcx>,
+    ) -> CValue<'tcx> {
+        CValue(CValueInner::ByRef(ptr, Some(meta)), layout)
+    }
+
+    pub(crate) fn by_val(value: Value, layout: TyAndLayout<'tcx>) -> CValue<'tcx> {
+        CValue(CValueInner::ByVal(value), layout)
+    }
+
+    pub(crate) fn by_val_pair(
+        value: Value,
+        extra: Value,
+        layout: TyAndLayout<'tcx>,
+    ) -> CValue<'tcx> {
+        CValue(CValueInner::ByValPair(value, extra), layout)
+    }
+
+    /// A value of a zero sized type, which doesn't need to be backed by anything.
+    pub(crate) fn zst(layout: TyAndLayout<'tcx>) -> CValue<'tcx> {
+        assert!(layout.is_zst());
+        CValue::by_ref(int(layout.align.pref.bytes() as i64), layout)
+    }
+
+    pub(crate) fn layout(&self) -> TyAndLayout<'tcx> {
+        self.1
+    }
+
+    /// Returns the address of the value, spilling it to the stack if necessary.
+    pub(crate) fn force_stack(self, fx: &mut FunctionCx<'_, 'tcx>) -> (Value, Option<Value>) {
+        let layout = self.1;
+        match self.0 {
+            CValueInner::ByRef(ptr, meta) => (ptr, meta),
+            CValueInner::ByVal(_) | CValueInner::ByValPair(_, _) => {
+                let cplace = CPlace::new_stack_slot(fx, layout);
+                cplace.write_cvalue(fx, self);
+                (cplace.to_ptr(), None)
+            }
+        }
+    }
+
+    pub(crate) fn try_to_ptr(&self) -> Option<(Value, Option<Value>)> {
+        match &self.0 {
+            CValueInner::ByRef(ptr, meta) => Some((ptr.clone(), meta.clone())),
+            CValueInner::ByVal(_) | CValueInner::ByValPair(_, _) => None,
+        }
+    }
+
+    /// Load a value with layout.abi of scalar
+    pub(crate) fn load_scalar(self, fx: &mut FunctionCx<'_, 'tcx>) -> Value {
+        let layout = self.1;
+        match self.0 {
+            CValueInner::ByRef(ptr, None) => {
+                let primitive = match layout.abi {
+                    Abi::Scalar(ref scalar) => scalar.value,
+                    _ => unreachable!("{:?}", layout.ty),
+                };
+                load_primitive(fx, ptr, primitive)
+            }
+            CValueInner::ByVal(value) => value,
+            CValueInner::ByRef(_, Some(_)) => bug!("load_scalar for unsized value not allowed"),
+            CValueInner::ByValPair(_, _) => bug!("Please use load_scalar_pair for ByValPair"),
+        }
+    }
+
+    /// Load a value pair with layout.abi of scalar pair
+    pub(crate) fn load_scalar_pair(self, fx: &mut FunctionCx<'_, 'tcx>) -> (Value, Value) {
+        let layout = self.1;
+        match self.0 {
+            CValueInner::ByRef(ptr, None) => {
+                let (a_scalar, b_scalar) = match &layout.abi {
+                    Abi::ScalarPair(a, b) => (a, b),
+                    _ => unreachable!("load_scalar_pair({:?})", layout.ty),
+                };
+                let b_offset = scalar_pair_calculate_b_offset(fx.tcx, a_scalar, b_scalar);
+                let ptr = fx.tmp(ptr);
+                let val1 = load_primitive(fx, ptr.clone(), a_scalar.value);
+                let val2 =
+                    load_primitive(fx, ptr_offset(ptr, b_offset.bytes() as i64), b_scalar.value);
+                (val1, val2)
+            }
+            CValueInner::ByRef(_, Some(_)) => {
+                bug!("load_scalar_pair for unsized value not allowed")
+            }
+            CValueInner::ByVal(_) => bug!("Please use load_scalar for ByVal"),
+            CValueInner::ByValPair(val1, val2) => (val1, val2),
+        }
+    }
+
+    pub(crate) fn value_field(
+        self,
+        fx: &mut FunctionCx<'_, 'tcx>,
+        field: mir::Field,
+    ) -> CValue<'tcx> {
+        let layout = self.1;
+        match self.0 {
+            CValueInner::ByVal(_) | CValueInner::ByValPair(_, _)
+                if layout.field(&*fx, field.index()).is_zst() =>
+            {
+                CValue::zst(layout.field(&*fx, field.index()))
+            }
+            CValueInner::ByVal(val) => {
+                // Only newtypes of a scalar can be fields of a scalar
+                let field_layout = layout.field(&*fx, field.index());
+                assert_eq!(layout.size, field_layout.size);
+                CValue::by_val(val, field_layout)
+            }
+            CValueInner::ByValPair(val1, val2) => {
+                let field_layout = layout.field(&*fx, field.index());
+                if field_layout.size == layout.size {
+                    return CValue::by_val_pair(val1, val2, field_layout);
+                }
+                match field.as_u32() {
+                    0 => CValue::by_val(val1, field_layout),
+                    1 => CValue::by_val(val2, field_layout),
+                    _ => bug!("field should be 0 or 1"),
+                }
+            }
+            CValueInner::ByRef(ptr, None) => {
+                let (field_ptr, field_layout) = codegen_field(fx, ptr, None, layout, field);
+                CValue::by_ref(field_ptr, field_layout)
+            }
+            CValueInner::ByRef(ptr, Some(meta)) => {
+                // The last field of an unsized value keeps its metadata
+                let (field_ptr, field_layout) =
+                    codegen_field(fx, ptr, Some(meta.clone()), layout, field);
+                if field_layout.is_unsized() {
+                    CValue::by_ref_unsized(field_ptr, meta, field_layout)
+                } else {
+                    CValue::by_ref(field_ptr, field_layout)
+                }
+            }
+        }
+    }
+
+    /// Reinterprets the value as having type `ty`, which must have the same size.
+    pub(crate) fn transmute(self, layout: TyAndLayout<'tcx>) -> CValue<'tcx> {
+        CValue(self.0, layout)
+    }
+}
+
+/// A place where you can write a value to or read a value from
+#[derive(Debug, Clone)]
+pub(crate) struct CPlace<'tcx> {
+    inner: CPlaceInner,
+    layout: TyAndLayout<'tcx>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum CPlaceInner {
+    Var(Local, LuaPlace),
+    VarPair(Local, LuaPlace, LuaPlace),
+    Addr(Value, Option<Value>),
+}
+
+impl<'tcx> CPlace<'tcx> {
+    pub(crate) fn layout(&self) -> TyAndLayout<'tcx> {
+        self.layout
+    }
+
+    pub(crate) fn inner(&self) -> &CPlaceInner {
+        &self.inner
+    }
+
+    pub(crate) fn no_place(layout: TyAndLayout<'tcx>) -> CPlace<'tcx> {
+        CPlace {
+            inner: CPlaceInner::Addr(int(layout.align.pref.bytes() as i64), None),
+            layout,
+        }
+    }
+
+    pub(crate) fn new_stack_slot(
+        fx: &mut FunctionCx<'_, 'tcx>,
+        layout: TyAndLayout<'tcx>,
+    ) -> CPlace<'tcx> {
+        assert!(!layout.is_unsized());
+        if layout.size.bytes() == 0 {
+            return CPlace::no_place(layout);
+        }
+
+        let ptr = fx.stack_slot(layout.size.bytes(), layout.align.abi.bytes());
+        CPlace::for_ptr(ptr, layout)
+    }
+
+    pub(crate) fn new_var(
+        fx: &mut FunctionCx<'_, 'tcx>,
+        local: Local,
+        layout: TyAndLayout<'tcx>,
+    ) -> CPlace<'tcx> {
+        let var = fx.new_var(format!("l{}", local.as_u32()));
+        CPlace {
+            inner: CPlaceInner::Var(local, var),
+            layout,
+        }
+    }
+
+    pub(crate) fn new_var_pair(
+        fx: &mut FunctionCx<'_, 'tcx>,
+        local: Local,
+        layout: TyAndLayout<'tcx>,
+    ) -> CPlace<'tcx> {
+        let var1 = fx.new_var(format!("l{}_0", local.as_u32()));
+        let var2 = fx.new_var(format!("l{}_1", local.as_u32()));
+        CPlace {
+            inner: CPlaceInner::VarPair(local, var1, var2),
+            layout,
+        }
+    }
+
+    pub(crate) fn for_ptr(ptr: Value, layout: TyAndLayout<'tcx>) -> CPlace<'tcx> {
+        CPlace {
+            inner: CPlaceInner::Addr(ptr, None),
+            layout,
+        }
+    }
+
+    pub(crate) fn for_ptr_with_extra(
+        ptr: Value,
+        extra: Value,
+        layout: TyAndLayout<'tcx>,
+    ) -> CPlace<'tcx> {
+        CPlace {
+            inner: CPlaceInner::Addr(ptr, Some(extra)),
+            layout,
+        }
+    }
+
+    pub(crate) fn to_cvalue(&self, _fx: &mut FunctionCx<'_, 'tcx>) -> CValue<'tcx> {
+        let layout = self.layout();
+        match &self.inner {
+            CPlaceInner::Var(_local, var) => {
+                CValue::by_val(ExprBuilder.get_place(var.clone()), layout)
+            }
+            CPlaceInner::VarPair(_local, var1, var2) => CValue::by_val_pair(
+                ExprBuilder.get_place(var1.clone()),
+                ExprBuilder.get_place(var2.clone()),
+                layout,
+            ),
+            CPlaceInner::Addr(ptr, extra) => {
+                if let Some(extra) = extra {
+                    CValue::by_ref_unsized(ptr.clone(), extra.clone(), layout)
+                } else {
+                    CValue::by_ref(ptr.clone(), layout)
+                }
+            }
+        }
+    }
+
+    pub(crate) fn to_ptr(&self) -> Value {
+        match self.to_ptr_maybe_unsized() {
+            (ptr, None) => ptr,
+            (_, Some(_)) => bug!("Expected sized cplace, found {:?}", self),
+        }
+    }
+
+    pub(crate) fn to_ptr_maybe_unsized(&self) -> (Value, Option<Value>) {
+        match &self.inner {
+            CPlaceInner::Addr(ptr, extra) => (ptr.clone(), extra.clone()),
+            CPlaceInner::Var(_, _) | CPlaceInner::VarPair(_, _, _) => {
+                bug!("Expected CPlace::Addr, found {:?}", self)
+            }
+        }
+    }
+
+    pub(crate) fn write_cvalue(self, fx: &mut FunctionCx<'_, 'tcx>, from: CValue<'tcx>) {
+        let dst_layout = self.layout();
+        match self.inner {
+            CPlaceInner::Var(_local, var) => {
+                let data = from.load_scalar(fx);
+                fx.cx.ctx.stat().assign(var, data);
+            }
+            CPlaceInner::VarPair(_local, var1, var2) => {
+                let (data1, data2) = from.load_scalar_pair(fx);
+                fx.cx
+                    .ctx
+                    .stat()
+                    .assign_multi(vec![var1, var2], vec![data1, data2]);
+            }
+            CPlaceInner::Addr(_, Some(_)) => bug!("Can't write value with incompatible type"),
+            CPlaceInner::Addr(to_ptr, None) => {
+                if dst_layout.size.bytes() == 0 || from.layout().size.bytes() == 0 {
+                    return;
+                }
+
+                let from_layout = from.layout();
+                match from.0 {
+                    CValueInner::ByVal(val) => {
+                        let primitive = match (&from_layout.abi, &dst_layout.abi) {
+                            (Abi::Scalar(scalar), _) | (_, Abi::Scalar(scalar)) => scalar.value,
+                            _ => bug!("writing scalar to {:?}", dst_layout.ty),
+                        };
+                        store_primitive(fx, to_ptr, primitive, val);
+                    }
+                    CValueInner::ByValPair(val1, val2) => {
+                        let (a_scalar, b_scalar) = match (&from_layout.abi, &dst_layout.abi) {
+                            (Abi::ScalarPair(a, b), _) | (_, Abi::ScalarPair(a, b)) => (a, b),
+                            _ => bug!("writing scalar pair to {:?}", dst_layout.ty),
+                        };
+                        let b_offset = scalar_pair_calculate_b_offset(fx.tcx, a_scalar, b_scalar);
+                        let to_ptr = fx.tmp(to_ptr);
+                        store_primitive(fx, to_ptr.clone(), a_scalar.value, val1);
+                        store_primitive(
+                            fx,
+                            ptr_offset(to_ptr, b_offset.bytes() as i64),
+                            b_scalar.value,
+                            val2,
+                        );
+                    }
+                    CValueInner::ByRef(from_ptr, None) => {
+                        let size = dst_layout.size.bytes().min(from_layout.size.bytes());
+                        fx.cx
+                            .ctx
+                            .stat()
+                            .call(rt_field("memcpy"), [to_ptr, from_ptr, int(size as i64)]);
+                    }
+                    CValueInner::ByRef(_, Some(_)) => {
+                        bug!("Non sized values are not supported")
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn place_field(
+        self,
+        fx: &mut FunctionCx<'_, 'tcx>,
+        field: mir::Field,
+    ) -> CPlace<'tcx> {
+        let layout = self.layout();
+
+        match self.inner {
+            CPlaceInner::Var(..) | CPlaceInner::VarPair(..)
+                if layout.field(&*fx, field.index()).is_zst() =>
+            {
+                return CPlace::no_place(layout.field(&*fx, field.index()));
+            }
+            CPlaceInner::Var(local, var) => {
+                let field_layout = layout.field(&*fx, field.index());
+                assert_eq!(field_layout.size, layout.size);
+                return CPlace {
+                    inner: CPlaceInner::Var(local, var),
+                    layout: field_layout,
+                };
+            }
+            CPlaceInner::VarPair(local, var1, var2) => {
+                let layout = layout.field(&*fx, field.index());
+
+                match field.as_u32() {
+                    0 => {
+                        return CPlace {
+                            inner: CPlaceInner::Var(local, var1),
+                            layout,
+                        }
+                    }
+                    1 => {
+                        return CPlace {
+                            inner: CPlaceInner::Var(local, var2),
+                            layout,
+                        }
+                    }
+                    _ => unreachable!("field should be 0 or 1"),
+                }
+            }
+            _ => {}
+        }
+
+        let (base, extra) = self.to_ptr_maybe_unsized();
+
+        let (field_ptr, field_layout) = codegen_field(fx, base, extra.clone(), layout, field);
+        if field_layout.is_unsized() {
+            CPlace::for_ptr_with_extra(field_ptr, extra.unwrap(), field_layout)
+        } else {
+            CPlace::for_ptr(field_ptr, field_layout)
+        }
+    }
+
+    pub(crate) fn place_index(self, fx: &mut FunctionCx<'_, 'tcx>, index: Value) -> CPlace<'tcx> {
+        let (elem_layout, ptr) = match self.layout().ty.kind() {
+            ty::Array(elem_ty, _) => (fx.layout_of(elem_ty), self.to_ptr()),
+            ty::Slice(elem_ty) => (fx.layout_of(elem_ty), self.to_ptr_maybe_unsized().0),
+            _ => bug!("place_index({:?})", self.layout().ty),
+        };
+
+        let elem_size = elem_layout.size.bytes() as i64;
+        let offset = match index.as_int() {
+            Some(index) => int(index.wrapping_mul(elem_size)),
+            None if elem_size == 1 => index,
+            None => binop(LuaBinOp::Mul, index, int(elem_size)),
+        };
+        let ptr = match offset.as_int() {
+            Some(offset) => ptr_offset(ptr, offset),
+            None => binop(LuaBinOp::Add, ptr, offset),
+        };
+
+        CPlace::for_ptr(ptr, elem_layout)
+    }
+
+    pub(crate) fn place_deref(self, fx: &mut FunctionCx<'_, 'tcx>) -> CPlace<'tcx> {
+        let inner_layout = fx.layout_of(self.layout().ty.builtin_deref(true).unwrap().ty);
+        if has_ptr_meta(fx.tcx, inner_layout.ty) {
+            let (addr, extra) = self.to_cvalue(fx).load_scalar_pair(fx);
+            let addr = fx.tmp(addr);
+            let extra = fx.tmp(extra);
+            CPlace::for_ptr_with_extra(addr, extra, inner_layout)
+        } else {
+            let addr = self.to_cvalue(fx).load_scalar(fx);
+            let addr = fx.tmp(addr);
+            CPlace::for_ptr(addr, inner_layout)
+        }
+    }
+
+    pub(crate) fn place_ref(
+        self,
+        fx: &mut FunctionCx<'_, 'tcx>,
+        layout: TyAndLayout<'tcx>,
+    ) -> CValue<'tcx> {
+        if has_ptr_meta(fx.tcx, self.layout().ty) {
+            let (ptr, extra) = self.to_ptr_maybe_unsized();
+            CValue::by_val_pair(ptr, extra.expect("unsized type without metadata"), layout)
+        } else {
+            CValue::by_val(self.to_ptr(), layout)
+        }
+    }
+
+    pub(crate) fn downcast_variant(
+        self,
+        fx: &FunctionCx<'_, 'tcx>,
+        variant: VariantIdx,
+    ) -> CPlace<'tcx> {
+        assert!(!self.layout().is_unsized());
+        let layout = self.layout().for_variant(fx, variant);
+        CPlace {
+            inner: self.inner,
+            layout,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Codegen vtables and vtable accesses.
+//!
+//! See `rustc_codegen_ssa/src/meth.rs` for reference.
+
+use crate::prelude::*;
+
+const DROP_FN_INDEX: usize = 0;
+const SIZE_INDEX: usize = 1;
+const ALIGN_INDEX: usize = 2;
+
+fn load_vtable_entry(fx: &mut FunctionCx<'_, '_>, vtable: Value, index: usize) -> Value {
+    let ptr_size = fx.pointer_size();
+    let entry = ptr_offset(vtable, (index as u64 * ptr_size) as i64);
+    let load = rt_call(
+        "load",
+        [entry, int(ptr_size as i64), ExprBuilder.bool(false)],
+    );
+    fx.tmp(load)
+}
+
+pub(crate) fn drop_fn_of_obj(fx: &mut FunctionCx<'_, '_>, vtable: Value) -> Value {
+    load_vtable_entry(fx, vtable, DROP_FN_INDEX)
+}
+
+pub(crate) fn size_of_obj(fx: &mut FunctionCx<'_, '_>, vtable: Value) -> Value {
+    load_vtable_entry(fx, vtable, SIZE_INDEX)
+}
+
+pub(crate) fn min_align_of_obj(fx: &mut FunctionCx<'_, '_>, vtable: Value) -> Value {
+    load_vtable_entry(fx, vtable, ALIGN_INDEX)
+}
+
+/// The data pointer of the trait object `arg` and the address of its method `idx`.
+pub(crate) fn get_ptr_and_method_ref<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    arg: CValue<'tcx>,
+    idx: usize,
+) -> (Value, Value) {
+    let (ptr, vtable) = if let Abi::ScalarPair(_, _) = arg.layout().abi {
+        arg.load_scalar_pair(fx)
+    } else {
+        let (ptr, vtable) = arg.try_to_ptr().unwrap();
+        (ptr, vtable.unwrap())
+    };
+
+    let ptr = fx.tmp(ptr);
+    let func_ref = load_vtable_entry(fx, vtable, idx);
+    (ptr, func_ref)
+}
+
+pub(crate) fn get_vtable<'tcx>(
+    fx: &mut FunctionCx<'_, 'tcx>,
+    ty: Ty<'tcx>,
+    trait_ref: Option<ty::PolyExistentialTraitRef<'tcx>>,
+) -> Value {
+    let alloc_id = fx.tcx.vtable_allocation(ty, trait_ref);
+    fx.cx.constants_cx.data_ref(fx.tcx, alloc_id)
+}
@@ -0,0 +1,26 @@
+//! Allocations use the allocator of the runtime of cg_lua. Freed memory is cleared, so all
+//! allocations are zeroed.
+
+use super::host;
+use crate::alloc::{GlobalAlloc, Layout, System};
+
+#[stable(feature = "alloc_system_type", since = "1.28.0")]
+unsafe impl GlobalAlloc for System {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // SAFETY: the caller guarantees that the layout has a non zero size
+        unsafe { host::__lua_alloc(layout.size(), layout.align()) }
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // SAFETY: same as `alloc`
+        unsafe { self.alloc(layout) }
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: the caller guarantees that `ptr` was allocated with `layout`
+        unsafe { host::__lua_dealloc(ptr, layout.size()) }
+    }
+}
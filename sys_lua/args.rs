@@ -0,0 +1,58 @@
+//! The arguments are the `arg` table of the standalone lua interpreter, `arg[0]` being the script.
+
+use super::host;
+use crate::ffi::OsString;
+use crate::fmt;
+use crate::sys::os_str::Buf;
+use crate::sys_common::FromInner;
+use crate::vec;
+
+pub struct Args {
+    iter: vec::IntoIter<OsString>,
+}
+
+pub fn args() -> Args {
+    // SAFETY: the runtime functions have no preconditions
+    let argc = unsafe { host::__lua_argc() };
+    let args = (0..argc)
+        .map(|i| {
+            let len = unsafe { host::__lua_arg(i) };
+            OsString::from_inner(Buf { inner: host::take_result(len as usize) })
+        })
+        .collect::<Vec<_>>();
+    Args { iter: args.into_iter() }
+}
+
+impl Args {
+    pub fn inner_debug(&self) -> &[OsString] {
+        self.iter.as_slice()
+    }
+}
+
+impl fmt::Debug for Args {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.iter.as_slice().fmt(f)
+    }
+}
+
+impl Iterator for Args {
+    type Item = OsString;
+    fn next(&mut self) -> Option<OsString> {
+        self.iter.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Args {
+    fn len(&self) -> usize {
+        self.iter.len()
+    }
+}
+
+impl DoubleEndedIterator for Args {
+    fn next_back(&mut self) -> Option<OsString> {
+        self.iter.next_back()
+    }
+}
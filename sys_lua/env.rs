@@ -0,0 +1,9 @@
+pub mod os {
+    pub const FAMILY: &str = "";
+    pub const OS: &str = "lua";
+    pub const DLL_PREFIX: &str = "";
+    pub const DLL_SUFFIX: &str = ".lua";
+    pub const DLL_EXTENSION: &str = "lua";
+    pub const EXE_SUFFIX: &str = ".lua";
+    pub const EXE_EXTENSION: &str = "lua";
+}
@@ -0,0 +1,372 @@
+//! Files are opened with `io.open`, which gives neither metadata nor directories. A file is assumed
+//! to be a regular file and its size is found by seeking to its end.
+
+use super::host;
+use super::unsupported;
+use crate::ffi::OsString;
+use crate::fmt;
+use crate::io::{self, IoSlice, IoSliceMut, SeekFrom};
+use crate::path::{Path, PathBuf};
+use crate::sys::time::SystemTime;
+
+#[path = "../unsupported/fs.rs"]
+mod unsupported_fs;
+pub use unsupported_fs::*;
+
+pub struct File {
+    fd: i32,
+}
+
+#[derive(Clone)]
+pub struct FileAttr {
+    size: u64,
+}
+
+pub struct ReadDir(!);
+
+pub struct DirEntry(!);
+
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FilePermissions {
+    readonly: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FileType {
+    is_dir: bool,
+}
+
+#[derive(Debug)]
+pub struct DirBuilder {}
+
+impl FileAttr {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn perm(&self) -> FilePermissions {
+        FilePermissions { readonly: false }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType { is_dir: false }
+    }
+
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        unsupported()
+    }
+
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        unsupported()
+    }
+
+    pub fn created(&self) -> io::Result<SystemTime> {
+        unsupported()
+    }
+}
+
+impl FilePermissions {
+    pub fn readonly(&self) -> bool {
+        self.readonly
+    }
+
+    pub fn set_readonly(&mut self, readonly: bool) {
+        self.readonly = readonly
+    }
+}
+
+impl FileType {
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        false
+    }
+}
+
+impl fmt::Debug for ReadDir {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<DirEntry>;
+
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        self.0
+    }
+}
+
+impl DirEntry {
+    pub fn path(&self) -> PathBuf {
+        self.0
+    }
+
+    pub fn file_name(&self) -> OsString {
+        self.0
+    }
+
+    pub fn metadata(&self) -> io::Result<FileAttr> {
+        self.0
+    }
+
+    pub fn file_type(&self) -> io::Result<FileType> {
+        self.0
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+        }
+    }
+
+    pub fn read(&mut self, read: bool) {
+        self.read = read;
+    }
+    pub fn write(&mut self, write: bool) {
+        self.write = write;
+    }
+    pub fn append(&mut self, append: bool) {
+        self.append = append;
+    }
+    pub fn truncate(&mut self, truncate: bool) {
+        self.truncate = truncate;
+    }
+    pub fn create(&mut self, create: bool) {
+        self.create = create;
+    }
+    pub fn create_new(&mut self, create_new: bool) {
+        self.create_new = create_new;
+    }
+}
+
+impl File {
+    pub fn open(path: &Path, opts: &OpenOptions) -> io::Result<File> {
+        let path = host::path_bytes(path);
+        let write = opts.write || opts.append;
+        let create_or_truncate = if opts.read { "w+b" } else { "wb" };
+
+        // The modes of `io.open` writing to a file always create it
+        if opts.create_new {
+            if File::open_mode(path, "rb").is_ok() {
+                return Err(io::Error::new_const(
+                    io::ErrorKind::AlreadyExists,
+                    &"file already exists",
+                ));
+            }
+        } else if write && !opts.create {
+            File::open_mode(path, "rb")?;
+        }
+
+        let mode = if opts.append {
+            if opts.read { "a+b" } else { "ab" }
+        } else if write && (opts.truncate || opts.create_new) {
+            create_or_truncate
+        } else if write {
+            // "r+b" keeps the contents of the file but doesn't create it
+            match File::open_mode(path, "r+b") {
+                Err(err) if opts.create && err.kind() == io::ErrorKind::NotFound => {
+                    create_or_truncate
+                }
+                res => return res,
+            }
+        } else if opts.read {
+            "rb"
+        } else {
+            return Err(io::Error::new_const(io::ErrorKind::InvalidInput, &"invalid access mode"));
+        };
+        File::open_mode(path, mode)
+    }
+
+    fn open_mode(path: &[u8], mode: &str) -> io::Result<File> {
+        // SAFETY: the runtime only reads the path and the mode
+        let fd = host::cvt(unsafe {
+            host::__lua_open(path.as_ptr(), path.len(), mode.as_ptr(), mode.len())
+        })?;
+        Ok(File { fd: fd as i32 })
+    }
+
+    pub fn file_attr(&self) -> io::Result<FileAttr> {
+        let pos = self.seek(SeekFrom::Current(0))?;
+        let size = self.seek(SeekFrom::End(0))?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(FileAttr { size })
+    }
+
+    pub fn fsync(&self) -> io::Result<()> {
+        self.flush()
+    }
+
+    pub fn datasync(&self) -> io::Result<()> {
+        self.flush()
+    }
+
+    pub fn truncate(&self, _size: u64) -> io::Result<()> {
+        unsupported()
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: the runtime writes at most `buf.len()` bytes
+        host::cvt(unsafe { host::__lua_read(self.fd, buf.as_mut_ptr(), buf.len()) })
+    }
+
+    pub fn read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        crate::io::default_read_vectored(|buf| self.read(buf), bufs)
+    }
+
+    pub fn is_read_vectored(&self) -> bool {
+        false
+    }
+
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: the runtime only reads the buffer
+        host::cvt(unsafe { host::__lua_write(self.fd, buf.as_ptr(), buf.len()) })
+    }
+
+    pub fn write_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        crate::io::default_write_vectored(|buf| self.write(buf), bufs)
+    }
+
+    pub fn is_write_vectored(&self) -> bool {
+        false
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        // SAFETY: the runtime functions have no preconditions
+        host::cvt(unsafe { host::__lua_flush(self.fd) }).map(drop)
+    }
+
+    pub fn seek(&self, pos: SeekFrom) -> io::Result<u64> {
+        let (whence, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (1, offset),
+            SeekFrom::End(offset) => (2, offset),
+        };
+        // SAFETY: the runtime functions have no preconditions
+        host::cvt64(unsafe { host::__lua_seek(self.fd, whence, offset) })
+    }
+
+    pub fn duplicate(&self) -> io::Result<File> {
+        unsupported()
+    }
+
+    pub fn set_permissions(&self, _perm: FilePermissions) -> io::Result<()> {
+        unsupported()
+    }
+
+    /// Files can't be used as the stdio of a process, which can't be spawned anyway.
+    pub fn diverge(&self) -> ! {
+        panic!("processes are not supported on this platform")
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // SAFETY: the file is not used anymore
+        unsafe {
+            host::__lua_close(self.fd);
+        }
+    }
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("File").field("fd", &self.fd).finish()
+    }
+}
+
+impl DirBuilder {
+    pub fn new() -> DirBuilder {
+        DirBuilder {}
+    }
+
+    pub fn mkdir(&self, _p: &Path) -> io::Result<()> {
+        unsupported()
+    }
+}
+
+pub fn readdir(_p: &Path) -> io::Result<ReadDir> {
+    unsupported()
+}
+
+pub fn unlink(p: &Path) -> io::Result<()> {
+    let p = host::path_bytes(p);
+    // SAFETY: the runtime only reads the path
+    host::cvt(unsafe { host::__lua_remove(p.as_ptr(), p.len()) }).map(drop)
+}
+
+pub fn rename(old: &Path, new: &Path) -> io::Result<()> {
+    let old = host::path_bytes(old);
+    let new = host::path_bytes(new);
+    // SAFETY: the runtime only reads the paths
+    host::cvt(unsafe { host::__lua_rename(old.as_ptr(), old.len(), new.as_ptr(), new.len()) })
+        .map(drop)
+}
+
+pub fn set_perm(_p: &Path, _perm: FilePermissions) -> io::Result<()> {
+    unsupported()
+}
+
+/// `os.remove` removes empty directories too.
+pub fn rmdir(p: &Path) -> io::Result<()> {
+    unlink(p)
+}
+
+pub fn stat(p: &Path) -> io::Result<FileAttr> {
+    let mut opts = OpenOptions::new();
+    opts.read(true);
+    File::open(p, &opts)?.file_attr()
+}
+
+pub fn lstat(p: &Path) -> io::Result<FileAttr> {
+    stat(p)
+}
+
+pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
+    let mut reader_opts = OpenOptions::new();
+    reader_opts.read(true);
+    let reader = File::open(from, &reader_opts)?;
+    let mut writer_opts = OpenOptions::new();
+    writer_opts.write(true);
+    writer_opts.create(true);
+    writer_opts.truncate(true);
+    let writer = File::open(to, &writer_opts)?;
+
+    let mut buf = [0; 8 * 1024];
+    let mut written = 0;
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok(written);
+        }
+        let mut chunk = &buf[..len];
+        while !chunk.is_empty() {
+            let n = writer.write(chunk)?;
+            chunk = &chunk[n..];
+        }
+        written += len as u64;
+    }
+}
@@ -0,0 +1,57 @@
+//! Functions of the runtime of cg_lua giving access to the host
+//!
+//! Failures return a negated `errno` value. Functions producing a string return its length and
+//! the string is then copied to memory by `__lua_result`.
+
+use crate::io;
+use crate::path::Path;
+use crate::sys_common::AsInner;
+
+extern "C" {
+    pub fn __lua_result(buf: *mut u8);
+
+    pub fn __lua_alloc(size: usize, align: usize) -> *mut u8;
+    pub fn __lua_dealloc(ptr: *mut u8, size: usize);
+
+    pub fn __lua_argc() -> usize;
+    pub fn __lua_arg(i: usize) -> isize;
+    pub fn __lua_getenv(name: *const u8, name_len: usize) -> isize;
+    pub fn __lua_strerror(code: i32) -> isize;
+
+    pub fn __lua_open(path: *const u8, path_len: usize, mode: *const u8, mode_len: usize)
+    -> isize;
+    pub fn __lua_close(fd: i32) -> isize;
+    pub fn __lua_read(fd: i32, buf: *mut u8, len: usize) -> isize;
+    pub fn __lua_write(fd: i32, buf: *const u8, len: usize) -> isize;
+    pub fn __lua_flush(fd: i32) -> isize;
+    pub fn __lua_seek(fd: i32, whence: i32, offset: i64) -> i64;
+    pub fn __lua_remove(path: *const u8, path_len: usize) -> isize;
+    pub fn __lua_rename(from: *const u8, from_len: usize, to: *const u8, to_len: usize) -> isize;
+
+    pub fn __lua_clock() -> f64;
+    pub fn __lua_time() -> i64;
+    pub fn __lua_exit(code: i32) -> !;
+}
+
+pub fn cvt(ret: isize) -> io::Result<usize> {
+    if ret < 0 { Err(io::Error::from_raw_os_error(-ret as i32)) } else { Ok(ret as usize) }
+}
+
+pub fn cvt64(ret: i64) -> io::Result<u64> {
+    if ret < 0 { Err(io::Error::from_raw_os_error(-ret as i32)) } else { Ok(ret as u64) }
+}
+
+/// Copies the string of length `len` produced by the last call.
+pub fn take_result(len: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(len);
+    // SAFETY: the runtime writes `len` bytes to the buffer
+    unsafe {
+        __lua_result(buf.as_mut_ptr());
+        buf.set_len(len);
+    }
+    buf
+}
+
+pub fn path_bytes(path: &Path) -> &[u8] {
+    &path.as_os_str().as_inner().inner
+}
@@ -0,0 +1,61 @@
+//! System bindings for the `lua` target of cg_lua
+//!
+//! This module is copied to `library/std/src/sys/lua` by `./y.rs prepare`. The host is reached
+//! through functions of the runtime of cg_lua declared in `host`, which implement printing, the
+//! arguments, environment variables, time, files and exiting with the standard library of lua.
+//! Everything else is unsupported like on `wasm32-unknown-unknown`.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+pub mod alloc;
+pub mod args;
+pub mod cmath;
+#[path = "../unsupported/condvar.rs"]
+pub mod condvar;
+pub mod env;
+pub mod fs;
+mod host;
+#[path = "../unsupported/io.rs"]
+pub mod io;
+#[path = "../unsupported/mutex.rs"]
+pub mod mutex;
+#[path = "../unsupported/net.rs"]
+pub mod net;
+pub mod os;
+#[path = "../unix/path.rs"]
+pub mod path;
+#[path = "../unsupported/pipe.rs"]
+pub mod pipe;
+#[path = "../unsupported/process.rs"]
+pub mod process;
+#[path = "../unsupported/rwlock.rs"]
+pub mod rwlock;
+pub mod stdio;
+#[path = "../unsupported/thread.rs"]
+pub mod thread;
+pub mod thread_local_dtor;
+#[path = "../unsupported/thread_local_key.rs"]
+pub mod thread_local_key;
+pub mod time;
+
+pub use crate::sys_common::os_str_bytes as os_str;
+
+#[path = "../unsupported/common.rs"]
+mod common;
+pub use common::*;
+
+/// Error codes come from the C library of the host, which agrees on these `errno` values on the
+/// platforms lua usually runs on.
+pub fn decode_error_kind(code: i32) -> crate::io::ErrorKind {
+    use crate::io::ErrorKind;
+
+    match code {
+        1 | 13 => ErrorKind::PermissionDenied,
+        2 => ErrorKind::NotFound,
+        4 => ErrorKind::Interrupted,
+        12 => ErrorKind::OutOfMemory,
+        17 => ErrorKind::AlreadyExists,
+        22 => ErrorKind::InvalidInput,
+        _ => ErrorKind::Other,
+    }
+}
@@ -0,0 +1,43 @@
+//! Environment variables and exiting go through the runtime, the rest of the process environment
+//! is unsupported.
+
+use super::host;
+// Used by `unsupported_os`
+#[allow(unused_imports)]
+use super::unsupported;
+use crate::ffi::{OsStr, OsString};
+use crate::io;
+use crate::sys::os_str::Buf;
+use crate::sys_common::{AsInner, FromInner};
+
+#[path = "../unsupported/os.rs"]
+mod unsupported_os;
+pub use unsupported_os::*;
+
+pub fn errno() -> i32 {
+    0
+}
+
+pub fn error_string(errno: i32) -> String {
+    // SAFETY: the runtime functions have no preconditions
+    let len = unsafe { host::__lua_strerror(errno) };
+    if len < 0 {
+        return "unknown error".to_string();
+    }
+    String::from_utf8_lossy(&host::take_result(len as usize)).into_owned()
+}
+
+pub fn getenv(key: &OsStr) -> io::Result<Option<OsString>> {
+    let key = &key.as_inner().inner;
+    // SAFETY: the runtime only reads the key
+    let len = unsafe { host::__lua_getenv(key.as_ptr(), key.len()) };
+    if len < 0 {
+        return Ok(None);
+    }
+    Ok(Some(OsString::from_inner(Buf { inner: host::take_result(len as usize) })))
+}
+
+pub fn exit(code: i32) -> ! {
+    // SAFETY: the runtime functions have no preconditions
+    unsafe { host::__lua_exit(code) }
+}
@@ -0,0 +1,68 @@
+use super::host;
+use crate::io;
+
+const EBADF: i32 = 9;
+
+pub struct Stdin;
+pub struct Stdout;
+pub struct Stderr;
+
+impl Stdin {
+    pub const fn new() -> Stdin {
+        Stdin
+    }
+}
+
+impl io::Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: the runtime writes at most `buf.len()` bytes
+        host::cvt(unsafe { host::__lua_read(0, buf.as_mut_ptr(), buf.len()) })
+    }
+}
+
+impl Stdout {
+    pub const fn new() -> Stdout {
+        Stdout
+    }
+}
+
+impl io::Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: the runtime only reads the buffer
+        host::cvt(unsafe { host::__lua_write(1, buf.as_ptr(), buf.len()) })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // SAFETY: the runtime functions have no preconditions
+        host::cvt(unsafe { host::__lua_flush(1) }).map(drop)
+    }
+}
+
+impl Stderr {
+    pub const fn new() -> Stderr {
+        Stderr
+    }
+}
+
+impl io::Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: the runtime only reads the buffer
+        host::cvt(unsafe { host::__lua_write(2, buf.as_ptr(), buf.len()) })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // SAFETY: the runtime functions have no preconditions
+        host::cvt(unsafe { host::__lua_flush(2) }).map(drop)
+    }
+}
+
+pub const STDIN_BUF_SIZE: usize = 8 * 1024;
+
+/// Hosts without the io library report all standard streams as closed.
+pub fn is_ebadf(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(EBADF)
+}
+
+pub fn panic_output() -> Option<impl io::Write> {
+    Some(Stderr::new())
+}
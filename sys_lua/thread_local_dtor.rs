@@ -0,0 +1,17 @@
+#![unstable(feature = "thread_local_internals", issue = "none")]
+
+// The runtime of cg_lua runs the destructors when the main function returns
+pub unsafe fn register_dtor(t: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
+    extern "C" {
+        fn __cxa_thread_atexit_impl(
+            dtor: unsafe extern "C" fn(*mut u8),
+            arg: *mut u8,
+            dso_handle: *mut u8,
+        ) -> i32;
+    }
+
+    // SAFETY: the runtime keeps `t` until it calls `dtor`
+    unsafe {
+        __cxa_thread_atexit_impl(dtor, t, crate::ptr::null_mut());
+    }
+}
@@ -37,13 +37,19 @@ mod config;
 mod prepare;
 #[path = "build_system/rustc_info.rs"]
 mod rustc_info;
+#[path = "build_system/tests.rs"]
+mod tests;
 #[path = "build_system/utils.rs"]
 mod utils;
 
+/// The target spec shipped in `target_specs`, used when no target is configured.
+const LUA_TARGET: &str = "lua-unknown-none";
+
 fn usage() {
     eprintln!("Usage:");
     eprintln!("  ./y.rs prepare");
     eprintln!("  ./y.rs build [--debug] [--sysroot none|lua|llvm] [--target-dir DIR]");
+    eprintln!("  ./y.rs test [--debug] [--sysroot none|lua|llvm] [--target-dir DIR]");
 }
 
 macro_rules! arg_error {
@@ -56,6 +62,7 @@ macro_rules! arg_error {
 
 enum Command {
     Build,
+    Test,
 }
 
 #[derive(Copy, Clone)]
@@ -72,7 +79,7 @@ fn main() {
     env::set_var("CARGO_TARGET_DIR", "target");
 
     let mut args = env::args().skip(1);
-    let _command = match args.next().as_deref() {
+    let command = match args.next().as_deref() {
         Some("prepare") => {
             if args.next().is_some() {
                 arg_error!("./x.rs prepare doesn't expect arguments");
@@ -81,6 +88,7 @@ fn main() {
             process::exit(0);
         }
         Some("build") => Command::Build,
+        Some("test") => Command::Test,
         Some(flag) if flag.starts_with('-') => arg_error!("Expected command found flag {}", flag),
         Some(command) => arg_error!("Unknown command {}", command),
         None => {
@@ -125,19 +133,17 @@ fn main() {
         if target_triple != "" {
             target_triple
         } else {
-            host_triple.clone() // Empty target triple can happen on GHA
+            LUA_TARGET.to_string() // Empty target triple can happen on GHA
         }
     } else if let Some(target_triple) = crate::config::get_value("target") {
         target_triple
     } else {
-        host_triple.clone()
+        LUA_TARGET.to_string()
     };
 
-    if target_triple.ends_with("-msvc") {
-        eprintln!("The MSVC toolchain is not yet supported by rustc_codegen_lua.");
-        eprintln!("Switch to the MinGW toolchain for Windows support.");
-        eprintln!("Hint: You can use `rustup set default-host x86_64-pc-windows-gnu` to");
-        eprintln!("set the global default target to MinGW");
+    if target_triple == LUA_TARGET && matches!(sysroot_kind, SysrootKind::Llvm) {
+        eprintln!("The LLVM sysroot only exists for the targets of rustc, not for {}.", LUA_TARGET);
+        eprintln!("Hint: Use `--sysroot lua` to build a sysroot for {}", LUA_TARGET);
         process::exit(1);
     }
 
@@ -150,4 +156,7 @@ fn main() {
         &host_triple,
         &target_triple,
     );
+    if let Command::Test = command {
+        tests::run_tests(channel, &target_dir, &host_triple, &target_triple);
+    }
 }